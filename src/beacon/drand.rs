@@ -2,25 +2,34 @@
 // SPDX-License-Identifier: Apache-2.0, MIT
 
 use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead as _, BufReader, ErrorKind, Write as _};
+use std::path::{Path, PathBuf};
 
 use crate::shim::clock::ChainEpoch;
 use crate::shim::version::NetworkVersion;
+use crate::utils::io::write_atomically;
 use crate::utils::net::global_http_client;
 use ahash::HashMap;
 use anyhow::Context as _;
 use async_trait::async_trait;
 use bls_signatures::{PublicKey, Serialize, Signature};
-use byteorder::{BigEndian, WriteBytesExt};
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use serde::{Deserialize as SerdeDeserialize, Serialize as SerdeSerialize};
 use sha2::Digest;
+use tracing::{debug, warn};
 
 use super::beacon_entries::BeaconEntry;
+use super::source::{BeaconSource, FetchedEntry, FileSource, GossipSource, HttpRelays};
 
 /// Environmental Variable to ignore `Drand`. Lotus parallel is
 /// `LOTUS_IGNORE_DRAND`
 pub const IGNORE_DRAND_VAR: &str = "IGNORE_DRAND";
 
+/// Number of the most recent entries kept by the on-disk cache, 30 days of 30-second rounds.
+const DISK_CACHE_ENTRIES: usize = 86_400;
+
 /// Coefficients of the publicly available `Drand` keys.
 /// This is shared by all participants on the `Drand` network.
 #[derive(Clone, Debug, SerdeSerialize, SerdeDeserialize)]
@@ -47,14 +56,39 @@ pub enum DrandNetwork {
 #[derive(Clone)]
 /// Configuration used when initializing a `Drand` beacon.
 pub struct DrandConfig<'a> {
-    /// URL endpoint to send JSON HTTP requests to.
-    pub server: &'static str,
+    /// HTTP relays to send JSON requests to, tried in turn.
+    pub servers: &'static [&'static str],
     /// Info about the beacon chain, used to verify correctness of endpoint.
     pub chain_info: ChainInfo<'a>,
     /// Network type
     pub network_type: DrandNetwork,
 }
 
+/// Sources of `drand` beacon entries, in addition to the HTTP relays of the network.
+#[derive(Debug, Clone, Default, PartialEq, Eq, SerdeSerialize, SerdeDeserialize)]
+#[cfg_attr(test, derive(derive_quickcheck_arbitrary::Arbitrary))]
+#[serde(default)]
+pub struct DrandSources {
+    /// HTTP relays of the `drand` mainnet used instead of the default ones.
+    pub relays: Vec<String>,
+    /// Directory of the on-disk cache of verified entries.
+    pub cache_dir: Option<PathBuf>,
+    /// Receive entries over the `drand` gossipsub topics.
+    pub gossip: bool,
+    /// JSON or CAR file of pre-fetched entries, for offline replays.
+    pub file: Option<PathBuf>,
+}
+
+impl DrandSources {
+    /// Checks that the custom relays are valid URLs.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !self.relays.is_empty() {
+            HttpRelays::new(self.relays.iter().map(String::as_str))?;
+        }
+        Ok(())
+    }
+}
+
 /// Contains the vector of `BeaconPoint`, which are mappings of epoch to the
 /// `Randomness` beacons used.
 pub struct BeaconSchedule(pub Vec<BeaconPoint>);
@@ -190,17 +224,162 @@ pub struct ChainInfo<'a> {
 /// API reference: <https://drand.love/developer/http-api/#public-round>.
 pub struct BeaconEntryJson {
     round: u64,
+    #[serde(default)]
     randomness: String,
     signature: String,
+    #[serde(default)]
     previous_signature: String,
 }
 
+impl BeaconEntryJson {
+    pub(super) fn into_entry(self) -> anyhow::Result<BeaconEntry> {
+        Ok(BeaconEntry::new(self.round, hex::decode(self.signature)?))
+    }
+
+    pub(super) fn into_fetched(self) -> anyhow::Result<FetchedEntry> {
+        let previous_signature = if self.previous_signature.is_empty() {
+            None
+        } else {
+            Some(hex::decode(&self.previous_signature)?)
+        };
+        Ok(FetchedEntry {
+            entry: self.into_entry()?,
+            previous_signature,
+        })
+    }
+}
+
+/// Verifies the signature of an entry of a chained beacon, which signs the hash of the signature
+/// of the previous round followed by the round.
+pub(super) fn verify_chained(
+    public_key: &PublicKey,
+    previous_signature: &[u8],
+    entry: &BeaconEntry,
+) -> bool {
+    let mut msg: Vec<u8> = Vec::with_capacity(previous_signature.len() + 8);
+    msg.extend_from_slice(previous_signature);
+    msg.extend_from_slice(&entry.round().to_be_bytes());
+    // H(prev sig | curr_round)
+    let digest = sha2::Sha256::digest(&msg);
+    Signature::from_bytes(entry.data()).map_or(false, |sig| {
+        bls_signatures::verify_messages(&sig, &[&digest], &[*public_key])
+    })
+}
+
+impl From<&FetchedEntry> for BeaconEntryJson {
+    fn from(fetched: &FetchedEntry) -> Self {
+        let entry = &fetched.entry;
+        BeaconEntryJson {
+            round: entry.round(),
+            randomness: hex::encode(sha2::Sha256::digest(entry.data())),
+            signature: hex::encode(entry.data()),
+            previous_signature: fetched
+                .previous_signature
+                .as_deref()
+                .map(hex::encode)
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Verified entries appended to a file as JSON lines, with the signatures of their previous
+/// rounds, so they aren't fetched again after a restart. The file is compacted to the `capacity`
+/// most recent entries when it's opened and once it has twice as many lines.
+pub(super) struct DiskCache {
+    path: PathBuf,
+    capacity: usize,
+    /// The file, opened for appending, and its number of lines.
+    file: Mutex<(File, usize)>,
+}
+
+impl DiskCache {
+    /// Opens the cache of the beacon chain `name` in `dir`, and returns the cached entries. The
+    /// entries are not verified, the file may have been tampered with.
+    pub(super) fn open(
+        dir: &Path,
+        name: &str,
+        capacity: usize,
+    ) -> anyhow::Result<(Self, Vec<FetchedEntry>)> {
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(format!("{name}.jsonl"));
+        let entries = Self::compact(&path, capacity)?;
+        let file = Self::open_append(&path)?;
+        debug!(
+            "Loaded {} drand entries from {}",
+            entries.len(),
+            path.display()
+        );
+        Ok((
+            DiskCache {
+                path,
+                capacity,
+                file: Mutex::new((file, entries.len())),
+            },
+            entries,
+        ))
+    }
+
+    fn open_append(path: &Path) -> anyhow::Result<File> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("couldn't open {}", path.display()))
+    }
+
+    /// Reads the `capacity` most recent entries of the file, and rewrites it with only those
+    /// when it has more lines.
+    fn compact(path: &Path, capacity: usize) -> anyhow::Result<Vec<FetchedEntry>> {
+        let lines = match File::open(path) {
+            Ok(file) => BufReader::new(file)
+                .lines()
+                .map_while(Result::ok)
+                .collect::<Vec<_>>(),
+            Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e).with_context(|| format!("couldn't open {}", path.display())),
+        };
+        // Lines may be truncated by an interruption, those are skipped
+        let mut entries = lines
+            .iter()
+            .filter_map(|line| serde_json::from_str::<BeaconEntryJson>(line).ok())
+            .filter_map(|json| json.into_fetched().ok())
+            .map(|fetched| (fetched.entry.round(), fetched))
+            .collect::<BTreeMap<_, _>>();
+        while entries.len() > capacity {
+            entries.pop_first();
+        }
+        if lines.len() > entries.len() {
+            let mut contents = vec![];
+            for entry in entries.values() {
+                serde_json::to_writer(&mut contents, &BeaconEntryJson::from(entry))?;
+                contents.push(b'\n');
+            }
+            write_atomically(path, contents)?;
+        }
+        Ok(entries.into_values().collect())
+    }
+
+    pub(super) fn insert(&self, entry: &FetchedEntry) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(&BeaconEntryJson::from(entry))?;
+        line.push(b'\n');
+        let mut file = self.file.lock();
+        file.0.write_all(&line)?;
+        file.1 += 1;
+        if file.1 >= 2 * self.capacity {
+            let entries = Self::compact(&self.path, self.capacity)?;
+            *file = (Self::open_append(&self.path)?, entries.len());
+        }
+        Ok(())
+    }
+}
+
 /// `Drand` randomness beacon that can be used to generate randomness for the
 /// Filecoin chain. Primary use is to satisfy the [Beacon] trait.
 pub struct DrandBeacon {
-    url: &'static str,
+    /// Sources of entries, tried in turn.
+    sources: Vec<Box<dyn BeaconSource>>,
 
-    pub_key: DrandPublic,
+    public_key: PublicKey,
     /// Interval between beacons, in seconds.
     interval: u64,
     drand_gen_time: u64,
@@ -209,17 +388,24 @@ pub struct DrandBeacon {
 
     /// Keeps track of computed beacon entries.
     local_cache: RwLock<HashMap<u64, BeaconEntry>>,
+    disk_cache: Option<DiskCache>,
 }
 
 impl DrandBeacon {
-    /// Construct a new `DrandBeacon`.
-    pub fn new(genesis_ts: u64, interval: u64, config: &DrandConfig<'_>) -> Self {
+    /// Construct a new `DrandBeacon`, fetching entries from the HTTP relays of `config` and from
+    /// `sources`.
+    pub fn new(
+        genesis_ts: u64,
+        interval: u64,
+        config: &DrandConfig<'_>,
+        sources: &DrandSources,
+    ) -> Self {
         assert_ne!(genesis_ts, 0, "Genesis timestamp cannot be 0");
 
         let chain_info = &config.chain_info;
 
         if cfg!(debug_assertions) && config.network_type == DrandNetwork::Mainnet {
-            let server = config.server;
+            let server = config.servers[0];
             let remote_chain_info = std::thread::spawn(move || {
                 let rt = tokio::runtime::Runtime::new()?;
                 rt.block_on(async {
@@ -241,17 +427,92 @@ impl DrandBeacon {
             debug_assert!(&remote_chain_info == chain_info);
         }
 
+        // Beacon chains are identified by their hash, or their public key if it's unknown
+        let name = if chain_info.hash.is_empty() {
+            &chain_info.public_key
+        } else {
+            &chain_info.hash
+        };
+        let mut beacon_sources: Vec<Box<dyn BeaconSource>> = vec![];
+        if let Some(file) = &sources.file {
+            beacon_sources.push(Box::new(FileSource::new(file.clone())));
+        }
+        let public_key = DrandPublic {
+            coefficient: hex::decode(chain_info.public_key.as_ref())
+                .expect("invalid static encoding of drand hex public key"),
+        }
+        .key()
+        .expect("invalid static encoding of drand public key");
+        if sources.gossip {
+            let gossip = GossipSource::for_chain(name);
+            gossip.set_public_key(public_key);
+            beacon_sources.push(Box::new(gossip));
+        }
+        // Custom relays are validated when the configuration is loaded
+        let relays = match config.network_type {
+            DrandNetwork::Mainnet if !sources.relays.is_empty() => {
+                HttpRelays::new(sources.relays.iter().map(String::as_str)).unwrap_or_else(|e| {
+                    warn!("Using the default drand relays: {e}");
+                    Self::default_relays(config)
+                })
+            }
+            _ => Self::default_relays(config),
+        };
+        beacon_sources.push(Box::new(relays));
+
+        let local_cache = RwLock::new(HashMap::default());
+        let disk_cache = sources.cache_dir.as_ref().and_then(|dir| {
+            match DiskCache::open(dir, name, DISK_CACHE_ENTRIES) {
+                Ok((disk_cache, entries)) => {
+                    let loaded = entries.len();
+                    local_cache.write().extend(
+                        entries
+                            .into_iter()
+                            .filter(|fetched| fetched.verify(&public_key))
+                            .map(|fetched| (fetched.entry.round(), fetched.entry)),
+                    );
+                    let dropped = loaded - local_cache.read().len();
+                    if dropped > 0 {
+                        warn!("Dropped {dropped} invalid drand entries from the cache");
+                    }
+                    Some(disk_cache)
+                }
+                Err(e) => {
+                    warn!("Failed to open the drand cache: {e}");
+                    None
+                }
+            }
+        });
+
         Self {
-            url: config.server,
-            pub_key: DrandPublic {
-                coefficient: hex::decode(chain_info.public_key.as_ref())
-                    .expect("invalid static encoding of drand hex public key"),
-            },
+            sources: beacon_sources,
+            public_key,
             interval: chain_info.period as u64,
             drand_gen_time: chain_info.genesis_time as u64,
             fil_round_time: interval,
             fil_gen_time: genesis_ts,
-            local_cache: Default::default(),
+            local_cache,
+            disk_cache,
+        }
+    }
+
+    fn default_relays(config: &DrandConfig<'_>) -> HttpRelays {
+        HttpRelays::new(config.servers.iter().copied()).expect("invalid static drand relays")
+    }
+
+    /// Caches a verified entry.
+    fn cache(&self, fetched: &FetchedEntry) {
+        let entry = &fetched.entry;
+        if self.local_cache.read().contains_key(&entry.round()) {
+            return;
+        }
+        self.local_cache
+            .write()
+            .insert(entry.round(), entry.clone());
+        if let Some(disk_cache) = &self.disk_cache {
+            if let Err(e) = disk_cache.insert(fetched) {
+                warn!("Failed to cache drand round {}: {e}", entry.round());
+            }
         }
     }
 }
@@ -264,20 +525,14 @@ impl Beacon for DrandBeacon {
             return Ok(true);
         }
 
-        // Hash the messages
-        let mut msg: Vec<u8> = Vec::with_capacity(104);
-        msg.extend_from_slice(prev.data());
-        msg.write_u64::<BigEndian>(curr.round())?;
-        // H(prev sig | curr_round)
-        let digest = sha2::Sha256::digest(&msg);
-        // Signature
-        let sig = Signature::from_bytes(curr.data())?;
-        let sig_match = bls_signatures::verify_messages(&sig, &[&digest], &[self.pub_key.key()?]);
+        let sig_match = verify_chained(&self.public_key, prev.data(), curr);
 
         // Cache the result
-        let contains_curr = self.local_cache.read().contains_key(&curr.round());
-        if sig_match && !contains_curr {
-            self.local_cache.write().insert(curr.round(), curr.clone());
+        if sig_match {
+            self.cache(&FetchedEntry {
+                entry: curr.clone(),
+                previous_signature: Some(prev.data().to_vec()),
+            });
         }
         Ok(sig_match)
     }
@@ -287,15 +542,31 @@ impl Beacon for DrandBeacon {
         match cached {
             Some(cached_entry) => Ok(cached_entry),
             None => {
-                let client = global_http_client();
-                let resp: BeaconEntryJson = client
-                    .get(format!("{}/public/{}", self.url, round))
-                    .send()
-                    .await?
-                    .error_for_status()?
-                    .json()
-                    .await?;
-                Ok(BeaconEntry::new(resp.round, hex::decode(resp.signature)?))
+                let mut last_error = None;
+                for source in &self.sources {
+                    match source.fetch(round).await {
+                        // Forged or corrupt entries fall back to the next source
+                        Ok(fetched) if fetched.verify(&self.public_key) => {
+                            self.cache(&fetched);
+                            return Ok(fetched.entry);
+                        }
+                        Ok(_) => {
+                            warn!("Invalid drand round {round} from {}", source.name());
+                            last_error = Some(anyhow::anyhow!(
+                                "invalid drand round {round} from {}",
+                                source.name()
+                            ));
+                        }
+                        Err(e) => {
+                            debug!(
+                                "drand round {round} not fetched from {}: {e}",
+                                source.name()
+                            );
+                            last_error = Some(e);
+                        }
+                    }
+                }
+                Err(last_error.context("no drand sources")?)
             }
         }
     }
@@ -5,9 +5,11 @@ pub mod beacon_entries;
 mod drand;
 #[cfg(test)]
 pub mod mock_beacon;
+mod source;
 
 pub use beacon_entries::*;
 pub use drand::*;
+pub use source::GossipSource;
 #[cfg(test)]
 mod tests {
     mod drand;
@@ -0,0 +1,364 @@
+// Copyright 2019-2023 ChainSafe Systems
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+//! Sources of `drand` beacon entries. Entries are fetched from HTTP relays by default, and may
+//! also come from the `drand` gossipsub topics or from a file of pre-fetched entries.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use ahash::HashMap;
+use anyhow::{bail, ensure, Context as _};
+use async_trait::async_trait;
+use bls_signatures::PublicKey;
+use futures::TryStreamExt as _;
+use once_cell::sync::Lazy;
+use parking_lot::{Mutex, RwLock};
+use tokio::io::AsyncBufReadExt as _;
+use tokio::sync::OnceCell;
+use tracing::{debug, warn};
+use url::Url;
+
+use super::beacon_entries::BeaconEntry;
+use super::drand::{verify_chained, BeaconEntryJson};
+use crate::blocks::BlockHeader;
+use crate::utils::db::car_stream::CarStream;
+use crate::utils::encoding::from_slice_with_fallback;
+use crate::utils::net::global_http_client;
+
+/// Timeout of the requests to a relay.
+const RELAY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest time a failing relay is skipped for.
+const MAX_RELAY_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// Number of the most recent entries received over gossip kept in memory.
+const GOSSIP_ENTRIES: usize = 1024;
+
+/// An entry fetched from a source, with the signature of the previous round it is chained to,
+/// when the source has it. Entries can't be verified without it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FetchedEntry {
+    pub entry: BeaconEntry,
+    pub previous_signature: Option<Vec<u8>>,
+}
+
+impl FetchedEntry {
+    /// Verifies the signature of the entry with the public key of its beacon chain.
+    pub fn verify(&self, public_key: &PublicKey) -> bool {
+        self.previous_signature
+            .as_deref()
+            .map_or(false, |previous_signature| {
+                verify_chained(public_key, previous_signature, &self.entry)
+            })
+    }
+}
+
+/// A source of the entries of a `drand` beacon chain. Entries are verified by the beacon, which
+/// falls back to the next source when they're invalid.
+#[async_trait]
+pub trait BeaconSource: Send + Sync {
+    /// Name of the source, for logs.
+    fn name(&self) -> String;
+
+    /// Returns the entry of `round`.
+    async fn fetch(&self, round: u64) -> anyhow::Result<FetchedEntry>;
+}
+
+/// An HTTP relay and its health.
+#[derive(Debug)]
+struct Relay {
+    url: Url,
+    /// Number of consecutive failures.
+    failures: AtomicU32,
+    /// The relay is skipped until then, unless all relays are failing.
+    retry_at: Mutex<Option<Instant>>,
+}
+
+impl Relay {
+    fn is_healthy(&self) -> bool {
+        self.retry_at
+            .lock()
+            .map_or(true, |retry_at| retry_at <= Instant::now())
+    }
+
+    fn succeeded(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+        *self.retry_at.lock() = None;
+    }
+
+    fn failed(&self) {
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        let backoff = Duration::from_secs(1 << failures.min(16)).min(MAX_RELAY_BACKOFF);
+        *self.retry_at.lock() = Some(Instant::now() + backoff);
+    }
+}
+
+/// HTTP relays of a `drand` beacon chain, fetching `/public/{round}`. Healthy relays are tried
+/// first, fewest recent failures first, and failing relays are backed off exponentially.
+#[derive(Debug)]
+pub struct HttpRelays {
+    relays: Vec<Relay>,
+}
+
+impl HttpRelays {
+    pub fn new<'a>(urls: impl IntoIterator<Item = &'a str>) -> anyhow::Result<Self> {
+        let relays = urls
+            .into_iter()
+            .map(|url| {
+                Ok(Relay {
+                    url: Url::parse(url).with_context(|| format!("invalid drand relay {url}"))?,
+                    failures: AtomicU32::new(0),
+                    retry_at: Mutex::new(None),
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        anyhow::ensure!(!relays.is_empty(), "no drand relays");
+        Ok(HttpRelays { relays })
+    }
+
+    /// The relays in the order they should be tried.
+    fn ordered(&self) -> Vec<&Relay> {
+        let mut relays = self.relays.iter().collect::<Vec<_>>();
+        relays.sort_by_key(|relay| (!relay.is_healthy(), relay.failures.load(Ordering::Relaxed)));
+        relays
+    }
+
+    async fn fetch_from(relay: &Relay, round: u64) -> anyhow::Result<FetchedEntry> {
+        let url = format!(
+            "{}/public/{round}",
+            relay.url.as_str().trim_end_matches('/')
+        );
+        let resp: BeaconEntryJson = global_http_client()
+            .get(url)
+            .timeout(RELAY_TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        resp.into_fetched()
+    }
+}
+
+#[async_trait]
+impl BeaconSource for HttpRelays {
+    fn name(&self) -> String {
+        "HTTP relays".into()
+    }
+
+    async fn fetch(&self, round: u64) -> anyhow::Result<FetchedEntry> {
+        let mut last_error = None;
+        for relay in self.ordered() {
+            match Self::fetch_from(relay, round).await {
+                Ok(entry) => {
+                    relay.succeeded();
+                    return Ok(entry);
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to fetch drand round {round} from {}: {e}",
+                        relay.url
+                    );
+                    relay.failed();
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error
+            .unwrap_or_else(|| anyhow::anyhow!("no drand relays"))
+            .context(format!("all drand relays failed to serve round {round}")))
+    }
+}
+
+/// Entries received over the `drand` gossipsub topic of a beacon chain. The libp2p service
+/// inserts them into the source shared by all the beacons of the chain, which only keeps the
+/// entries signed by the chain once a beacon has set its public key.
+#[derive(Debug, Default)]
+pub struct GossipSource {
+    public_key: once_cell::sync::OnceCell<PublicKey>,
+    entries: RwLock<BTreeMap<u64, FetchedEntry>>,
+}
+
+static GOSSIP_SOURCES: Lazy<Mutex<HashMap<String, Arc<GossipSource>>>> =
+    Lazy::new(Default::default);
+
+impl GossipSource {
+    /// The source of the beacon chain with hash `chain_hash`.
+    pub fn for_chain(chain_hash: &str) -> Arc<Self> {
+        GOSSIP_SOURCES
+            .lock()
+            .entry(chain_hash.into())
+            .or_default()
+            .clone()
+    }
+
+    /// The gossipsub topic of the beacon chain with hash `chain_hash`.
+    pub fn topic(chain_hash: &str) -> String {
+        format!("/drand/pubsub/v0.0.0/{chain_hash}")
+    }
+
+    /// Sets the public key entries are verified with.
+    pub fn set_public_key(&self, public_key: PublicKey) {
+        // All the beacons of a chain share its public key
+        let _ = self.public_key.set(public_key);
+    }
+
+    /// Inserts an entry if it's signed by the beacon chain. Since future rounds can't be forged,
+    /// evicting the lowest rounds only evicts the oldest entries.
+    pub fn insert(&self, fetched: FetchedEntry) -> anyhow::Result<()> {
+        let public_key = self
+            .public_key
+            .get()
+            .context("public key of the drand chain unknown")?;
+        let round = fetched.entry.round();
+        ensure!(
+            fetched.verify(public_key),
+            "invalid signature of drand round {round}"
+        );
+        let mut entries = self.entries.write();
+        entries.insert(round, fetched);
+        while entries.len() > GOSSIP_ENTRIES {
+            entries.pop_first();
+        }
+        Ok(())
+    }
+
+    /// Decodes a gossip message, a `drand.PublicRandResponse` protocol buffer.
+    pub fn decode(message: &[u8]) -> anyhow::Result<FetchedEntry> {
+        let mut input = protobuf::CodedInputStream::from_bytes(message);
+        let (mut round, mut signature, mut previous_signature) = (None, None, None);
+        while let Some(tag) = input.read_raw_tag_or_eof()? {
+            match tag {
+                // round = 1
+                8 => round = Some(input.read_uint64()?),
+                // signature = 2
+                18 => signature = Some(input.read_bytes()?),
+                // previous_signature = 3
+                26 => previous_signature = Some(input.read_bytes()?),
+                _ => input.skip_field(
+                    protobuf::rt::WireType::new(tag & 7).context("invalid wire type")?,
+                )?,
+            }
+        }
+        match (round, signature) {
+            (Some(round), Some(signature)) => Ok(FetchedEntry {
+                entry: BeaconEntry::new(round, signature),
+                previous_signature,
+            }),
+            _ => bail!("incomplete drand gossip message"),
+        }
+    }
+}
+
+#[async_trait]
+impl BeaconSource for Arc<GossipSource> {
+    fn name(&self) -> String {
+        "gossip".into()
+    }
+
+    async fn fetch(&self, round: u64) -> anyhow::Result<FetchedEntry> {
+        self.entries
+            .read()
+            .get(&round)
+            .cloned()
+            .with_context(|| format!("drand round {round} not received over gossip"))
+    }
+}
+
+/// Pre-fetched entries, for offline replays. The file is either a JSON array of entries in the
+/// format of the `drand` HTTP API, or a CAR archive (a snapshot for instance) of which the
+/// beacon entries of the block headers are used, chained to the entries of the previous rounds.
+/// It is loaded on first use.
+#[derive(Debug)]
+pub struct FileSource {
+    path: PathBuf,
+    entries: OnceCell<HashMap<u64, FetchedEntry>>,
+}
+
+impl FileSource {
+    pub fn new(path: PathBuf) -> Self {
+        FileSource {
+            path,
+            entries: OnceCell::new(),
+        }
+    }
+
+    async fn load(&self) -> anyhow::Result<HashMap<u64, FetchedEntry>> {
+        let file = tokio::fs::File::open(&self.path)
+            .await
+            .with_context(|| format!("couldn't open {}", self.path.display()))?;
+        let mut reader = tokio::io::BufReader::new(file);
+        let is_json = reader
+            .fill_buf()
+            .await?
+            .iter()
+            .find(|b| !b.is_ascii_whitespace())
+            == Some(&b'[');
+        let entries: HashMap<_, _> = if is_json {
+            let bytes = tokio::fs::read(&self.path).await?;
+            serde_json::from_slice::<Vec<BeaconEntryJson>>(&bytes)?
+                .into_iter()
+                .map(|json| {
+                    json.into_fetched()
+                        .map(|fetched| (fetched.entry.round(), fetched))
+                })
+                .collect::<anyhow::Result<_>>()?
+        } else {
+            let mut entries = BTreeMap::new();
+            let mut stream = CarStream::new(reader).await?;
+            while let Some(block) = stream.try_next().await? {
+                if block.cid.codec() != fvm_ipld_encoding::DAG_CBOR {
+                    continue;
+                }
+                if let Ok(header) = from_slice_with_fallback::<BlockHeader>(&block.data) {
+                    for entry in header.beacon_entries() {
+                        entries.insert(entry.round(), entry.clone());
+                    }
+                }
+            }
+            // Entries of consecutive rounds are chained together
+            entries
+                .iter()
+                .map(|(&round, entry)| {
+                    let previous_signature = round
+                        .checked_sub(1)
+                        .and_then(|previous| entries.get(&previous))
+                        .map(|previous| previous.data().to_vec());
+                    (
+                        round,
+                        FetchedEntry {
+                            entry: entry.clone(),
+                            previous_signature,
+                        },
+                    )
+                })
+                .collect()
+        };
+        debug!(
+            "Loaded {} drand entries from {}",
+            entries.len(),
+            self.path.display()
+        );
+        Ok(entries)
+    }
+}
+
+#[async_trait]
+impl BeaconSource for FileSource {
+    fn name(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    async fn fetch(&self, round: u64) -> anyhow::Result<FetchedEntry> {
+        self.entries
+            .get_or_try_init(|| self.load())
+            .await?
+            .get(&round)
+            .cloned()
+            .with_context(|| format!("no drand round {round} in {}", self.path.display()))
+    }
+}
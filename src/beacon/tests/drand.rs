@@ -1,15 +1,26 @@
 // Copyright 2019-2023 ChainSafe Systems
 // SPDX-License-Identifier: Apache-2.0, MIT
 
-use crate::beacon::{Beacon, ChainInfo, DrandBeacon, DrandConfig};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::beacon::drand::DiskCache;
+use crate::beacon::source::{BeaconSource as _, FetchedEntry, HttpRelays};
+use crate::beacon::{
+    Beacon, BeaconEntry, ChainInfo, DrandBeacon, DrandConfig, DrandNetwork, DrandSources,
+    GossipSource,
+};
+use bls_signatures::Serialize as _;
+use byteorder::{BigEndian, WriteBytesExt as _};
 use serde::{Deserialize, Serialize};
+use sha2::Digest as _;
 
 fn new_beacon() -> DrandBeacon {
     DrandBeacon::new(
         15904451751,
         25,
         &DrandConfig {
-            server: "https://pl-us.incentinet.drand.sh",
+            servers: &["https://pl-us.incentinet.drand.sh"],
             chain_info: ChainInfo {
                 public_key: "922a2e93828ff83345bae533f5172669a26c02dc76d6bf59c80892e12ab1455c229211886f35bb56af6d5bea981024df"
                     .into(),
@@ -17,6 +28,7 @@ fn new_beacon() -> DrandBeacon {
             },
             network_type: crate::beacon::DrandNetwork::Incentinet,
         },
+        &Default::default(),
     )
 }
 
@@ -41,3 +53,258 @@ async fn ask_and_verify_beacon_entry_fail() {
     let e3 = beacon.entry(3).await.unwrap();
     assert!(!beacon.verify_entry(&e2, &e3).unwrap());
 }
+
+/// Serves rounds, or fails with `500 Internal Server Error`, and counts requests.
+fn create_relay(fail: bool) -> (String, Arc<AtomicUsize>) {
+    use axum::{extract::Path, http::StatusCode, routing::get, Json, Router};
+
+    let requests = Arc::new(AtomicUsize::new(0));
+    let counter = requests.clone();
+    let app = Router::new().route(
+        "/public/:round",
+        get(move |Path(round): Path<u64>| async move {
+            counter.fetch_add(1, Ordering::Relaxed);
+            if fail {
+                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+            Ok(Json(serde_json::json!({
+                "round": round,
+                "randomness": "",
+                "signature": "abcd",
+                "previous_signature": "",
+            })))
+        }),
+    );
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(
+        axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(app.into_make_service()),
+    );
+    (url, requests)
+}
+
+#[tokio::test]
+async fn relays_failover() {
+    let (failing, failing_requests) = create_relay(true);
+    let (healthy, healthy_requests) = create_relay(false);
+    let relays = HttpRelays::new([failing.as_str(), healthy.as_str()]).unwrap();
+
+    let fetched = relays.fetch(5).await.unwrap();
+    assert_eq!(fetched.entry, BeaconEntry::new(5, vec![0xab, 0xcd]));
+    // The failing relay is backed off
+    relays.fetch(6).await.unwrap();
+    assert_eq!(failing_requests.load(Ordering::Relaxed), 1);
+    assert_eq!(healthy_requests.load(Ordering::Relaxed), 2);
+
+    let relays = HttpRelays::new([failing.as_str()]).unwrap();
+    assert!(relays.fetch(5).await.is_err());
+}
+
+/// Configuration of a chain signed with `key`, without reachable relays.
+fn local_config(key: &bls_signatures::PrivateKey) -> DrandConfig<'static> {
+    DrandConfig {
+        servers: &["http://127.0.0.1:1"],
+        chain_info: ChainInfo {
+            public_key: hex::encode(key.public_key().as_bytes()).into(),
+            ..Default::default()
+        },
+        network_type: DrandNetwork::Incentinet,
+    }
+}
+
+fn new_local_beacon(sources: &DrandSources) -> (DrandBeacon, bls_signatures::PrivateKey) {
+    let key = bls_signatures::PrivateKey::generate(&mut rand::thread_rng());
+    let beacon = DrandBeacon::new(15904451751, 25, &local_config(&key), sources);
+    (beacon, key)
+}
+
+fn sign(key: &bls_signatures::PrivateKey, prev: &BeaconEntry, round: u64) -> BeaconEntry {
+    let mut msg = prev.data().to_vec();
+    msg.write_u64::<BigEndian>(round).unwrap();
+    let digest = sha2::Sha256::digest(&msg);
+    BeaconEntry::new(round, key.sign(digest).as_bytes())
+}
+
+#[tokio::test]
+async fn verified_entries_are_cached_on_disk() {
+    let dir = tempfile::tempdir().unwrap();
+    let sources = DrandSources {
+        cache_dir: Some(dir.path().into()),
+        ..Default::default()
+    };
+    let (beacon, key) = new_local_beacon(&sources);
+    let prev = BeaconEntry::new(1, vec![1; 96]);
+    let curr = sign(&key, &prev, 2);
+    assert!(beacon.verify_entry(&curr, &prev).unwrap());
+    drop(beacon);
+
+    let beacon = DrandBeacon::new(15904451751, 25, &local_config(&key), &sources);
+    assert_eq!(beacon.entry(2).await.unwrap(), curr);
+    assert!(beacon.entry(3).await.is_err());
+}
+
+/// Writes a JSON file of entries, in the format of the `drand` HTTP API.
+fn write_entries(path: &std::path::Path, entries: &[(&BeaconEntry, &BeaconEntry)]) {
+    let json = entries
+        .iter()
+        .map(|(prev, entry)| {
+            serde_json::json!({
+                "round": entry.round(),
+                "signature": hex::encode(entry.data()),
+                "previous_signature": hex::encode(prev.data()),
+            })
+        })
+        .collect::<Vec<_>>();
+    std::fs::write(path, serde_json::to_vec(&json).unwrap()).unwrap();
+}
+
+#[tokio::test]
+async fn entries_from_json_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("entries.json");
+    let key = bls_signatures::PrivateKey::generate(&mut rand::thread_rng());
+    let e6 = BeaconEntry::new(6, vec![1; 96]);
+    let e7 = sign(&key, &e6, 7);
+    let e8 = sign(&key, &e7, 8);
+    write_entries(&path, &[(&e6, &e7), (&e7, &e8)]);
+    let beacon = DrandBeacon::new(
+        15904451751,
+        25,
+        &local_config(&key),
+        &DrandSources {
+            file: Some(path),
+            ..Default::default()
+        },
+    );
+    assert_eq!(beacon.entry(8).await.unwrap(), e8);
+    assert!(beacon.entry(9).await.is_err());
+}
+
+#[tokio::test]
+async fn forged_file_entries_are_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("entries.json");
+    let forger = bls_signatures::PrivateKey::generate(&mut rand::thread_rng());
+    let prev = BeaconEntry::new(6, vec![1; 96]);
+    let forged = sign(&forger, &prev, 7);
+    write_entries(&path, &[(&prev, &forged)]);
+    let (beacon, _) = new_local_beacon(&DrandSources {
+        file: Some(path),
+        ..Default::default()
+    });
+    assert!(beacon.entry(7).await.is_err());
+}
+
+#[tokio::test]
+async fn gossip_entries_are_verified() {
+    let key = bls_signatures::PrivateKey::generate(&mut rand::thread_rng());
+    let forger = bls_signatures::PrivateKey::generate(&mut rand::thread_rng());
+    let gossip = GossipSource::default();
+    let prev = BeaconEntry::new(6, vec![1; 96]);
+    let fetched = |key, round| FetchedEntry {
+        entry: sign(key, &prev, round),
+        previous_signature: Some(prev.data().to_vec()),
+    };
+    // Nothing is accepted before the public key is known
+    assert!(gossip.insert(fetched(&key, 7)).is_err());
+    gossip.set_public_key(key.public_key());
+    gossip.insert(fetched(&key, 7)).unwrap();
+    assert!(gossip.insert(fetched(&forger, 7)).is_err());
+    assert!(gossip.insert(fetched(&forger, u64::MAX)).is_err());
+    assert!(gossip
+        .insert(FetchedEntry {
+            previous_signature: None,
+            ..fetched(&key, 7)
+        })
+        .is_err());
+}
+
+#[test]
+fn disk_cache_is_compacted() {
+    let dir = tempfile::tempdir().unwrap();
+    let entry = |round| FetchedEntry {
+        entry: BeaconEntry::new(round, vec![round as u8]),
+        previous_signature: Some(vec![round as u8 - 1]),
+    };
+    let (cache, entries) = DiskCache::open(dir.path(), "chain", 2).unwrap();
+    assert!(entries.is_empty());
+    for round in 1..=3 {
+        cache.insert(&entry(round)).unwrap();
+    }
+    let lines = || {
+        std::fs::read_to_string(dir.path().join("chain.jsonl"))
+            .unwrap()
+            .lines()
+            .count()
+    };
+    assert_eq!(lines(), 3);
+    // Compacted once twice the capacity is reached
+    cache.insert(&entry(4)).unwrap();
+    assert_eq!(lines(), 2);
+    cache.insert(&entry(5)).unwrap();
+    drop(cache);
+
+    let (_, entries) = DiskCache::open(dir.path(), "chain", 2).unwrap();
+    assert_eq!(entries, vec![entry(4), entry(5)]);
+    assert_eq!(lines(), 2);
+}
+
+#[tokio::test]
+async fn tampered_cached_entries_are_dropped() {
+    let dir = tempfile::tempdir().unwrap();
+    let key = bls_signatures::PrivateKey::generate(&mut rand::thread_rng());
+    let forger = bls_signatures::PrivateKey::generate(&mut rand::thread_rng());
+    let prev = BeaconEntry::new(6, vec![1; 96]);
+    let fetched = |key, round| FetchedEntry {
+        entry: sign(key, &prev, round),
+        previous_signature: Some(prev.data().to_vec()),
+    };
+    // The cache of a chain without a hash is named after its public key
+    let name = hex::encode(key.public_key().as_bytes());
+    let (cache, _) = DiskCache::open(dir.path(), &name, 16).unwrap();
+    cache.insert(&fetched(&key, 7)).unwrap();
+    cache.insert(&fetched(&forger, 8)).unwrap();
+    cache
+        .insert(&FetchedEntry {
+            previous_signature: None,
+            ..fetched(&key, 9)
+        })
+        .unwrap();
+    drop(cache);
+
+    let sources = DrandSources {
+        cache_dir: Some(dir.path().into()),
+        ..Default::default()
+    };
+    let beacon = DrandBeacon::new(15904451751, 25, &local_config(&key), &sources);
+    assert_eq!(beacon.entry(7).await.unwrap(), fetched(&key, 7).entry);
+    // The relays are unreachable, so entries not served from the cache fail
+    assert!(beacon.entry(8).await.is_err());
+    assert!(beacon.entry(9).await.is_err());
+}
+
+#[test]
+fn decode_gossip_entry() {
+    // round = 300, signature = [1, 2, 3], previous_signature = [4]
+    let message = [0x08, 0xac, 0x02, 0x12, 3, 1, 2, 3, 0x1a, 1, 4];
+    assert_eq!(
+        GossipSource::decode(&message).unwrap(),
+        FetchedEntry {
+            entry: BeaconEntry::new(300, vec![1, 2, 3]),
+            previous_signature: Some(vec![4]),
+        }
+    );
+    assert!(GossipSource::decode(&message[..3]).is_err());
+}
+
+#[test]
+fn invalid_relays_are_rejected() {
+    let sources = DrandSources {
+        relays: vec!["not a url".into()],
+        ..Default::default()
+    };
+    assert!(sources.validate().is_err());
+    assert!(DrandSources::default().validate().is_ok());
+}
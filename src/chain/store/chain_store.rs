@@ -171,6 +171,11 @@ where
         &self.genesis_block_header
     }
 
+    /// Returns the settings store backing this chain store.
+    pub fn settings(&self) -> Arc<dyn SettingsStore + Sync + Send> {
+        self.settings.clone()
+    }
+
     /// Returns the currently tracked heaviest tipset.
     pub fn heaviest_tipset(&self) -> Arc<Tipset> {
         self.tipset_from_keys(
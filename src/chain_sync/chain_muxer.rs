@@ -2,6 +2,7 @@
 // SPDX-License-Identifier: Apache-2.0, MIT
 
 use std::{
+    path::PathBuf,
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
@@ -33,6 +34,7 @@ use tracing::{debug, error, info, trace, warn};
 
 use crate::chain_sync::{
     bad_block_cache::BadBlockCache,
+    checkpoint::{sync_checkpoint, Checkpoint},
     metrics,
     network_context::SyncNetworkContext,
     sync_state::SyncState,
@@ -68,6 +70,8 @@ pub enum ChainMuxerError {
     Block(#[from] ForestBlockError),
     #[error("Following network unexpectedly failed: {0}")]
     NetworkFollowingFailure(String),
+    #[error("Checkpoint sync failed: {0}")]
+    Checkpoint(String),
 }
 
 /// Structure that defines syncing configuration options
@@ -80,6 +84,14 @@ pub struct SyncConfig {
     /// head is
     #[cfg_attr(test, arbitrary(gen(|g| u32::arbitrary(g) as _)))]
     pub tipset_sample_size: usize,
+    /// Path of a checkpoint file signed by `checkpoint_signer`, to bootstrap
+    /// from instead of `checkpoint`
+    pub checkpoint_file: Option<PathBuf>,
+    /// Address of the key signing `checkpoint_file`
+    pub checkpoint_signer: Option<String>,
+    /// Trusted tipset to bootstrap from when the local head is behind it,
+    /// instead of importing a snapshot
+    pub checkpoint: Option<Checkpoint>,
 }
 
 impl Default for SyncConfig {
@@ -87,6 +99,9 @@ impl Default for SyncConfig {
         Self {
             req_window: 200,
             tipset_sample_size: 5,
+            checkpoint_file: None,
+            checkpoint_signer: None,
+            checkpoint: None,
         }
     }
 }
@@ -152,6 +167,9 @@ pub struct ChainMuxer<DB, M> {
 
     /// Syncing configurations
     sync_config: SyncConfig,
+
+    /// Trusted checkpoint to bootstrap from, until it is synced
+    checkpoint: Option<Checkpoint>,
 }
 
 impl<DB, M> ChainMuxer<DB, M>
@@ -170,6 +188,7 @@ where
         tipset_sender: flume::Sender<Arc<Tipset>>,
         tipset_receiver: flume::Receiver<Arc<Tipset>>,
         cfg: SyncConfig,
+        checkpoint: Option<Checkpoint>,
     ) -> Result<Self, ChainMuxerError> {
         let network =
             SyncNetworkContext::new(network_send, peer_manager, state_manager.blockstore_owned());
@@ -186,6 +205,7 @@ where
             tipset_sender,
             tipset_receiver,
             sync_config: cfg,
+            checkpoint,
         })
     }
 
@@ -303,18 +323,18 @@ where
             "Getting messages of gossipblock, epoch: {epoch}, block: {}",
             block.header.cid()
         );
-        // Get bls_message in the store or over Bitswap
+        // Get bls_message in the store or over Bitswap, or graphsync if it fails
         let bls_messages: Vec<_> = block
             .bls_messages
             .into_iter()
-            .map(|m| network.bitswap_get::<Message>(m))
+            .map(|m| network.get_with_fallback::<Message>(m))
             .collect();
 
-        // Get secp_messages in the store or over Bitswap
+        // Get secp_messages in the store or over Bitswap, or graphsync if it fails
         let secp_messages: Vec<_> = block
             .secpk_messages
             .into_iter()
-            .map(|m| network.bitswap_get::<SignedMessage>(m))
+            .map(|m| network.get_with_fallback::<SignedMessage>(m))
             .collect();
 
         let (bls_messages, secp_messages) =
@@ -590,6 +610,18 @@ where
         Box::pin(evaluator)
     }
 
+    fn sync_checkpoint(&self, checkpoint: Checkpoint) -> ChainMuxerFuture<(), ChainMuxerError> {
+        let network = self.network.clone();
+        let chain_store = self.state_manager.chain_store().clone();
+        let req_window = self.sync_config.req_window;
+        let lookback = self.state_manager.chain_config().policy.chain_finality;
+        Box::pin(async move {
+            sync_checkpoint(checkpoint, network, chain_store, req_window, lookback)
+                .await
+                .map_err(|e| ChainMuxerError::Checkpoint(format!("{e:#}")))
+        })
+    }
+
     fn bootstrap(
         &self,
         network_head: FullTipset,
@@ -823,6 +855,7 @@ where
 
 enum ChainMuxerState {
     Idle,
+    Checkpoint(ChainMuxerFuture<(), ChainMuxerError>),
     Connect(ChainMuxerFuture<NetworkHeadEvaluation, ChainMuxerError>),
     Bootstrap(ChainMuxerFuture<(), ChainMuxerError>),
     Follow(ChainMuxerFuture<(), ChainMuxerError>),
@@ -839,7 +872,10 @@ where
         loop {
             match self.state {
                 ChainMuxerState::Idle => {
-                    if self.sync_config.tipset_sample_size == 0 {
+                    if let Some(checkpoint) = self.checkpoint.clone() {
+                        info!("Syncing the checkpoint {}", checkpoint.tipset_keys());
+                        self.state = ChainMuxerState::Checkpoint(self.sync_checkpoint(checkpoint));
+                    } else if self.sync_config.tipset_sample_size == 0 {
                         // A standalone node might use this option to not be stuck waiting for P2P
                         // messages.
                         info!("Skip evaluating network head, assume in-sync.");
@@ -850,6 +886,21 @@ where
                         self.state = ChainMuxerState::Connect(self.evaluate_network_head());
                     }
                 }
+                ChainMuxerState::Checkpoint(ref mut checkpoint) => {
+                    match checkpoint.as_mut().poll(cx) {
+                        Poll::Ready(Ok(_)) => {
+                            info!(
+                                "Checkpoint successfully synced, now evaluating the network head"
+                            );
+                            self.checkpoint = None;
+                            self.state = ChainMuxerState::Idle;
+                        }
+                        // Fetch failures are retried by `sync_checkpoint`, so the checkpoint
+                        // does not match the network
+                        Poll::Ready(Err(why)) => return Poll::Ready(why),
+                        Poll::Pending => return Poll::Pending,
+                    }
+                }
                 ChainMuxerState::Connect(ref mut connect) => match connect.as_mut().poll(cx) {
                     Poll::Ready(Ok(evaluation)) => match evaluation {
                         NetworkHeadEvaluation::Behind {
@@ -0,0 +1,580 @@
+// Copyright 2019-2023 ChainSafe Systems
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+//! Bootstrapping a node from a tipset trusted by the operator instead of
+//! importing a snapshot. Only the headers of the tipsets within the lookback
+//! window behind the checkpoint, their state trees and the messages of the
+//! checkpoint are fetched from the network before the node starts following
+//! the chain from the checkpoint.
+
+use std::{path::Path, sync::Arc, time::Duration};
+
+use crate::blocks::{Tipset, TipsetKeys};
+use crate::chain::{persist_objects, ChainStore};
+use crate::ipld::selector::Selector;
+use crate::libp2p::fetch_dag;
+use crate::shim::{
+    address::Address,
+    clock::ChainEpoch,
+    crypto::{Signature, SignatureType},
+    state_tree::StateTree,
+};
+use anyhow::{anyhow, bail, ensure, Context as _};
+use cid::Cid;
+use fvm_ipld_blockstore::Blockstore;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use tracing::{info, warn};
+
+use crate::chain_sync::{network_context::SyncNetworkContext, SyncConfig};
+
+/// Delay before retrying to fetch the checkpoint from the network.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Longest delay between two attempts to sync the checkpoint.
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// A tipset trusted by the operator, identified by the CIDs of its blocks and
+/// its parent state root.
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(test, derive(derive_quickcheck_arbitrary::Arbitrary))]
+pub struct Checkpoint {
+    /// CIDs of the blocks of the tipset.
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    pub tipset_key: Vec<Cid>,
+    /// Parent state root of the tipset.
+    #[serde_as(as = "DisplayFromStr")]
+    pub state_root: Cid,
+}
+
+impl Checkpoint {
+    pub fn tipset_keys(&self) -> TipsetKeys {
+        self.tipset_key.iter().copied().collect()
+    }
+
+    /// The bytes signed by the signer of a [`SignedCheckpoint`], the `CBOR`
+    /// encoding of the tipset key and state root.
+    pub fn signing_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(fvm_ipld_encoding::to_vec(&(
+            &self.tipset_key,
+            &self.state_root,
+        ))?)
+    }
+}
+
+/// A [`Checkpoint`] signed by a `secp256k1` or `BLS` key, as read from a
+/// checkpoint file.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SignedCheckpoint {
+    #[serde(flatten)]
+    pub checkpoint: Checkpoint,
+    #[serde(with = "crate::lotus_json")]
+    pub signature: Signature,
+}
+
+impl SignedCheckpoint {
+    /// Returns the checkpoint if it is signed by `signer`.
+    pub fn verify(self, signer: &Address) -> anyhow::Result<Checkpoint> {
+        ensure!(
+            self.signature.signature_type() != SignatureType::Delegated,
+            "Delegated signatures are not supported for checkpoints"
+        );
+        self.signature
+            .verify(&self.checkpoint.signing_bytes()?, signer)
+            .map_err(|e| anyhow::anyhow!("Invalid checkpoint signature: {e}"))?;
+        Ok(self.checkpoint)
+    }
+
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Failed to open checkpoint file {}", path.display()))?;
+        Ok(serde_json::from_reader(std::io::BufReader::new(file))?)
+    }
+}
+
+/// Returns the checkpoint to bootstrap from, either set in the configuration or
+/// read from a checkpoint file and verified against the configured signer.
+pub fn load_checkpoint(config: &SyncConfig) -> anyhow::Result<Option<Checkpoint>> {
+    match (&config.checkpoint, &config.checkpoint_file) {
+        (Some(_), Some(_)) => bail!("Can't set checkpoint and checkpoint_file at the same time!"),
+        (Some(checkpoint), None) => Ok(Some(checkpoint.clone())),
+        (None, Some(path)) => {
+            let signer: Address = config
+                .checkpoint_signer
+                .as_deref()
+                .context("checkpoint_signer is required to verify the checkpoint file")?
+                .parse()?;
+            Ok(Some(SignedCheckpoint::read(path)?.verify(&signer)?))
+        }
+        (None, None) => Ok(None),
+    }
+}
+
+/// A failure to sync a [`Checkpoint`].
+#[derive(Debug, thiserror::Error)]
+enum CheckpointError {
+    /// The checkpoint does not match the chain of the network.
+    #[error("{0:#}")]
+    Mismatch(anyhow::Error),
+    /// The checkpoint couldn't be fetched, which may succeed later.
+    #[error("{0:#}")]
+    Fetch(anyhow::Error),
+}
+
+/// Fetches the checkpoint from the network, verifies it and sets it as the
+/// heaviest tipset, unless the local head is already past it. Fetch failures
+/// are retried with an exponential backoff, a checkpoint that does not match
+/// the chain of the network is an error.
+pub(in crate::chain_sync) async fn sync_checkpoint<DB>(
+    checkpoint: Checkpoint,
+    network: SyncNetworkContext<DB>,
+    chain_store: Arc<ChainStore<DB>>,
+    req_window: i64,
+    lookback: ChainEpoch,
+) -> anyhow::Result<()>
+where
+    DB: Blockstore + Sync + Send + 'static,
+{
+    // The checkpoint tipset is stored once synced, so that it isn't fetched
+    // again after a restart
+    if let Ok(tipset) = chain_store
+        .chain_index
+        .load_tipset(&checkpoint.tipset_keys())
+    {
+        if is_past_checkpoint(&chain_store, &tipset) {
+            return Ok(());
+        }
+    }
+
+    let mut retry_interval = RETRY_INTERVAL;
+    loop {
+        match try_sync_checkpoint(&checkpoint, &network, &chain_store, req_window, lookback).await {
+            Ok(()) => return Ok(()),
+            Err(CheckpointError::Mismatch(e)) => return Err(e),
+            Err(CheckpointError::Fetch(e)) => {
+                warn!(
+                    "Syncing the checkpoint failed, retrying in {}s: {e:#}",
+                    retry_interval.as_secs()
+                );
+                tokio::time::sleep(retry_interval).await;
+                retry_interval = (retry_interval * 2).min(MAX_RETRY_INTERVAL);
+            }
+        }
+    }
+}
+
+/// Returns true if the local head is at or past the checkpoint tipset.
+fn is_past_checkpoint<DB: Blockstore>(chain_store: &ChainStore<DB>, tipset: &Tipset) -> bool {
+    let head = chain_store.heaviest_tipset();
+    let is_past = head.epoch() >= tipset.epoch();
+    if is_past {
+        info!(
+            "Local head at epoch {} is past the checkpoint at epoch {}",
+            head.epoch(),
+            tipset.epoch()
+        );
+    }
+    is_past
+}
+
+async fn try_sync_checkpoint<DB>(
+    checkpoint: &Checkpoint,
+    network: &SyncNetworkContext<DB>,
+    chain_store: &ChainStore<DB>,
+    req_window: i64,
+    lookback: ChainEpoch,
+) -> Result<(), CheckpointError>
+where
+    DB: Blockstore + Sync + Send + 'static,
+{
+    let tsk = checkpoint.tipset_keys();
+    let tipset = retry("checkpoint tipset", || {
+        network.chain_exchange_headers(None, &tsk, 1)
+    })
+    .await
+    .into_iter()
+    .next()
+    .context("Empty chain exchange response for the checkpoint tipset")
+    .map_err(CheckpointError::Fetch)?;
+    if tipset.key() != &tsk {
+        return Err(CheckpointError::Fetch(anyhow!(
+            "Peers returned tipset {} for checkpoint {tsk}",
+            tipset.key(),
+        )));
+    }
+    if tipset.parent_state() != &checkpoint.state_root {
+        return Err(CheckpointError::Mismatch(anyhow!(
+            "Checkpoint state root {} does not match the parent state {} of the tipset",
+            checkpoint.state_root,
+            tipset.parent_state()
+        )));
+    }
+
+    if is_past_checkpoint(chain_store, &tipset) {
+        return Ok(());
+    }
+    info!("Syncing from the checkpoint at epoch {}", tipset.epoch());
+
+    // The lookback of the tipsets validated after the checkpoint reaches
+    // `lookback` epochs behind it
+    let mut tipsets = vec![tipset.clone()];
+    while let Some(oldest) = tipsets.last().filter(|ts| ts.epoch() > 0) {
+        let window_start = tipset.epoch() - lookback;
+        if oldest.epoch() <= window_start {
+            break;
+        }
+        let count = req_window.min(oldest.epoch() - window_start).max(1) as u64;
+        let parents = oldest.parents().clone();
+        let batch = retry("checkpoint lookback headers", || {
+            network.chain_exchange_headers(None, &parents, count)
+        })
+        .await;
+        let mut expected_key = parents;
+        for ts in &batch {
+            if ts.key() != &expected_key {
+                return Err(CheckpointError::Fetch(anyhow!(
+                    "Peers returned tipset {} instead of {expected_key}",
+                    ts.key()
+                )));
+            }
+            expected_key = ts.parents().clone();
+        }
+        tipsets.extend(batch);
+    }
+    for ts in &tipsets {
+        persist_objects(chain_store.blockstore(), ts.blocks())
+            .map_err(|e| CheckpointError::Fetch(e.into()))?;
+    }
+
+    // The state trees of the window, and the messages of the checkpoint that
+    // are applied to its parent state to compute its state
+    let roots: Vec<Cid> = tipsets
+        .iter()
+        .map(|ts| *ts.parent_state())
+        .chain(tipset.blocks().iter().map(|header| *header.messages()))
+        .collect();
+    // Each whole tree is requested over graphsync, and fetched block by block
+    // over bitswap if that fails
+    let mut missing_roots = vec![];
+    for root in roots {
+        match network
+            .graphsync_get(root, Selector::explore_all_recursively())
+            .await
+        {
+            Ok(count) => info!("Fetched {count} blocks of {root} over graphsync"),
+            Err(e) => {
+                warn!("Failed to fetch {root} over graphsync: {e}");
+                missing_roots.push(root);
+            }
+        }
+    }
+    if !missing_roots.is_empty() {
+        let stats = fetch_dag(
+            network.network_send(),
+            chain_store.blockstore(),
+            missing_roots,
+            |_| Ok(()),
+        )
+        .await
+        .map_err(CheckpointError::Fetch)?;
+        info!("Fetched the checkpoint state trees: {stats:?}");
+        if stats.failures > 0 {
+            return Err(CheckpointError::Fetch(anyhow!(
+                "Failed to fetch {} blocks of the checkpoint state",
+                stats.failures
+            )));
+        }
+    }
+    StateTree::new_from_root(chain_store.db.clone(), &checkpoint.state_root)
+        .context("Failed to load the checkpoint state tree")
+        .map_err(CheckpointError::Fetch)?;
+
+    chain_store
+        .set_heaviest_tipset(tipset)
+        .map_err(|e| CheckpointError::Fetch(e.into()))?;
+    Ok(())
+}
+
+async fn retry<F, Fut>(what: &str, mut request: F) -> Vec<Arc<Tipset>>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<Vec<Arc<Tipset>>, String>>,
+{
+    loop {
+        match request().await {
+            Ok(tipsets) if !tipsets.is_empty() => return tipsets,
+            Ok(_) => warn!("Fetching {what} returned no tipsets, retrying"),
+            Err(e) => warn!("Fetching {what} failed, retrying: {e}"),
+        }
+        tokio::time::sleep(RETRY_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocks::BlockHeader;
+    use crate::db::MemoryDB;
+    use crate::key_management::{generate_key, KeyStore, KeyStoreConfig, Wallet};
+    use crate::libp2p::chain_exchange::{
+        ChainExchangeResponse, ChainExchangeResponseStatus, TipsetBundle,
+    };
+    use crate::libp2p::graphsync::{make_graphsync_response, GraphsyncMessage, GraphsyncReply};
+    use crate::libp2p::{NetworkMessage, PeerId, PeerManager};
+    use crate::libp2p_bitswap::request_manager::BitswapRequestManager;
+    use crate::networks::ChainConfig;
+    use crate::shim::state_tree::StateTreeVersion;
+    use crate::utils::cid::CidCborExt;
+    use crate::utils::db::CborStoreExt as _;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn checkpoint() -> Checkpoint {
+        Checkpoint {
+            tipset_key: vec![Cid::from_cbor_blake2b256(&"block").unwrap()],
+            state_root: Cid::from_cbor_blake2b256(&"state").unwrap(),
+        }
+    }
+
+    #[test]
+    fn signed_checkpoint_roundtrip() {
+        let key = generate_key(SignatureType::Secp256k1).unwrap();
+        let signer = key.address;
+        let mut wallet = Wallet::new_from_keys(
+            KeyStore::new(KeyStoreConfig::Memory).unwrap(),
+            std::iter::once(key),
+        );
+        let checkpoint = checkpoint();
+        let signed = SignedCheckpoint {
+            signature: wallet
+                .sign(&signer, &checkpoint.signing_bytes().unwrap())
+                .unwrap(),
+            checkpoint: checkpoint.clone(),
+        };
+        let json = serde_json::to_string(&signed).unwrap();
+        let signed: SignedCheckpoint = serde_json::from_str(&json).unwrap();
+        assert_eq!(signed.clone().verify(&signer).unwrap(), checkpoint);
+
+        let other = generate_key(SignatureType::Secp256k1).unwrap().address;
+        assert!(signed.clone().verify(&other).is_err());
+
+        let mut tampered = signed;
+        tampered.checkpoint.state_root = tampered.checkpoint.tipset_key[0];
+        assert!(tampered.verify(&signer).is_err());
+    }
+
+    #[test]
+    fn load_checkpoint_from_config() {
+        let config = SyncConfig {
+            checkpoint: Some(checkpoint()),
+            ..Default::default()
+        };
+        assert_eq!(load_checkpoint(&config).unwrap(), Some(checkpoint()));
+
+        let config = SyncConfig {
+            checkpoint: Some(checkpoint()),
+            checkpoint_file: Some("checkpoint.json".into()),
+            ..Default::default()
+        };
+        assert!(load_checkpoint(&config).is_err());
+
+        // The signer is required to trust a checkpoint file
+        let config = SyncConfig {
+            checkpoint_file: Some("checkpoint.json".into()),
+            ..Default::default()
+        };
+        assert!(load_checkpoint(&config).is_err());
+    }
+
+    /// A chain store at genesis, and a checkpoint at epoch 1 whose state tree
+    /// and messages are in the store already.
+    fn checkpoint_chain() -> (Arc<MemoryDB>, Arc<ChainStore<MemoryDB>>, BlockHeader) {
+        let db = Arc::new(MemoryDB::default());
+        let genesis = BlockHeader::builder()
+            .miner_address(Address::new_id(0))
+            .build()
+            .unwrap();
+        db.put_cbor_default(&genesis).unwrap();
+        let chain_store = Arc::new(
+            ChainStore::new(
+                db.clone(),
+                db.clone(),
+                Arc::new(ChainConfig::default()),
+                genesis.clone(),
+            )
+            .unwrap(),
+        );
+        let state_root = StateTree::new(db.clone(), StateTreeVersion::V5)
+            .unwrap()
+            .flush()
+            .unwrap();
+        let messages = db.put_cbor_default(&Vec::<Cid>::new()).unwrap();
+        let header = BlockHeader::builder()
+            .miner_address(Address::new_id(0))
+            .epoch(1)
+            .parents(TipsetKeys::from_iter([*genesis.cid()]))
+            .state_root(state_root)
+            .messages(messages)
+            .build()
+            .unwrap();
+        (db, chain_store, header)
+    }
+
+    /// A network serving `header` over chain exchange, whose first bitswap
+    /// session fails. Graphsync requests are served from `remote` if set, and
+    /// fail otherwise. Returns the number of sessions requested.
+    async fn checkpoint_network(
+        db: Arc<MemoryDB>,
+        header: BlockHeader,
+        remote: Option<Arc<MemoryDB>>,
+    ) -> (SyncNetworkContext<MemoryDB>, Arc<AtomicUsize>) {
+        let (network_send, network_receive) = flume::unbounded();
+        let peer_manager = Arc::new(PeerManager::default());
+        peer_manager
+            .update_peer_head(PeerId::random(), Arc::new(header.clone().into()))
+            .await;
+        let sessions = Arc::new(AtomicUsize::new(0));
+        let bitswap = Arc::new(BitswapRequestManager::default());
+        tokio::spawn({
+            let db = db.clone();
+            let sessions = sessions.clone();
+            async move {
+                while let Ok(message) = network_receive.recv_async().await {
+                    match message {
+                        NetworkMessage::ChainExchangeRequest {
+                            response_channel, ..
+                        } => {
+                            let _ = response_channel.send(Ok(ChainExchangeResponse {
+                                status: ChainExchangeResponseStatus::Success,
+                                message: String::new(),
+                                chain: vec![TipsetBundle {
+                                    blocks: vec![header.clone()],
+                                    messages: None,
+                                }],
+                            }));
+                        }
+                        NetworkMessage::BitswapSession {
+                            timeout,
+                            response_channel,
+                        } => {
+                            if sessions.fetch_add(1, Ordering::Relaxed) > 0 {
+                                let _ = response_channel
+                                    .send(bitswap.clone().session(db.clone(), timeout));
+                            }
+                        }
+                        NetworkMessage::FindProviders {
+                            response_channel, ..
+                        } => {
+                            let _ = response_channel.send(Default::default());
+                        }
+                        NetworkMessage::GraphsyncRequest {
+                            request,
+                            response_channel,
+                            ..
+                        } => {
+                            let (Some(remote), Some(root), Some(selector)) =
+                                (remote.clone(), request.root, request.selector)
+                            else {
+                                continue;
+                            };
+                            let send = move |message: GraphsyncMessage| {
+                                for response in message.responses {
+                                    let _ = response_channel.send(Ok(GraphsyncReply {
+                                        response,
+                                        blocks: message.blocks.clone(),
+                                    }));
+                                }
+                                Ok(())
+                            };
+                            make_graphsync_response(remote, request.id, root, selector, send).await;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        });
+        (
+            SyncNetworkContext::new(network_send, peer_manager, db),
+            sessions,
+        )
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sync_checkpoint_retries_failed_fetches() {
+        let (db, chain_store, header) = checkpoint_chain();
+        let checkpoint = Checkpoint {
+            tipset_key: vec![*header.cid()],
+            state_root: *header.state_root(),
+        };
+        let (network, sessions) = checkpoint_network(db.clone(), header.clone(), None).await;
+        sync_checkpoint(checkpoint.clone(), network, chain_store.clone(), 1, 0)
+            .await
+            .unwrap();
+        assert_eq!(sessions.load(Ordering::Relaxed), 2);
+        assert_eq!(
+            chain_store.heaviest_tipset().key(),
+            &checkpoint.tipset_keys()
+        );
+
+        // Once synced, the checkpoint isn't fetched again
+        let (network_send, _) = flume::unbounded();
+        let network = SyncNetworkContext::new(network_send, Default::default(), db);
+        tokio::time::timeout(
+            Duration::from_secs(60),
+            sync_checkpoint(checkpoint, network, chain_store, 1, 0),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sync_checkpoint_rejects_mismatch() {
+        let (db, chain_store, header) = checkpoint_chain();
+        let checkpoint = Checkpoint {
+            tipset_key: vec![*header.cid()],
+            state_root: *header.messages(),
+        };
+        let (network, sessions) = checkpoint_network(db, header, None).await;
+        assert!(
+            sync_checkpoint(checkpoint, network, chain_store.clone(), 1, 0)
+                .await
+                .is_err()
+        );
+        assert_eq!(sessions.load(Ordering::Relaxed), 0);
+        assert_eq!(chain_store.heaviest_tipset().epoch(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sync_checkpoint_fetches_state_over_graphsync() {
+        let (remote, remote_store, header) = checkpoint_chain();
+        let db = Arc::new(MemoryDB::default());
+        let genesis = remote_store.genesis().clone();
+        db.put_cbor_default(&genesis).unwrap();
+        let chain_store = Arc::new(
+            ChainStore::new(
+                db.clone(),
+                db.clone(),
+                Arc::new(ChainConfig::default()),
+                genesis,
+            )
+            .unwrap(),
+        );
+        let checkpoint = Checkpoint {
+            tipset_key: vec![*header.cid()],
+            state_root: *header.state_root(),
+        };
+        let (network, sessions) =
+            checkpoint_network(db.clone(), header.clone(), Some(remote)).await;
+        sync_checkpoint(checkpoint.clone(), network, chain_store.clone(), 1, 0)
+            .await
+            .unwrap();
+        // Bitswap wasn't needed
+        assert_eq!(sessions.load(Ordering::Relaxed), 0);
+        assert!(db.has(header.state_root()).unwrap());
+        assert_eq!(
+            chain_store.heaviest_tipset().key(),
+            &checkpoint.tipset_keys()
+        );
+    }
+}
@@ -213,8 +213,47 @@ pub static FOLLOW_NETWORK_ERRORS: Lazy<Box<GenericCounter<AtomicU64>>> = Lazy::n
     follow_network_errors
 });
 
+pub static TIPSET_RANGE_SYNC_TIPSETS_TOTAL: Lazy<Box<GenericCounterVec<AtomicU64>>> = Lazy::new(
+    || {
+        let tipset_range_sync_tipsets_total = Box::new(
+            GenericCounterVec::<AtomicU64>::new(
+                Opts::new(
+                    "tipset_range_sync_tipsets_total",
+                    "Total number of tipsets processed by each stage of the TipsetRangeSyncers",
+                ),
+                &[labels::SYNC_STAGE],
+            )
+            .expect("Defining the tipset_range_sync_tipsets_total metric must succeed"),
+        );
+        prometheus::default_registry()
+        .register(tipset_range_sync_tipsets_total.clone())
+        .expect(
+            "Registering the tipset_range_sync_tipsets_total metric with the metrics registry must succeed",
+        );
+        tipset_range_sync_tipsets_total
+    },
+);
+pub static TIPSET_RANGE_SYNC_VALIDATION_QUEUE: Lazy<Box<GenericGauge<AtomicI64>>> = Lazy::new(
+    || {
+        let tipset_range_sync_validation_queue = Box::new(
+            GenericGauge::<AtomicI64>::new(
+                "tipset_range_sync_validation_queue",
+                "Number of tipset batches downloaded and waiting to be validated",
+            )
+            .expect("Defining the tipset_range_sync_validation_queue metric must succeed"),
+        );
+        prometheus::default_registry()
+        .register(tipset_range_sync_validation_queue.clone())
+        .expect(
+            "Registering the tipset_range_sync_validation_queue metric with the metrics registry must succeed",
+        );
+        tipset_range_sync_validation_queue
+    },
+);
+
 pub mod labels {
     pub const GOSSIPSUB_MESSAGE_KIND: &str = "libp2p_message_kind";
+    pub const SYNC_STAGE: &str = "stage";
 }
 
 pub mod values {
@@ -236,6 +275,11 @@ pub mod values {
     pub const BASE_FEE_CHECK: &str = "base_fee_check";
     pub const PARENT_WEIGHT_CAL: &str = "parent_weight_check";
     pub const BLOCK_SIGNATURE_CHECK: &str = "block_signature_check";
+
+    // tipset_range_sync_tipsets_total
+    pub const HEADERS_STAGE: &str = "headers";
+    pub const MESSAGES_STAGE: &str = "messages";
+    pub const VALIDATION_STAGE: &str = "validation";
 }
 
 #[cfg(test)]
@@ -267,5 +311,7 @@ mod tests {
         test_counter!(BOOTSTRAP_ERRORS);
         test_counter!(FOLLOW_NETWORK_INTERRUPTIONS);
         test_counter!(FOLLOW_NETWORK_ERRORS);
+        test_counter_vec!(TIPSET_RANGE_SYNC_TIPSETS_TOTAL);
+        test_counter!(TIPSET_RANGE_SYNC_VALIDATION_QUEUE);
     }
 }
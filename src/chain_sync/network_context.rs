@@ -11,11 +11,13 @@ use std::{
 };
 
 use crate::blocks::{FullTipset, Tipset, TipsetKeys};
+use crate::ipld::selector::Selector;
 use crate::libp2p::{
     chain_exchange::{
         ChainExchangeRequest, ChainExchangeResponse, CompactedMessages, TipsetBundle, HEADERS,
         MESSAGES,
     },
+    graphsync::{store_graphsync_response, GraphsyncError, GraphsyncRequest, GRAPHSYNC_TIMEOUT},
     hello::{HelloRequest, HelloResponse},
     rpc::RequestResponseError,
     NetworkMessage, PeerId, PeerManager, BITSWAP_TIMEOUT,
@@ -122,6 +124,11 @@ where
         }
     }
 
+    /// Returns the sender of messages to the network service.
+    pub fn network_send(&self) -> &flume::Sender<NetworkMessage> {
+        &self.network_send
+    }
+
     /// Returns a reference to the peer manager of the network context.
     pub fn peer_manager(&self) -> &PeerManager {
         self.peer_manager.as_ref()
@@ -139,6 +146,49 @@ where
         self.handle_chain_exchange_request(peer_id, tsk, count, HEADERS)
             .await
     }
+    /// Sends the same `chain_exchange` request for block headers to all of
+    /// `peers` at once, and returns the first response that starts at `tsk`.
+    pub async fn chain_exchange_headers_from(
+        &self,
+        peers: &[PeerId],
+        tsk: &TipsetKeys,
+        count: u64,
+    ) -> Result<Vec<Arc<Tipset>>, String> {
+        let request = ChainExchangeRequest {
+            start: tsk.cids.clone().into_iter().collect(),
+            request_len: count,
+            options: HEADERS,
+        };
+        let mut batch = RaceBatch::new(peers.len().max(1));
+        for &peer_id in peers {
+            let peer_manager = self.peer_manager.clone();
+            let network_send = self.network_send.clone();
+            let request = request.clone();
+            let tsk = tsk.clone();
+            batch.add(async move {
+                let tipsets =
+                    Self::chain_exchange_request(peer_manager, network_send, peer_id, request)
+                        .await?
+                        .into_result::<Arc<Tipset>>()
+                        .map_err(|e| {
+                            debug!("Failed chain_exchange response from peer {peer_id:?}: {e}");
+                            e
+                        })?;
+                match tipsets.first() {
+                    Some(tipset) if tipset.key() == &tsk => Ok(tipsets),
+                    _ => Err(format!(
+                        "Peer {peer_id:?} returned headers not starting at {tsk}"
+                    )),
+                }
+            });
+        }
+        batch.get_ok().await.ok_or_else(|| {
+            format!(
+                "ChainExchange request for {count} headers from {tsk} failed for peers {peers:?}"
+            )
+        })
+    }
+
     /// Send a `chain_exchange` request for only messages (ignore block
     /// headers). If `peer_id` is `None`, requests will be sent to a set of
     /// shuffled peers.
@@ -212,6 +262,95 @@ where
         }
     }
 
+    /// Requests that some content with a particular `Cid` get fetched over
+    /// `Bitswap`, falling back to graphsync if `Bitswap` fails.
+    pub async fn get_with_fallback<TMessage: DeserializeOwned>(
+        &self,
+        content: Cid,
+    ) -> Result<TMessage, String>
+    where
+        DB: Send + Sync,
+    {
+        match self.bitswap_get(content).await {
+            Ok(message) => Ok(message),
+            Err(bitswap_error) => {
+                debug!("Falling back to graphsync for {content}: {bitswap_error}");
+                self.graphsync_get(content, Selector::Matcher)
+                    .await
+                    .map_err(|e| format!("{bitswap_error}, graphsync: {e}"))?;
+                self.db
+                    .get_cbor(&content)
+                    .map_err(|e| e.to_string())?
+                    .ok_or_else(|| format!("Not found in db, graphsync. cid, {content:?}"))
+            }
+        }
+    }
+
+    /// Fetches the blocks of the DAG traversed by `selector` from `root` over
+    /// graphsync and puts them in the `BlockStore` as they are received.
+    /// Requests are sent to shuffled top peers, one at a time, until one of them
+    /// returns the complete DAG, and nothing else.
+    /// Returns the number of blocks fetched.
+    pub async fn graphsync_get(&self, root: Cid, selector: Selector) -> Result<usize, String>
+    where
+        DB: Send + Sync,
+    {
+        for peer_id in self.peer_manager.top_peers_shuffled().await {
+            match self
+                .graphsync_request(peer_id, root, selector.clone())
+                .await
+            {
+                Ok(count) => return Ok(count),
+                Err(e) => debug!("Failed graphsync request to peer {peer_id:?}: {e}"),
+            }
+        }
+        Err(format!(
+            "Graphsync request for {root} failed for all top peers"
+        ))
+    }
+
+    /// Send a graphsync request to the network and store the response.
+    async fn graphsync_request(
+        &self,
+        peer_id: PeerId,
+        root: Cid,
+        selector: Selector,
+    ) -> Result<usize, String>
+    where
+        DB: Send + Sync,
+    {
+        let req_pre_time = SystemTime::now();
+        let (tx, rx) = flume::unbounded();
+        self.network_send
+            .send_async(NetworkMessage::GraphsyncRequest {
+                peer_id,
+                request: GraphsyncRequest::new(root, selector.clone()),
+                response_channel: tx,
+            })
+            .await
+            .map_err(|_| "Failed to send graphsync request to network".to_string())?;
+
+        let res =
+            store_graphsync_response(self.db.clone(), root, selector, rx, GRAPHSYNC_TIMEOUT).await;
+        let res_duration = SystemTime::now()
+            .duration_since(req_pre_time)
+            .unwrap_or_default();
+        match res {
+            Ok(count) => {
+                self.peer_manager.log_success(peer_id, res_duration).await;
+                Ok(count)
+            }
+            // Peers not supporting graphsync are not penalized
+            Err(GraphsyncError::Network(RequestResponseError::UnsupportedProtocols)) => {
+                Err(format!("{peer_id} does not support graphsync"))
+            }
+            Err(e) => {
+                self.peer_manager.log_failure(peer_id, res_duration).await;
+                Err(e.to_string())
+            }
+        }
+    }
+
     /// Helper function to handle the peer retrieval if no peer supplied as well
     /// as the logging and updating of the peer info in the `PeerManager`.
     async fn handle_chain_exchange_request<T>(
@@ -28,11 +28,12 @@ use crate::{
 };
 use ahash::{HashMap, HashMapExt, HashSet};
 use cid::Cid;
-use futures::stream::TryStreamExt as _;
+use futures::future::{BoxFuture, FutureExt as _, Shared};
 use futures::{stream, stream::FuturesUnordered, StreamExt, TryFutureExt};
 use fvm_ipld_blockstore::Blockstore;
 use fvm_ipld_encoding::to_vec;
 use nonempty::{nonempty, NonEmpty};
+use parking_lot::Mutex;
 use thiserror::Error;
 use tokio::task::JoinSet;
 use tracing::{debug, error, info, trace, warn};
@@ -44,6 +45,22 @@ use crate::chain_sync::{
 
 const MAX_TIPSETS_TO_REQUEST: u64 = 100;
 
+/// Maximum number of message batches downloaded concurrently.
+const MAX_CONCURRENT_MESSAGE_BATCHES: usize = 64;
+
+/// Number of tipsets whose headers are downloaded ahead of the message
+/// prefetching stage.
+const HEADERS_AHEAD: usize = 16 * MAX_TIPSETS_TO_REQUEST as usize;
+
+/// Number of peers each window of headers is requested from at once.
+/// Consecutive windows are requested from different peers, spreading the
+/// requests over their chain exchange quotas.
+const HEADER_WINDOW_PEERS: usize = 3;
+
+/// Number of downloaded message batches waiting to be validated. Downloads
+/// pause when validation falls this far behind.
+const VALIDATION_QUEUE_SIZE: usize = 16;
+
 #[derive(Debug, Error)]
 pub enum TipsetProcessorError {
     #[error("TipsetRangeSyncer error: {0}")]
@@ -729,6 +746,19 @@ async fn sync_tipset_range<DB: Blockstore + Sync + Send + 'static>(
         .write()
         .init(current_head.clone(), proposed_head.clone());
 
+    // Messages are prefetched as soon as their headers are downloaded, while
+    // the headers of older tipsets are still being downloaded. The prefetching
+    // task is aborted when it is dropped with the range syncer.
+    let request_window = state_manager.chain_config().request_window as usize;
+    let downloads = MessageDownloads::new(network.clone(), chain_store.clone());
+    let (headers_tx, headers_rx) = flume::bounded(HEADERS_AHEAD);
+    let mut prefetch = JoinSet::new();
+    prefetch.spawn(prefetch_messages(
+        headers_rx,
+        downloads.clone(),
+        request_window,
+    ));
+
     let parent_tipsets = match sync_headers_in_reverse(
         tracker.clone(),
         tipset_range_length,
@@ -737,6 +767,7 @@ async fn sync_tipset_range<DB: Blockstore + Sync + Send + 'static>(
         &bad_block_cache,
         &chain_store,
         network.clone(),
+        headers_tx,
     )
     .await
     {
@@ -760,7 +791,7 @@ async fn sync_tipset_range<DB: Blockstore + Sync + Send + 'static>(
     if let Err(why) = sync_messages_check_state(
         tracker.clone(),
         state_manager,
-        network,
+        downloads,
         chain_store.clone(),
         &bad_block_cache,
         parent_tipsets,
@@ -797,7 +828,14 @@ async fn sync_tipset_range<DB: Blockstore + Sync + Send + 'static>(
 
 /// Download headers between the proposed head and the current one available
 /// locally. If they turn out to be on different forks, download more headers up
-/// to a certain limit to try to find a common ancestor.
+/// to a certain limit to try to find a common ancestor. Every tipset of the range
+/// is sent to `headers_tx`, from the newest to the oldest, to prefetch its
+/// messages.
+///
+/// A window of headers can only be requested once the oldest header of the
+/// previous window is known, so windows are requested in turn, each from
+/// [`HEADER_WINDOW_PEERS`] peers at once.
+#[allow(clippy::too_many_arguments)]
 async fn sync_headers_in_reverse<DB: Blockstore + Sync + Send + 'static>(
     tracker: crate::chain_sync::chain_muxer::WorkerState,
     tipset_range_length: u64,
@@ -806,11 +844,24 @@ async fn sync_headers_in_reverse<DB: Blockstore + Sync + Send + 'static>(
     bad_block_cache: &BadBlockCache,
     chain_store: &ChainStore<DB>,
     network: SyncNetworkContext<DB>,
+    headers_tx: flume::Sender<Arc<Tipset>>,
 ) -> Result<Vec<Arc<Tipset>>, TipsetRangeSyncerError> {
     let mut parent_blocks: Vec<Cid> = vec![];
     let mut parent_tipsets = Vec::with_capacity(tipset_range_length as usize + 1);
     parent_tipsets.push(proposed_head.clone());
     tracker.write().set_epoch(current_head.epoch());
+    // Prefetching is best effort, the messages are downloaded when validating
+    // if it failed
+    let prefetch = |tipset: Arc<Tipset>| {
+        let headers_tx = headers_tx.clone();
+        async move {
+            let _ = headers_tx.send_async(tipset).await;
+        }
+    };
+    prefetch(proposed_head.clone()).await;
+    let peers = network.peer_manager().top_peers_shuffled().await;
+    let window_peer_count = HEADER_WINDOW_PEERS.min(peers.len());
+    let mut peers = peers.iter().copied().cycle();
 
     let total_size = proposed_head.epoch() - current_head.epoch();
     #[allow(deprecated)] // Tracking issue: https://github.com/ChainSafe/forest/issues/3157
@@ -833,26 +884,45 @@ async fn sync_headers_in_reverse<DB: Blockstore + Sync + Send + 'static>(
         // Attempt to load the parent tipset from local store
         if let Ok(tipset) = chain_store.tipset_from_keys(oldest_parent.parents()) {
             parent_blocks.extend(tipset.cids());
-            parent_tipsets.push(tipset);
+            parent_tipsets.push(tipset.clone());
+            prefetch(tipset).await;
             continue;
         }
 
         let epoch_diff = oldest_parent.epoch() - current_head.epoch();
         let window = min(epoch_diff, MAX_TIPSETS_TO_REQUEST as i64);
-        let network_tipsets = network
-            .chain_exchange_headers(None, oldest_parent.parents(), window as u64)
-            .await
-            .map_err(TipsetRangeSyncerError::NetworkTipsetQueryFailed)?;
+        let window_peers: Vec<_> = peers.by_ref().take(window_peer_count).collect();
+        let network_tipsets = if window_peers.is_empty() {
+            network
+                .chain_exchange_headers(None, oldest_parent.parents(), window as u64)
+                .await
+        } else {
+            network
+                .chain_exchange_headers_from(&window_peers, oldest_parent.parents(), window as u64)
+                .await
+        }
+        .map_err(TipsetRangeSyncerError::NetworkTipsetQueryFailed)?;
 
+        let mut downloaded = 0;
+        let mut range_traversed = false;
         for tipset in network_tipsets {
-            // Break if have already traversed the entire tipset range
+            // Stop if have already traversed the entire tipset range
             if tipset.epoch() < current_head.epoch() {
-                break 'sync;
+                range_traversed = true;
+                break;
             }
             validate_tipset_against_cache(bad_block_cache, tipset.key(), &parent_blocks)?;
             parent_blocks.extend(tipset.cids());
             tracker.write().set_epoch(tipset.epoch());
-            parent_tipsets.push(tipset);
+            parent_tipsets.push(tipset.clone());
+            prefetch(tipset).await;
+            downloaded += 1;
+        }
+        metrics::TIPSET_RANGE_SYNC_TIPSETS_TOTAL
+            .with_label_values(&[metrics::values::HEADERS_STAGE])
+            .inc_by(downloaded);
+        if range_traversed {
+            break 'sync;
         }
     }
     drop(wp);
@@ -885,7 +955,10 @@ async fn sync_headers_in_reverse<DB: Blockstore + Sync + Send + 'static>(
                 // iterator is immediately dropped
                 let mut fork_tipsets = fork_tipsets;
                 fork_tipsets.drain((i + 1)..);
-                parent_tipsets.extend(fork_tipsets);
+                for tipset in fork_tipsets {
+                    parent_tipsets.push(tipset.clone());
+                    prefetch(tipset).await;
+                }
                 break;
             }
 
@@ -931,7 +1004,7 @@ async fn sync_tipset<DB: Blockstore + Sync + Send + 'static>(
         // Include a dummy WorkerState
         crate::chain_sync::chain_muxer::WorkerState::default(),
         state_manager,
-        network,
+        MessageDownloads::new(network, chain_store.clone()),
         chain_store.clone(),
         &bad_block_cache,
         vec![proposed_head.clone()],
@@ -1019,34 +1092,163 @@ async fn fetch_batch<DB: Blockstore>(
     }
 }
 
+/// Returns the batch of a chunk of consecutive tipsets, from the newest to the
+/// oldest, whose messages are downloaded together. Both the prefetching and the
+/// validation of a range chunk it by `request_window` tipsets from its newest
+/// tipset, so that they download the same batches.
+fn message_batch(tipsets: &[Arc<Tipset>]) -> Vec<Arc<Tipset>> {
+    tipsets.iter().rev().cloned().collect()
+}
+
+type MessageDownload = Shared<BoxFuture<'static, Result<(), String>>>;
+
+/// Message downloads of a tipset range, shared by the prefetching and the
+/// validation so that each batch is downloaded once. Downloaded messages are
+/// persisted in the `BlockStore`.
+struct MessageDownloads<DB> {
+    network: SyncNetworkContext<DB>,
+    chainstore: Arc<ChainStore<DB>>,
+    /// Downloads by the key of the newest tipset of their batch.
+    downloads: Mutex<HashMap<TipsetKeys, MessageDownload>>,
+}
+
+impl<DB: Blockstore + Send + Sync + 'static> MessageDownloads<DB> {
+    fn new(network: SyncNetworkContext<DB>, chainstore: Arc<ChainStore<DB>>) -> Arc<Self> {
+        Arc::new(Self {
+            network,
+            chainstore,
+            downloads: Default::default(),
+        })
+    }
+
+    /// Downloads the messages of `batch`, or joins the download of the batch
+    /// that is already running.
+    fn download(self: &Arc<Self>, batch: &[Arc<Tipset>]) -> MessageDownload {
+        let Some(head) = batch.last() else {
+            return futures::future::ready(Ok(())).boxed().shared();
+        };
+        let this = self.clone();
+        let batch = batch.to_vec();
+        self.downloads
+            .lock()
+            .entry(head.key().clone())
+            .or_insert_with(|| {
+                async move {
+                    let db = this.chainstore.blockstore();
+                    let batch = fetch_batch(batch, &this.network, db)
+                        .await
+                        .map_err(|e| e.to_string())?;
+                    // Persists the message roots, so that the full tipsets
+                    // can be loaded from the `BlockStore`
+                    for full_tipset in batch {
+                        let validator = TipsetValidator(&full_tipset);
+                        if let Err(e) = full_tipset
+                            .blocks()
+                            .iter()
+                            .try_for_each(|block| validator.validate_msg_root(db, block))
+                        {
+                            debug!(
+                                "Downloaded messages of tipset at epoch {} are invalid: {e}",
+                                full_tipset.epoch()
+                            );
+                        }
+                    }
+                    Ok(())
+                }
+                .boxed()
+                .shared()
+            })
+            .clone()
+    }
+
+    /// Returns the full tipsets of `batch`, waiting for the download of their
+    /// messages. Messages that failed to download or that don't match their
+    /// message roots are requested again, the latter failing the validation.
+    async fn full_tipsets(
+        self: Arc<Self>,
+        batch: Vec<Arc<Tipset>>,
+    ) -> Result<Vec<FullTipset>, TipsetRangeSyncerError> {
+        let download = self.download(&batch).await;
+        if let Some(head) = batch.last() {
+            self.downloads.lock().remove(head.key());
+            if let Err(e) = download {
+                debug!(
+                    "Downloading the messages of tipsets up to epoch {} failed: {e}",
+                    head.epoch()
+                );
+            }
+        }
+        fetch_batch(batch, &self.network, self.chainstore.blockstore()).await
+    }
+}
+
+/// Downloads the messages of the tipsets received from `headers_rx`, going
+/// backwards from the proposed head. Runs concurrently with the forward
+/// download of [`sync_messages_check_state`], the two meeting somewhere in the
+/// middle of the range.
+async fn prefetch_messages<DB: Blockstore + Send + Sync + 'static>(
+    headers_rx: flume::Receiver<Arc<Tipset>>,
+    downloads: Arc<MessageDownloads<DB>>,
+    request_window: usize,
+) {
+    headers_rx
+        .into_stream()
+        .chunks(request_window)
+        .map(|tipsets| downloads.download(&message_batch(&tipsets)))
+        .buffer_unordered(MAX_CONCURRENT_MESSAGE_BATCHES)
+        .for_each(|_| async {})
+        .await
+}
+
 /// Going forward along the tipsets, try to load the messages in them from the
 /// `BlockStore`, or download them from the network, then validate the full
-/// tipset on each epoch.
+/// tipset on each epoch. Downloading and validating run in separate tasks, the
+/// downloads pausing when [`VALIDATION_QUEUE_SIZE`] batches are waiting to be
+/// validated.
 #[allow(clippy::too_many_arguments)]
 async fn sync_messages_check_state<DB: Blockstore + Send + Sync + 'static>(
     tracker: crate::chain_sync::chain_muxer::WorkerState,
     state_manager: Arc<StateManager<DB>>,
-    network: SyncNetworkContext<DB>,
+    downloads: Arc<MessageDownloads<DB>>,
     chainstore: Arc<ChainStore<DB>>,
     bad_block_cache: &BadBlockCache,
     tipsets: Vec<Arc<Tipset>>,
     genesis: &Tipset,
     invalid_block_strategy: InvalidBlockStrategy,
 ) -> Result<(), TipsetRangeSyncerError> {
-    let request_window = state_manager.chain_config().request_window;
-    let db = chainstore.blockstore();
-
-    // Stream through the tipsets from lowest epoch to highest epoch
-    stream::iter(tipsets.into_iter().rev())
-        // Chunk tipsets in batches (default batch size is 8)
-        .chunks(request_window as usize)
-        // Request batches from the p2p network
-        .map(|batch| fetch_batch(batch, &network, db))
-        // run 64 batches concurrently
-        .buffered(64)
-        // validate each full tipset in each batch
-        .try_for_each(|batch| async {
-            for full_tipset in batch {
+    let request_window = state_manager.chain_config().request_window as usize;
+
+    // The download task is aborted when the validation fails
+    let (batch_tx, batch_rx) = flume::bounded(VALIDATION_QUEUE_SIZE);
+    let mut download = JoinSet::new();
+    download.spawn(async move {
+        // Stream through the batches from lowest epoch to highest epoch
+        let batches: Vec<_> = tipsets.chunks(request_window).map(message_batch).collect();
+        let mut batches = stream::iter(batches.into_iter().rev())
+            .map(|batch| downloads.clone().full_tipsets(batch))
+            .buffered(MAX_CONCURRENT_MESSAGE_BATCHES);
+        while let Some(batch) = batches.next().await {
+            let failed = batch.is_err();
+            if let Ok(batch) = &batch {
+                metrics::TIPSET_RANGE_SYNC_TIPSETS_TOTAL
+                    .with_label_values(&[metrics::values::MESSAGES_STAGE])
+                    .inc_by(batch.len() as u64);
+            }
+            if batch_tx.send_async(batch).await.is_err() {
+                break;
+            }
+            metrics::TIPSET_RANGE_SYNC_VALIDATION_QUEUE.inc();
+            if failed {
+                break;
+            }
+        }
+    });
+
+    // Validate each full tipset in each batch
+    let validated = async {
+        while let Ok(batch) = batch_rx.recv_async().await {
+            metrics::TIPSET_RANGE_SYNC_VALIDATION_QUEUE.dec();
+            for full_tipset in batch? {
                 let current_epoch = full_tipset.epoch();
                 let timer = metrics::TIPSET_PROCESSING_TIME.start_timer();
                 validate_tipset(
@@ -1062,10 +1264,19 @@ async fn sync_messages_check_state<DB: Blockstore + Send + Sync + 'static>(
                 chainstore.set_heaviest_tipset(Arc::new(full_tipset.into_tipset()))?;
                 tracker.write().set_epoch(current_epoch);
                 metrics::LAST_VALIDATED_TIPSET_EPOCH.set(current_epoch as u64);
+                metrics::TIPSET_RANGE_SYNC_TIPSETS_TOTAL
+                    .with_label_values(&[metrics::values::VALIDATION_STAGE])
+                    .inc();
             }
-            Ok(())
-        })
-        .await
+        }
+        Ok(())
+    }
+    .await;
+
+    // Batches left in the queue when the validation failed are never validated
+    download.shutdown().await;
+    metrics::TIPSET_RANGE_SYNC_VALIDATION_QUEUE.sub(batch_rx.len() as i64);
+    validated
 }
 
 /// Validates full blocks in the tipset in parallel (since the messages are not
@@ -1541,8 +1752,14 @@ fn validate_tipset_against_cache(
 mod test {
     use crate::blocks::VRFProof;
     use crate::blocks::{BlockHeader, ElectionProof, Ticket, Tipset};
+    use crate::db::MemoryDB;
+    use crate::libp2p::chain_exchange::{make_chain_exchange_response, ChainExchangeRequest};
+    use crate::libp2p::{NetworkMessage, PeerId, PeerManager};
+    use crate::networks::ChainConfig;
     use crate::shim::address::Address;
+    use crate::utils::db::CborStoreExt as _;
     use cid::Cid;
+    use futures::TryStreamExt as _;
     use num_bigint::BigInt;
 
     use super::*;
@@ -1592,4 +1809,182 @@ mod test {
         assert_eq!(ts, ts3);
         assert_eq!(ts.weight(), &BigInt::from(10));
     }
+
+    /// Returns a chain store holding a chain of `len` tipsets after its
+    /// genesis, each with a message of its own, and the tipsets from the newest
+    /// to the genesis.
+    fn chain(len: i64) -> (Arc<ChainStore<MemoryDB>>, Vec<Arc<Tipset>>) {
+        let db = Arc::new(MemoryDB::default());
+        let header = |parents: TipsetKeys, epoch: ChainEpoch| {
+            let message = Message {
+                sequence: epoch as u64,
+                ..Default::default()
+            };
+            db.put_cbor_default(&message).unwrap();
+            let messages = TipsetValidator::compute_msg_root(&db, &[message], &[]).unwrap();
+            BlockHeader::builder()
+                .miner_address(Address::new_id(0))
+                .messages(messages)
+                .parents(parents)
+                .epoch(epoch)
+                .build()
+                .unwrap()
+        };
+        let genesis = header(TipsetKeys::default(), 0);
+        db.put_cbor_default(&genesis).unwrap();
+        let mut tipsets = vec![Arc::new(Tipset::from(&genesis))];
+        for epoch in 1..=len {
+            let block = header(tipsets.last().unwrap().key().clone(), epoch);
+            db.put_cbor_default(&block).unwrap();
+            tipsets.push(Arc::new(Tipset::from(block)));
+        }
+        tipsets.reverse();
+        let cs = ChainStore::new(db.clone(), db, Arc::new(ChainConfig::default()), genesis);
+        (Arc::new(cs.unwrap()), tipsets)
+    }
+
+    /// A network of `peers` peers serving the chain of `remote`. The chain
+    /// exchange requests of each peer are answered by `respond`, and recorded
+    /// with the peer.
+    async fn network(
+        remote: Arc<ChainStore<MemoryDB>>,
+        local: Arc<MemoryDB>,
+        peers: usize,
+        respond: impl Fn(usize, &ChainExchangeRequest) -> Option<ChainExchangeRequest> + Send + 'static,
+    ) -> (
+        SyncNetworkContext<MemoryDB>,
+        Vec<PeerId>,
+        Arc<Mutex<Vec<(PeerId, ChainExchangeRequest)>>>,
+    ) {
+        let peer_manager = Arc::new(PeerManager::default());
+        let peers: Vec<_> = (0..peers).map(|_| PeerId::random()).collect();
+        for peer in &peers {
+            peer_manager
+                .update_peer_head(*peer, Arc::new(Tipset::from(remote.genesis())))
+                .await;
+        }
+        let requests = Arc::new(Mutex::new(vec![]));
+        let (network_send, network_rx) = flume::unbounded();
+        tokio::spawn({
+            let (peers, requests) = (peers.clone(), requests.clone());
+            async move {
+                while let Ok(message) = network_rx.recv_async().await {
+                    if let NetworkMessage::ChainExchangeRequest {
+                        peer_id,
+                        request,
+                        response_channel,
+                    } = message
+                    {
+                        requests.lock().push((peer_id, request.clone()));
+                        let peer = peers.iter().position(|peer| peer == &peer_id).unwrap();
+                        // Unanswered requests fail
+                        if let Some(request) = respond(peer, &request) {
+                            let response = make_chain_exchange_response(&remote, &request);
+                            let _ = response_channel.send(Ok(response));
+                        }
+                    }
+                }
+            }
+        });
+        let network = SyncNetworkContext::new(network_send, peer_manager, local);
+        (network, peers, requests)
+    }
+
+    #[tokio::test]
+    async fn headers_are_requested_from_several_peers() {
+        let (remote, tipsets) = chain(250);
+        let genesis = tipsets.last().unwrap().clone();
+        let local = Arc::new(MemoryDB::default());
+        local.put_cbor_default(genesis.min_ticket_block()).unwrap();
+        let local_cs = ChainStore::new(
+            local.clone(),
+            local.clone(),
+            Arc::new(ChainConfig::default()),
+            genesis.min_ticket_block().clone(),
+        )
+        .unwrap();
+        // The first peer never answers, the second one answers with the wrong
+        // headers, only the third one is honest
+        let chain = tipsets.clone();
+        let (network, _, requests) = network(remote, local, 3, move |peer, request| match peer {
+            0 => None,
+            1 => {
+                let position = chain
+                    .iter()
+                    .position(|tipset| tipset.cids() == request.start)
+                    .unwrap();
+                Some(ChainExchangeRequest {
+                    start: chain[(position + 1).min(chain.len() - 1)].cids(),
+                    ..request.clone()
+                })
+            }
+            _ => Some(request.clone()),
+        })
+        .await;
+        let (headers_tx, headers_rx) = flume::unbounded();
+
+        let synced = sync_headers_in_reverse(
+            Default::default(),
+            250,
+            tipsets[0].clone(),
+            &genesis,
+            &BadBlockCache::default(),
+            &local_cs,
+            network,
+            headers_tx,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(synced, tipsets);
+        assert_eq!(headers_rx.drain().collect::<Vec<_>>(), tipsets);
+        // Windows of 100, 100 and 49 headers
+        let windows: HashSet<_> = requests
+            .lock()
+            .iter()
+            .map(|(_, request)| request.start.clone())
+            .collect();
+        assert_eq!(windows.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn message_batches_are_downloaded_once() {
+        const REQUEST_WINDOW: usize = 8;
+        let (remote, mut tipsets) = chain(20);
+        // The messages of the genesis are not downloaded
+        tipsets.pop();
+        let local = Arc::new(MemoryDB::default());
+        let local_cs = ChainStore::new(
+            local.clone(),
+            local.clone(),
+            Arc::new(ChainConfig::default()),
+            remote.genesis().clone(),
+        )
+        .unwrap();
+        // A single peer, requests for messages are raced among peers
+        let (network, _, requests) =
+            network(remote, local, 1, |_, request| Some(request.clone())).await;
+        let downloads = MessageDownloads::new(network, Arc::new(local_cs));
+
+        // The prefetching and the validation go through the batches in
+        // opposite directions
+        let (headers_tx, headers_rx) = flume::unbounded();
+        for tipset in &tipsets {
+            headers_tx.send(tipset.clone()).unwrap();
+        }
+        drop(headers_tx);
+        let batches: Vec<_> = tipsets.chunks(REQUEST_WINDOW).map(message_batch).collect();
+        let validated = stream::iter(batches.into_iter().rev())
+            .map(|batch| downloads.clone().full_tipsets(batch))
+            .buffered(MAX_CONCURRENT_MESSAGE_BATCHES)
+            .try_concat();
+        let ((), full_tipsets) = tokio::join!(
+            prefetch_messages(headers_rx, downloads.clone(), REQUEST_WINDOW),
+            validated
+        );
+
+        let epochs: Vec<_> = full_tipsets.unwrap().iter().map(|ts| ts.epoch()).collect();
+        assert_eq!(epochs, (1..=20).collect::<Vec<_>>());
+        assert_eq!(requests.lock().len(), 3);
+    }
 }
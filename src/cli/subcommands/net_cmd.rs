@@ -2,11 +2,15 @@
 // SPDX-License-Identifier: Apache-2.0, MIT
 
 use crate::libp2p::{Multiaddr, Protocol};
-use crate::rpc_api::data_types::AddrInfo;
+use crate::rpc_api::{
+    data_types::AddrInfo,
+    net_api::{NetBandwidthStats, NetBlockList},
+};
 use crate::rpc_client::net_ops::*;
 use ahash::HashSet;
 use cid::multibase;
 use clap::Subcommand;
+use human_repr::HumanCount;
 use itertools::Itertools;
 
 use super::{handle_rpc_err, print_stdout, Config};
@@ -19,7 +23,18 @@ pub enum NetCommands {
     /// Lists `libp2p` swarm network info
     Info,
     /// Lists `libp2p` swarm peers
-    Peers,
+    Peers {
+        /// Print a table with the agent version, connection direction and
+        /// request statistics of each peer
+        #[arg(long)]
+        detailed: bool,
+    },
+    /// Prints the bandwidth used by the node, summed over all peers
+    Bandwidth {
+        /// Break the bandwidth down per `libp2p` protocol
+        #[arg(long)]
+        by_protocol: bool,
+    },
     /// Connects to a peer by its peer ID and multi-addresses
     Connect {
         /// Multi-address (with `/p2p/` protocol)
@@ -30,6 +45,101 @@ pub enum NetCommands {
         /// Peer ID to disconnect from
         id: String,
     },
+    /// Prints the node's reachability as determined by `AutoNAT`
+    Reachability,
+    /// Manages the peers, IP addresses and IP subnets the node refuses
+    /// connections with
+    #[command(subcommand)]
+    Block(NetBlockCommands),
+    /// Manages the peers that are exempt from connection pruning and bans
+    #[command(subcommand)]
+    Protect(NetProtectCommands),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum NetBlockCommands {
+    /// Adds entries to the block list
+    #[command(subcommand)]
+    Add(NetBlockTarget),
+    /// Removes entries from the block list
+    #[command(subcommand)]
+    Remove(NetBlockTarget),
+    /// Lists the block list
+    List,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum NetBlockTarget {
+    /// Peer IDs
+    Peer {
+        #[arg(required = true)]
+        peers: Vec<String>,
+    },
+    /// IP addresses
+    Ip {
+        #[arg(required = true)]
+        ip_addrs: Vec<String>,
+    },
+    /// IP subnets in CIDR notation, e.g. `10.0.0.0/8`
+    Subnet {
+        #[arg(required = true)]
+        ip_subnets: Vec<String>,
+    },
+    /// Multi-addresses; their IP address and `/p2p/` peer ID, if present, are
+    /// used
+    Addr {
+        #[arg(required = true)]
+        addrs: Vec<Multiaddr>,
+    },
+}
+
+impl From<NetBlockTarget> for NetBlockList {
+    fn from(target: NetBlockTarget) -> Self {
+        match target {
+            NetBlockTarget::Peer { peers } => Self {
+                peers,
+                ..Default::default()
+            },
+            NetBlockTarget::Ip { ip_addrs } => Self {
+                ip_addrs,
+                ..Default::default()
+            },
+            NetBlockTarget::Subnet { ip_subnets } => Self {
+                ip_subnets,
+                ..Default::default()
+            },
+            NetBlockTarget::Addr { addrs } => {
+                let mut list = Self::default();
+                for protocol in addrs.iter().flat_map(|addr| addr.iter()) {
+                    match protocol {
+                        Protocol::Ip4(ip) => list.ip_addrs.push(ip.to_string()),
+                        Protocol::Ip6(ip) => list.ip_addrs.push(ip.to_string()),
+                        Protocol::P2p(peer) => list.peers.push(peer.to_string()),
+                        _ => {}
+                    }
+                }
+                list
+            }
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum NetProtectCommands {
+    /// Protects peers from connection pruning and bans
+    Add {
+        /// Peer IDs
+        #[arg(required = true)]
+        peers: Vec<String>,
+    },
+    /// Lifts the protection of peers
+    Remove {
+        /// Peer IDs
+        #[arg(required = true)]
+        peers: Vec<String>,
+    },
+    /// Lists the protected peers
+    List,
 }
 
 impl NetCommands {
@@ -60,7 +170,38 @@ impl NetCommands {
                 println!("num established: {}", info.num_established);
                 Ok(())
             }
-            Self::Peers => {
+            Self::Peers { detailed: true } => {
+                let peers = net_peers((), &config.client.rpc_token)
+                    .await
+                    .map_err(handle_rpc_err)?;
+                println!(
+                    "{:<52} {:<9} {:<32} {:>8} {:>8} {:>8} {:>10} {:>8}",
+                    "ID", "Direction", "Agent", "Head", "Success", "Failure", "Latency", "Cost"
+                );
+                for peer in peers.into_iter().sorted_by(|a, b| a.id.cmp(&b.id)) {
+                    // The peer may have disconnected in the meantime
+                    let Ok(info) = net_peer_info((peer.id,), &config.client.rpc_token).await else {
+                        continue;
+                    };
+                    println!(
+                        "{:<52} {:<9} {:<32} {:>8} {:>8} {:>8} {:>10} {:>8}",
+                        info.id,
+                        info.direction,
+                        info.agent,
+                        info.head_epoch
+                            .map(|epoch| epoch.to_string())
+                            .unwrap_or_default(),
+                        info.successes,
+                        info.failures,
+                        format!("{}ms", info.average_time_ms),
+                        info.cost
+                            .map(|cost| format!("{cost:.3}"))
+                            .unwrap_or_default(),
+                    );
+                }
+                Ok(())
+            }
+            Self::Peers { detailed: false } => {
                 let addrs = net_peers((), &config.client.rpc_token)
                     .await
                     .map_err(handle_rpc_err)?;
@@ -87,6 +228,36 @@ impl NetCommands {
                 print_stdout(output.join("\n"));
                 Ok(())
             }
+            Self::Bandwidth { by_protocol } => {
+                println!(
+                    "{:<28} {:>12} {:>12} {:>12} {:>12}",
+                    "Protocol", "TotalIn", "TotalOut", "RateIn", "RateOut"
+                );
+                let print_row = |protocol: &str, stats: &NetBandwidthStats| {
+                    println!(
+                        "{:<28} {:>12} {:>12} {:>12} {:>12}",
+                        protocol,
+                        stats.total_in.human_count_bytes().to_string(),
+                        stats.total_out.human_count_bytes().to_string(),
+                        format!("{}/s", stats.rate_in.human_count_bytes()),
+                        format!("{}/s", stats.rate_out.human_count_bytes()),
+                    )
+                };
+                if by_protocol {
+                    let stats = net_bandwidth_stats_by_protocol((), &config.client.rpc_token)
+                        .await
+                        .map_err(handle_rpc_err)?;
+                    for (protocol, stats) in stats.iter().sorted_by(|a, b| a.0.cmp(b.0)) {
+                        print_row(protocol, stats);
+                    }
+                } else {
+                    let stats = net_bandwidth_stats((), &config.client.rpc_token)
+                        .await
+                        .map_err(handle_rpc_err)?;
+                    print_row("Total", &stats);
+                }
+                Ok(())
+            }
             Self::Connect { address } => {
                 let addr: Multiaddr = address
                     .parse()
@@ -127,6 +298,67 @@ impl NetCommands {
                 println!("disconnect {id}: success");
                 Ok(())
             }
+            Self::Reachability => {
+                let nat_status = net_auto_nat_status((), &config.client.rpc_token)
+                    .await
+                    .map_err(handle_rpc_err)?;
+                println!("AutoNAT status: {}", nat_status.reachability_as_str());
+                if let Some(addrs) = nat_status.public_addrs {
+                    if !addrs.is_empty() {
+                        println!("Public address: {}", addrs.join(", "));
+                    }
+                }
+                Ok(())
+            }
+            Self::Block(NetBlockCommands::Add(target)) => {
+                net_block_add((target.into(),), &config.client.rpc_token)
+                    .await
+                    .map_err(handle_rpc_err)?;
+                Ok(())
+            }
+            Self::Block(NetBlockCommands::Remove(target)) => {
+                net_block_remove((target.into(),), &config.client.rpc_token)
+                    .await
+                    .map_err(handle_rpc_err)?;
+                Ok(())
+            }
+            Self::Block(NetBlockCommands::List) => {
+                let block_list = net_block_list((), &config.client.rpc_token)
+                    .await
+                    .map_err(handle_rpc_err)?;
+                for (title, entries) in [
+                    ("Blocked Peers", block_list.peers),
+                    ("Blocked IPs", block_list.ip_addrs),
+                    ("Blocked Subnets", block_list.ip_subnets),
+                ] {
+                    if !entries.is_empty() {
+                        println!("{title}:");
+                        for entry in entries {
+                            println!("\t{entry}");
+                        }
+                    }
+                }
+                Ok(())
+            }
+            Self::Protect(NetProtectCommands::Add { peers }) => {
+                net_protect_add((peers,), &config.client.rpc_token)
+                    .await
+                    .map_err(handle_rpc_err)?;
+                Ok(())
+            }
+            Self::Protect(NetProtectCommands::Remove { peers }) => {
+                net_protect_remove((peers,), &config.client.rpc_token)
+                    .await
+                    .map_err(handle_rpc_err)?;
+                Ok(())
+            }
+            Self::Protect(NetProtectCommands::List) => {
+                let peers = net_protect_list((), &config.client.rpc_token)
+                    .await
+                    .map_err(handle_rpc_err)?;
+                print_stdout(peers.join("\n"));
+                Ok(())
+            }
         }
     }
 }
@@ -10,7 +10,7 @@ use crate::lotus_json::LotusJson;
 use crate::rpc_client::state_ops::state_fetch_root;
 use crate::shim::clock::ChainEpoch;
 use crate::shim::econ::TokenAmount;
-use crate::statediff::print_state_diff;
+use crate::statediff::{print_state_diff, state_diff};
 use cid::Cid;
 use clap::Subcommand;
 use serde_tuple::{self, Deserialize_tuple, Serialize_tuple};
@@ -29,6 +29,16 @@ struct VestingScheduleEntry {
     amount: TokenAmount,
 }
 
+/// Output format of `forest-cli state diff`.
+#[derive(Debug, Clone, Copy, Default, clap::ValueEnum)]
+pub enum DiffFormat {
+    /// A colored diff of the actor states
+    #[default]
+    Text,
+    /// Field-level changes of the actor states, for tooling
+    Json,
+}
+
 #[derive(Debug, Subcommand)]
 pub enum StateCommands {
     Fetch {
@@ -45,6 +55,9 @@ pub enum StateCommands {
         /// The depth at which IPLD links are resolved
         #[arg(short, long)]
         depth: Option<u64>,
+        /// The output format
+        #[arg(long, value_enum, default_value_t)]
+        format: DiffFormat,
     },
 }
 
@@ -59,7 +72,12 @@ impl StateCommands {
                         .map_err(handle_rpc_err)?
                 );
             }
-            Self::Diff { pre, post, depth } => {
+            Self::Diff {
+                pre,
+                post,
+                depth,
+                format,
+            } => {
                 let chain_path = config
                     .client
                     .data_dir
@@ -67,8 +85,16 @@ impl StateCommands {
                 let blockstore =
                     Arc::new(open_proxy_db(db_root(&chain_path)?, Default::default())?);
 
-                if let Err(err) = print_state_diff(&blockstore, &pre, &post, depth) {
-                    eprintln!("Failed to print state diff: {err}");
+                match format {
+                    DiffFormat::Text => {
+                        if let Err(err) = print_state_diff(&blockstore, &pre, &post, depth) {
+                            eprintln!("Failed to print state diff: {err}");
+                        }
+                    }
+                    DiffFormat::Json => {
+                        let diff = state_diff(blockstore.as_ref(), &pre, &post)?;
+                        println!("{}", serde_json::to_string_pretty(&diff)?);
+                    }
                 }
             }
         }
@@ -7,6 +7,8 @@ use std::{
     str::FromStr,
 };
 
+use crate::cli_shared::snapshot::SnapshotVendor;
+use crate::networks::Height;
 use crate::rpc_client::DEFAULT_PORT;
 use crate::utils::io::ProgressBarVisibility;
 use chrono::Duration;
@@ -34,6 +36,39 @@ impl Default for BufferSize {
     }
 }
 
+/// An actor bundle CAR file, loaded from a local path or a URL in addition to the compiled-in
+/// bundles. On devnets, it may set the bundle of the network upgrade at `height`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(test, derive(derive_quickcheck_arbitrary::Arbitrary))]
+pub struct ActorBundleSource {
+    pub location: String,
+    pub height: Option<Height>,
+}
+
+/// Parses `[HEIGHT=]LOCATION`, e.g. `Watermelon=./builtin-actors-devnet.car`.
+impl FromStr for ActorBundleSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use serde::de::{value::Error, IntoDeserializer as _};
+
+        if let Some((height, location)) = s.split_once('=') {
+            if let Ok(height) = Height::deserialize(
+                height.into_deserializer() as serde::de::value::StrDeserializer<Error>
+            ) {
+                return Ok(ActorBundleSource {
+                    location: location.into(),
+                    height: Some(height),
+                });
+            }
+        }
+        Ok(ActorBundleSource {
+            location: s.into(),
+            height: None,
+        })
+    }
+}
+
 #[serde_as]
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(default)]
@@ -48,6 +83,9 @@ pub struct Client {
     pub snapshot: bool,
     /// If this is true, delete the snapshot at `snapshot_path` if it's a local file.
     pub consume_snapshot: bool,
+    /// If this is true, snapshots imported from a URL are downloaded and kept on disk instead
+    /// of being streamed into the database.
+    pub keep_snapshot: bool,
     pub snapshot_height: Option<i64>,
     pub snapshot_head: Option<i64>,
     pub snapshot_path: Option<PathBuf>,
@@ -73,6 +111,10 @@ pub struct Client {
     pub token_exp: Duration,
     /// Display progress bars mode. Auto will display if TTY.
     pub show_progress_bars: ProgressBarVisibility,
+    /// Hosts of snapshots tried before the trusted vendors when fetching snapshots.
+    pub snapshot_vendors: Vec<SnapshotVendor>,
+    /// Actor bundles loaded in addition to the compiled-in ones.
+    pub actor_bundles: Vec<ActorBundleSource>,
 }
 
 impl Default for Client {
@@ -86,6 +128,7 @@ impl Default for Client {
             snapshot_path: None,
             snapshot: false,
             consume_snapshot: false,
+            keep_snapshot: false,
             snapshot_height: None,
             snapshot_head: None,
             skip_load: false,
@@ -96,6 +139,8 @@ impl Default for Client {
             rpc_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), DEFAULT_PORT),
             token_exp: Duration::seconds(5184000), // 60 Days = 5184000 Seconds
             show_progress_bars: Default::default(),
+            snapshot_vendors: vec![],
+            actor_bundles: vec![],
         }
     }
 }
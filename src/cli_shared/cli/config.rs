@@ -1,6 +1,8 @@
 // Copyright 2019-2023 ChainSafe Systems
 // SPDX-License-Identifier: Apache-2.0, MIT
 
+use crate::beacon::DrandSources;
+use crate::chain::ChainEpochDelta;
 use crate::chain_sync::SyncConfig;
 use crate::db::db_engine::DbConfig;
 use crate::libp2p::Libp2pConfig;
@@ -37,6 +39,52 @@ impl Default for DaemonConfig {
     }
 }
 
+/// Structure that defines the snapshots periodically published by the daemon
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone)]
+#[cfg_attr(test, derive(derive_quickcheck_arbitrary::Arbitrary))]
+#[serde(default)]
+pub struct SnapshotServiceConfig {
+    pub enabled: bool,
+    /// Snapshots are exported at every multiple of this number of epochs, once it is final.
+    pub interval: ChainEpochDelta,
+    /// Number of most recent snapshots kept on the target, older ones are deleted.
+    pub retain: u32,
+    pub target: SnapshotTarget,
+}
+
+impl Default for SnapshotServiceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval: 2880,
+            retain: 3,
+            target: SnapshotTarget::Filesystem {
+                path: "snapshots".into(),
+            },
+        }
+    }
+}
+
+/// Where the snapshot service publishes snapshots
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone)]
+#[cfg_attr(test, derive(derive_quickcheck_arbitrary::Arbitrary))]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SnapshotTarget {
+    /// A directory on the local filesystem.
+    Filesystem { path: PathBuf },
+    /// A bucket of an S3-compatible object storage, addressed with path-style URLs. The
+    /// credentials default to the `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY` environment
+    /// variables.
+    S3 {
+        endpoint: String,
+        region: String,
+        bucket: String,
+        prefix: String,
+        access_key_id: Option<String>,
+        secret_access_key: Option<String>,
+    },
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Default, Debug, Clone)]
 #[cfg_attr(test, derive(derive_quickcheck_arbitrary::Arbitrary))]
 #[serde(default)]
@@ -47,6 +95,8 @@ pub struct Config {
     pub sync: SyncConfig,
     pub chain: Arc<ChainConfig>,
     pub daemon: DaemonConfig,
+    pub snapshot_service: SnapshotServiceConfig,
+    pub drand: DrandSources,
 }
 
 impl Config {
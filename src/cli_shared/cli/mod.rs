@@ -80,6 +80,14 @@ pub struct CliOpts {
     /// Import a snapshot from a local CAR file and delete it, or from a URL
     #[arg(long)]
     pub consume_snapshot: Option<String>,
+    /// Keep snapshots imported from a URL on disk instead of streaming them into the database
+    #[arg(long)]
+    pub keep_snapshot: bool,
+    /// Load an actor bundle CAR file from a path or URL, in addition to the compiled-in bundles.
+    /// Prefix with `HEIGHT=` to use it for the network upgrade at this height on devnets, e.g.
+    /// `Watermelon=./builtin-actors-devnet.car`
+    #[arg(long)]
+    pub actor_bundle: Vec<ActorBundleSource>,
     /// Halt with exit code 0 after successfully importing a snapshot
     #[arg(long)]
     pub halt_after_import: bool,
@@ -161,6 +169,7 @@ impl CliOpts {
             }
             None => Config::default(),
         };
+        cfg.drand.validate()?;
 
         if let Some(chain) = &self.chain {
             // override the chain configuration
@@ -206,6 +215,12 @@ impl CliOpts {
             cfg.client.snapshot = true;
             cfg.client.consume_snapshot = true;
         }
+        if self.keep_snapshot {
+            cfg.client.keep_snapshot = true;
+        }
+        cfg.client
+            .actor_bundles
+            .extend(self.actor_bundle.iter().cloned());
         if let Some(snapshot_path) = &self.import_chain {
             cfg.client.snapshot_path = Some(snapshot_path.into());
             cfg.client.snapshot = false;
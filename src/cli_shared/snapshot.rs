@@ -9,11 +9,19 @@ use std::{
 
 use crate::{
     networks::NetworkChain,
-    utils::{retry, RetryArgs},
+    shim::clock::ChainEpoch,
+    utils::{
+        io::{AsyncWriterWithChecksum, Checksum as _},
+        reqwest_resume::{RangedDownload, DEFAULT_CONNECTIONS},
+        retry, RetryArgs,
+    },
 };
-use anyhow::{bail, Context as _};
+use anyhow::{bail, ensure, Context as _};
 use chrono::NaiveDate;
-use tracing::event;
+use serde::{Deserialize, Serialize};
+use sha2::{digest::Output, Sha256};
+use tokio::io::AsyncWriteExt as _;
+use tracing::{event, warn};
 use url::Url;
 
 use crate::cli_shared::snapshot::parse::ParsedFilename;
@@ -39,6 +47,99 @@ pub enum TrustedVendor {
     Filops,
 }
 
+impl TrustedVendor {
+    /// The snapshots of the vendor for `chain`. Only the latest snapshot is available.
+    pub fn snapshot_vendor(self, chain: &NetworkChain) -> anyhow::Result<SnapshotVendor> {
+        Ok(SnapshotVendor {
+            name: self.to_string(),
+            latest_url: stable_url(self, chain)?.to_string(),
+            epoch_url: None,
+            checksum_url: match self {
+                TrustedVendor::Forest => Some("{url}.sha256sum".into()),
+                TrustedVendor::Filops => None,
+            },
+        })
+    }
+}
+
+/// A host of snapshots, as set in the `snapshot_vendors` of the client configuration. The URLs
+/// are templates in which `{chain}` is replaced with the name of the chain.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(test, derive(derive_quickcheck_arbitrary::Arbitrary))]
+pub struct SnapshotVendor {
+    /// Name of the vendor, used in the names of the downloaded snapshots.
+    pub name: String,
+    /// URL of the latest snapshot.
+    pub latest_url: String,
+    /// URL of the snapshot at `{epoch}`, if the vendor publishes past snapshots.
+    pub epoch_url: Option<String>,
+    /// URL of the SHA-256 checksum of the snapshot at `{url}`, after redirects. The checksum is
+    /// the first word of the file, as written by `sha256sum`.
+    pub checksum_url: Option<String>,
+}
+
+impl SnapshotVendor {
+    fn snapshot_url(&self, chain: &NetworkChain, epoch: Option<ChainEpoch>) -> anyhow::Result<Url> {
+        let template = match epoch {
+            Some(epoch) => self
+                .epoch_url
+                .as_deref()
+                .with_context(|| format!("vendor {} has no snapshots by epoch", self.name))?
+                .replace("{epoch}", &epoch.to_string()),
+            None => self.latest_url.clone(),
+        };
+        Ok(Url::parse(
+            &template.replace("{chain}", &chain.to_string()),
+        )?)
+    }
+
+    fn checksum_url(
+        &self,
+        chain: &NetworkChain,
+        snapshot_url: &Url,
+    ) -> anyhow::Result<Option<Url>> {
+        self.checksum_url
+            .as_deref()
+            .map(|template| {
+                Ok(Url::parse(
+                    &template
+                        .replace("{chain}", &chain.to_string())
+                        .replace("{url}", snapshot_url.as_str()),
+                )?)
+            })
+            .transpose()
+    }
+}
+
+/// Returns the vendors named in `names`, in that order, looking them up in `custom` and then in
+/// the trusted vendors. Without names, returns the `custom` vendors followed by the trusted ones.
+pub fn vendors(
+    names: &[String],
+    custom: &[SnapshotVendor],
+    chain: &NetworkChain,
+) -> anyhow::Result<Vec<SnapshotVendor>> {
+    use clap::ValueEnum as _;
+
+    let trusted = TrustedVendor::value_variants()
+        .iter()
+        .map(|vendor| vendor.snapshot_vendor(chain))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if names.is_empty() {
+        return Ok(custom.iter().cloned().chain(trusted).collect());
+    }
+    names
+        .iter()
+        .map(|name| {
+            custom
+                .iter()
+                .chain(&trusted)
+                .find(|vendor| &vendor.name == name)
+                .cloned()
+                .with_context(|| format!("unknown snapshot vendor {name}"))
+        })
+        .collect()
+}
+
 /// Create a filename in the "full" format. See [`parse`].
 // Common between export, and [`fetch`].
 // Keep in sync with the CLI documentation for the `snapshot` sub-command.
@@ -61,20 +162,112 @@ pub fn filename(
     .to_string()
 }
 
-/// Returns the path to the downloaded file.
+/// Returns the height of the snapshot named `filename` if it's a snapshot of `chain` from
+/// `vendor`, named as by [`filename`].
+pub fn height_of(filename: &str, vendor: impl Display, chain: impl Display) -> Option<i64> {
+    match ParsedFilename::parse_str(filename).ok()? {
+        ParsedFilename::Full {
+            vendor: v,
+            chain: c,
+            height,
+            ..
+        } if v == vendor.to_string() && c == chain.to_string() => Some(height),
+        _ => None,
+    }
+}
+
+/// Fetches the snapshot at `epoch`, or the latest one, from the first of `vendors` that
+/// succeeds. Snapshots of vendors publishing checksums are verified while downloading. Up to
+/// `connections` ranges of the snapshot are downloaded in parallel. Returns the path to the
+/// downloaded file.
 pub async fn fetch(
     directory: &Path,
     chain: &NetworkChain,
-    vendor: TrustedVendor,
+    vendors: &[SnapshotVendor],
+    epoch: Option<ChainEpoch>,
+    connections: usize,
+) -> anyhow::Result<PathBuf> {
+    ensure!(!vendors.is_empty(), "no snapshot vendors");
+    let mut last_error = None;
+    for vendor in vendors {
+        match fetch_from(directory, chain, vendor, epoch, connections).await {
+            Ok(path) => return Ok(path),
+            Err(e) => {
+                warn!("Failed fetching the snapshot from {}: {e:#}", vendor.name);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.expect("vendors is not empty"))
+}
+
+async fn fetch_from(
+    directory: &Path,
+    chain: &NetworkChain,
+    vendor: &SnapshotVendor,
+    epoch: Option<ChainEpoch>,
+    connections: usize,
 ) -> anyhow::Result<PathBuf> {
-    let (_len, path) = peek(vendor, chain).await?;
-    let (date, height, forest_format) = ParsedFilename::parse_str(&path)
-        .context("unexpected path format")?
-        .date_and_height_and_forest();
-    let url = stable_url(vendor, chain)?;
-    let filename = filename(vendor, chain, date, height, forest_format);
-
-    download_file_with_retry(&url, directory, &filename).await
+    let Peek { url, path, .. } = peek_url(vendor.snapshot_url(chain, epoch)?).await?;
+    // Snapshots with unknown names are saved as is
+    let filename = match ParsedFilename::parse_str(&path) {
+        Ok(parsed) => {
+            let (date, height, forest_format) = parsed.date_and_height_and_forest();
+            filename(&vendor.name, chain, date, height, forest_format)
+        }
+        Err(_) => path,
+    };
+    let sha256 = match vendor.checksum_url(chain, &url)? {
+        Some(checksum_url) => fetch_sha256(&checksum_url).await?,
+        None => None,
+    };
+    // Only the download is retried, a snapshot that does not match its checksum would not match
+    // it again
+    let (path, actual) = retry(
+        RetryArgs {
+            timeout: None,
+            ..Default::default()
+        },
+        || download_http(&url, directory, &filename, sha256.is_some(), connections),
+    )
+    .await
+    .with_context(|| format!("couldn't download {url}"))?;
+    if let (Some(expected), Some(actual)) = (sha256, actual) {
+        if expected != actual.as_slice() {
+            tokio::fs::remove_file(&path).await?;
+            bail!(
+                "checksum mismatch for {url}: expected {}, got {}",
+                hex::encode(expected),
+                hex::encode(actual)
+            );
+        }
+    }
+    Ok(path)
+}
+
+/// Fetches a checksum file, as written by `sha256sum`. Returns [`None`] if there is no such
+/// file, in which case the snapshot is not verified.
+async fn fetch_sha256(url: &Url) -> anyhow::Result<Option<Vec<u8>>> {
+    let response = crate::utils::net::global_http_client()
+        .get(url.clone())
+        .send()
+        .await?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        warn!("No checksum at {url}, the snapshot will not be verified");
+        return Ok(None);
+    }
+    let text = response
+        .error_for_status()
+        .with_context(|| format!("couldn't fetch the checksum at {url}"))?
+        .text()
+        .await?;
+    let checksum = text
+        .split_whitespace()
+        .next()
+        .with_context(|| format!("empty checksum at {url}"))?;
+    let checksum = hex::decode(checksum).with_context(|| format!("invalid checksum at {url}"))?;
+    ensure!(checksum.len() == 32, "invalid SHA-256 checksum at {url}");
+    Ok(Some(checksum))
 }
 
 pub async fn download_file_with_retry(
@@ -87,35 +280,55 @@ pub async fn download_file_with_retry(
             timeout: None,
             ..Default::default()
         },
-        || download_http(url, directory, filename),
+        || download_http(url, directory, filename, false, DEFAULT_CONNECTIONS),
     )
-    .await?)
+    .await?
+    .0)
 }
 
 /// Returns
 /// - The size of the snapshot from this vendor on this chain
 /// - The filename of the snapshot
 pub async fn peek(vendor: TrustedVendor, chain: &NetworkChain) -> anyhow::Result<(u64, String)> {
-    let stable_url = stable_url(vendor, chain)?;
+    let Peek { len, path, .. } = peek_url(stable_url(vendor, chain)?).await?;
+    Ok((len.context("no content-length header")?, path))
+}
+
+struct Peek {
+    /// The URL after redirects.
+    url: Url,
+    len: Option<u64>,
+    path: String,
+}
+
+async fn peek_url(url: Url) -> anyhow::Result<Peek> {
     // issue an actual GET, so the content length will be of the body
     // (we never actually fetch the body)
     // if we issue a HEAD, the content-length will be zero for our stable URLs
     // (this is a bug, maybe in reqwest - HEAD _should_ give us the length)
     // (probably because the stable URLs are all double-redirects 301 -> 302 -> 200)
-    let response = reqwest::get(stable_url)
+    let response = reqwest::get(url)
         .await?
         .error_for_status()
         .context("server returned an error response")?;
-    let cd_path = response
+    let url = response.url().clone();
+    // Fall back to the last segment of the URL without a content-disposition
+    let path = response
         .headers()
         .get(reqwest::header::CONTENT_DISPOSITION)
-        .and_then(parse_content_disposition);
-    Ok((
-        response
-            .content_length()
-            .context("no content-length header")?,
-        cd_path.context("no content-disposition filepath")?,
-    ))
+        .and_then(parse_content_disposition)
+        .or_else(|| {
+            url.path_segments()?
+                .last()
+                .filter(|segment| !segment.is_empty())
+                .map(str::to_owned)
+        })
+        .context("no content-disposition filepath")?;
+    Ok(Peek {
+        len: response.content_length(),
+        url,
+        path,
+    })
 }
 
 // Extract file paths from content-disposition values:
@@ -128,21 +341,36 @@ fn parse_content_disposition(value: &reqwest::header::HeaderValue) -> Option<Str
     Some(cap.get(1)?.as_str().to_owned())
 }
 
-/// Download the file at `url` with a private HTTP client, returning the path to the downloaded file
-async fn download_http(url: &Url, directory: &Path, filename: &str) -> anyhow::Result<PathBuf> {
+/// Download the file at `url` with a private HTTP client, returning the path to the downloaded
+/// file, and its SHA-256 hash with `checksum`. The file is downloaded over `connections` parallel
+/// ranged requests if the server accepts them, and as a single stream otherwise.
+async fn download_http(
+    url: &Url,
+    directory: &Path,
+    filename: &str,
+    checksum: bool,
+    connections: usize,
+) -> anyhow::Result<(PathBuf, Option<Output<Sha256>>)> {
     let dst_path = directory.join(filename);
 
     event!(target: "forest::snapshot", tracing::Level::INFO, %url, "downloading snapshot");
-    let mut reader = crate::utils::net::reader(url.as_str()).await?;
-
-    let mut dst = tokio::fs::File::create(&dst_path)
-        .await
-        .context("couldn't create destination file")?;
-
-    tokio::io::copy(&mut reader, &mut dst)
-        .await
-        .map(|_| dst_path)
-        .context("couldn't download file")
+    let actual = match RangedDownload::probe(url.clone(), connections).await? {
+        Some(download) => download.run(&dst_path, checksum).await?,
+        None => {
+            let mut reader = crate::utils::net::reader(url.as_str()).await?;
+            let dst = tokio::fs::File::create(&dst_path)
+                .await
+                .context("couldn't create destination file")?;
+            let mut dst =
+                AsyncWriterWithChecksum::<Sha256, _>::new(tokio::io::BufWriter::new(dst), checksum);
+            tokio::io::copy(&mut reader, &mut dst)
+                .await
+                .context("couldn't download file")?;
+            dst.flush().await?;
+            dst.finalize()?
+        }
+    };
+    Ok((dst_path, actual))
 }
 
 /// Also defines an `ALL_URLS` constant for test purposes
@@ -447,8 +675,196 @@ mod parse {
 
 #[cfg(test)]
 mod tests {
-    use super::parse_content_disposition;
+    use super::*;
+    use axum::{
+        http::{header, HeaderMap, StatusCode},
+        response::{IntoResponse as _, Redirect},
+        routing::get,
+        Router,
+    };
     use reqwest::header::HeaderValue;
+    use sha2::Digest as _;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    const SNAPSHOT: &[u8] = b"snapshot data";
+    const SNAPSHOT_PATH: &str = "/good/calibnet/900_2023_09_14T06_13_00Z.car.zst";
+    const MISMATCH_PATH: &str = "/mismatch/calibnet/900_2023_09_14T06_13_00Z.car.zst";
+    const UNVERIFIED_PATH: &str = "/unverified/calibnet/900_2023_09_14T06_13_00Z.car.zst";
+
+    /// Serves the snapshot from the `good` vendor, with a checksum, from the `mismatch` vendor
+    /// with a wrong checksum and byte ranges, from the `unverified` vendor without a checksum,
+    /// from the `epoch` vendor by epoch, and fails from the `broken` vendor. Returns the number of
+    /// ranged requests of the snapshot of the `mismatch` vendor.
+    fn serve_vendors() -> (SocketAddr, Arc<AtomicUsize>) {
+        let sha256 = hex::encode(sha2::Sha256::digest(SNAPSHOT));
+        let wrong_sha256 = hex::encode(sha2::Sha256::digest(b"other data"));
+        let mismatch_requests = Arc::new(AtomicUsize::new(0));
+        let counter = mismatch_requests.clone();
+        let app = Router::new()
+            .route(
+                "/broken/calibnet/latest",
+                get(|| async { StatusCode::INTERNAL_SERVER_ERROR }),
+            )
+            .route(
+                "/good/calibnet/latest",
+                get(|| async { Redirect::temporary(SNAPSHOT_PATH) }),
+            )
+            .route(SNAPSHOT_PATH, get(|| async { SNAPSHOT }))
+            .route(
+                &format!("{SNAPSHOT_PATH}.sha256sum"),
+                get(|| async move { format!("{sha256}  snapshot.car.zst") }),
+            )
+            .route(
+                "/mismatch/calibnet/latest",
+                get(|| async { Redirect::temporary(MISMATCH_PATH) }),
+            )
+            .route(
+                MISMATCH_PATH,
+                get(move |headers: HeaderMap| async move {
+                    let accept_ranges = (header::ACCEPT_RANGES, "bytes".to_owned());
+                    if !headers.contains_key(header::RANGE) {
+                        return ([accept_ranges.clone()], SNAPSHOT).into_response();
+                    }
+                    counter.fetch_add(1, Ordering::Relaxed);
+                    let content_range =
+                        format!("bytes 0-{}/{}", SNAPSHOT.len() - 1, SNAPSHOT.len());
+                    (
+                        StatusCode::PARTIAL_CONTENT,
+                        [accept_ranges, (header::CONTENT_RANGE, content_range)],
+                        SNAPSHOT,
+                    )
+                        .into_response()
+                }),
+            )
+            .route(
+                &format!("{MISMATCH_PATH}.sha256sum"),
+                get(|| async move { format!("{wrong_sha256}  snapshot.car.zst") }),
+            )
+            .route(
+                "/unverified/calibnet/latest",
+                get(|| async { Redirect::temporary(UNVERIFIED_PATH) }),
+            )
+            .route(UNVERIFIED_PATH, get(|| async { SNAPSHOT }))
+            .route("/epoch/calibnet/100.car.zst", get(|| async { SNAPSHOT }));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        (addr, mismatch_requests)
+    }
+
+    fn vendor(addr: SocketAddr, name: &str) -> SnapshotVendor {
+        SnapshotVendor {
+            name: name.into(),
+            latest_url: format!("http://{addr}/{name}/{{chain}}/latest"),
+            epoch_url: None,
+            checksum_url: Some("{url}.sha256sum".into()),
+        }
+    }
+
+    #[tokio::test]
+    async fn fetch_from_vendors() {
+        let (addr, mismatch_requests) = serve_vendors();
+        let directory = tempfile::tempdir().unwrap();
+        let chain = NetworkChain::Calibnet;
+
+        // Vendors are tried in order
+        let vendors = [vendor(addr, "broken"), vendor(addr, "good")];
+        let path = fetch(
+            directory.path(),
+            &chain,
+            &vendors,
+            None,
+            DEFAULT_CONNECTIONS,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            path.file_name().unwrap(),
+            "good_snapshot_calibnet_2023-09-14_height_900.car.zst"
+        );
+        assert_eq!(std::fs::read(&path).unwrap(), SNAPSHOT);
+
+        // Snapshots that do not match their checksum are downloaded, then deleted
+        assert!(fetch(
+            directory.path(),
+            &chain,
+            &[vendor(addr, "mismatch")],
+            None,
+            DEFAULT_CONNECTIONS
+        )
+        .await
+        .is_err());
+        // The snapshot is downloaded once, the mismatch is not retried
+        assert_eq!(mismatch_requests.load(Ordering::Relaxed), 1);
+        let mismatch_path = directory
+            .path()
+            .join("mismatch_snapshot_calibnet_2023-09-14_height_900.car.zst");
+        assert!(!mismatch_path.exists());
+
+        // Snapshots without a checksum file are not verified
+        let path = fetch(
+            directory.path(),
+            &chain,
+            &[vendor(addr, "unverified")],
+            None,
+            DEFAULT_CONNECTIONS,
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), SNAPSHOT);
+
+        // Only vendors with past snapshots can fetch by epoch
+        let mut by_epoch = vendor(addr, "epoch");
+        by_epoch.checksum_url = None;
+        by_epoch.epoch_url = Some(format!("http://{addr}/epoch/{{chain}}/{{epoch}}.car.zst"));
+        assert!(fetch(
+            directory.path(),
+            &chain,
+            &vendors,
+            Some(100),
+            DEFAULT_CONNECTIONS
+        )
+        .await
+        .is_err());
+        let path = fetch(
+            directory.path(),
+            &chain,
+            &[by_epoch],
+            Some(100),
+            DEFAULT_CONNECTIONS,
+        )
+        .await
+        .unwrap();
+        assert_eq!(path.file_name().unwrap(), "100.car.zst");
+        assert_eq!(std::fs::read(&path).unwrap(), SNAPSHOT);
+    }
+
+    #[test]
+    fn vendors_by_name() {
+        let chain = NetworkChain::Mainnet;
+        let custom = [vendor(SocketAddr::from(([127, 0, 0, 1], 0)), "mirror")];
+        let names = |vendors: Vec<SnapshotVendor>| {
+            vendors
+                .into_iter()
+                .map(|vendor| vendor.name)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(vendors(&[], &custom, &chain).unwrap()),
+            ["mirror", "forest", "filops"]
+        );
+        assert_eq!(
+            names(vendors(&["filops".into(), "mirror".into()], &custom, &chain).unwrap()),
+            ["filops", "mirror"]
+        );
+        assert!(vendors(&["unknown".into()], &custom, &chain).is_err());
+    }
 
     #[test]
     fn content_disposition_forest() {
@@ -1,9 +1,13 @@
 // Copyright 2019-2023 ChainSafe Systems
 // SPDX-License-Identifier: Apache-2.0, MIT
 
+use crate::cli_shared::cli::ActorBundleSource;
+use crate::networks::{ChainConfig, NetworkChain};
+use crate::shim::machine::BuiltinActorManifest;
 use crate::utils::db::{car_stream::CarHeader, car_util::load_car};
-use anyhow::Context as _;
+use anyhow::{bail, ensure, Context as _};
 use fvm_ipld_blockstore::Blockstore;
+use tracing::info;
 
 pub async fn load_actor_bundles(db: &impl Blockstore) -> anyhow::Result<CarHeader> {
     const ERROR_MESSAGE: &str = "Actor bundles assets are not properly downloaded, make sure git-lfs is installed and run `git lfs pull` again. See <https://github.com/git-lfs/git-lfs/blob/main/INSTALLING.md>";
@@ -15,10 +19,66 @@ pub async fn load_actor_bundles(db: &impl Blockstore) -> anyhow::Result<CarHeade
         .context(ERROR_MESSAGE)
 }
 
+/// Loads the actor bundles of `sources` from disk or from URLs, and checks that their roots are
+/// valid manifests and the bundles of network upgrades in `chain_config`. Devnets may instead declare the bundle
+/// of an upgrade with [`ActorBundleSource::height`]. Returns the updated chain configuration.
+pub async fn load_extra_actor_bundles(
+    db: &impl Blockstore,
+    sources: &[ActorBundleSource],
+    chain_config: &ChainConfig,
+) -> anyhow::Result<ChainConfig> {
+    let mut chain_config = chain_config.clone();
+    for source in sources {
+        let ActorBundleSource { location, height } = source;
+        let reader = Box::pin(crate::utils::net::reader(location).await?);
+        let CarHeader { roots, .. } = load_car(db, reader)
+            .await
+            .with_context(|| format!("couldn't load the actor bundle {location}"))?;
+        for root in &roots {
+            BuiltinActorManifest::load_manifest(db, root).with_context(|| {
+                format!("the root {root} of the actor bundle {location} isn't a valid manifest")
+            })?;
+        }
+        match height {
+            Some(height) => {
+                let [manifest] = roots[..] else {
+                    bail!("the actor bundle {location} must have a single manifest");
+                };
+                let info = chain_config
+                    .height_infos
+                    .iter_mut()
+                    .find(|info| info.height == *height)
+                    .with_context(|| format!("no network upgrade {height} on this network"))?;
+                match (&chain_config.network, info.bundle) {
+                    (NetworkChain::Devnet(_), _) => info.bundle = Some(manifest),
+                    (_, Some(bundle)) if bundle == manifest => {}
+                    (network, _) => bail!(
+                        "the manifest {manifest} of the actor bundle {location} isn't the bundle of {height} on {network}"
+                    ),
+                }
+            }
+            None => {
+                for root in &roots {
+                    ensure!(
+                        chain_config
+                            .height_infos
+                            .iter()
+                            .any(|info| info.bundle == Some(*root)),
+                        "the manifest {root} of the actor bundle {location} isn't the bundle of any network upgrade on {}",
+                        chain_config.network
+                    );
+                }
+            }
+        }
+        info!("Loaded the actor bundle {location}");
+    }
+    Ok(chain_config)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::networks::ACTOR_BUNDLES;
+    use crate::networks::{Height, ACTOR_BUNDLES};
     use ahash::HashSet;
     use cid::Cid;
     use pretty_assertions::assert_eq;
@@ -30,4 +90,100 @@ mod tests {
         let roots_expected: HashSet<Cid> = ACTOR_BUNDLES.iter().map(|b| b.manifest).collect();
         assert_eq!(roots, roots_expected);
     }
+
+    /// Writes a bundle whose manifest lists `actors`, and returns the manifest.
+    async fn write_bundle(path: &std::path::Path, actors: &[&str]) -> Cid {
+        use crate::utils::db::car_stream::{CarBlock, CarWriter};
+        use cid::multihash::{Code, MultihashDigest as _};
+        use futures::StreamExt as _;
+
+        fn block(value: &impl serde::Serialize) -> CarBlock {
+            let data = fvm_ipld_encoding::to_vec(value).unwrap();
+            CarBlock {
+                cid: Cid::new_v1(fvm_ipld_encoding::DAG_CBOR, Code::Blake2b256.digest(&data)),
+                data,
+            }
+        }
+
+        let actor_list = block(
+            &actors
+                .iter()
+                .map(|name| (name.to_string(), block(name).cid))
+                .collect::<Vec<_>>(),
+        );
+        let manifest = block(&(1u32, actor_list.cid));
+        let cid = manifest.cid;
+        let file = tokio::fs::File::create(path).await.unwrap();
+        futures::stream::iter([Ok(manifest), Ok(actor_list)])
+            .forward(CarWriter::new_carv1(vec![cid], file).unwrap())
+            .await
+            .unwrap();
+        cid
+    }
+
+    #[tokio::test]
+    async fn test_load_extra_actor_bundles_devnet() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bundle.car");
+        let manifest = write_bundle(&path, &["system", "init", "account"]).await;
+        let db = fvm_ipld_blockstore::MemoryBlockstore::new();
+        let chain_config = ChainConfig::devnet();
+        let source: ActorBundleSource = format!("Watermelon={}", path.display()).parse().unwrap();
+
+        let chain_config = load_extra_actor_bundles(&db, &[source], &chain_config)
+            .await
+            .unwrap();
+        let info = chain_config
+            .height_infos
+            .iter()
+            .find(|info| info.height == Height::Watermelon)
+            .unwrap();
+        assert_eq!(info.bundle, Some(manifest));
+        assert!(db.has(&manifest).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_load_extra_actor_bundles_unknown_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bundle.car");
+        write_bundle(&path, &["system", "init", "account"]).await;
+        let db = fvm_ipld_blockstore::MemoryBlockstore::new();
+        let chain_config = ChainConfig::calibnet();
+
+        for source in [
+            path.display().to_string(),
+            format!("Watermelon={}", path.display()),
+        ] {
+            let source = source.parse().unwrap();
+            assert!(load_extra_actor_bundles(&db, &[source], &chain_config)
+                .await
+                .is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_load_extra_actor_bundles_invalid_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bundle.car");
+        // The system actor is mandatory
+        write_bundle(&path, &["init", "account"]).await;
+        let db = fvm_ipld_blockstore::MemoryBlockstore::new();
+        let source: ActorBundleSource = format!("Watermelon={}", path.display()).parse().unwrap();
+
+        assert!(
+            load_extra_actor_bundles(&db, &[source], &ChainConfig::devnet())
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_actor_bundle_source() {
+        let source: ActorBundleSource = "Hygge=https://example.com/a=b.car".parse().unwrap();
+        assert_eq!(source.height, Some(Height::Hygge));
+        assert_eq!(source.location, "https://example.com/a=b.car");
+        let source: ActorBundleSource = "/tmp/a=b.car".parse().unwrap();
+        assert_eq!(source.height, None);
+        assert_eq!(source.location, "/tmp/a=b.car");
+    }
 }
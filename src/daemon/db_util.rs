@@ -1,25 +1,38 @@
 // Copyright 2019-2023 ChainSafe Systems
 // SPDX-License-Identifier: Apache-2.0, MIT
 
-use crate::blocks::Tipset;
+use crate::blocks::{Tipset, TipsetKeys};
 use crate::cli_shared::snapshot;
 use crate::db::car::forest::FOREST_CAR_FILE_EXTENSION;
+use crate::db::car::v2::{CarV2Header, CARV2_PRAGMA};
 use crate::db::car::{ForestCar, ManyCar};
-use crate::utils::db::car_stream::CarStream;
+use crate::db::setting_keys::SNAPSHOT_IMPORT_KEY;
+use crate::db::{SettingsStore, SettingsStoreExt as _};
+use crate::utils::db::car_stream::{CarBlock, CarHeader, CarStream};
+use crate::utils::encoding::from_slice_with_fallback;
 use crate::utils::io::EitherMmapOrRandomAccessFile;
-use anyhow::Context as _;
-use futures::TryStreamExt;
+use crate::utils::reqwest_resume;
+use anyhow::{ensure, Context as _};
+use bytes::{Buf as _, Bytes, BytesMut};
+use futures::{Stream, TryStreamExt};
+use fvm_ipld_blockstore::Blockstore;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
 use std::ffi::OsStr;
 use std::fs;
 use std::io;
+use std::pin::Pin;
 use std::{
     path::{Path, PathBuf},
     time,
 };
 use tokio::io::AsyncWriteExt;
+use tokio_util::codec::Decoder as _;
 use tracing::{debug, info};
+use unsigned_varint::codec::UviBytes;
 use url::Url;
 use walkdir::WalkDir;
+use zstd::stream::raw::Operation as _;
 
 pub fn load_all_forest_cars<T>(store: &ManyCar<T>, forest_car_db_dir: &Path) -> anyhow::Result<()> {
     if !forest_car_db_dir.is_dir() {
@@ -94,6 +107,347 @@ pub async fn import_chain_as_forest_car(
     Ok((forest_car_db_path, ts))
 }
 
+/// Progress of a snapshot streamed into the database by [`import_chain_from_url`], so that an
+/// interrupted import doesn't write the same blocks again.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct ImportCheckpoint {
+    url: String,
+    head: TipsetKeys,
+    /// Number of blocks, in snapshot order, written to the database.
+    blocks: u64,
+    /// Where the download of the blocks that follow resumes, if the snapshot can be downloaded
+    /// from an offset. Otherwise, the snapshot is downloaded again from the start.
+    #[serde(default)]
+    resume: Option<ResumePoint>,
+}
+
+/// Offset in a snapshot from which its CAR data can be decoded again.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+struct ResumePoint {
+    /// Length of the snapshot, checked so that a snapshot replaced under the same URL isn't
+    /// resumed.
+    len: u64,
+    /// Offset of the first byte to download, the start of a zstd frame if `compressed`.
+    offset: u64,
+    /// Number of decompressed bytes from `offset` on that belong to blocks already written.
+    skip: u64,
+    compressed: bool,
+    /// Offset of the end of the CAR data, for CARv2 snapshots.
+    end: Option<u64>,
+}
+
+/// This function streams the CAR snapshot at `url` straight into `db`, without writing it to
+/// disk, and returns its heaviest tipset. Blocks are checked against their CIDs as they are
+/// read and committed `chunk_size` blocks at a time. An import interrupted after committing
+/// blocks resumes the download with a range request from the blocks that follow them, or
+/// downloads the snapshot again but only writes these blocks if the server doesn't support it.
+pub async fn import_chain_from_url<DB>(
+    url: &Url,
+    db: &DB,
+    chunk_size: usize,
+) -> anyhow::Result<Tipset>
+where
+    DB: Blockstore + SettingsStore,
+{
+    info!("Importing chain from snapshot at: {url}");
+
+    let stopwatch = time::Instant::now();
+
+    let previous = db
+        .read_obj::<Option<ImportCheckpoint>>(SNAPSHOT_IMPORT_KEY)?
+        .flatten()
+        .filter(|previous| previous.url == url.as_str());
+    let resumed = match &previous {
+        Some(
+            previous @ ImportCheckpoint {
+                resume: Some(resume),
+                ..
+            },
+        ) => SnapshotReader::resume(url, resume).await?.map(|reader| {
+            info!(
+                "Resuming the download after the {} blocks written by a previous import",
+                previous.blocks
+            );
+            (reader, previous.head.clone(), previous.blocks)
+        }),
+        _ => None,
+    };
+    let (mut reader, head, mut read, skip) = match resumed {
+        Some((reader, head, blocks)) => (reader, head, blocks, 0),
+        None => {
+            let (reader, header) = SnapshotReader::new(url).await?;
+            let head = TipsetKeys::from_iter(header.roots);
+            let skip = match previous {
+                Some(previous) if previous.head == head => {
+                    info!(
+                        "Skipping the {} blocks written by a previous import",
+                        previous.blocks
+                    );
+                    previous.blocks
+                }
+                _ => 0,
+            };
+            (reader, head, 0, skip)
+        }
+    };
+    let mut checkpoint = ImportCheckpoint {
+        url: url.to_string(),
+        head,
+        blocks: read,
+        resume: None,
+    };
+
+    let chunk_size = chunk_size.max(1);
+    let mut chunk = Vec::with_capacity(chunk_size);
+    while let Some(frame) = reader.next_frame().await? {
+        let block = CarBlock::from_bytes(frame)?;
+        ensure!(block.valid(), "block {} doesn't match its CID", block.cid);
+        read += 1;
+        if read <= skip {
+            continue;
+        }
+        chunk.push((block.cid, block.data));
+        if chunk.len() == chunk_size {
+            db.put_many_keyed(chunk.drain(..))?;
+            checkpoint.blocks = read;
+            checkpoint.resume = reader.resume_point();
+            db.write_obj(SNAPSHOT_IMPORT_KEY, &Some(&checkpoint))?;
+        }
+    }
+    db.put_many_keyed(chunk)?;
+    db.write_obj(SNAPSHOT_IMPORT_KEY, &None::<ImportCheckpoint>)?;
+
+    let ts = Tipset::load_required(db, &checkpoint.head)?;
+    info!(
+        "Imported snapshot in: {}s, heaviest tipset epoch: {}",
+        stopwatch.elapsed().as_secs(),
+        ts.epoch()
+    );
+
+    Ok(ts)
+}
+
+/// Size of the buffer zstd frames are decompressed into.
+const DECOMPRESSION_BUFFER_LEN: usize = 128 * 1024;
+
+/// Reads the CAR frames of a snapshot download, keeping track of the offsets in the snapshot
+/// from which the download can be resumed. Compressed snapshots can only be decoded again from
+/// the start of a zstd frame, so the positions in the decompressed data where frames start are
+/// recorded.
+struct SnapshotReader {
+    body: Pin<Box<dyn Stream<Item = reqwest::Result<Bytes>> + Send>>,
+    /// Length of the snapshot, if known.
+    len: Option<u64>,
+    /// Downloaded bytes not decoded yet.
+    pending: BytesMut,
+    /// Offset in the snapshot of the start of `pending`.
+    offset: u64,
+    /// Offset of the end of the CAR data.
+    end: Option<u64>,
+    decoder: Option<(zstd::stream::raw::Decoder<'static>, Vec<u8>)>,
+    /// Whether the decoder is in the middle of a frame.
+    in_frame: bool,
+    /// Decoded bytes not read yet.
+    buf: BytesMut,
+    frames: UviBytes,
+    /// Position in the decoded data, since the start of the download, of the start of `buf`.
+    position: u64,
+    /// Positions in the decoded data from which decoding can start over, with their offsets in
+    /// the snapshot.
+    restarts: VecDeque<(u64, u64)>,
+}
+
+impl SnapshotReader {
+    /// Downloads the snapshot at `url` from the start and reads its header.
+    async fn new(url: &Url) -> anyhow::Result<(Self, CarHeader)> {
+        let response = reqwest_resume::get(url.clone()).await?;
+        response.response().error_for_status_ref()?;
+        let mut reader = Self::from_response(response, None);
+        if reader.fill_pending(CARV2_PRAGMA.len()).await?
+            && reader.pending.starts_with(&CARV2_PRAGMA)
+        {
+            let header_len = CARV2_PRAGMA.len() + CarV2Header::SIZE;
+            ensure!(
+                reader.fill_pending(header_len).await?,
+                "truncated CARv2 header"
+            );
+            let header = reader.pending.split_to(header_len);
+            let header = CarV2Header::from_le_bytes(
+                header[CARV2_PRAGMA.len()..].try_into().expect("infallible"),
+            );
+            let padding = header
+                .data_offset
+                .checked_sub(header_len as u64)
+                .context("invalid CARv2 data offset")?;
+            reader.offset = header_len as u64;
+            reader.discard_pending(padding).await?;
+            reader.end = Some(header.data_offset + header.data_size);
+        }
+        reader.fill_pending(18).await?;
+        if zstd::zstd_safe::get_frame_content_size(&reader.pending).is_ok() {
+            reader.decoder = Some(Self::decoder()?);
+        }
+        reader.restarts.push_back((0, reader.offset));
+
+        let header = reader.next_frame().await?.context("missing CAR header")?;
+        let header = from_slice_with_fallback::<CarHeader>(&header)?;
+        ensure!(header.version == 1, "invalid CAR header version");
+        Ok((reader, header))
+    }
+
+    /// Downloads the snapshot at `url` from `resume`. Returns `None` if the server doesn't
+    /// support it, or if the snapshot has changed.
+    async fn resume(url: &Url, resume: &ResumePoint) -> anyhow::Result<Option<Self>> {
+        let response = reqwest_resume::Client::new()
+            .get(url.clone())
+            .starting_at(resume.offset)
+            .send()
+            .await?;
+        if response.pos() != resume.offset || response.total_len() != Some(resume.len) {
+            info!("The snapshot download can't be resumed, it is downloaded again");
+            return Ok(None);
+        }
+        let mut reader = Self::from_response(response, resume.end);
+        reader.offset = resume.offset;
+        if resume.compressed {
+            reader.decoder = Some(Self::decoder()?);
+        }
+        reader.restarts.push_back((0, reader.offset));
+        while (reader.buf.len() as u64) < resume.skip {
+            ensure!(reader.fill().await?, "truncated snapshot");
+        }
+        reader.buf.advance(resume.skip as usize);
+        reader.position = resume.skip;
+        Ok(Some(reader))
+    }
+
+    fn from_response(response: reqwest_resume::Response, end: Option<u64>) -> Self {
+        Self {
+            len: response.total_len(),
+            body: Box::pin(response.bytes_stream()),
+            pending: BytesMut::new(),
+            offset: 0,
+            end,
+            decoder: None,
+            in_frame: false,
+            buf: BytesMut::new(),
+            frames: UviBytes::default(),
+            position: 0,
+            restarts: VecDeque::new(),
+        }
+    }
+
+    fn decoder() -> io::Result<(zstd::stream::raw::Decoder<'static>, Vec<u8>)> {
+        Ok((
+            zstd::stream::raw::Decoder::new()?,
+            vec![0; DECOMPRESSION_BUFFER_LEN],
+        ))
+    }
+
+    /// Downloads bytes until at least `len` bytes are pending. Returns false if the download
+    /// ends before.
+    async fn fill_pending(&mut self, len: usize) -> anyhow::Result<bool> {
+        while self.pending.len() < len {
+            match self.body.try_next().await? {
+                Some(bytes) => self.pending.extend_from_slice(&bytes),
+                None => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+
+    async fn discard_pending(&mut self, mut len: u64) -> anyhow::Result<()> {
+        while len > 0 {
+            if self.pending.is_empty() {
+                ensure!(self.fill_pending(1).await?, "truncated snapshot");
+            }
+            let discarded = len.min(self.pending.len() as u64);
+            self.pending.advance(discarded as usize);
+            self.offset += discarded;
+            len -= discarded;
+        }
+        Ok(())
+    }
+
+    /// Decodes more downloaded bytes. Returns false at the end of the CAR data.
+    async fn fill(&mut self) -> anyhow::Result<bool> {
+        if self.pending.is_empty() && !self.fill_pending(1).await? {
+            ensure!(!self.in_frame, "truncated zstd frame");
+            return Ok(false);
+        }
+        let mut input = self.pending.split();
+        if let Some(end) = self.end {
+            input.truncate(end.saturating_sub(self.offset) as usize);
+            if input.is_empty() {
+                return Ok(false);
+            }
+        }
+        match &mut self.decoder {
+            None => {
+                self.offset += input.len() as u64;
+                self.buf.extend_from_slice(&input);
+            }
+            Some((decoder, output)) => {
+                let mut input = &input[..];
+                loop {
+                    let status = decoder.run_on_buffers(input, output)?;
+                    input = &input[status.bytes_read..];
+                    self.offset += status.bytes_read as u64;
+                    self.buf.extend_from_slice(&output[..status.bytes_written]);
+                    self.in_frame = status.remaining != 0;
+                    if !self.in_frame {
+                        let restart = (self.position + self.buf.len() as u64, self.offset);
+                        if self.restarts.back() != Some(&restart) {
+                            self.restarts.push_back(restart);
+                        }
+                    }
+                    if input.is_empty() && status.bytes_written < output.len() {
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Reads the next CAR frame, the header or a block.
+    async fn next_frame(&mut self) -> anyhow::Result<Option<Bytes>> {
+        loop {
+            let len = self.buf.len();
+            let frame = self.frames.decode(&mut self.buf)?;
+            self.position += (len - self.buf.len()) as u64;
+            if let Some(frame) = frame {
+                return Ok(Some(frame.freeze()));
+            }
+            if !self.fill().await? {
+                ensure!(self.buf.is_empty(), "truncated CAR frame");
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Where to resume the download to read the frames that follow the ones read so far.
+    fn resume_point(&mut self) -> Option<ResumePoint> {
+        while self
+            .restarts
+            .get(1)
+            .is_some_and(|(position, _)| *position <= self.position)
+        {
+            self.restarts.pop_front();
+        }
+        let (position, offset) = *self.restarts.front()?;
+        let skip = self.position - position;
+        let compressed = self.decoder.is_some();
+        Some(ResumePoint {
+            len: self.len?,
+            offset: if compressed { offset } else { offset + skip },
+            skip: if compressed { skip } else { 0 },
+            compressed,
+            end: self.end,
+        })
+    }
+}
+
 async fn download_to(url: &Url, destination: &Path) -> anyhow::Result<()> {
     snapshot::download_file_with_retry(
         url,
@@ -145,6 +499,9 @@ async fn transcode_into_forest_car(from: &Path, to: &Path) -> anyhow::Result<()>
 #[cfg(test)]
 mod test {
     use super::*;
+    use parking_lot::Mutex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
 
     #[tokio::test]
     async fn import_snapshot_from_file_valid() {
@@ -177,6 +534,236 @@ mod test {
             .unwrap_err();
     }
 
+    async fn serve_file(file_path: &str) -> Url {
+        let bytes = std::fs::read(file_path).unwrap();
+        let app =
+            axum::Router::new().route("/snapshot.car", axum::routing::get(|| async { bytes }));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        Url::parse(&format!("http://{addr}/snapshot.car")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn import_snapshot_from_url_valid() {
+        let url = serve_file("test-snapshots/chain4.car.zst").await;
+        let db = crate::db::MemoryDB::default();
+        let ts = import_chain_from_url(&url, &db, 100).await.unwrap();
+        assert!(ts.epoch() > 0);
+        assert!(db
+            .read_obj::<Option<ImportCheckpoint>>(SNAPSHOT_IMPORT_KEY)
+            .unwrap()
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn import_snapshot_from_url_resume() {
+        let url = serve_file("test-snapshots/chain4.car").await;
+        let mut car_stream = CarStream::new(tokio::io::BufReader::new(
+            tokio::fs::File::open("test-snapshots/chain4.car")
+                .await
+                .unwrap(),
+        ))
+        .await
+        .unwrap();
+        let head = TipsetKeys::from_iter(car_stream.header.roots.clone());
+        let mut blocks = vec![];
+        while let Some(block) = car_stream.try_next().await.unwrap() {
+            blocks.push(block);
+        }
+
+        // A previous import committed all but the last block
+        let db = crate::db::MemoryDB::default();
+        let checkpoint = ImportCheckpoint {
+            url: url.to_string(),
+            head,
+            blocks: blocks.len() as u64 - 1,
+            resume: None,
+        };
+        db.write_obj(SNAPSHOT_IMPORT_KEY, &Some(&checkpoint))
+            .unwrap();
+        let (last, committed) = blocks.split_last().unwrap();
+        for block in committed {
+            db.put_keyed(&block.cid, &block.data).unwrap();
+        }
+        import_chain_from_url(&url, &db, 100).await.unwrap();
+        assert!(db.has(&last.cid).unwrap());
+
+        // Blocks before the checkpoint are not written again
+        let db = crate::db::MemoryDB::default();
+        db.write_obj(SNAPSHOT_IMPORT_KEY, &Some(&checkpoint))
+            .unwrap();
+        import_chain_from_url(&url, &db, 100).await.unwrap_err();
+        assert!(!db.has(&blocks[0].cid).unwrap());
+        assert!(db.has(&last.cid).unwrap());
+    }
+
+    /// Serves `file_path`, answering range requests, and records the offsets they start at.
+    async fn serve_file_ranges(file_path: &str) -> (Url, Arc<Mutex<Vec<u64>>>) {
+        use axum::http::{header, HeaderMap, StatusCode};
+
+        let bytes = std::fs::read(file_path).unwrap();
+        let ranges = Arc::new(Mutex::new(vec![]));
+        let app = axum::Router::new().route(
+            "/snapshot.car",
+            axum::routing::get({
+                let ranges = ranges.clone();
+                move |headers: HeaderMap| async move {
+                    let start = headers.get(header::RANGE).and_then(|range| {
+                        range
+                            .to_str()
+                            .ok()?
+                            .strip_prefix("bytes=")?
+                            .strip_suffix('-')?
+                            .parse::<u64>()
+                            .ok()
+                    });
+                    let response = axum::response::Response::builder();
+                    match start {
+                        Some(start) => {
+                            ranges.lock().push(start);
+                            response
+                                .status(StatusCode::PARTIAL_CONTENT)
+                                .header(
+                                    header::CONTENT_RANGE,
+                                    format!("bytes {start}-{}/{}", bytes.len() - 1, bytes.len()),
+                                )
+                                .body(axum::body::Body::from(bytes[start as usize..].to_vec()))
+                        }
+                        None => response
+                            .header(header::ACCEPT_RANGES, "bytes")
+                            .body(axum::body::Body::from(bytes.clone())),
+                    }
+                    .unwrap()
+                }
+            }),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        (
+            Url::parse(&format!("http://{addr}/snapshot.car")).unwrap(),
+            ranges,
+        )
+    }
+
+    /// A database that fails after a number of block writes, to interrupt imports.
+    struct InterruptedDb {
+        db: crate::db::MemoryDB,
+        writes: AtomicUsize,
+    }
+
+    impl Blockstore for InterruptedDb {
+        fn get(&self, k: &cid::Cid) -> anyhow::Result<Option<Vec<u8>>> {
+            self.db.get(k)
+        }
+
+        fn put_keyed(&self, k: &cid::Cid, block: &[u8]) -> anyhow::Result<()> {
+            self.db.put_keyed(k, block)
+        }
+
+        fn put_many_keyed<D, I>(&self, blocks: I) -> anyhow::Result<()>
+        where
+            Self: Sized,
+            D: AsRef<[u8]>,
+            I: IntoIterator<Item = (cid::Cid, D)>,
+        {
+            ensure!(
+                self.writes.fetch_sub(1, Ordering::Relaxed) > 0,
+                "interrupted"
+            );
+            self.db.put_many_keyed(blocks)
+        }
+    }
+
+    impl SettingsStore for InterruptedDb {
+        fn read_bin(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+            self.db.read_bin(key)
+        }
+
+        fn write_bin(&self, key: &str, value: &[u8]) -> anyhow::Result<()> {
+            self.db.write_bin(key, value)
+        }
+
+        fn exists(&self, key: &str) -> anyhow::Result<bool> {
+            self.db.exists(key)
+        }
+
+        fn setting_keys(&self) -> anyhow::Result<Vec<String>> {
+            self.db.setting_keys()
+        }
+    }
+
+    async fn import_snapshot_from_url_resume_range(file_path: &str) {
+        let (url, ranges) = serve_file_ranges(file_path).await;
+        let interrupted = InterruptedDb {
+            db: crate::db::MemoryDB::default(),
+            writes: AtomicUsize::new(3),
+        };
+        import_chain_from_url(&url, &interrupted, 10)
+            .await
+            .unwrap_err();
+        let checkpoint = interrupted
+            .db
+            .require_obj::<Option<ImportCheckpoint>>(SNAPSHOT_IMPORT_KEY)
+            .unwrap()
+            .unwrap();
+        assert_eq!(checkpoint.blocks, 30);
+        let resume = checkpoint.resume.unwrap();
+        assert!(resume.offset > 0);
+
+        // The download resumes from the checkpoint
+        let db = interrupted.db;
+        import_chain_from_url(&url, &db, 10).await.unwrap();
+        assert_eq!(*ranges.lock(), vec![resume.offset]);
+        let mut car_stream = CarStream::new(crate::utils::net::reader(file_path).await.unwrap())
+            .await
+            .unwrap();
+        while let Some(block) = car_stream.try_next().await.unwrap() {
+            assert!(db.has(&block.cid).unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn import_snapshot_from_url_resume_range_uncompressed() {
+        import_snapshot_from_url_resume_range("test-snapshots/chain4.car").await;
+    }
+
+    #[tokio::test]
+    async fn import_snapshot_from_url_resume_range_compressed() {
+        // A forest CAR, made of small zstd frames
+        let car_stream = CarStream::new(tokio::io::BufReader::new(
+            tokio::fs::File::open("test-snapshots/chain4.car")
+                .await
+                .unwrap(),
+        ))
+        .await
+        .unwrap();
+        let roots = car_stream.header.roots.clone();
+        let frames = crate::db::car::forest::Encoder::compress_stream(
+            4096,
+            3,
+            car_stream.map_err(anyhow::Error::from),
+        );
+        let forest_car = tempfile::Builder::new().tempfile().unwrap();
+        let mut writer = tokio::fs::File::create(forest_car.path()).await.unwrap();
+        crate::db::car::forest::Encoder::write(&mut writer, roots, frames)
+            .await
+            .unwrap();
+        writer.shutdown().await.unwrap();
+
+        import_snapshot_from_url_resume_range(forest_car.path().to_str().unwrap()).await;
+    }
+
     async fn import_snapshot_from_file(file_path: &str) -> anyhow::Result<()> {
         let temp = tempfile::Builder::new().tempdir()?;
         let (path, ts) =
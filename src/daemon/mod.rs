@@ -4,18 +4,23 @@
 pub mod bundle;
 mod db_util;
 pub mod main;
+mod snapshot_service;
 
 use crate::auth::{create_token, generate_priv_key, ADMIN, JWT_IDENTIFIER};
+use crate::beacon::DrandSources;
 use crate::blocks::Tipset;
 use crate::chain::ChainStore;
-use crate::chain_sync::ChainMuxer;
+use crate::chain_sync::{load_checkpoint, ChainMuxer};
 use crate::cli_shared::snapshot;
 use crate::cli_shared::{
     chain_path,
     cli::{CliOpts, Config},
 };
 
-use crate::daemon::db_util::{import_chain_as_forest_car, load_all_forest_cars};
+use crate::daemon::db_util::{
+    import_chain_as_forest_car, import_chain_from_url, load_all_forest_cars,
+};
+use crate::daemon::snapshot_service::SnapshotService;
 use crate::db::car::ManyCar;
 use crate::db::db_engine::{db_root, open_proxy_db};
 use crate::db::rolling::DbGarbageCollector;
@@ -36,7 +41,7 @@ use crate::utils::{
     version::FOREST_VERSION_STRING,
 };
 use anyhow::{bail, Context as _};
-use bundle::load_actor_bundles;
+use bundle::{load_actor_bundles, load_extra_actor_bundles};
 use dialoguer::console::Term;
 use dialoguer::theme::ColorfulTheme;
 use futures::{select, Future, FutureExt};
@@ -55,6 +60,7 @@ use tokio::{
     task::JoinSet,
 };
 use tracing::{debug, info, warn};
+use url::Url;
 
 static IPC_PATH: Lazy<TempPath> = Lazy::new(|| {
     Builder::new()
@@ -182,6 +188,22 @@ pub(super) async fn start(
     let forest_car_db_dir = db_root_dir.join("car_db");
     load_all_forest_cars(&db, &forest_car_db_dir)?;
     load_actor_bundles(&db).await?;
+    let mut config = config;
+    if !config.client.actor_bundles.is_empty() {
+        config.chain = Arc::new(
+            load_extra_actor_bundles(&db, &config.client.actor_bundles, &config.chain).await?,
+        );
+    }
+    Arc::make_mut(&mut config.chain).drand = DrandSources {
+        cache_dir: Some(
+            config
+                .drand
+                .cache_dir
+                .clone()
+                .unwrap_or_else(|| config.client.data_dir.join("drand")),
+        ),
+        ..config.drand.clone()
+    };
 
     let mut services = JoinSet::new();
 
@@ -290,7 +312,7 @@ pub(super) async fn start(
     services.spawn(peer_manager.clone().peer_operation_event_loop_task());
     let genesis_cid = *genesis_header.cid();
     // Libp2p service setup
-    let p2p_service = Libp2pService::new(
+    let mut p2p_service = Libp2pService::new(
         config.network.clone(),
         Arc::clone(&chain_store),
         peer_manager.clone(),
@@ -298,6 +320,9 @@ pub(super) async fn start(
         &network_name,
         genesis_cid,
     )?;
+    if config.drand.gossip {
+        p2p_service.subscribe_drand(&config.chain.drand_chain_hashes())?;
+    }
 
     let network_rx = p2p_service.network_receiver();
     let network_send = p2p_service.network_sender();
@@ -316,6 +341,7 @@ pub(super) async fn start(
     let mpool = Arc::new(mpool);
 
     // Initialize ChainMuxer
+    let checkpoint = load_checkpoint(&config.sync)?;
     let chain_muxer = ChainMuxer::new(
         Arc::clone(&state_manager),
         peer_manager,
@@ -326,6 +352,7 @@ pub(super) async fn start(
         tipset_sink,
         tipset_stream,
         config.sync.clone(),
+        checkpoint.clone(),
     )?;
     let bad_blocks = chain_muxer.bad_blocks_cloned();
     let sync_state = chain_muxer.sync_state_cloned();
@@ -386,9 +413,10 @@ pub(super) async fn start(
         &config.client.data_dir,
     );
 
-    // Sets the latest snapshot if needed for downloading later
+    // Sets the latest snapshot if needed for downloading later, a checkpoint
+    // replaces it
     let mut config = config;
-    if config.client.snapshot_path.is_none() {
+    if config.client.snapshot_path.is_none() && checkpoint.is_none() {
         set_snapshot_path_if_needed(
             &mut config,
             epoch,
@@ -401,14 +429,28 @@ pub(super) async fn start(
     // Import chain if needed
     if !opts.skip_load.unwrap_or_default() {
         if let Some(path) = &config.client.snapshot_path {
-            let (car_db_path, ts) = import_chain_as_forest_car(
-                path,
-                &forest_car_db_dir,
-                config.client.consume_snapshot,
-            )
-            .await?;
-            db.read_only_files(std::iter::once(car_db_path.clone()))?;
-            debug!("Loaded car DB at {}", car_db_path.display());
+            let url = match config.client.keep_snapshot {
+                true => None,
+                false => Url::parse(&path.display().to_string()).ok(),
+            };
+            let ts = match url {
+                // Stream snapshots from the web straight into the database
+                Some(url) => {
+                    import_chain_from_url(&url, db.writer(), config.client.chunk_size.0 as usize)
+                        .await?
+                }
+                None => {
+                    let (car_db_path, ts) = import_chain_as_forest_car(
+                        path,
+                        &forest_car_db_dir,
+                        config.client.consume_snapshot,
+                    )
+                    .await?;
+                    db.read_only_files(std::iter::once(car_db_path.clone()))?;
+                    debug!("Loaded car DB at {}", car_db_path.display());
+                    ts
+                }
+            };
             state_manager
                 .chain_store()
                 .set_heaviest_tipset(Arc::new(ts))?;
@@ -443,6 +485,17 @@ pub(super) async fn start(
     ensure_params_downloaded().await?;
     services.spawn(p2p_service.run());
 
+    if config.snapshot_service.enabled {
+        let snapshot_service = SnapshotService::new(
+            &config.snapshot_service,
+            state_manager.chain_store().clone(),
+            &config.chain,
+            config.chain.network.to_string(),
+            &config.client.data_dir,
+        )?;
+        services.spawn(snapshot_service.run());
+    }
+
     // blocking until any of the services returns an error,
     propagate_error(&mut services)
         .await
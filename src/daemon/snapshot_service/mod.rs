@@ -0,0 +1,570 @@
+// Copyright 2019-2023 ChainSafe Systems
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+//! Service periodically exporting snapshots of the chain and publishing them to a directory or
+//! to an S3-compatible object storage, along with their checksum and metadata.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context as _;
+use chrono::NaiveDateTime;
+use futures::TryStreamExt as _;
+use fvm_ipld_blockstore::Blockstore;
+use object_store::aws::AmazonS3Builder;
+use object_store::path::Path as ObjectPath;
+use object_store::prefix::PrefixStore;
+use object_store::ObjectStore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tempfile::TempPath;
+use tokio::io::AsyncWriteExt as _;
+use tracing::{info, warn};
+
+use crate::chain::index::ResolveNullTipset;
+use crate::chain::{ChainEpochDelta, ChainStore};
+use crate::cid_collections::CidHashSet;
+use crate::cli_shared::cli::{SnapshotServiceConfig, SnapshotTarget};
+use crate::cli_shared::snapshot::{self, TrustedVendor};
+use crate::networks::ChainConfig;
+use crate::shim::clock::ChainEpoch;
+
+/// Suffix of the checksum published along each snapshot, in the `sha256sum` format.
+const CHECKSUM_SUFFIX: &str = ".sha256sum";
+/// Suffix of the [`SnapshotMetadata`] published along each snapshot.
+const METADATA_SUFFIX: &str = ".metadata.json";
+
+/// Describes a published snapshot.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct SnapshotMetadata {
+    pub network: String,
+    pub epoch: ChainEpoch,
+    /// CIDs of the blocks of the exported tipset.
+    pub head: Vec<String>,
+    /// Timestamp of the exported tipset.
+    pub timestamp: u64,
+    pub size: u64,
+    pub sha256: String,
+}
+
+pub struct SnapshotService<DB> {
+    chain_store: Arc<ChainStore<DB>>,
+    chain: String,
+    interval: ChainEpochDelta,
+    retain: usize,
+    recent_roots: ChainEpochDelta,
+    /// Snapshots are only exported once their epoch is final.
+    finality: ChainEpochDelta,
+    /// How often the head is checked.
+    poll_interval: Duration,
+    target: Target,
+    /// Directory where snapshots are exported before being published.
+    staging_dir: PathBuf,
+}
+
+impl<DB: Blockstore + Send + Sync + 'static> SnapshotService<DB> {
+    pub fn new(
+        config: &SnapshotServiceConfig,
+        chain_store: Arc<ChainStore<DB>>,
+        chain_config: &ChainConfig,
+        chain: String,
+        data_dir: &Path,
+    ) -> anyhow::Result<Self> {
+        anyhow::ensure!(config.interval > 0, "snapshot interval must be positive");
+        let target = Target::new(&config.target)?;
+        if let Target::Filesystem { dir, .. } = &target {
+            std::fs::create_dir_all(dir)?;
+        }
+        let staging_dir = target.staging_dir(data_dir);
+        std::fs::create_dir_all(&staging_dir)?;
+        Ok(SnapshotService {
+            chain_store,
+            chain,
+            interval: config.interval,
+            retain: config.retain as usize,
+            recent_roots: chain_config.recent_state_roots,
+            finality: chain_config.policy.chain_finality,
+            poll_interval: Duration::from_secs(chain_config.block_delay_secs as u64),
+            target,
+            staging_dir,
+        })
+    }
+
+    /// Publishes a snapshot every time a multiple of the interval becomes final, and deletes the
+    /// oldest snapshots. Failures are logged and retried later.
+    pub async fn run(self) -> anyhow::Result<()> {
+        let mut last = None;
+        loop {
+            if last.is_none() {
+                match self.published().await {
+                    Ok(published) => last = Some(published.last().map_or(0, |(epoch, _)| *epoch)),
+                    Err(e) => warn!("Failed listing published snapshots: {e:#}"),
+                }
+            }
+            let epoch = self.snapshot_epoch(self.chain_store.heaviest_tipset().epoch());
+            if matches!(last, Some(last) if epoch > last) {
+                match self.publish(epoch).await {
+                    Ok(name) => {
+                        info!("Published snapshot {name}");
+                        last = Some(epoch);
+                        if let Err(e) = self.prune().await {
+                            warn!("Failed deleting old snapshots: {e:#}");
+                        }
+                    }
+                    Err(e) => warn!("Failed publishing the snapshot at epoch {epoch}: {e:#}"),
+                }
+            }
+            tokio::time::sleep(self.poll_interval).await;
+        }
+    }
+
+    /// Returns the latest multiple of the interval that is final at `head`.
+    fn snapshot_epoch(&self, head: ChainEpoch) -> ChainEpoch {
+        let settled = head - self.finality;
+        settled - settled.rem_euclid(self.interval)
+    }
+
+    /// Exports and publishes the snapshot at `epoch`, returning its name.
+    async fn publish(&self, epoch: ChainEpoch) -> anyhow::Result<String> {
+        let ts = self.chain_store.chain_index.tipset_by_height(
+            epoch,
+            self.chain_store.heaviest_tipset(),
+            ResolveNullTipset::TakeOlder,
+        )?;
+        let name = snapshot::filename(
+            TrustedVendor::Forest,
+            &self.chain,
+            NaiveDateTime::from_timestamp_opt(ts.min_timestamp() as i64, 0)
+                .unwrap_or_default()
+                .into(),
+            epoch,
+            true,
+        );
+
+        info!("Exporting snapshot {name}");
+        let path = tempfile::NamedTempFile::new_in(&self.staging_dir)?.into_temp_path();
+        let file = tokio::fs::File::create(&path).await?;
+        let sha256 = crate::chain::export::<Sha256>(
+            Arc::clone(&self.chain_store.db),
+            &ts,
+            self.recent_roots,
+            file,
+            CidHashSet::default(),
+            false,
+        )
+        .await?
+        .context("no snapshot checksum")?;
+        let sha256 = hex::encode(sha256);
+        let metadata = SnapshotMetadata {
+            network: self.chain.clone(),
+            epoch,
+            head: ts.cids().iter().map(ToString::to_string).collect(),
+            timestamp: ts.min_timestamp(),
+            size: std::fs::metadata(&path)?.len(),
+            sha256: sha256.clone(),
+        };
+
+        // The metadata is published last so its presence marks a complete snapshot
+        self.target.upload(&name, path).await?;
+        self.target
+            .put(
+                &format!("{name}{CHECKSUM_SUFFIX}"),
+                format!("{sha256}  {name}\n").into_bytes(),
+            )
+            .await?;
+        self.target
+            .put(
+                &format!("{name}{METADATA_SUFFIX}"),
+                serde_json::to_vec_pretty(&metadata)?,
+            )
+            .await?;
+        Ok(name)
+    }
+
+    /// Returns the epochs and names of the published snapshots of the chain, oldest first.
+    async fn published(&self) -> anyhow::Result<Vec<(ChainEpoch, String)>> {
+        let mut published = self
+            .target
+            .list()
+            .await?
+            .into_iter()
+            .filter_map(|name| {
+                Some((
+                    snapshot::height_of(&name, TrustedVendor::Forest, &self.chain)?,
+                    name,
+                ))
+            })
+            .collect::<Vec<_>>();
+        published.sort();
+        Ok(published)
+    }
+
+    /// Deletes all but the last `retain` snapshots.
+    async fn prune(&self) -> anyhow::Result<()> {
+        let published = self.published().await?;
+        let stale = published.len().saturating_sub(self.retain);
+        for (_, name) in &published[..stale] {
+            info!("Deleting snapshot {name}");
+            for name in [
+                format!("{name}{METADATA_SUFFIX}"),
+                format!("{name}{CHECKSUM_SUFFIX}"),
+                name.clone(),
+            ] {
+                self.target.delete(&name).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+enum Target {
+    /// Snapshots are exported to `staging_dir`, a sibling of `dir`, and moved to `dir` once
+    /// complete so readers listing `dir` never see partial files.
+    Filesystem {
+        dir: PathBuf,
+        staging_dir: PathBuf,
+    },
+    S3(Box<dyn ObjectStore>),
+}
+
+impl Target {
+    fn new(config: &SnapshotTarget) -> anyhow::Result<Self> {
+        Ok(match config {
+            SnapshotTarget::Filesystem { path } => {
+                let name = path
+                    .file_name()
+                    .context("snapshot directory has no name")?
+                    .to_string_lossy();
+                Target::Filesystem {
+                    dir: path.clone(),
+                    staging_dir: path.with_file_name(format!(".{name}.staging")),
+                }
+            }
+            SnapshotTarget::S3 {
+                endpoint,
+                region,
+                bucket,
+                prefix,
+                access_key_id,
+                secret_access_key,
+            } => {
+                let mut builder = AmazonS3Builder::from_env()
+                    .with_endpoint(endpoint)
+                    .with_allow_http(endpoint.starts_with("http://"))
+                    .with_region(region)
+                    .with_bucket_name(bucket);
+                if let Some(access_key_id) = access_key_id {
+                    builder = builder.with_access_key_id(access_key_id);
+                }
+                if let Some(secret_access_key) = secret_access_key {
+                    builder = builder.with_secret_access_key(secret_access_key);
+                }
+                let store = builder.build().context("invalid S3 target")?;
+                Target::S3(Box::new(PrefixStore::new(store, prefix.as_str())))
+            }
+        })
+    }
+
+    /// Directory where snapshots are exported before being published.
+    fn staging_dir(&self, data_dir: &Path) -> PathBuf {
+        match self {
+            Target::Filesystem { staging_dir, .. } => staging_dir.clone(),
+            Target::S3(_) => data_dir.join("snapshot_service"),
+        }
+    }
+
+    /// Publishes the file at `path` as `name`.
+    async fn upload(&self, name: &str, path: TempPath) -> anyhow::Result<()> {
+        match self {
+            Target::Filesystem { dir, .. } => Ok(path.persist(dir.join(name))?),
+            Target::S3(store) => {
+                let location = ObjectPath::from(name);
+                let (upload_id, mut writer) = store.put_multipart(&location).await?;
+                let result = async {
+                    let mut file = tokio::fs::File::open(&path).await?;
+                    tokio::io::copy(&mut file, &mut writer).await?;
+                    writer.shutdown().await?;
+                    anyhow::Ok(())
+                }
+                .await;
+                if result.is_err() {
+                    // Best effort, incomplete uploads are otherwise billed until they expire
+                    let _ = store.abort_multipart(&location, &upload_id).await;
+                }
+                result
+            }
+        }
+    }
+
+    async fn put(&self, name: &str, bytes: Vec<u8>) -> anyhow::Result<()> {
+        match self {
+            Target::Filesystem { dir, staging_dir } => {
+                // Write to a temporary file first so readers never see a partial file
+                let path = tempfile::NamedTempFile::new_in(staging_dir)?.into_temp_path();
+                tokio::fs::write(&path, bytes).await?;
+                Ok(path.persist(dir.join(name))?)
+            }
+            Target::S3(store) => {
+                store.put(&ObjectPath::from(name), bytes.into()).await?;
+                Ok(())
+            }
+        }
+    }
+
+    async fn list(&self) -> anyhow::Result<Vec<String>> {
+        match self {
+            Target::Filesystem { dir, .. } => {
+                let mut names = vec![];
+                let mut entries = tokio::fs::read_dir(dir).await?;
+                while let Some(entry) = entries.next_entry().await? {
+                    if let Ok(name) = entry.file_name().into_string() {
+                        names.push(name);
+                    }
+                }
+                Ok(names)
+            }
+            Target::S3(store) => Ok(store
+                .list(None)
+                .map_ok(|object| object.location.to_string())
+                .try_collect()
+                .await?),
+        }
+    }
+
+    async fn delete(&self, name: &str) -> anyhow::Result<()> {
+        match self {
+            Target::Filesystem { dir, .. } => match tokio::fs::remove_file(dir.join(name)).await {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                result => Ok(result?),
+            },
+            Target::S3(store) => Ok(store.delete(&ObjectPath::from(name)).await?),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MemoryDB;
+    use crate::genesis::read_genesis_header;
+    use crate::networks::calibnet;
+    use ahash::HashMap;
+    use axum::extract::{Path as UrlPath, Query, State};
+    use axum::http::{HeaderMap, Method, StatusCode};
+    use parking_lot::Mutex;
+
+    async fn calibnet_chain_store() -> Arc<ChainStore<MemoryDB>> {
+        let db = Arc::new(MemoryDB::default());
+        let genesis_header = read_genesis_header(None, Some(calibnet::DEFAULT_GENESIS), &db)
+            .await
+            .unwrap();
+        Arc::new(
+            ChainStore::new(
+                db.clone(),
+                db,
+                Arc::new(ChainConfig::calibnet()),
+                genesis_header,
+            )
+            .unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn publish_to_filesystem() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("snapshots");
+        let config = SnapshotServiceConfig {
+            enabled: true,
+            retain: 1,
+            target: SnapshotTarget::Filesystem { path: dir.clone() },
+            ..Default::default()
+        };
+        let service = SnapshotService::new(
+            &config,
+            calibnet_chain_store().await,
+            &ChainConfig::calibnet(),
+            "calibnet".into(),
+            root.path(),
+        )
+        .unwrap();
+        // Partial files are staged out of the published directory
+        assert_eq!(service.staging_dir, root.path().join(".snapshots.staging"));
+        // Snapshots are exported once their epoch is final
+        let finality = ChainConfig::calibnet().policy.chain_finality;
+        assert_eq!(service.snapshot_epoch(2880 + finality - 1), 0);
+        assert_eq!(service.snapshot_epoch(2880 + finality), 2880);
+        // An older snapshot, deleted once a new one is published
+        let stale = snapshot::filename(
+            TrustedVendor::Forest,
+            "calibnet",
+            chrono::NaiveDate::default(),
+            0,
+            true,
+        );
+        std::fs::write(dir.join(&stale), b"").unwrap();
+        std::fs::write(dir.join(format!("{stale}{CHECKSUM_SUFFIX}")), b"").unwrap();
+
+        let name = service.publish(0).await.unwrap();
+        service.prune().await.unwrap();
+
+        let mut files = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(
+            files,
+            [
+                name.clone(),
+                format!("{name}{METADATA_SUFFIX}"),
+                format!("{name}{CHECKSUM_SUFFIX}")
+            ]
+        );
+        let metadata: SnapshotMetadata = serde_json::from_slice(
+            &std::fs::read(dir.join(format!("{name}{METADATA_SUFFIX}"))).unwrap(),
+        )
+        .unwrap();
+        let car = crate::db::car::ForestCar::try_from(dir.join(&name).as_path()).unwrap();
+        assert_eq!(metadata.epoch, 0);
+        assert_eq!(
+            metadata.head,
+            car.roots()
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join(format!("{name}{CHECKSUM_SUFFIX}"))).unwrap(),
+            format!("{}  {name}\n", metadata.sha256)
+        );
+    }
+
+    #[derive(Default)]
+    struct Bucket {
+        objects: HashMap<String, Vec<u8>>,
+        parts: HashMap<(String, u64), Vec<u8>>,
+    }
+
+    /// Serves the subset of the S3 API used to publish snapshots from memory.
+    async fn handle_object(
+        State(bucket): State<Arc<Mutex<Bucket>>>,
+        method: Method,
+        headers: HeaderMap,
+        UrlPath((_, key)): UrlPath<(String, String)>,
+        Query(query): Query<HashMap<String, String>>,
+        body: axum::body::Bytes,
+    ) -> (StatusCode, HeaderMap, String) {
+        let authorization = headers.get("authorization").unwrap().to_str().unwrap();
+        assert!(authorization.starts_with("AWS4-HMAC-SHA256 Credential=minio/"));
+        let mut bucket = bucket.lock();
+        let mut response_headers = HeaderMap::new();
+        response_headers.insert("etag", "\"etag\"".parse().unwrap());
+        let body = match (method, query.get("uploadId")) {
+            (Method::PUT, None) => {
+                bucket.objects.insert(key, body.to_vec());
+                String::new()
+            }
+            (Method::PUT, Some(_)) => {
+                let part_number = query["partNumber"].parse().unwrap();
+                bucket.parts.insert((key, part_number), body.to_vec());
+                String::new()
+            }
+            (Method::POST, None) => {
+                "<InitiateMultipartUploadResult><UploadId>upload</UploadId></InitiateMultipartUploadResult>".into()
+            }
+            (Method::POST, Some(_)) => {
+                let mut object = vec![];
+                for part_number in 1.. {
+                    match bucket.parts.remove(&(key.clone(), part_number)) {
+                        Some(part) => object.extend(part),
+                        None => break,
+                    }
+                }
+                bucket.objects.insert(key, object);
+                "<CompleteMultipartUploadResult><ETag>\"etag\"</ETag></CompleteMultipartUploadResult>"
+                    .into()
+            }
+            (Method::DELETE, _) => {
+                bucket.objects.remove(&key);
+                String::new()
+            }
+            _ => return (StatusCode::BAD_REQUEST, response_headers, String::new()),
+        };
+        (StatusCode::OK, response_headers, body)
+    }
+
+    async fn handle_bucket(
+        State(bucket): State<Arc<Mutex<Bucket>>>,
+        Query(query): Query<HashMap<String, String>>,
+    ) -> String {
+        let contents = bucket
+            .lock()
+            .objects
+            .iter()
+            .filter(|(key, _)| key.starts_with(&query["prefix"]))
+            .map(|(key, object)| {
+                let size = object.len().to_string();
+                [
+                    "<Contents><Key>",
+                    key,
+                    "</Key><Size>",
+                    &size,
+                    "</Size><LastModified>2023-01-01T00:00:00Z</LastModified></Contents>",
+                ]
+                .concat()
+            })
+            .collect::<String>();
+        format!("<ListBucketResult>{contents}</ListBucketResult>")
+    }
+
+    #[tokio::test]
+    async fn publish_to_s3() {
+        let bucket = Arc::new(Mutex::new(Bucket::default()));
+        let app = axum::Router::new()
+            .route("/:bucket", axum::routing::get(handle_bucket))
+            .route("/:bucket/*key", axum::routing::any(handle_object))
+            .with_state(bucket.clone());
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = SnapshotServiceConfig {
+            enabled: true,
+            target: SnapshotTarget::S3 {
+                endpoint: format!("http://{addr}"),
+                region: "us-east-1".into(),
+                bucket: "snapshots".into(),
+                prefix: "calibnet/".into(),
+                access_key_id: Some("minio".into()),
+                secret_access_key: Some("minio123".into()),
+            },
+            ..Default::default()
+        };
+        let service = SnapshotService::new(
+            &config,
+            calibnet_chain_store().await,
+            &ChainConfig::calibnet(),
+            "calibnet".into(),
+            dir.path(),
+        )
+        .unwrap();
+
+        let name = service.publish(0).await.unwrap();
+        assert_eq!(service.published().await.unwrap(), [(0, name.clone())]);
+        let objects = std::mem::take(&mut bucket.lock().objects);
+        let metadata: SnapshotMetadata =
+            serde_json::from_slice(&objects[&format!("calibnet/{name}{METADATA_SUFFIX}")]).unwrap();
+        let snapshot = &objects[&format!("calibnet/{name}")];
+        assert_eq!(metadata.size, snapshot.len() as u64);
+        assert_eq!(
+            metadata.sha256,
+            hex::encode(<Sha256 as sha2::Digest>::digest(snapshot))
+        );
+    }
+}
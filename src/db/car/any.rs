@@ -1,12 +1,10 @@
 // Copyright 2019-2023 ChainSafe Systems
 // SPDX-License-Identifier: Apache-2.0, MIT
 
-//! There are three different CAR formats: `.car`, `.car.zst` and
-//! `.forest.car.zst`. [`AnyCar`] identifies the format by inspecting the CAR
-//! header and the first key-value block, and picks the appropriate block store
-//! (either [`super::ForestCar`] or [`super::PlainCar`]).
-//!
-//! CARv2 is not supported yet.
+//! There are four different CAR formats: `.car` (CARv1 or CARv2), `.car.zst`
+//! and `.forest.car.zst`. [`AnyCar`] identifies the format by inspecting the
+//! CAR header and the first key-value block, and picks the appropriate block
+//! store (either [`super::ForestCar`], [`super::CarV2`] or [`super::PlainCar`]).
 
 use super::{CacheKey, ZstdFrameCache};
 use crate::blocks::Tipset;
@@ -22,12 +20,13 @@ use std::sync::Arc;
 pub enum AnyCar<ReaderT> {
     Plain(super::PlainCar<ReaderT>),
     Forest(super::ForestCar<ReaderT>),
+    V2(super::CarV2<ReaderT>),
     Memory(super::PlainCar<Vec<u8>>),
 }
 
 impl<ReaderT: super::RandomAccessFileReader> AnyCar<ReaderT> {
-    /// Open an archive. May be formatted as `.car`, `.car.zst` or
-    /// `.forest.car.zst`. This call may block for an indeterminate amount of
+    /// Open an archive. May be formatted as `.car` (CARv1 or CARv2), `.car.zst`
+    /// or `.forest.car.zst`. This call may block for an indeterminate amount of
     /// time while data is decoded and indexed.
     pub fn new(reader: ReaderT) -> Result<Self> {
         if super::ForestCar::is_valid(&reader) {
@@ -35,6 +34,10 @@ impl<ReaderT: super::RandomAccessFileReader> AnyCar<ReaderT> {
             return Ok(AnyCar::Forest(forest_car));
         }
 
+        if super::CarV2::is_valid(&reader) {
+            return Ok(AnyCar::V2(super::CarV2::new(reader)?));
+        }
+
         // Maybe use a tempfile for this in the future.
         if let Ok(decompressed) = zstd::stream::decode_all(positioned_io::Cursor::new(&reader)) {
             if let Ok(mem_car) = super::PlainCar::new(decompressed) {
@@ -47,7 +50,7 @@ impl<ReaderT: super::RandomAccessFileReader> AnyCar<ReaderT> {
         }
         Err(Error::new(
             ErrorKind::InvalidData,
-            "input not recognized as any kind of CAR data (.car, .car.zst, .forest.car)",
+            "input not recognized as any kind of CAR data (.car, .car.zst, .forest.car, CARv2)",
         ))
     }
 
@@ -56,16 +59,18 @@ impl<ReaderT: super::RandomAccessFileReader> AnyCar<ReaderT> {
     pub fn heaviest_tipset(&self) -> anyhow::Result<Tipset> {
         match self {
             AnyCar::Forest(forest) => forest.heaviest_tipset(),
+            AnyCar::V2(v2) => v2.heaviest_tipset(),
             AnyCar::Plain(plain) => plain.heaviest_tipset(),
             AnyCar::Memory(mem) => mem.heaviest_tipset(),
         }
     }
 
-    /// Return the identified CAR format variant. There are three variants:
-    /// `CARv1`, `CARv1.zst` and `ForestCARv1.zst`.
+    /// Return the identified CAR format variant. There are four variants:
+    /// `CARv1`, `CARv1.zst`, `CARv2` and `ForestCARv1.zst`.
     pub fn variant(&self) -> &'static str {
         match self {
             AnyCar::Forest(_) => "ForestCARv1.zst",
+            AnyCar::V2(_) => "CARv2",
             AnyCar::Plain(_) => "CARv1",
             AnyCar::Memory(_) => "CARv1.zst",
         }
@@ -75,6 +80,7 @@ impl<ReaderT: super::RandomAccessFileReader> AnyCar<ReaderT> {
     pub fn into_dyn(self) -> AnyCar<Box<dyn super::RandomAccessFileReader>> {
         match self {
             AnyCar::Forest(f) => AnyCar::Forest(f.into_dyn()),
+            AnyCar::V2(v2) => AnyCar::V2(v2.into_dyn()),
             AnyCar::Plain(p) => AnyCar::Plain(p.into_dyn()),
             AnyCar::Memory(m) => AnyCar::Memory(m),
         }
@@ -84,6 +90,7 @@ impl<ReaderT: super::RandomAccessFileReader> AnyCar<ReaderT> {
     pub fn with_cache(self, cache: Arc<Mutex<ZstdFrameCache>>, key: CacheKey) -> Self {
         match self {
             AnyCar::Forest(f) => AnyCar::Forest(f.with_cache(cache, key)),
+            AnyCar::V2(v2) => AnyCar::V2(v2),
             AnyCar::Plain(p) => AnyCar::Plain(p),
             AnyCar::Memory(m) => AnyCar::Memory(m),
         }
@@ -111,6 +118,7 @@ where
     fn get(&self, k: &Cid) -> anyhow::Result<Option<Vec<u8>>> {
         match self {
             AnyCar::Forest(forest) => forest.get(k),
+            AnyCar::V2(v2) => v2.get(k),
             AnyCar::Plain(plain) => plain.get(k),
             AnyCar::Memory(mem) => mem.get(k),
         }
@@ -119,6 +127,7 @@ where
     fn put_keyed(&self, k: &Cid, block: &[u8]) -> anyhow::Result<()> {
         match self {
             AnyCar::Forest(forest) => forest.put_keyed(k, block),
+            AnyCar::V2(v2) => v2.put_keyed(k, block),
             AnyCar::Plain(plain) => plain.put_keyed(k, block),
             AnyCar::Memory(mem) => mem.put_keyed(k, block),
         }
@@ -131,6 +140,12 @@ impl<ReaderT> From<super::ForestCar<ReaderT>> for AnyCar<ReaderT> {
     }
 }
 
+impl<ReaderT> From<super::CarV2<ReaderT>> for AnyCar<ReaderT> {
+    fn from(car: super::CarV2<ReaderT>) -> Self {
+        Self::V2(car)
+    }
+}
+
 impl<ReaderT> From<super::PlainCar<ReaderT>> for AnyCar<ReaderT> {
     fn from(car: super::PlainCar<ReaderT>) -> Self {
         Self::Plain(car)
@@ -57,7 +57,6 @@
 //! - Use safe arithmetic for all operations - a malicious frame shouldn't cause a crash.
 //! - Theoretically, file-backed blockstores should be clonable (or even [`Sync`]) with very low
 //!   overhead, so that multiple threads could perform operations concurrently.
-//! - CARv2 support
 //! - A wrapper that abstracts over car formats for reading.
 
 use crate::cid_collections::{hash_map::Entry as CidHashMapEntry, CidHashMap};
@@ -305,7 +304,7 @@ fn cid_error_to_io_error(cid_error: cid::Error) -> io::Error {
 ///        └───────────┴──────────┘
 /// ```
 #[tracing::instrument(level = "trace", skip_all, ret)]
-fn read_header(mut reader: impl Read) -> io::Result<CarHeader> {
+pub(super) fn read_header(mut reader: impl Read) -> io::Result<CarHeader> {
     let header_len =
         read_varint_body_length_or_eof(&mut reader)?.ok_or(io::Error::from(UnexpectedEof))?;
     let mut buffer = vec![0; usize::try_from(header_len).unwrap()];
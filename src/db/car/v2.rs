@@ -0,0 +1,595 @@
+// Copyright 2019-2023 ChainSafe Systems
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+//! # CARv2
+//!
+//! A CARv2 archive wraps a CARv1 payload (see [`crate::db::car::plain`]) with a
+//! fixed-size header and an index of the blocks in the payload. It is the
+//! format emitted by Boost and `go-car`.
+//!
+//! ```text
+//! ┌──────────┬──────────┬─────────────────────┬───────┐
+//! │  Pragma  │  Header  │   CARv1 data        │ Index │
+//! │ 11 bytes │ 40 bytes │ (header and blocks) │       │
+//! └──────────┴──────────┴─────────────────────┴───────┘
+//! ```
+//!
+//! The header holds the characteristics of the archive, and the offset and size
+//! of the data as well as the offset of the index. Only the
+//! `MultihashIndexSorted` index is supported. It groups the blocks by
+//! multihash code, then by digest width, and sorts each group by digest. Each
+//! entry is the digest followed by the little-endian offset of the block frame
+//! from the start of the data:
+//!
+//! ```text
+//! codec (varint 0x0401)
+//! number of multihash codes (i32)
+//! ├─ multihash code (u64)
+//! │  number of widths (i32)
+//! │  ├─ width (u32), length in bytes (u64)
+//! │  │  ├─ digest, offset (u64)
+//! ```
+//!
+//! Lookups binary search the index on disk, so opening an archive does not
+//! read more than its header and the layout of the index.
+//!
+//! # Additional reading
+//!
+//! CARv2 specification: <https://ipld.io/specs/transport/car/carv2/>
+
+use crate::blocks::{Tipset, TipsetKeys};
+use crate::cid_collections::CidHashMap;
+use crate::utils::db::car_stream::{CarBlock, CarHeader};
+use crate::utils::io::EitherMmapOrRandomAccessFile;
+use anyhow::{ensure, Context as _};
+use cid::Cid;
+use futures::{TryStream, TryStreamExt as _};
+use fvm_ipld_blockstore::Blockstore;
+use fvm_ipld_encoding::to_vec;
+use integer_encoding::{VarInt as _, VarIntReader as _};
+use parking_lot::RwLock;
+use positioned_io::{Cursor, ReadAt};
+use std::collections::BTreeMap;
+use std::io::{self, BufReader, ErrorKind::InvalidData, Read, SeekFrom};
+use std::path::Path;
+use tokio::io::{AsyncSeek, AsyncSeekExt as _, AsyncWrite, AsyncWriteExt as _};
+
+/// The CBOR encoding of `{"version": 2}` as a varint frame.
+pub const CARV2_PRAGMA: [u8; 11] = [
+    0x0a, 0xa1, 0x67, 0x76, 0x65, 0x72, 0x73, 0x69, 0x6f, 0x6e, 0x02,
+];
+
+/// Multicodec of the `MultihashIndexSorted` index.
+const MULTIHASH_INDEX_SORTED: u64 = 0x0401;
+
+/// Size of the block offset at the end of each index entry.
+const OFFSET_SIZE: usize = 8;
+
+/// The characteristic set when all the blocks of the data, including those
+/// with identity CIDs, are indexed. It is bit 7 of the most significant `u64`,
+/// i.e. the first byte of the characteristics, as set by `go-car`.
+const FULLY_INDEXED: u128 = 1 << 71;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(test, derive(derive_quickcheck_arbitrary::Arbitrary))]
+pub struct CarV2Header {
+    pub characteristics: u128,
+    pub data_offset: u64,
+    pub data_size: u64,
+    pub index_offset: u64,
+}
+
+impl CarV2Header {
+    pub const SIZE: usize = 40;
+
+    /// The characteristics are two little-endian `u64`, the most significant
+    /// one first.
+    pub fn to_le_bytes(self) -> [u8; Self::SIZE] {
+        let mut buffer = [0; Self::SIZE];
+        buffer[0..8].copy_from_slice(&((self.characteristics >> 64) as u64).to_le_bytes());
+        buffer[8..16].copy_from_slice(&(self.characteristics as u64).to_le_bytes());
+        buffer[16..24].copy_from_slice(&self.data_offset.to_le_bytes());
+        buffer[24..32].copy_from_slice(&self.data_size.to_le_bytes());
+        buffer[32..40].copy_from_slice(&self.index_offset.to_le_bytes());
+        buffer
+    }
+
+    pub fn from_le_bytes(bytes: [u8; Self::SIZE]) -> Self {
+        let u64_at = |offset: usize| {
+            u64::from_le_bytes(bytes[offset..offset + 8].try_into().expect("infallible"))
+        };
+        CarV2Header {
+            characteristics: (u128::from(u64_at(0)) << 64) | u128::from(u64_at(8)),
+            data_offset: u64_at(16),
+            data_size: u64_at(24),
+            index_offset: u64_at(32),
+        }
+    }
+}
+
+/// The entries of the index for a multihash code and digest width.
+#[derive(Debug, Clone, Copy)]
+struct IndexBucket {
+    code: u64,
+    width: usize,
+    /// Offset of the first entry in the file.
+    offset: u64,
+    count: u64,
+}
+
+/// **Note that all operations on this store are blocking**.
+///
+/// An implementer of [`Blockstore`] wrapping a [CARv2
+/// file](https://ipld.io/specs/transport/car/carv2) with a
+/// `MultihashIndexSorted` index. Blocks are looked up in the on-disk index and
+/// read on-demand. Writes are cached in-memory.
+///
+/// See [module documentation](mod@self) for more.
+pub struct CarV2<ReaderT> {
+    reader: ReaderT,
+    header: CarV2Header,
+    index: Vec<IndexBucket>,
+    write_cache: RwLock<CidHashMap<Vec<u8>>>,
+    roots: Vec<Cid>,
+}
+
+impl<ReaderT: super::RandomAccessFileReader> CarV2<ReaderT> {
+    pub fn new(reader: ReaderT) -> io::Result<Self> {
+        let header = read_carv2_header(&reader)?;
+        let roots = match super::plain::read_header(Cursor::new_pos(&reader, header.data_offset))? {
+            CarHeader { roots, version: 1 } => roots,
+            _ => return Err(io::Error::new(InvalidData, "CARv2 data must be a CARv1")),
+        };
+        let index = read_index_layout(&reader, &header)?;
+        Ok(CarV2 {
+            reader,
+            header,
+            index,
+            write_cache: RwLock::new(CidHashMap::new()),
+            roots,
+        })
+    }
+
+    pub fn is_valid(reader: &ReaderT) -> bool {
+        let mut pragma = [0; CARV2_PRAGMA.len()];
+        reader.read_exact_at(0, &mut pragma).is_ok() && pragma == CARV2_PRAGMA
+    }
+
+    pub fn roots(&self) -> Vec<Cid> {
+        self.roots.clone()
+    }
+
+    pub fn heaviest_tipset(&self) -> anyhow::Result<Tipset> {
+        Tipset::load_required(self, &TipsetKeys::from_iter(self.roots()))
+    }
+
+    pub fn into_dyn(self) -> CarV2<Box<dyn super::RandomAccessFileReader>> {
+        CarV2 {
+            reader: Box::new(self.reader),
+            header: self.header,
+            index: self.index,
+            write_cache: self.write_cache,
+            roots: self.roots,
+        }
+    }
+}
+
+impl<ReaderT: ReadAt> CarV2<ReaderT> {
+    /// Checks that the index is sorted and that each entry points at a block
+    /// frame of the data with the indexed multihash. Returns the number of
+    /// entries.
+    pub fn validate_index(&self) -> anyhow::Result<u64> {
+        let mut entries = 0;
+        for bucket in &self.index {
+            let mut reader = BufReader::new(Cursor::new_pos(&self.reader, bucket.offset));
+            let digest_len = bucket.width - OFFSET_SIZE;
+            let mut previous: Option<Vec<u8>> = None;
+            for _ in 0..bucket.count {
+                let mut entry = vec![0; bucket.width];
+                reader.read_exact(&mut entry)?;
+                let offset = entry_offset(&entry, digest_len);
+                let block = self.read_block(offset)?;
+                ensure!(
+                    block.cid.hash().code() == bucket.code
+                        && block.cid.hash().digest() == &entry[..digest_len],
+                    "Index entry at data offset {offset} does not match block {}",
+                    block.cid
+                );
+                if let Some(previous) = &previous {
+                    ensure!(
+                        previous.as_slice() <= &entry[..digest_len],
+                        "Index is not sorted at block {}",
+                        block.cid
+                    );
+                }
+                entry.truncate(digest_len);
+                previous = Some(entry);
+                entries += 1;
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Finds the offset of the block frame with the multihash of `cid` in the
+    /// data.
+    fn locate(&self, cid: &Cid) -> io::Result<Option<u64>> {
+        let hash = cid.hash();
+        let digest = hash.digest();
+        let width = digest.len() + OFFSET_SIZE;
+        let Some(bucket) = self
+            .index
+            .iter()
+            .find(|bucket| bucket.code == hash.code() && bucket.width == width)
+        else {
+            return Ok(None);
+        };
+        let mut entry = vec![0; width];
+        let (mut low, mut high) = (0, bucket.count);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            self.reader
+                .read_exact_at(bucket.offset + middle * width as u64, &mut entry)?;
+            match entry[..digest.len()].cmp(digest) {
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Greater => high = middle,
+                std::cmp::Ordering::Equal => return Ok(Some(entry_offset(&entry, digest.len()))),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Reads the block frame at `offset` from the start of the data. The length
+    /// of the frame is bounded by the size of the data, so a corrupt length
+    /// can't allocate more than the archive holds.
+    fn read_block(&self, offset: u64) -> io::Result<CarBlock> {
+        if offset >= self.header.data_size {
+            return Err(io::Error::new(
+                InvalidData,
+                format!("block offset {offset} is out of the CARv2 data"),
+            ));
+        }
+        let start = self.header.data_offset + offset;
+        let mut cursor = Cursor::new_pos(&self.reader, start);
+        let length: u64 = cursor.read_varint()?;
+        let remaining = self
+            .header
+            .data_size
+            .saturating_sub(offset + (cursor.position() - start));
+        if length > remaining {
+            return Err(io::Error::new(
+                InvalidData,
+                format!("block frame at offset {offset} overruns the CARv2 data"),
+            ));
+        }
+        let mut frame =
+            vec![0; usize::try_from(length).map_err(|e| io::Error::new(InvalidData, e))?];
+        cursor.read_exact(&mut frame)?;
+        CarBlock::from_bytes(frame)
+    }
+}
+
+impl TryFrom<&Path> for CarV2<EitherMmapOrRandomAccessFile> {
+    type Error = std::io::Error;
+    fn try_from(path: &Path) -> std::io::Result<Self> {
+        CarV2::new(EitherMmapOrRandomAccessFile::open(path)?)
+    }
+}
+
+impl<ReaderT> Blockstore for CarV2<ReaderT>
+where
+    ReaderT: ReadAt,
+{
+    #[tracing::instrument(level = "trace", skip(self))]
+    fn get(&self, k: &Cid) -> anyhow::Result<Option<Vec<u8>>> {
+        if let Some(value) = self.write_cache.read().get(k) {
+            return Ok(Some(value.clone()));
+        }
+        match self.locate(k)? {
+            Some(offset) => {
+                let block = self.read_block(offset)?;
+                // The index is keyed by multihash, the codec of the CID may differ
+                ensure!(
+                    block.cid.hash() == k.hash(),
+                    "CARv2 index points at block {} instead of {k}",
+                    block.cid
+                );
+                Ok(Some(block.data))
+            }
+            None => Ok(None),
+        }
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, block))]
+    fn put_keyed(&self, k: &Cid, block: &[u8]) -> anyhow::Result<()> {
+        self.write_cache.write().insert(*k, Vec::from(block));
+        Ok(())
+    }
+}
+
+fn entry_offset(entry: &[u8], digest_len: usize) -> u64 {
+    u64::from_le_bytes(entry[digest_len..].try_into().expect("infallible"))
+}
+
+fn read_carv2_header(reader: &impl ReadAt) -> io::Result<CarV2Header> {
+    let mut buffer = [0; CARV2_PRAGMA.len() + CarV2Header::SIZE];
+    reader.read_exact_at(0, &mut buffer)?;
+    if buffer[..CARV2_PRAGMA.len()] != CARV2_PRAGMA {
+        return Err(io::Error::new(
+            InvalidData,
+            "not recognizable as a CARv2 file",
+        ));
+    }
+    let header =
+        CarV2Header::from_le_bytes(buffer[CARV2_PRAGMA.len()..].try_into().expect("infallible"));
+    if header.index_offset == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "CARv2 files without an index are not supported",
+        ));
+    }
+    Ok(header)
+}
+
+/// Reads the multihash codes and digest widths of the index, skipping over the
+/// entries.
+fn read_index_layout(reader: &impl ReadAt, header: &CarV2Header) -> io::Result<Vec<IndexBucket>> {
+    fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+        let mut buffer = [0; 4];
+        reader.read_exact(&mut buffer)?;
+        Ok(u32::from_le_bytes(buffer))
+    }
+    fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+        let mut buffer = [0; 8];
+        reader.read_exact(&mut buffer)?;
+        Ok(u64::from_le_bytes(buffer))
+    }
+
+    let mut cursor = Cursor::new_pos(reader, header.index_offset);
+    let codec: u64 = cursor.read_varint()?;
+    if codec != MULTIHASH_INDEX_SORTED {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("unsupported CARv2 index codec {codec:#x}"),
+        ));
+    }
+    let mut buckets = vec![];
+    for _ in 0..read_u32(&mut cursor)? {
+        let code = read_u64(&mut cursor)?;
+        for _ in 0..read_u32(&mut cursor)? {
+            let width = read_u32(&mut cursor)? as usize;
+            let length = read_u64(&mut cursor)?;
+            if width <= OFFSET_SIZE || length % width as u64 != 0 {
+                return Err(io::Error::new(InvalidData, "malformed CARv2 index"));
+            }
+            let offset = cursor.position();
+            buckets.push(IndexBucket {
+                code,
+                width,
+                offset,
+                count: length / width as u64,
+            });
+            std::io::Seek::seek(&mut cursor, SeekFrom::Current(length as i64))?;
+        }
+    }
+    Ok(buckets)
+}
+
+pub struct Encoder {}
+
+impl Encoder {
+    /// Writes a CARv2 archive with a `MultihashIndexSorted` index of all the
+    /// blocks. The header is written last, so `sink` has to be seekable.
+    ///
+    /// The index is sorted in memory before it is written: it takes the digest
+    /// and offset of each block (40 bytes for a 32-byte digest), and 16 more
+    /// bytes per block while sorting. Archives of hundreds of millions of
+    /// blocks, like full mainnet snapshots, need several GiB of memory.
+    pub async fn write(
+        sink: &mut (impl AsyncWrite + AsyncSeek + Unpin),
+        roots: Vec<Cid>,
+        mut stream: impl TryStream<Ok = CarBlock, Error = anyhow::Error> + Unpin,
+    ) -> anyhow::Result<()> {
+        let start = sink.stream_position().await?;
+        sink.write_all(&CARV2_PRAGMA).await?;
+        sink.write_all(&[0; CarV2Header::SIZE]).await?;
+
+        let car_header = to_vec(&CarHeader { roots, version: 1 })?;
+        let mut frame = car_header.len().encode_var_vec();
+        frame.extend_from_slice(&car_header);
+        sink.write_all(&frame).await?;
+        let mut data_size = frame.len() as u64;
+
+        // Concatenated entries by multihash code and digest width
+        let mut index: BTreeMap<u64, BTreeMap<usize, Vec<u8>>> = BTreeMap::new();
+        while let Some(block) = stream.try_next().await? {
+            let hash = block.cid.hash();
+            let entries = index
+                .entry(hash.code())
+                .or_default()
+                .entry(hash.digest().len() + OFFSET_SIZE)
+                .or_default();
+            entries.extend_from_slice(hash.digest());
+            entries.extend_from_slice(&data_size.to_le_bytes());
+
+            frame.clear();
+            block.write(&mut frame)?;
+            sink.write_all(&frame).await?;
+            data_size += frame.len() as u64;
+        }
+
+        sink.write_all(&MULTIHASH_INDEX_SORTED.encode_var_vec())
+            .await?;
+        sink.write_all(&i32::try_from(index.len())?.to_le_bytes())
+            .await?;
+        for (code, widths) in index {
+            sink.write_all(&code.to_le_bytes()).await?;
+            sink.write_all(&i32::try_from(widths.len())?.to_le_bytes())
+                .await?;
+            for (width, entries) in widths {
+                let mut entries: Vec<&[u8]> = entries.chunks_exact(width).collect();
+                entries.sort_unstable();
+                sink.write_all(&u32::try_from(width)?.to_le_bytes()).await?;
+                sink.write_all(&((width * entries.len()) as u64).to_le_bytes())
+                    .await?;
+                for entry in entries {
+                    sink.write_all(entry).await?;
+                }
+            }
+        }
+
+        let data_offset = (CARV2_PRAGMA.len() + CarV2Header::SIZE) as u64;
+        let header = CarV2Header {
+            characteristics: FULLY_INDEXED,
+            data_offset,
+            data_size,
+            index_offset: data_offset + data_size,
+        };
+        sink.seek(SeekFrom::Start(start + CARV2_PRAGMA.len() as u64))
+            .await?;
+        sink.write_all(&header.to_le_bytes()).await?;
+        sink.seek(SeekFrom::End(0))
+            .await
+            .context("Failed to seek to the end of the CARv2 archive")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networks::calibnet;
+    use crate::utils::db::car_stream::CarStream;
+    use futures::executor::block_on;
+    use quickcheck_macros::quickcheck;
+
+    fn mk_encoded_car(roots: Vec<Cid>, blocks: Vec<CarBlock>) -> Vec<u8> {
+        block_on(async {
+            let mut encoded = std::io::Cursor::new(vec![]);
+            Encoder::write(
+                &mut encoded,
+                roots,
+                futures::stream::iter(blocks.into_iter().map(Ok)),
+            )
+            .await
+            .unwrap();
+            encoded.into_inner()
+        })
+    }
+
+    #[quickcheck]
+    fn carv2_create_basic(head: CarBlock, mut tail: Vec<CarBlock>, roots: Vec<Cid>) {
+        tail.push(head);
+        let car = CarV2::new(mk_encoded_car(roots.clone(), tail.clone())).unwrap();
+        assert_eq!(car.roots(), roots);
+        assert_ne!(car.header.characteristics & FULLY_INDEXED, 0);
+        assert_eq!(car.validate_index().unwrap(), tail.len() as u64);
+        for block in tail {
+            assert_eq!(car.get(&block.cid).unwrap(), Some(block.data));
+        }
+    }
+
+    #[quickcheck]
+    fn carv2_open_invalid(junk: Vec<u8>) {
+        assert!(CarV2::new(junk).is_err());
+    }
+
+    #[quickcheck]
+    fn carv2_header_roundtrip(header: CarV2Header) {
+        assert_eq!(CarV2Header::from_le_bytes(header.to_le_bytes()), header);
+    }
+
+    #[test]
+    fn carv2_fully_indexed_characteristic() {
+        let header = CarV2Header {
+            characteristics: FULLY_INDEXED,
+            ..Default::default()
+        };
+        let mut characteristics = [0; 16];
+        characteristics[0] = 0x80;
+        assert_eq!(header.to_le_bytes()[..16], characteristics);
+    }
+
+    /// `sample-unixfs-v2.car` from the `go-car` test data.
+    #[tokio::test]
+    async fn carv2_go_car_fixture() {
+        const FIXTURE: &[u8] =
+            include_bytes!("../../../test-snapshots/go-car-sample-unixfs-v2.car");
+        let car = CarV2::new(FIXTURE.to_vec()).unwrap();
+        assert_eq!(car.header.characteristics & FULLY_INDEXED, 0);
+        assert_eq!(car.roots().len(), 1);
+
+        let stream = CarStream::new(FIXTURE).await.unwrap();
+        assert_eq!(stream.header.roots, car.roots());
+        let blocks: Vec<CarBlock> = stream.try_collect().await.unwrap();
+        assert!(!blocks.is_empty());
+        assert_eq!(car.validate_index().unwrap(), blocks.len() as u64);
+        for block in blocks {
+            assert_eq!(car.get(&block.cid).unwrap(), Some(block.data));
+        }
+    }
+
+    #[tokio::test]
+    async fn carv2_calibnet_genesis() {
+        let stream = CarStream::new(calibnet::DEFAULT_GENESIS).await.unwrap();
+        let roots = stream.header.roots.clone();
+        let blocks: Vec<CarBlock> = stream.try_collect().await.unwrap();
+        let car = CarV2::new(mk_encoded_car(roots, blocks.clone())).unwrap();
+        assert!(car.has(&calibnet::GENESIS_CID).unwrap());
+        car.heaviest_tipset().unwrap();
+
+        // The data of the CARv2 archive is streamed like a CARv1
+        let encoded = mk_encoded_car(car.roots(), blocks.clone());
+        let streamed: Vec<CarBlock> = CarStream::new(encoded.as_slice())
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(streamed, blocks);
+    }
+
+    #[test]
+    fn carv2_corrupt_index() {
+        let blocks: Vec<CarBlock> = (0..10u8)
+            .map(|i| {
+                use cid::multihash::{Code, MultihashDigest as _};
+                let data = vec![i; 32];
+                CarBlock {
+                    cid: Cid::new_v1(fvm_ipld_encoding::IPLD_RAW, Code::Blake2b256.digest(&data)),
+                    data,
+                }
+            })
+            .collect();
+        let mut encoded = mk_encoded_car(vec![], blocks);
+        assert!(CarV2::new(encoded.clone())
+            .unwrap()
+            .validate_index()
+            .is_ok());
+
+        // Point the last index entry at the first block
+        let len = encoded.len();
+        encoded[len - OFFSET_SIZE..].copy_from_slice(&0u64.to_le_bytes());
+        assert!(CarV2::new(encoded).unwrap().validate_index().is_err());
+    }
+
+    #[test]
+    fn carv2_block_overruns_data() {
+        use cid::multihash::{Code, MultihashDigest as _};
+        let data = vec![0; 32];
+        let block = CarBlock {
+            cid: Cid::new_v1(fvm_ipld_encoding::IPLD_RAW, Code::Blake2b256.digest(&data)),
+            data,
+        };
+        let mut frame = vec![];
+        block.write(&mut frame).unwrap();
+        let mut encoded = mk_encoded_car(vec![], vec![block.clone()]);
+
+        // Cut the data right after the length of the last frame
+        let header_range = CARV2_PRAGMA.len()..CARV2_PRAGMA.len() + CarV2Header::SIZE;
+        let mut header =
+            CarV2Header::from_le_bytes(encoded[header_range.clone()].try_into().unwrap());
+        header.data_size -= frame.len() as u64 - 2;
+        encoded[header_range].copy_from_slice(&header.to_le_bytes());
+        assert!(CarV2::new(encoded).unwrap().get(&block.cid).is_err());
+    }
+}
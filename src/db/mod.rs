@@ -6,6 +6,7 @@ mod memory;
 pub mod parity_db;
 pub mod parity_db_config;
 pub mod rolling;
+pub mod tipset_state;
 pub use memory::MemoryDB;
 mod db_mode;
 pub mod migration;
@@ -21,6 +22,12 @@ pub mod setting_keys {
     pub const ESTIMATED_RECORDS_KEY: &str = "estimated_reachable_records";
     /// Key used to store the memory pool configuration in the settings store.
     pub const MPOOL_CONFIG_KEY: &str = "/mpool/config";
+    /// Key used to store the operator managed block list in the settings store. This is expected to be a [`crate::rpc_api::net_api::NetBlockList`]
+    pub const NET_BLOCK_LIST_KEY: &str = "/libp2p/block_list";
+    /// Key used to store the peers exempt from connection pruning and bans in the settings store. This is expected to be a `Vec<String>` of peer IDs
+    pub const NET_PROTECTED_PEERS_KEY: &str = "/libp2p/protected_peers";
+    /// Key used to store the progress of a snapshot streamed from a URL into the database. This is expected to be a `crate::daemon::db_util::ImportCheckpoint`
+    pub const SNAPSHOT_IMPORT_KEY: &str = "/snapshot/import";
 }
 
 /// Interface used to store and retrieve settings from the database.
@@ -277,8 +277,11 @@ where
             reachable_bytes.human_count_bytes(),
         );
 
-        // Use the latest head here
-        self.db.writer().next_current((self.get_tipset)().epoch())?;
+        // Use the latest head here, and drop the tipset states whose state roots weren't kept
+        self.db.writer().next_current(
+            (self.get_tipset)().epoch(),
+            tipset.epoch() - self.recent_state_roots,
+        )?;
 
         Ok(())
     }
@@ -12,7 +12,7 @@ use parking_lot::RwLock;
 use uuid::Uuid;
 
 use super::*;
-use crate::db::*;
+use crate::db::{tipset_state::tipset_state_epoch, *};
 
 impl Blockstore for RollingDB {
     fn has(&self, k: &Cid) -> anyhow::Result<bool> {
@@ -168,7 +168,13 @@ impl RollingDB {
 
     /// Sets `current` as `old`, and sets a new DB as `current`, finally delete
     /// the dangling `old` DB.
-    pub(super) fn next_current(&self, current_epoch: i64) -> anyhow::Result<()> {
+    /// Creates a new current database space, deleting the old one. The persisted tipset states
+    /// older than `tipset_states_from` aren't carried over.
+    pub(super) fn next_current(
+        &self,
+        current_epoch: i64,
+        tipset_states_from: i64,
+    ) -> anyhow::Result<()> {
         let new_db_name = Uuid::new_v4().simple().to_string();
         info!("Setting {new_db_name} as current db");
         let db = open_db(&self.db_root.join(&new_db_name), &self.db_config)?;
@@ -184,7 +190,7 @@ impl RollingDB {
 
         delete_db(&old_db_path);
 
-        self.transfer_settings()?;
+        self.transfer_settings(tipset_states_from)?;
 
         Ok(())
     }
@@ -222,9 +228,12 @@ impl RollingDB {
         [self.current.read().clone(), self.old.read().clone()]
     }
 
-    fn transfer_settings(&self) -> anyhow::Result<()> {
+    fn transfer_settings(&self, tipset_states_from: i64) -> anyhow::Result<()> {
         let current = self.current.read();
         for key in self.setting_keys()? {
+            if tipset_state_epoch(&key).is_some_and(|epoch| epoch < tipset_states_from) {
+                continue;
+            }
             if !current.exists(&key)? {
                 if let Some(v) = self.read_bin(&key)? {
                     current.write_bin(&key, &v)?;
@@ -304,7 +313,7 @@ mod tests {
             if i == split_index {
                 sleep(Duration::from_millis(1));
                 println!("Creating a new current db");
-                rolling_db.next_current(0).unwrap();
+                rolling_db.next_current(0, 0).unwrap();
                 println!("Created a new current db");
             }
             rolling_db.put_keyed(k, block).unwrap();
@@ -319,7 +328,7 @@ mod tests {
             );
         }
 
-        rolling_db.next_current(0).unwrap();
+        rolling_db.next_current(0, 0).unwrap();
 
         for (i, (k, _)) in pairs.iter().enumerate() {
             if i < split_index {
@@ -341,4 +350,41 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn rolling_db_drops_stale_tipset_states() {
+        use crate::blocks::{BlockHeader, Tipset};
+        use crate::db::tipset_state::{read_tipset_state, write_tipset_state};
+        use crate::shim::address::Address;
+
+        let db_root = TempDir::new().unwrap();
+        let rolling_db =
+            RollingDB::load_or_create(db_root.path().into(), Default::default()).unwrap();
+        let cid = Cid::new_v0(cid::multihash::Code::Sha2_256.digest(b"state")).unwrap();
+        let [stale, recent] = [1, 10].map(|epoch| {
+            Tipset::from(
+                BlockHeader::builder()
+                    .miner_address(Address::new_id(0))
+                    .epoch(epoch)
+                    .build()
+                    .unwrap(),
+            )
+        });
+        for tipset in [&stale, &recent] {
+            write_tipset_state(&rolling_db, tipset, (cid, cid)).unwrap();
+        }
+
+        rolling_db.next_current(10, 5).unwrap();
+        // The stale state is only left in the old space
+        assert_eq!(
+            read_tipset_state(&rolling_db, &stale).unwrap(),
+            Some((cid, cid))
+        );
+        rolling_db.next_current(10, 5).unwrap();
+        assert_eq!(read_tipset_state(&rolling_db, &stale).unwrap(), None);
+        assert_eq!(
+            read_tipset_state(&rolling_db, &recent).unwrap(),
+            Some((cid, cid))
+        );
+    }
 }
@@ -0,0 +1,97 @@
+// Copyright 2019-2023 ChainSafe Systems
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+//! Computed tipset states, persisted in the settings store so they aren't recomputed after a
+//! restart. Each record holds the state root and the receipt root resulting from the execution
+//! of a tipset, under a key made of the epoch and the CID of the tipset key.
+//!
+//! The records aren't IPLD blocks, so they are kept out of the blockstore, whose columns skip
+//! overwrites and whose blocks are served to other peers. The garbage collector drops the
+//! records older than the state roots it keeps, see [`tipset_state_epoch`].
+
+use cid::Cid;
+
+use crate::blocks::Tipset;
+use crate::db::SettingsStore;
+use crate::shim::clock::ChainEpoch;
+
+/// Prefix of the settings keys of the records.
+const TIPSET_STATE_KEY_PREFIX: &str = "/tipset_state/";
+
+/// The settings key of the record of the state of `tipset`.
+pub fn tipset_state_key(tipset: &Tipset) -> anyhow::Result<String> {
+    Ok(format!(
+        "{TIPSET_STATE_KEY_PREFIX}{}/{}",
+        tipset.epoch(),
+        tipset.key().cid()?
+    ))
+}
+
+/// The epoch of the tipset whose state is recorded under the settings key `key`, or `None` if
+/// `key` isn't the key of a record.
+pub fn tipset_state_epoch(key: &str) -> Option<ChainEpoch> {
+    let (epoch, _) = key.strip_prefix(TIPSET_STATE_KEY_PREFIX)?.split_once('/')?;
+    epoch.parse().ok()
+}
+
+/// Returns the persisted state root and receipt root of `tipset`.
+pub fn read_tipset_state(
+    settings: &(impl SettingsStore + ?Sized),
+    tipset: &Tipset,
+) -> anyhow::Result<Option<(Cid, Cid)>> {
+    settings
+        .read_bin(&tipset_state_key(tipset)?)?
+        .map(|bytes| fvm_ipld_encoding::from_slice(&bytes))
+        .transpose()
+        .map_err(Into::into)
+}
+
+pub fn write_tipset_state(
+    settings: &(impl SettingsStore + ?Sized),
+    tipset: &Tipset,
+    state: (Cid, Cid),
+) -> anyhow::Result<()> {
+    settings.write_bin(
+        &tipset_state_key(tipset)?,
+        &fvm_ipld_encoding::to_vec(&state)?,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::parity_db::ParityDb;
+    use crate::db::MemoryDB;
+    use crate::utils::db::CborStoreExt as _;
+
+    #[test]
+    fn tipset_state_roundtrip() {
+        let db = MemoryDB::default();
+        let tipset = Tipset::from(crate::blocks::BlockHeader::default());
+        assert_eq!(read_tipset_state(&db, &tipset).unwrap(), None);
+
+        let state = (
+            db.put_cbor_default(&1).unwrap(),
+            db.put_cbor_default(&2).unwrap(),
+        );
+        write_tipset_state(&db, &tipset, state).unwrap();
+        assert_eq!(read_tipset_state(&db, &tipset).unwrap(), Some(state));
+        let key = tipset_state_key(&tipset).unwrap();
+        assert_eq!(tipset_state_epoch(&key), Some(tipset.epoch()));
+        assert_eq!(tipset_state_epoch(crate::db::setting_keys::HEAD_KEY), None);
+    }
+
+    #[test]
+    fn corrupt_tipset_state_is_overwritten() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = ParityDb::open(dir.path(), &Default::default()).unwrap();
+        let tipset = Tipset::from(crate::blocks::BlockHeader::default());
+        db.write_bin(&tipset_state_key(&tipset).unwrap(), b"corrupt")
+            .unwrap();
+        assert!(read_tipset_state(&db, &tipset).is_err());
+
+        let state = (Cid::default(), Cid::default());
+        write_tipset_state(&db, &tipset, state).unwrap();
+        assert_eq!(read_tipset_state(&db, &tipset).unwrap(), Some(state));
+    }
+}
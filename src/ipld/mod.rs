@@ -16,4 +16,5 @@ mod tests {
     mod json_tests;
     mod selector_explore;
     mod selector_gen_tests;
+    mod selector_walk;
 }
@@ -7,16 +7,16 @@ use std::ops::SubAssign;
 
 use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
-#[cfg(test)]
 use Selector::*;
 
 pub use self::walk::*;
-#[cfg(test)]
 use super::Ipld;
 
 /// Selectors are expressions that identify and select a subset of data from an
 /// IPLD DAG. Selectors are themselves IPLD and can be serialized and
-/// de-serialized as such.
+/// de-serialized as such. Fields are declared in the canonical DAG-CBOR order
+/// of their keys, so that selectors are encoded as other implementations
+/// expect.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum Selector {
     /// `Matcher` marks a node to be included in the "result" set.
@@ -61,22 +61,22 @@ pub enum Selector {
     /// selector to the reached node.
     #[serde(rename = "i")]
     ExploreIndex {
-        #[serde(rename = "i")]
-        index: usize,
         #[serde(rename = ">")]
         next: Box<Selector>,
+        #[serde(rename = "i")]
+        index: usize,
     },
 
     /// `ExploreRange` traverses a list, and for each element in the range
     /// specified, will apply a next selector to those reached nodes.
     #[serde(rename = "r")]
     ExploreRange {
-        #[serde(rename = "^")]
-        start: usize,
         #[serde(rename = "$")]
         end: usize,
         #[serde(rename = ">")]
         next: Box<Selector>,
+        #[serde(rename = "^")]
+        start: usize,
     },
 
     /// `ExploreRecursive` traverses some structure recursively.
@@ -115,14 +115,14 @@ pub enum Selector {
     /// appropriate max depth as necessary so that recursion is not infinite
     #[serde(rename = "R")]
     ExploreRecursive {
-        #[serde(rename = ":>")]
-        sequence: Box<Selector>,
-        #[serde(rename = "l")]
-        limit: RecursionLimit,
         /// if a node matches, we won't match it nor explore its children.
-        #[serde(rename = "!")]
+        #[serde(rename = "!", default, skip_serializing_if = "Option::is_none")]
         stop_at: Option<Condition>,
-        #[serde(skip_deserializing)]
+        #[serde(rename = "l")]
+        limit: RecursionLimit,
+        #[serde(rename = ":>")]
+        sequence: Box<Selector>,
+        #[serde(skip)]
         /// Used to index current
         current: Option<Box<Selector>>,
     },
@@ -201,8 +201,57 @@ pub enum Condition {
     Or,
 }
 
-#[cfg(test)]
 impl Selector {
+    /// Selects the whole DAG below the root, following every link.
+    pub fn explore_all_recursively() -> Self {
+        ExploreRecursive {
+            sequence: Box::new(ExploreAll {
+                next: Box::new(ExploreRecursiveEdge),
+            }),
+            limit: RecursionLimit::None,
+            stop_at: None,
+            current: None,
+        }
+    }
+
+    /// Returns the path segments the selector can explore from the current
+    /// node, in the order they should be visited, or `None` if any segment
+    /// may be explored.
+    pub fn interests(&self) -> Option<Vec<String>> {
+        match self {
+            ExploreAll { .. } | ExploreRange { .. } => None,
+            ExploreFields { fields } => Some(fields.keys().cloned().collect()),
+            ExploreIndex { index, .. } => Some(vec![index.to_string()]),
+            ExploreRecursive {
+                current, sequence, ..
+            } => current.as_ref().unwrap_or(sequence).interests(),
+            ExploreUnion(selectors) => {
+                let mut segments = vec![];
+                for selector in selectors {
+                    for segment in selector.interests()? {
+                        if !segments.contains(&segment) {
+                            segments.push(segment);
+                        }
+                    }
+                }
+                Some(segments)
+            }
+            ExploreRecursiveEdge | Matcher => Some(vec![]),
+        }
+    }
+
+    /// Returns whether the current node is in the "result" set.
+    pub fn decide(&self) -> bool {
+        match self {
+            Matcher => true,
+            ExploreUnion(selectors) => selectors.iter().any(Selector::decide),
+            ExploreRecursive {
+                current, sequence, ..
+            } => current.as_ref().unwrap_or(sequence).decide(),
+            _ => false,
+        }
+    }
+
     /// Processes and returns resultant selector node
     pub fn explore(self, ipld: &Ipld, p: &str) -> Option<Selector> {
         match self {
@@ -308,7 +357,6 @@ impl Selector {
     }
 }
 
-#[cfg(test)]
 fn replace_recursive_edge(next_sel: Selector, replace: Option<Selector>) -> Option<Selector> {
     match next_sel {
         ExploreRecursiveEdge => replace,
@@ -324,7 +372,6 @@ fn replace_recursive_edge(next_sel: Selector, replace: Option<Selector>) -> Opti
         _ => Some(next_sel),
     }
 }
-#[cfg(test)]
 fn has_recursive_edge(next_sel: &Selector) -> bool {
     match next_sel {
         ExploreRecursiveEdge { .. } => true,
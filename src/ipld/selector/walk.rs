@@ -4,7 +4,10 @@
 use async_trait::async_trait;
 use cid::Cid;
 
-use super::super::{Ipld, Path};
+use super::{
+    super::{Ipld, Path},
+    Selector,
+};
 
 #[async_trait]
 pub trait LinkResolver {
@@ -26,3 +29,166 @@ pub struct LastBlockInfo {
     pub path: Path,
     pub link: Cid,
 }
+
+/// Reason a node is visited by a selector walk.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum VisitReason {
+    /// The node is in the "result" set of the selector.
+    SelectionMatch,
+    /// The node is only traversed on the way to other nodes.
+    SelectionCandidate,
+}
+
+/// Errors of a selector walk.
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum Error {
+    /// The link resolver failed to load a link.
+    #[error("failed to load link: {0}")]
+    Link(String),
+    /// The visit callback returned an error.
+    #[error("{0}")]
+    Custom(String),
+}
+
+/// State of a selector walk, passed to the visit callback.
+pub struct Progress<L = ()> {
+    resolver: Option<L>,
+    path: Vec<String>,
+    last_block: Option<LastBlockInfo>,
+}
+
+impl<L> Progress<L> {
+    fn new(resolver: Option<L>) -> Self {
+        Self {
+            resolver,
+            path: vec![],
+            last_block: None,
+        }
+    }
+
+    /// Path of the visited node from the root of the walk.
+    pub fn path(&self) -> Path {
+        Path::from(self.path.clone())
+    }
+
+    /// The last link resolved on the way to the visited node, if any.
+    pub fn last_block(&self) -> Option<&LastBlockInfo> {
+        self.last_block.as_ref()
+    }
+}
+
+impl Selector {
+    /// Walks the selector over `ipld` and calls `callback` on every node
+    /// visited, matched or not. Links are resolved transparently with
+    /// `resolver`, or left untraversed if there is none.
+    pub async fn walk_all<L, F>(
+        self,
+        ipld: &Ipld,
+        resolver: Option<L>,
+        callback: F,
+    ) -> Result<(), Error>
+    where
+        L: LinkResolver + Send + Sync,
+        F: Fn(&Progress<L>, &Ipld, VisitReason) -> Result<(), String> + Send + Sync,
+    {
+        Progress::new(resolver)
+            .walk_all(ipld, self, &callback)
+            .await
+    }
+}
+
+impl<L> Progress<L>
+where
+    L: LinkResolver + Send + Sync,
+{
+    #[async_recursion::async_recursion]
+    async fn walk_all<F>(
+        &mut self,
+        ipld: &Ipld,
+        selector: Selector,
+        callback: &F,
+    ) -> Result<(), Error>
+    where
+        F: Fn(&Progress<L>, &Ipld, VisitReason) -> Result<(), String> + Send + Sync,
+    {
+        // Links are resolved transparently, the link itself is never visited.
+        if let Ipld::Link(cid) = ipld {
+            if let Some(resolver) = &mut self.resolver {
+                self.last_block = Some(LastBlockInfo {
+                    path: Path::from(self.path.clone()),
+                    link: *cid,
+                });
+                let mut node = resolver.load_link(cid).await.map_err(Error::Link)?;
+                // A block may itself be a link to another block
+                while let Some(Ipld::Link(cid)) = node {
+                    node = resolver.load_link(&cid).await.map_err(Error::Link)?;
+                }
+                // Blocks missing from the resolver are not traversed
+                return match node {
+                    Some(node) => self.walk_all(&node, selector, callback).await,
+                    None => Ok(()),
+                };
+            }
+        }
+
+        let reason = if selector.decide() {
+            VisitReason::SelectionMatch
+        } else {
+            VisitReason::SelectionCandidate
+        };
+        callback(self, ipld, reason).map_err(Error::Custom)?;
+
+        match (selector.interests(), ipld) {
+            (Some(segments), Ipld::Map(_) | Ipld::List(_)) => {
+                for segment in segments {
+                    if let Some(child) = lookup_segment(ipld, &segment) {
+                        self.visit_child(ipld, child, segment, &selector, callback)
+                            .await?;
+                    }
+                }
+            }
+            (None, Ipld::Map(map)) => {
+                for (key, child) in map {
+                    self.visit_child(ipld, child, key.clone(), &selector, callback)
+                        .await?;
+                }
+            }
+            (None, Ipld::List(list)) => {
+                for (index, child) in list.iter().enumerate() {
+                    self.visit_child(ipld, child, index.to_string(), &selector, callback)
+                        .await?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    async fn visit_child<F>(
+        &mut self,
+        parent: &Ipld,
+        child: &Ipld,
+        segment: String,
+        selector: &Selector,
+        callback: &F,
+    ) -> Result<(), Error>
+    where
+        F: Fn(&Progress<L>, &Ipld, VisitReason) -> Result<(), String> + Send + Sync,
+    {
+        if let Some(next) = selector.clone().explore(parent, &segment) {
+            self.path.push(segment);
+            self.walk_all(child, next, callback).await?;
+            self.path.pop();
+        }
+        Ok(())
+    }
+}
+
+/// Returns the child of a map or list at the given path segment.
+fn lookup_segment<'a>(ipld: &'a Ipld, segment: &str) -> Option<&'a Ipld> {
+    match ipld {
+        Ipld::Map(map) => map.get(segment),
+        Ipld::List(list) => list.get(segment.parse::<usize>().ok()?),
+        _ => None,
+    }
+}
@@ -0,0 +1,167 @@
+// Copyright 2019-2023 ChainSafe Systems
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+use crate::ipld::{
+    json,
+    selector::{LastBlockInfo, LinkResolver, Selector, VisitReason},
+    Ipld, Path,
+};
+use crate::utils::cid::CidCborExt;
+use ahash::HashMap;
+use async_trait::async_trait;
+use cid::Cid;
+use parking_lot::Mutex;
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum NodeKind {
+    Map(()),
+    List(()),
+    Null(()),
+    Bool(bool),
+    Integer(i128),
+    String(String),
+}
+
+impl From<&Ipld> for NodeKind {
+    fn from(ipld: &Ipld) -> Self {
+        match ipld {
+            Ipld::Map(_) => NodeKind::Map(()),
+            Ipld::List(_) => NodeKind::List(()),
+            Ipld::Null => NodeKind::Null(()),
+            Ipld::Bool(b) => NodeKind::Bool(*b),
+            Ipld::Integer(i) => NodeKind::Integer(*i),
+            Ipld::String(s) => NodeKind::String(s.clone()),
+            other => panic!("unexpected node in test vectors: {other:?}"),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ExpectLastBlock {
+    path: String,
+    #[serde(with = "json")]
+    link: Ipld,
+}
+
+#[derive(Deserialize)]
+struct ExpectVisit {
+    path: String,
+    node: NodeKind,
+    // Only checked by the vectors traversing links
+    #[serde(default, deserialize_with = "present")]
+    last_block: Option<Option<ExpectLastBlock>>,
+    matched: bool,
+}
+
+/// Distinguishes a `null` field from a missing one.
+fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+#[derive(Deserialize)]
+struct TestVector {
+    description: Option<String>,
+    #[serde(with = "json")]
+    ipld: Ipld,
+    selector: Selector,
+    expect_visit: Vec<ExpectVisit>,
+    #[serde(default)]
+    cbor_ipld_storage: Vec<serde_json::Value>,
+}
+
+#[derive(Clone, Default)]
+struct TestResolver {
+    blocks: HashMap<Cid, Ipld>,
+}
+
+#[async_trait]
+impl LinkResolver for TestResolver {
+    async fn load_link(&mut self, link: &Cid) -> Result<Option<Ipld>, String> {
+        Ok(self.blocks.get(link).cloned())
+    }
+}
+
+struct Visit {
+    path: Path,
+    node: Ipld,
+    last_block: Option<LastBlockInfo>,
+    matched: bool,
+}
+
+async fn process_vector(tv: TestVector) {
+    let description = tv
+        .description
+        .unwrap_or_else(|| "Unnamed test case".to_owned());
+    let mut resolver = TestResolver::default();
+    for value in tv.cbor_ipld_storage {
+        let ipld: Ipld = json::deserialize(value).unwrap();
+        let cid = Cid::from_cbor_blake2b256(&ipld).unwrap();
+        resolver.blocks.insert(cid, ipld);
+    }
+
+    let visits = Mutex::new(vec![]);
+    tv.selector
+        .walk_all(&tv.ipld, Some(resolver), |progress, node, reason| {
+            visits.lock().push(Visit {
+                path: progress.path(),
+                node: node.clone(),
+                last_block: progress.last_block().cloned(),
+                matched: reason == VisitReason::SelectionMatch,
+            });
+            Ok(())
+        })
+        .await
+        .unwrap();
+
+    let visits = visits.lock();
+    assert_eq!(
+        visits.len(),
+        tv.expect_visit.len(),
+        "({description}) unexpected number of visits"
+    );
+    for (visit, expected) in visits.iter().zip(tv.expect_visit) {
+        assert_eq!(visit.path, Path::from(expected.path), "({description})");
+        assert_eq!(
+            NodeKind::from(&visit.node),
+            expected.node,
+            "({description})"
+        );
+        assert_eq!(visit.matched, expected.matched, "({description})");
+        if let Some(last_block) = expected.last_block {
+            let last_block = last_block.map(|info| LastBlockInfo {
+                path: Path::from(info.path),
+                link: match info.link {
+                    Ipld::Link(cid) => cid,
+                    other => panic!("expected link, found {other:?}"),
+                },
+            });
+            assert_eq!(visit.last_block, last_block, "({description})");
+        }
+    }
+}
+
+#[tokio::test]
+async fn selector_walk_tests() {
+    let s = include_str!("ipld-traversal-vectors/selector_walk.json");
+    let vectors: Vec<TestVector> =
+        serde_json::from_str(s).expect("Test vector deserialization failed");
+    for tv in vectors {
+        process_vector(tv).await;
+    }
+}
+
+#[tokio::test]
+async fn selector_walk_links_tests() {
+    let s = include_str!("ipld-traversal-vectors/selector_walk_links.json");
+    let vectors: Vec<TestVector> =
+        serde_json::from_str(s).expect("Test vector deserialization failed");
+    for tv in vectors {
+        process_vector(tv).await;
+    }
+}
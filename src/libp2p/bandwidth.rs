@@ -0,0 +1,135 @@
+// Copyright 2019-2023 ChainSafe Systems
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+//! Bytes sent and received per protocol. Request-response protocols are
+//! counted by their codecs, `bitswap` by its own message size metrics, and
+//! `gossipsub` by the payloads of the messages published and received by this
+//! node (control messages and forwarded messages are not accounted for).
+
+use std::time::Instant;
+
+use ahash::HashMap;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+/// Protocol `gossipsub` traffic is reported under.
+pub const GOSSIPSUB_PROTOCOL_NAME: &str = "/meshsub/1.1.0";
+/// Protocol `bitswap` traffic is reported under.
+pub const BITSWAP_PROTOCOL_NAME: &str = "/chain/ipfs/bitswap";
+
+static METERS: Lazy<Mutex<Meters>> = Lazy::new(Default::default);
+
+/// Totals and rates (in bytes per second, as of the last [`tick`]) of the
+/// traffic over a protocol.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct BandwidthStats {
+    pub total_in: u64,
+    pub total_out: u64,
+    pub rate_in: f64,
+    pub rate_out: f64,
+}
+
+#[derive(Default)]
+struct Meters {
+    by_protocol: HashMap<String, Meter>,
+    last_tick: Option<Instant>,
+}
+
+#[derive(Default)]
+struct Meter {
+    stats: BandwidthStats,
+    last_in: u64,
+    last_out: u64,
+}
+
+impl Meters {
+    fn meter(&mut self, protocol: &str) -> &mut Meter {
+        if !self.by_protocol.contains_key(protocol) {
+            self.by_protocol
+                .insert(protocol.to_owned(), Default::default());
+        }
+        self.by_protocol.get_mut(protocol).expect("Inserted above")
+    }
+
+    fn sync_bitswap(&mut self) {
+        let (inbound, outbound) = crate::libp2p_bitswap::message_bytes();
+        let stats = &mut self.meter(BITSWAP_PROTOCOL_NAME).stats;
+        stats.total_in = inbound;
+        stats.total_out = outbound;
+    }
+}
+
+/// Records bytes received over a protocol.
+pub fn record_inbound(protocol: &str, bytes: usize) {
+    METERS.lock().meter(protocol).stats.total_in += bytes as u64;
+}
+
+/// Records bytes sent over a protocol.
+pub fn record_outbound(protocol: &str, bytes: usize) {
+    METERS.lock().meter(protocol).stats.total_out += bytes as u64;
+}
+
+/// Updates the rates of all protocols from the traffic since the previous
+/// call.
+pub fn tick() {
+    let mut meters = METERS.lock();
+    meters.sync_bitswap();
+    let now = Instant::now();
+    if let Some(elapsed) = meters.last_tick.map(|last| (now - last).as_secs_f64()) {
+        if elapsed > 0.0 {
+            for meter in meters.by_protocol.values_mut() {
+                meter.stats.rate_in = (meter.stats.total_in - meter.last_in) as f64 / elapsed;
+                meter.stats.rate_out = (meter.stats.total_out - meter.last_out) as f64 / elapsed;
+            }
+        }
+    }
+    for meter in meters.by_protocol.values_mut() {
+        meter.last_in = meter.stats.total_in;
+        meter.last_out = meter.stats.total_out;
+    }
+    meters.last_tick = Some(now);
+}
+
+/// Returns the traffic of each protocol.
+pub fn stats_by_protocol() -> HashMap<String, BandwidthStats> {
+    let mut meters = METERS.lock();
+    meters.sync_bitswap();
+    meters
+        .by_protocol
+        .iter()
+        .map(|(protocol, meter)| (protocol.clone(), meter.stats))
+        .collect()
+}
+
+/// Returns the traffic summed over all protocols.
+pub fn total_stats() -> BandwidthStats {
+    stats_by_protocol()
+        .into_values()
+        .fold(BandwidthStats::default(), |acc, stats| BandwidthStats {
+            total_in: acc.total_in + stats.total_in,
+            total_out: acc.total_out + stats.total_out,
+            rate_in: acc.rate_in + stats.rate_in,
+            rate_out: acc.rate_out + stats.rate_out,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bandwidth_is_recorded_per_protocol() {
+        const PROTOCOL: &str = "/forest/bandwidth-test";
+        record_inbound(PROTOCOL, 3);
+        record_outbound(PROTOCOL, 5);
+        record_inbound(PROTOCOL, 7);
+        let stats = stats_by_protocol()[PROTOCOL];
+        assert_eq!(stats.total_in, 10);
+        assert_eq!(stats.total_out, 5);
+        assert!(stats_by_protocol().contains_key(BITSWAP_PROTOCOL_NAME));
+
+        let total = total_stats();
+        assert!(total.total_in >= 10);
+        assert!(total.total_out >= 5);
+    }
+}
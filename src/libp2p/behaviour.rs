@@ -4,8 +4,9 @@
 use crate::libp2p_bitswap::BitswapBehaviour;
 use crate::utils::{encoding::blake2b_256, version::FOREST_VERSION_STRING};
 use ahash::{HashMap, HashSet};
+use cid::Cid;
 use libp2p::{
-    allow_block_list, connection_limits,
+    allow_block_list, autonat, connection_limits, dcutr,
     gossipsub::{
         self, IdentTopic as Topic, MessageAuthenticity, MessageId, PublishError, SubscriptionError,
         ValidationMode,
@@ -14,18 +15,22 @@ use libp2p::{
     identity::{Keypair, PeerId},
     kad::QueryId,
     metrics::{Metrics, Recorder},
-    ping,
+    ping, relay,
     swarm::NetworkBehaviour,
     Multiaddr,
 };
 use tracing::warn;
 
 use crate::libp2p::{
+    bandwidth,
+    block_list::BlockListBehaviour,
     chain_exchange::ChainExchangeBehaviour,
     config::Libp2pConfig,
     discovery::{DiscoveryBehaviour, DiscoveryConfig},
     gossip_params::{build_peer_score_params, build_peer_score_threshold},
+    graphsync::GraphsyncBehaviour,
     hello::HelloBehaviour,
+    protection::{ProtectedPeers, ProtectionBehaviour},
 };
 
 /// Libp2p behavior for the Forest node. This handles all sub protocols needed
@@ -36,10 +41,15 @@ pub(in crate::libp2p) struct ForestBehaviour {
     discovery: DiscoveryBehaviour,
     ping: ping::Behaviour,
     identify: identify::Behaviour,
-    connection_limits: connection_limits::Behaviour,
+    connection_limits: ProtectionBehaviour,
+    pub(super) autonat: autonat::Behaviour,
+    relay_client: relay::client::Behaviour,
+    dcutr: dcutr::Behaviour,
     pub(super) blocked_peers: allow_block_list::Behaviour<allow_block_list::BlockedPeers>,
+    pub(super) block_list: BlockListBehaviour,
     pub(super) hello: HelloBehaviour,
     pub(super) chain_exchange: ChainExchangeBehaviour,
+    pub(super) graphsync: GraphsyncBehaviour,
     pub(super) bitswap: BitswapBehaviour,
 }
 
@@ -49,17 +59,26 @@ impl Recorder<ForestBehaviourEvent> for Metrics {
             ForestBehaviourEvent::Gossipsub(e) => self.record(e),
             ForestBehaviourEvent::Ping(ping_event) => self.record(ping_event),
             ForestBehaviourEvent::Identify(id_event) => self.record(id_event),
+            ForestBehaviourEvent::Dcutr(dcutr_event) => self.record(dcutr_event),
             _ => {}
         }
     }
 }
 
 impl ForestBehaviour {
+    /// Creates the behaviour. `relay_client` must be the behaviour half of the
+    /// relay client whose transport half was passed to the swarm transport,
+    /// see [`crate::libp2p::build_transport`]. The connection limits do not
+    /// apply to the `protected` peers.
     pub fn new(
         local_key: &Keypair,
         config: &Libp2pConfig,
         network_name: &str,
+        relay_client: relay::client::Behaviour,
+        protected: ProtectedPeers,
     ) -> anyhow::Result<Self> {
+        let local_peer_id = local_key.public().to_peer_id();
+
         let mut gs_config_builder = gossipsub::ConfigBuilder::default();
         gs_config_builder.max_transmit_size(1 << 20);
         gs_config_builder.validation_mode(ValidationMode::Strict);
@@ -102,13 +121,14 @@ impl ForestBehaviour {
             .with_user_defined(config.bootstrap_peers.clone())
             .target_peer_count(config.target_peer_count as u64);
 
-        let connection_limits = connection_limits::Behaviour::new(
+        let connection_limits = ProtectionBehaviour::new(
             connection_limits::ConnectionLimits::default()
                 .with_max_pending_incoming(Some(4096))
                 .with_max_pending_outgoing(Some(8192))
                 .with_max_established_incoming(Some(8192))
                 .with_max_established_outgoing(Some(8192))
                 .with_max_established_per_peer(Some(5)),
+            protected,
         );
 
         warn!("libp2p Forest version: {}", FOREST_VERSION_STRING.as_str());
@@ -121,10 +141,15 @@ impl ForestBehaviour {
                     .with_agent_version(format!("forest-{}", FOREST_VERSION_STRING.as_str())),
             ),
             connection_limits,
+            autonat: autonat::Behaviour::new(local_peer_id, Default::default()),
+            relay_client,
+            dcutr: dcutr::Behaviour::new(local_peer_id),
             blocked_peers: Default::default(),
+            block_list: Default::default(),
             bitswap,
             hello: HelloBehaviour::default(),
             chain_exchange: ChainExchangeBehaviour::default(),
+            graphsync: GraphsyncBehaviour::default(),
         })
     }
 
@@ -133,12 +158,24 @@ impl ForestBehaviour {
         self.discovery.bootstrap()
     }
 
+    /// Announces the node as a provider of a block on the DHT.
+    pub fn start_providing(&mut self, cid: Cid) -> Result<QueryId, String> {
+        self.discovery.start_providing(cid)
+    }
+
+    /// Looks up the providers of a block on the DHT.
+    pub fn get_providers(&mut self, cid: Cid, response_channel: flume::Sender<HashSet<PeerId>>) {
+        self.discovery.get_providers(cid, response_channel)
+    }
+
     /// Publish data over the gossip network.
     pub fn publish(
         &mut self,
         topic: Topic,
         data: impl Into<Vec<u8>>,
     ) -> Result<MessageId, PublishError> {
+        let data = data.into();
+        bandwidth::record_outbound(bandwidth::GOSSIPSUB_PROTOCOL_NAME, data.len());
         self.gossipsub.publish(topic, data)
     }
 
@@ -0,0 +1,377 @@
+// Copyright 2019-2023 ChainSafe Systems
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    fmt,
+    hash::Hash,
+    net::IpAddr,
+    str::FromStr,
+    task::{Context, Poll, Waker},
+};
+
+use crate::rpc_api::net_api::NetBlockList;
+use ahash::{HashMap, HashSet};
+use anyhow::Context as _;
+use ipnet::IpNet;
+use itertools::Itertools;
+use libp2p::{
+    core::Endpoint,
+    multiaddr::Protocol,
+    swarm::{
+        dummy, CloseConnection, ConnectionDenied, ConnectionId, FromSwarm, NetworkBehaviour,
+        PollParameters, THandler, THandlerInEvent, THandlerOutEvent, ToSwarm,
+    },
+    Multiaddr, PeerId,
+};
+use tracing::warn;
+
+/// Peers, IP addresses and IP subnets the node refuses to be connected with.
+/// Unlike the bans issued by [`crate::libp2p::PeerManager`], these are managed
+/// by the node operator and never expire.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BlockList {
+    pub peers: HashSet<PeerId>,
+    pub ip_addrs: HashSet<IpAddr>,
+    pub ip_subnets: HashSet<IpNet>,
+}
+
+impl BlockList {
+    /// Adds all entries of `other` to this list.
+    pub fn extend(&mut self, other: BlockList) {
+        self.peers.extend(other.peers);
+        self.ip_addrs.extend(other.ip_addrs);
+        self.ip_subnets.extend(other.ip_subnets);
+    }
+
+    /// Removes all entries of `other` from this list.
+    pub fn remove(&mut self, other: &BlockList) {
+        self.peers.retain(|peer| !other.peers.contains(peer));
+        self.ip_addrs.retain(|ip| !other.ip_addrs.contains(ip));
+        self.ip_subnets
+            .retain(|net| !other.ip_subnets.contains(net));
+    }
+
+    /// Converts a persisted list, skipping its malformed entries with a
+    /// warning rather than failing.
+    pub fn from_persisted(list: NetBlockList) -> Self {
+        fn parse<T: FromStr + Eq + Hash>(entries: &[String], kind: &str) -> HashSet<T> {
+            entries
+                .iter()
+                .filter_map(|entry| match entry.parse() {
+                    Ok(parsed) => Some(parsed),
+                    Err(_) => {
+                        warn!("Skipping invalid {kind} {entry} of the block list");
+                        None
+                    }
+                })
+                .collect()
+        }
+        Self {
+            peers: parse(&list.peers, "peer ID"),
+            ip_addrs: parse(&list.ip_addrs, "IP address"),
+            ip_subnets: parse(&list.ip_subnets, "IP subnet"),
+        }
+    }
+
+    fn is_peer_blocked(&self, peer: &PeerId) -> bool {
+        self.peers.contains(peer)
+    }
+
+    fn is_addr_blocked(&self, addr: &Multiaddr) -> bool {
+        match ip_of(addr) {
+            Some(ip) => {
+                self.ip_addrs.contains(&ip) || self.ip_subnets.iter().any(|net| net.contains(&ip))
+            }
+            None => false,
+        }
+    }
+}
+
+impl TryFrom<NetBlockList> for BlockList {
+    type Error = anyhow::Error;
+
+    fn try_from(list: NetBlockList) -> anyhow::Result<Self> {
+        Ok(Self {
+            peers: list
+                .peers
+                .iter()
+                .map(|peer| {
+                    peer.parse()
+                        .with_context(|| format!("invalid peer ID {peer}"))
+                })
+                .try_collect()?,
+            ip_addrs: list
+                .ip_addrs
+                .iter()
+                .map(|ip| {
+                    ip.parse()
+                        .with_context(|| format!("invalid IP address {ip}"))
+                })
+                .try_collect()?,
+            ip_subnets: list
+                .ip_subnets
+                .iter()
+                .map(|net| {
+                    net.parse()
+                        .with_context(|| format!("invalid IP subnet {net}"))
+                })
+                .try_collect()?,
+        })
+    }
+}
+
+impl From<&BlockList> for NetBlockList {
+    fn from(list: &BlockList) -> Self {
+        Self {
+            peers: list.peers.iter().map(PeerId::to_string).sorted().collect(),
+            ip_addrs: list
+                .ip_addrs
+                .iter()
+                .sorted()
+                .map(IpAddr::to_string)
+                .collect(),
+            ip_subnets: list
+                .ip_subnets
+                .iter()
+                .sorted()
+                .map(IpNet::to_string)
+                .collect(),
+        }
+    }
+}
+
+/// First IP address of a multi-address, if any.
+fn ip_of(addr: &Multiaddr) -> Option<IpAddr> {
+    addr.iter().find_map(|protocol| match protocol {
+        Protocol::Ip4(ip) => Some(ip.into()),
+        Protocol::Ip6(ip) => Some(ip.into()),
+        _ => None,
+    })
+}
+
+/// A connection was [`denied`](ConnectionDenied) because the peer or its
+/// address is in the [`BlockList`].
+#[derive(Debug)]
+pub struct Blocked(String);
+
+impl fmt::Display for Blocked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is in the block list", self.0)
+    }
+}
+
+impl std::error::Error for Blocked {}
+
+/// [`NetworkBehaviour`] enforcing a [`BlockList`]. Connections already
+/// established with newly blocked peers or addresses are closed.
+#[derive(Default)]
+pub struct BlockListBehaviour {
+    list: BlockList,
+    connections: HashMap<ConnectionId, (PeerId, Multiaddr)>,
+    close_connections: VecDeque<(PeerId, ConnectionId)>,
+    waker: Option<Waker>,
+}
+
+impl BlockListBehaviour {
+    /// Returns the enforced block list.
+    pub fn block_list(&self) -> &BlockList {
+        &self.list
+    }
+
+    /// Blocks the given entries and closes any connection they match.
+    pub fn block(&mut self, list: BlockList) {
+        self.list.extend(list);
+        for (connection, (peer, addr)) in &self.connections {
+            if self.list.is_peer_blocked(peer) || self.list.is_addr_blocked(addr) {
+                self.close_connections.push_back((*peer, *connection));
+            }
+        }
+        if let Some(waker) = self.waker.take() {
+            waker.wake()
+        }
+    }
+
+    /// Unblocks the given entries.
+    pub fn unblock(&mut self, list: &BlockList) {
+        self.list.remove(list);
+    }
+
+    fn enforce(
+        &self,
+        peer: Option<&PeerId>,
+        addr: Option<&Multiaddr>,
+    ) -> Result<(), ConnectionDenied> {
+        if let Some(peer) = peer.filter(|peer| self.list.is_peer_blocked(peer)) {
+            return Err(ConnectionDenied::new(Blocked(format!("peer {peer}"))));
+        }
+        if let Some(addr) = addr.filter(|addr| self.list.is_addr_blocked(addr)) {
+            return Err(ConnectionDenied::new(Blocked(format!("address {addr}"))));
+        }
+        Ok(())
+    }
+}
+
+impl NetworkBehaviour for BlockListBehaviour {
+    type ConnectionHandler = dummy::ConnectionHandler;
+    type ToSwarm = Infallible;
+
+    fn handle_pending_inbound_connection(
+        &mut self,
+        _: ConnectionId,
+        _: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<(), ConnectionDenied> {
+        self.enforce(None, Some(remote_addr))
+    }
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        _: ConnectionId,
+        peer: PeerId,
+        _: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.enforce(Some(&peer), Some(remote_addr))?;
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn handle_pending_outbound_connection(
+        &mut self,
+        _: ConnectionId,
+        peer: Option<PeerId>,
+        _: &[Multiaddr],
+        _: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        self.enforce(peer.as_ref(), None)?;
+        Ok(vec![])
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        _: ConnectionId,
+        peer: PeerId,
+        addr: &Multiaddr,
+        _: Endpoint,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.enforce(Some(&peer), Some(addr))?;
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm<Self::ConnectionHandler>) {
+        match event {
+            FromSwarm::ConnectionEstablished(e) => {
+                self.connections.insert(
+                    e.connection_id,
+                    (e.peer_id, e.endpoint.get_remote_address().clone()),
+                );
+            }
+            FromSwarm::ConnectionClosed(e) => {
+                self.connections.remove(&e.connection_id);
+            }
+            _ => {}
+        }
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        _: PeerId,
+        _: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        match event {}
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        _: &mut impl PollParameters,
+    ) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        if let Some((peer_id, connection)) = self.close_connections.pop_front() {
+            return Poll::Ready(ToSwarm::CloseConnection {
+                peer_id,
+                connection: CloseConnection::One(connection),
+            });
+        }
+
+        self.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_list_roundtrip() {
+        let list = NetBlockList {
+            peers: vec![PeerId::random().to_string()],
+            ip_addrs: vec!["1.2.3.4".into(), "::1".into()],
+            ip_subnets: vec!["10.0.0.0/8".into()],
+        };
+        let block_list = BlockList::try_from(list.clone()).unwrap();
+        assert_eq!(NetBlockList::from(&block_list), list);
+    }
+
+    #[test]
+    fn block_list_rejects_invalid_entries() {
+        for list in [
+            NetBlockList {
+                peers: vec!["not a peer".into()],
+                ..Default::default()
+            },
+            NetBlockList {
+                ip_addrs: vec!["1.2.3".into()],
+                ..Default::default()
+            },
+            NetBlockList {
+                ip_subnets: vec!["10.0.0.0".into()],
+                ..Default::default()
+            },
+        ] {
+            assert!(BlockList::try_from(list).is_err());
+        }
+    }
+
+    #[test]
+    fn persisted_block_list_skips_invalid_entries() {
+        let peer = PeerId::random();
+        let block_list = BlockList::from_persisted(NetBlockList {
+            peers: vec![peer.to_string(), "not a peer".into()],
+            ip_addrs: vec!["1.2.3".into(), "1.2.3.4".into()],
+            ip_subnets: vec!["10.0.0.0".into()],
+        });
+        assert_eq!(block_list.peers, HashSet::from_iter([peer]));
+        assert_eq!(
+            block_list.ip_addrs,
+            HashSet::from_iter(["1.2.3.4".parse().unwrap()])
+        );
+        assert!(block_list.ip_subnets.is_empty());
+    }
+
+    #[test]
+    fn block_list_matches_addresses() {
+        let mut block_list = BlockList::try_from(NetBlockList {
+            ip_addrs: vec!["1.2.3.4".into()],
+            ip_subnets: vec!["10.0.0.0/8".into()],
+            ..Default::default()
+        })
+        .unwrap();
+        let addr = |s: &str| s.parse::<Multiaddr>().unwrap();
+        assert!(block_list.is_addr_blocked(&addr("/ip4/1.2.3.4/tcp/1347")));
+        assert!(block_list.is_addr_blocked(&addr("/ip4/10.1.2.3/udp/1347/quic-v1")));
+        assert!(!block_list.is_addr_blocked(&addr("/ip4/1.2.3.5/tcp/1347")));
+        assert!(!block_list.is_addr_blocked(&addr("/dns4/example.com/tcp/1347")));
+
+        block_list.remove(&BlockList {
+            ip_addrs: [ip_of(&addr("/ip4/1.2.3.4")).unwrap()]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        });
+        assert!(!block_list.is_addr_blocked(&addr("/ip4/1.2.3.4/tcp/1347")));
+        assert!(block_list.is_addr_blocked(&addr("/ip4/10.1.2.3/tcp/1347")));
+    }
+}
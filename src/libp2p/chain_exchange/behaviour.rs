@@ -1,6 +1,8 @@
 // Copyright 2019-2023 ChainSafe Systems
 // SPDX-License-Identifier: Apache-2.0, MIT
 
+use std::sync::Arc;
+
 use ahash::HashMap;
 use libp2p::{
     request_response::{self, OutboundFailure, ProtocolSupport, RequestId, ResponseChannel},
@@ -18,9 +20,15 @@ pub struct ChainExchangeBehaviour {
     inner: InnerBehaviour,
     response_channels:
         HashMap<RequestId, flume::Sender<Result<ChainExchangeResponse, RequestResponseError>>>,
+    server: Arc<ChainExchangeServer>,
 }
 
 impl ChainExchangeBehaviour {
+    /// Returns the server handling inbound requests.
+    pub fn server(&self) -> Arc<ChainExchangeServer> {
+        self.server.clone()
+    }
+
     pub fn send_request(
         &mut self,
         peer: &PeerId,
@@ -85,6 +93,7 @@ impl Default for ChainExchangeBehaviour {
                 Default::default(),
             ),
             response_channels: Default::default(),
+            server: Default::default(),
         }
     }
 }
@@ -17,7 +17,7 @@ pub const MESSAGES: u64 = 0b10;
 
 /// The payload that gets sent to another node to request for blocks and
 /// messages.
-#[derive(Clone, Debug, PartialEq, Eq, Serialize_tuple, Deserialize_tuple)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize_tuple, Deserialize_tuple)]
 pub struct ChainExchangeRequest {
     /// The tipset [Cid] to start the request from.
     pub start: Vec<Cid>,
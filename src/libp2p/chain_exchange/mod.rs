@@ -4,9 +4,10 @@
 mod behaviour;
 mod message;
 mod provider;
+mod server;
 pub use behaviour::*;
 
-pub use self::{message::*, provider::*};
+pub use self::{message::*, provider::*, server::*};
 use super::rpc::CborRequestResponse;
 
 /// Libp2p protocol name for `ChainExchange`.
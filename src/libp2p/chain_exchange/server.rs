@@ -0,0 +1,384 @@
+// Copyright 2019-2023 ChainSafe Systems
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+use std::{sync::Arc, time::Instant};
+
+use crate::chain::ChainStore;
+use ahash::HashMap;
+use fvm_ipld_blockstore::Blockstore;
+use libp2p::PeerId;
+use lru::LruCache;
+use parking_lot::Mutex;
+use tokio::sync::Semaphore;
+use tracing::debug;
+
+use super::{
+    make_chain_exchange_response, ChainExchangeRequest, ChainExchangeResponse,
+    ChainExchangeResponseStatus,
+};
+use crate::libp2p::service::metrics;
+
+/// Maximum number of tipsets served per request, longer requests are clamped.
+/// Same as `MaxRequestLength` in Lotus.
+pub const MAX_REQUEST_LEN: u64 = 900;
+/// Number of tipsets a peer can request in a burst.
+const QUOTA_BURST: f64 = 2.0 * MAX_REQUEST_LEN as f64;
+/// Number of tipsets per second added back to a peer's quota.
+const QUOTA_REFILL_PER_SEC: f64 = 100.0;
+/// Number of peer quotas above which the quotas of idle peers are dropped.
+const MAX_TRACKED_PEERS: usize = 4096;
+/// Maximum number of responses built concurrently.
+const MAX_CONCURRENT_RESPONSES: usize = 8;
+/// Maximum number of encoded bytes of the responses kept for repeated
+/// requests. A response of [`MAX_REQUEST_LEN`] tipsets with their messages
+/// can weigh tens of megabytes.
+const RESPONSE_CACHE_BYTES: usize = 256 * 1024 * 1024;
+
+/// Token bucket tracking how many tipsets a peer may still request.
+#[derive(Debug, Clone, Copy)]
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(now: Instant) -> Self {
+        Self {
+            tokens: QUOTA_BURST,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * QUOTA_REFILL_PER_SEC).min(QUOTA_BURST);
+        self.last_refill = now;
+    }
+
+    fn try_take(&mut self, cost: f64, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= cost {
+            self.tokens -= cost;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn is_full(&self, now: Instant) -> bool {
+        let mut bucket = *self;
+        bucket.refill(now);
+        bucket.tokens >= QUOTA_BURST
+    }
+}
+
+/// Least recently used responses, bounded by their encoded size.
+struct ResponseCache {
+    responses: LruCache<ChainExchangeRequest, (ChainExchangeResponse, usize)>,
+    bytes: usize,
+}
+
+impl ResponseCache {
+    fn get(&mut self, request: &ChainExchangeRequest) -> Option<ChainExchangeResponse> {
+        self.responses
+            .get(request)
+            .map(|(response, _)| response.clone())
+    }
+
+    /// Caches a response of `size` bytes, evicting the least recently used
+    /// responses to stay within [`RESPONSE_CACHE_BYTES`]. Responses larger
+    /// than the whole cache are not cached.
+    fn put(&mut self, request: ChainExchangeRequest, response: ChainExchangeResponse, size: usize) {
+        if size > RESPONSE_CACHE_BYTES {
+            return;
+        }
+        if let Some((_, (_, replaced))) = self.responses.push(request, (response, size)) {
+            self.bytes -= replaced;
+        }
+        self.bytes += size;
+        while self.bytes > RESPONSE_CACHE_BYTES {
+            match self.responses.pop_lru() {
+                Some((_, (_, evicted))) => self.bytes -= evicted,
+                None => break,
+            }
+        }
+    }
+}
+
+/// Serves inbound `ChainExchange` requests. Requests are validated and
+/// clamped to [`MAX_REQUEST_LEN`], each peer is given a quota of tipsets that
+/// refills over time, the number of responses built concurrently is capped,
+/// and responses are cached so that peers syncing the same range are served
+/// without walking the chain again.
+pub struct ChainExchangeServer {
+    quotas: Mutex<HashMap<PeerId, TokenBucket>>,
+    concurrency: Semaphore,
+    cache: Mutex<ResponseCache>,
+}
+
+impl Default for ChainExchangeServer {
+    fn default() -> Self {
+        Self {
+            quotas: Default::default(),
+            concurrency: Semaphore::new(MAX_CONCURRENT_RESPONSES),
+            cache: Mutex::new(ResponseCache {
+                responses: LruCache::unbounded(),
+                bytes: 0,
+            }),
+        }
+    }
+}
+
+impl ChainExchangeServer {
+    /// Builds the response to a request from `peer`.
+    pub async fn serve<DB>(
+        &self,
+        cs: Arc<ChainStore<DB>>,
+        peer: PeerId,
+        mut request: ChainExchangeRequest,
+    ) -> ChainExchangeResponse
+    where
+        DB: Blockstore + Send + Sync + 'static,
+    {
+        if let Err(message) = validate(&request) {
+            record(metrics::values::BAD_REQUEST);
+            return error_response(ChainExchangeResponseStatus::BadRequest, message);
+        }
+        let requested_len = request.request_len;
+        request.request_len = requested_len.min(MAX_REQUEST_LEN);
+
+        if !self.try_take_quota(peer, request.request_len, Instant::now()) {
+            debug!("Peer {peer} exceeded its chain exchange quota");
+            record(metrics::values::QUOTA_EXCEEDED);
+            return error_response(ChainExchangeResponseStatus::GoAway, "too many requests");
+        }
+
+        let cached = self.cache.lock().get(&request);
+        let response = match cached {
+            Some(response) => {
+                record(metrics::values::CACHED);
+                response
+            }
+            None => {
+                let _permit = self
+                    .concurrency
+                    .acquire()
+                    .await
+                    .expect("The semaphore is never closed");
+                let key = request.clone();
+                let response = tokio::task::spawn_blocking(move || {
+                    make_chain_exchange_response(&cs, &request)
+                })
+                .await
+                .unwrap_or_else(|e| {
+                    error_response(ChainExchangeResponseStatus::InternalError, e.to_string())
+                });
+                if matches!(
+                    response.status,
+                    ChainExchangeResponseStatus::Success
+                        | ChainExchangeResponseStatus::PartialResponse
+                ) {
+                    if let Ok(bytes) = fvm_ipld_encoding::to_vec(&response) {
+                        self.cache.lock().put(key, response.clone(), bytes.len());
+                    }
+                }
+                record(metrics::values::SERVED);
+                response
+            }
+        };
+
+        clamped(response, requested_len)
+    }
+
+    fn try_take_quota(&self, peer: PeerId, request_len: u64, now: Instant) -> bool {
+        let mut quotas = self.quotas.lock();
+        if quotas.len() >= MAX_TRACKED_PEERS {
+            quotas.retain(|_, bucket| !bucket.is_full(now));
+        }
+        quotas
+            .entry(peer)
+            .or_insert_with(|| TokenBucket::new(now))
+            .try_take(request_len as f64, now)
+    }
+}
+
+fn validate(request: &ChainExchangeRequest) -> Result<(), &'static str> {
+    if request.request_len == 0 {
+        return Err("invalid request length of zero");
+    }
+    if request.start.is_empty() {
+        return Err("no cids in request");
+    }
+    if !request.include_blocks() && !request.include_messages() {
+        return Err("request with no options set");
+    }
+    Ok(())
+}
+
+/// Marks a full response to a request clamped to [`MAX_REQUEST_LEN`] as
+/// partial.
+fn clamped(mut response: ChainExchangeResponse, requested_len: u64) -> ChainExchangeResponse {
+    if response.status == ChainExchangeResponseStatus::Success && requested_len > MAX_REQUEST_LEN {
+        response.status = ChainExchangeResponseStatus::PartialResponse;
+    }
+    response
+}
+
+fn error_response(
+    status: ChainExchangeResponseStatus,
+    message: impl Into<String>,
+) -> ChainExchangeResponse {
+    ChainExchangeResponse {
+        chain: vec![],
+        status,
+        message: message.into(),
+    }
+}
+
+fn record(result: &str) {
+    metrics::CHAIN_EXCHANGE_INBOUND_REQUESTS
+        .with_label_values(&[result])
+        .inc();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocks::BlockHeader;
+    use crate::db::MemoryDB;
+    use crate::genesis::EXPORT_SR_40;
+    use crate::libp2p::chain_exchange::{HEADERS, MESSAGES};
+    use crate::networks::ChainConfig;
+    use crate::shim::address::Address;
+    use crate::utils::db::car_util::load_car;
+    use std::time::Duration;
+
+    #[test]
+    fn token_bucket_refills() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(now);
+        assert!(bucket.try_take(QUOTA_BURST, now));
+        assert!(!bucket.try_take(1.0, now));
+        assert!(!bucket.is_full(now));
+
+        let later = now + Duration::from_secs(1);
+        assert!(bucket.try_take(QUOTA_REFILL_PER_SEC, later));
+        assert!(!bucket.try_take(1.0, later));
+        assert!(bucket.is_full(later + Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn quota_is_per_peer() {
+        let server = ChainExchangeServer::default();
+        let now = Instant::now();
+        let (a, b) = (PeerId::random(), PeerId::random());
+        assert!(server.try_take_quota(a, MAX_REQUEST_LEN, now));
+        assert!(server.try_take_quota(a, MAX_REQUEST_LEN, now));
+        assert!(!server.try_take_quota(a, MAX_REQUEST_LEN, now));
+        assert!(server.try_take_quota(b, MAX_REQUEST_LEN, now));
+    }
+
+    #[test]
+    fn only_clamped_responses_are_partial() {
+        let response = || error_response(ChainExchangeResponseStatus::Success, "");
+        assert_eq!(
+            clamped(response(), MAX_REQUEST_LEN).status,
+            ChainExchangeResponseStatus::Success
+        );
+        assert_eq!(
+            clamped(response(), MAX_REQUEST_LEN + 1).status,
+            ChainExchangeResponseStatus::PartialResponse
+        );
+    }
+
+    #[test]
+    fn response_cache_is_bounded_by_size() {
+        let mut cache = ResponseCache {
+            responses: LruCache::unbounded(),
+            bytes: 0,
+        };
+        let request = |request_len| ChainExchangeRequest {
+            start: vec![],
+            request_len,
+            options: HEADERS,
+        };
+        let response = error_response(ChainExchangeResponseStatus::Success, "");
+        let half = RESPONSE_CACHE_BYTES / 2;
+
+        cache.put(request(1), response.clone(), half);
+        cache.put(request(2), response.clone(), half);
+        assert_eq!(cache.bytes, RESPONSE_CACHE_BYTES);
+        // Replacing a response accounts for the size of the replaced one
+        cache.put(request(2), response.clone(), half);
+        assert_eq!(cache.bytes, RESPONSE_CACHE_BYTES);
+
+        // The least recently used response is evicted to make room
+        assert!(cache.get(&request(1)).is_some());
+        cache.put(request(3), response.clone(), 1);
+        assert!(cache.get(&request(2)).is_none());
+        assert!(cache.get(&request(1)).is_some());
+        assert_eq!(cache.bytes, half + 1);
+
+        // Responses larger than the cache are not cached
+        cache.put(request(4), response, RESPONSE_CACHE_BYTES + 1);
+        assert!(cache.get(&request(4)).is_none());
+        assert_eq!(cache.bytes, half + 1);
+    }
+
+    #[tokio::test]
+    async fn serve_validates_clamps_and_limits() {
+        let db = Arc::new(MemoryDB::default());
+        let cids = load_car(&db, EXPORT_SR_40).await.unwrap().roots;
+        let gen_block = BlockHeader::builder()
+            .miner_address(Address::new_id(0))
+            .build()
+            .unwrap();
+        let cs = Arc::new(
+            ChainStore::new(db.clone(), db, Arc::new(ChainConfig::default()), gen_block).unwrap(),
+        );
+        let server = ChainExchangeServer::default();
+        let peer = PeerId::random();
+        let request = |request_len, options| ChainExchangeRequest {
+            start: cids.clone(),
+            request_len,
+            options,
+        };
+
+        let response = server.serve(cs.clone(), peer, request(0, HEADERS)).await;
+        assert_eq!(response.status, ChainExchangeResponseStatus::BadRequest);
+        let response = server.serve(cs.clone(), peer, request(2, 0)).await;
+        assert_eq!(response.status, ChainExchangeResponseStatus::BadRequest);
+
+        // Served from the cache the second time
+        let other_peer = PeerId::random();
+        let response = server
+            .serve(cs.clone(), other_peer, request(2, HEADERS | MESSAGES))
+            .await;
+        assert_eq!(response.status, ChainExchangeResponseStatus::Success);
+        assert_eq!(response.chain.len(), 2);
+        assert_eq!(
+            server
+                .serve(cs.clone(), other_peer, request(2, HEADERS | MESSAGES))
+                .await,
+            response
+        );
+
+        // The chain is shorter than the clamped length
+        for _ in 0..2 {
+            let response = server
+                .serve(cs.clone(), peer, request(u64::MAX, HEADERS))
+                .await;
+            assert_eq!(
+                response.status,
+                ChainExchangeResponseStatus::PartialResponse
+            );
+            assert!((response.chain.len() as u64) < MAX_REQUEST_LEN);
+        }
+
+        // Clamped requests count against the quota with the clamped length
+        let response = server
+            .serve(cs.clone(), peer, request(u64::MAX, HEADERS))
+            .await;
+        assert_eq!(response.status, ChainExchangeResponseStatus::GoAway);
+    }
+}
@@ -11,9 +11,9 @@ use std::net::Ipv4Addr;
 #[serde(default)]
 #[cfg_attr(test, derive(derive_quickcheck_arbitrary::Arbitrary))]
 pub struct Libp2pConfig {
-    /// Local addresses. TCP and WebSocket with DNS are supported. By making it
-    /// empty, the libp2p node will not be capable of working as a callee but
-    /// can still work as a dialer
+    /// Local addresses. TCP and QUIC (`/udp/<port>/quic-v1`) are supported. By
+    /// making it empty, the libp2p node will not be capable of working as a
+    /// callee but can still work as a dialer
     #[cfg_attr(test, arbitrary(gen(
         |g: &mut quickcheck::Gen| {
             let addr = Ipv4Addr::arbitrary(&mut *g);
@@ -38,7 +38,10 @@ pub struct Libp2pConfig {
 impl Default for Libp2pConfig {
     fn default() -> Self {
         Self {
-            listening_multiaddrs: vec!["/ip4/0.0.0.0/tcp/0".parse().expect("Infallible")],
+            listening_multiaddrs: vec![
+                "/ip4/0.0.0.0/tcp/0".parse().expect("Infallible"),
+                "/ip4/0.0.0.0/udp/0/quic-v1".parse().expect("Infallible"),
+            ],
             bootstrap_peers: vec![],
             mdns: false,
             kademlia: true,
@@ -0,0 +1,141 @@
+// Copyright 2019-2023 ChainSafe Systems
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+use std::time::Duration;
+
+use crate::cid_collections::CidHashSet;
+use anyhow::Context as _;
+use cid::Cid;
+use fvm_ipld_blockstore::Blockstore;
+use fvm_ipld_encoding::{CborStore, DAG_CBOR};
+use libipld_core::ipld::Ipld;
+use tracing::debug;
+
+use super::NetworkMessage;
+
+const MAX_OUTSTANDING_WANTS: usize = 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const PROVIDER_LOOKUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Counters of a [`fetch_dag`] walk.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DagFetchStats {
+    /// Number of CIDs walked.
+    pub cids: usize,
+    /// Number of blocks fetched from the network.
+    pub fetched: usize,
+    /// Number of blocks that could not be fetched.
+    pub failures: usize,
+}
+
+/// Walks the `DAG_CBOR` blocks of the DAGs under `roots`, fetching the blocks
+/// missing from `db` with a single bitswap session. The providers of the first
+/// root are looked up on the DHT to be asked first. `on_block` is called with
+/// every block of the DAGs, whether it was fetched or already in `db`.
+pub async fn fetch_dag<DB>(
+    network_send: &flume::Sender<NetworkMessage>,
+    db: &DB,
+    roots: impl IntoIterator<Item = Cid>,
+    mut on_block: impl FnMut(Cid) -> anyhow::Result<()>,
+) -> anyhow::Result<DagFetchStats>
+where
+    DB: Blockstore,
+{
+    // Do a depth-first-search of the IPLD graph (DAG). Nodes that are _not_ present in our database
+    // form the frontier of missing links, which is fetched by a single bitswap session. If the
+    // number of outstanding wants reaches MAX_OUTSTANDING_WANTS, the depth-first-search pauses
+    // until the session fetches a block or gives up on it. The memory usage of this algorithm is
+    // dominated by the set of seen CIDs and the 'dfs' stack is not expected to grow to more than
+    // 1000 elements (even when walking tens of millions of nodes).
+    let mut dfs: Vec<_> = roots.into_iter().map(Ipld::Link).collect();
+
+    let (session_tx, session_rx) = flume::bounded(1);
+    network_send
+        .send_async(NetworkMessage::BitswapSession {
+            timeout: REQUEST_TIMEOUT,
+            response_channel: session_tx,
+        })
+        .await?;
+    let session = session_rx.recv_async().await?;
+
+    // Prefer the peers announcing the root on the DHT over broadcasting wants to everyone
+    if let Some(&Ipld::Link(root)) = dfs.first() {
+        let (providers_tx, providers_rx) = flume::bounded(1);
+        network_send
+            .send_async(NetworkMessage::FindProviders {
+                cid: root,
+                response_channel: providers_tx,
+            })
+            .await?;
+        match tokio::time::timeout(PROVIDER_LOOKUP_TIMEOUT, providers_rx.recv_async()).await {
+            Ok(Ok(providers)) => {
+                debug!("Found {} providers of {root}", providers.len());
+                session.add_providers(providers);
+            }
+            _ => debug!("No providers of {root} found"),
+        }
+    }
+
+    let mut seen = CidHashSet::default();
+    let mut stats = DagFetchStats::default();
+    let mut outstanding: usize = 0;
+
+    // When walking an Ipld graph, we're only interested in the DAG_CBOR encoded nodes.
+    let mut get_ipld_link = |ipld: &Ipld| match ipld {
+        &Ipld::Link(cid) if cid.codec() == DAG_CBOR && seen.insert(cid) => Some(cid),
+        _ => None,
+    };
+
+    // Loop until: No more items in `dfs` AND no outstanding wants.
+    loop {
+        while let Some(ipld) = dfs.pop() {
+            // Scan for unseen CIDs. Available IPLD nodes are pushed to the depth-first-search
+            // stack, unavailable nodes are wanted from the session.
+            for new_cid in ipld.iter().filter_map(&mut get_ipld_link) {
+                stats.cids += 1;
+                if stats.cids % 1_000 == 0 {
+                    debug!(
+                        "Graph walk: {stats:?}, dfs: {}, outstanding: {outstanding}",
+                        dfs.len()
+                    );
+                }
+
+                if let Some(next_ipld) = db.get_cbor(&new_cid)? {
+                    dfs.push(next_ipld);
+                    on_block(new_cid)?;
+                } else {
+                    session.want(new_cid);
+                    outstanding += 1;
+                }
+            }
+            if outstanding >= MAX_OUTSTANDING_WANTS {
+                break;
+            }
+        }
+        if outstanding == 0 {
+            // We are out of work items (dfs) and all wanted blocks have been fetched or given up
+            // on, this means the entire graph has been walked and fetched.
+            break;
+        }
+        // Bitswap requests do not fail. They are just ignored if no-one has the requested data.
+        // The session only waits for REQUEST_TIMEOUT before judging that the data is unavailable.
+        let (cid, success) = session
+            .next()
+            .await
+            .context("Bitswap session ended unexpectedly")?;
+        outstanding -= 1;
+        match db.get_cbor::<Ipld>(&cid)? {
+            Some(new_ipld) if success => {
+                stats.fetched += 1;
+                dfs.push(new_ipld);
+                on_block(cid)?;
+            }
+            _ => {
+                stats.failures += 1;
+                debug!("Request failed: {cid}");
+            }
+        }
+    }
+
+    Ok(stats)
+}
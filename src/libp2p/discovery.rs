@@ -9,20 +9,33 @@ use std::{
 };
 
 use ahash::{HashMap, HashMapExt, HashSet, HashSetExt};
+use cid::Cid;
 use libp2p::{
     core::Multiaddr,
     identity::{PeerId, PublicKey},
-    kad::{record::store::MemoryStore, Kademlia, KademliaConfig, KademliaEvent, QueryId},
+    kad::{
+        record::{store::MemoryStore, Key as RecordKey},
+        GetProvidersOk, Kademlia, KademliaConfig, KademliaEvent, QueryId, QueryResult,
+    },
     mdns::{tokio::Behaviour as Mdns, Event as MdnsEvent},
     multiaddr::Protocol,
     swarm::{
-        behaviour::toggle::Toggle, derive_prelude::*, NetworkBehaviour, PollParameters, ToSwarm,
+        behaviour::toggle::Toggle,
+        derive_prelude::*,
+        dial_opts::{DialOpts, PeerCondition},
+        NetworkBehaviour, PollParameters, ToSwarm,
     },
     StreamProtocol,
 };
 use tokio::time::Interval;
 use tracing::{debug, error, trace, warn};
 
+/// Maximum number of blocks the node announces itself as a provider of. The
+/// oldest announcements are withdrawn past it.
+const MAX_PROVIDED_KEYS: usize = 256;
+/// Number of providers after which a provider lookup stops.
+const MAX_PROVIDERS_PER_LOOKUP: usize = 20;
+
 /// Event generated by the `DiscoveryBehaviour`.
 #[derive(Debug)]
 pub enum DiscoveryEvent {
@@ -150,6 +163,9 @@ impl<'a> DiscoveryConfig<'a> {
             peers,
             peer_addresses,
             target_peer_count,
+            provided_keys: VecDeque::new(),
+            provider_queries: HashMap::new(),
+            pending_dials: VecDeque::new(),
         })
     }
 }
@@ -179,6 +195,17 @@ pub struct DiscoveryBehaviour {
     peer_addresses: HashMap<PeerId, HashSet<Multiaddr>>,
     /// Number of connected peers to pause discovery on.
     target_peer_count: u64,
+    /// Keys of the blocks the node is a provider of, oldest first.
+    provided_keys: VecDeque<RecordKey>,
+    /// Ongoing provider lookups.
+    provider_queries: HashMap<QueryId, ProviderQuery>,
+    /// Providers found by lookups to connect to.
+    pending_dials: VecDeque<PeerId>,
+}
+
+struct ProviderQuery {
+    providers: HashSet<PeerId>,
+    response_channel: flume::Sender<HashSet<PeerId>>,
 }
 
 impl DiscoveryBehaviour {
@@ -200,6 +227,75 @@ impl DiscoveryBehaviour {
             Err("Kademlia is not activated".to_string())
         }
     }
+
+    /// Announces the node as a provider of a block on the DHT, so that peers
+    /// can find it with [`DiscoveryBehaviour::get_providers`].
+    pub fn start_providing(&mut self, cid: Cid) -> Result<QueryId, String> {
+        let Some(kad) = self.kademlia.as_mut() else {
+            return Err("Kademlia is not activated".to_string());
+        };
+        let key = RecordKey::new(&cid.to_bytes());
+        if !self.provided_keys.contains(&key) {
+            if self.provided_keys.len() == MAX_PROVIDED_KEYS {
+                if let Some(oldest) = self.provided_keys.pop_front() {
+                    kad.stop_providing(&oldest);
+                }
+            }
+            self.provided_keys.push_back(key.clone());
+        }
+        kad.start_providing(key).map_err(|e| e.to_string())
+    }
+
+    /// Looks up the providers of a block on the DHT and connects to them. The
+    /// providers found are sent to the channel once the lookup completes.
+    pub fn get_providers(&mut self, cid: Cid, response_channel: flume::Sender<HashSet<PeerId>>) {
+        if let Some(kad) = self.kademlia.as_mut() {
+            let id = kad.get_providers(RecordKey::new(&cid.to_bytes()));
+            self.provider_queries.insert(
+                id,
+                ProviderQuery {
+                    providers: HashSet::new(),
+                    response_channel,
+                },
+            );
+        } else {
+            _ = response_channel.send(HashSet::new());
+        }
+    }
+
+    fn on_get_providers_progressed(
+        &mut self,
+        id: QueryId,
+        result: Result<GetProvidersOk, String>,
+        last: bool,
+    ) {
+        let Some(query) = self.provider_queries.get_mut(&id) else {
+            return;
+        };
+        match result {
+            Ok(GetProvidersOk::FoundProviders { providers, .. }) => {
+                for provider in providers {
+                    if !self.peers.contains(&provider) {
+                        self.pending_dials.push_back(provider);
+                    }
+                    query.providers.insert(provider);
+                }
+            }
+            Ok(GetProvidersOk::FinishedWithNoAdditionalRecord { .. }) => {}
+            Err(e) => debug!("Provider lookup failed: {e}"),
+        }
+        let enough = query.providers.len() >= MAX_PROVIDERS_PER_LOOKUP;
+        if last || enough {
+            if let Some(query) = self.provider_queries.remove(&id) {
+                _ = query.response_channel.send(query.providers);
+            }
+            if !last {
+                if let Some(mut query) = self.kademlia.as_mut().and_then(|k| k.query_mut(&id)) {
+                    query.finish();
+                }
+            }
+        }
+    }
 }
 
 impl NetworkBehaviour for DiscoveryBehaviour {
@@ -351,6 +447,23 @@ impl NetworkBehaviour for DiscoveryBehaviour {
                     KademliaEvent::PendingRoutablePeer { .. } => {
                         // Intentionally ignore
                     }
+                    KademliaEvent::OutboundQueryProgressed {
+                        id,
+                        result: QueryResult::GetProviders(result),
+                        step,
+                        ..
+                    } => self.on_get_providers_progressed(
+                        id,
+                        result.map_err(|e| e.to_string()),
+                        step.last,
+                    ),
+                    KademliaEvent::OutboundQueryProgressed {
+                        result: QueryResult::StartProviding(result),
+                        ..
+                    } => match result {
+                        Ok(ok) => trace!("Announced provider record {:?}", ok.key),
+                        Err(e) => debug!("Failed to announce provider record: {e}"),
+                    },
                     other => {
                         trace!("Libp2p => Unhandled Kademlia event: {:?}", other)
                     }
@@ -394,6 +507,15 @@ impl NetworkBehaviour for DiscoveryBehaviour {
             }
         }
 
+        // Connect to the providers found by lookups.
+        if let Some(peer_id) = self.pending_dials.pop_front() {
+            return Poll::Ready(ToSwarm::Dial {
+                opts: DialOpts::peer_id(peer_id)
+                    .condition(PeerCondition::Disconnected)
+                    .build(),
+            });
+        }
+
         // Poll mdns.
         while let Poll::Ready(ev) = self.mdns.poll(cx, params) {
             match ev {
@@ -0,0 +1,272 @@
+// Copyright 2019-2023 ChainSafe Systems
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+use std::sync::Arc;
+
+use ahash::HashMap;
+use libp2p::{
+    request_response::{self, OutboundFailure, ProtocolSupport, RequestId, ResponseChannel},
+    swarm::{derive_prelude::*, NetworkBehaviour, THandlerOutEvent},
+    PeerId,
+};
+use tracing::debug;
+
+use super::*;
+use crate::libp2p::{rpc::RequestResponseError, service::metrics};
+
+type InnerBehaviour = request_response::Behaviour<GraphsyncCodec>;
+
+/// An outbound message being written.
+enum OutboundMessage {
+    /// A new request, failing the request if it can't be sent.
+    Request(GraphsyncRequestId),
+    /// A cancellation.
+    Cancel,
+    /// A message of a response, with the channel told whether it was written.
+    Response(flume::Sender<bool>),
+}
+
+pub struct GraphsyncBehaviour {
+    inner: InnerBehaviour,
+    /// Requests sent to other peers, with the peer they were sent to and the
+    /// channel their replies are forwarded to.
+    requests: HashMap<
+        GraphsyncRequestId,
+        (
+            PeerId,
+            flume::Sender<Result<GraphsyncReply, RequestResponseError>>,
+        ),
+    >,
+    outbound: HashMap<RequestId, OutboundMessage>,
+    server: Arc<GraphsyncServer>,
+}
+
+impl GraphsyncBehaviour {
+    /// Returns the server handling inbound requests.
+    pub fn server(&self) -> Arc<GraphsyncServer> {
+        self.server.clone()
+    }
+
+    /// Sends a new request to `peer`, its replies are forwarded to
+    /// `reply_channel` until the final one. The request is cancelled if the
+    /// channel is closed before.
+    pub fn send_request(
+        &mut self,
+        peer: &PeerId,
+        request: GraphsyncRequest,
+        reply_channel: flume::Sender<Result<GraphsyncReply, RequestResponseError>>,
+    ) {
+        let id = request.id;
+        self.requests.insert(id, (*peer, reply_channel));
+        self.send(peer, request_message(request), OutboundMessage::Request(id));
+        self.track_metrics();
+    }
+
+    /// Sends a message of a response to `peer`, `written` is told whether it
+    /// was written.
+    pub fn send_message(
+        &mut self,
+        peer: &PeerId,
+        message: GraphsyncMessage,
+        written: flume::Sender<bool>,
+    ) {
+        self.send(peer, message, OutboundMessage::Response(written));
+    }
+
+    fn send(&mut self, peer: &PeerId, message: GraphsyncMessage, outbound: OutboundMessage) {
+        let request_id = self.inner.send_request(peer, message);
+        self.outbound.insert(request_id, outbound);
+    }
+
+    /// Closes the inbound stream of a message, the responses are sent on
+    /// outbound streams.
+    pub fn close_inbound_stream(&mut self, channel: ResponseChannel<()>) {
+        let _ = self.inner.send_response(channel, ());
+    }
+
+    /// Forwards the responses of a message received from `peer` to their
+    /// requests, and cancels the requests being served that it cancels.
+    /// Returns the new requests to serve.
+    pub fn handle_inbound_message(
+        &mut self,
+        peer: &PeerId,
+        message: GraphsyncMessage,
+    ) -> Vec<GraphsyncRequest> {
+        let mut new_requests = vec![];
+        for request in message.requests {
+            match request.ty {
+                GraphsyncRequestType::New => new_requests.push(request),
+                GraphsyncRequestType::Cancel => self.server.cancel(*peer, request.id),
+                GraphsyncRequestType::Update => {
+                    debug!("Ignoring graphsync request update from {peer}")
+                }
+            }
+        }
+        let mut blocks: HashMap<_, _> = message
+            .blocks
+            .into_iter()
+            .map(|block| (block.cid, block))
+            .collect();
+        for response in message.responses {
+            let id = response.id;
+            let Some((requested, channel)) = self.requests.get(&id) else {
+                continue;
+            };
+            if requested != peer {
+                continue;
+            }
+            let blocks = response
+                .metadata
+                .iter()
+                .filter(|metadatum| metadatum.action == GraphsyncLinkAction::Present)
+                .filter_map(|metadatum| blocks.remove(&metadatum.link))
+                .collect();
+            let terminal = response.status.is_terminal();
+            if channel
+                .send(Ok(GraphsyncReply { response, blocks }))
+                .is_err()
+            {
+                // The requester gave up on the request
+                self.requests.remove(&id);
+                self.send(
+                    peer,
+                    request_message(GraphsyncRequest::cancel(id)),
+                    OutboundMessage::Cancel,
+                );
+            } else if terminal {
+                self.requests.remove(&id);
+            }
+        }
+        self.track_metrics();
+        new_requests
+    }
+
+    pub fn on_outbound_success(&mut self, request_id: &RequestId) {
+        if let Some(OutboundMessage::Response(written)) = self.outbound.remove(request_id) {
+            let _ = written.send(true);
+        }
+    }
+
+    pub fn on_outbound_error(&mut self, request_id: &RequestId, error: OutboundFailure) {
+        match self.outbound.remove(request_id) {
+            Some(OutboundMessage::Request(id)) => {
+                if let Some((_, tx)) = self.requests.remove(&id) {
+                    if let Err(err) = tx.send(Err(error.into())) {
+                        debug!("{err}");
+                    }
+                }
+                self.track_metrics();
+            }
+            Some(OutboundMessage::Response(written)) => {
+                debug!("Failed to send graphsync response: {error:?}");
+                let _ = written.send(false);
+            }
+            Some(OutboundMessage::Cancel) | None => {}
+        }
+    }
+
+    fn track_metrics(&self) {
+        metrics::NETWORK_CONTAINER_CAPACITIES
+            .with_label_values(&[metrics::values::GRAPHSYNC_REQUEST_TABLE])
+            .set(self.requests.capacity() as u64);
+    }
+}
+
+fn request_message(request: GraphsyncRequest) -> GraphsyncMessage {
+    GraphsyncMessage {
+        requests: vec![request],
+        ..Default::default()
+    }
+}
+
+impl Default for GraphsyncBehaviour {
+    fn default() -> Self {
+        let mut config = request_response::Config::default();
+        config.set_request_timeout(GRAPHSYNC_TIMEOUT);
+        Self {
+            inner: InnerBehaviour::new([(GRAPHSYNC_PROTOCOL_NAME, ProtocolSupport::Full)], config),
+            requests: Default::default(),
+            outbound: Default::default(),
+            server: Default::default(),
+        }
+    }
+}
+
+impl NetworkBehaviour for GraphsyncBehaviour {
+    type ConnectionHandler = <InnerBehaviour as NetworkBehaviour>::ConnectionHandler;
+
+    type ToSwarm = <InnerBehaviour as NetworkBehaviour>::ToSwarm;
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        local_addr: &libp2p::Multiaddr,
+        remote_addr: &libp2p::Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.inner.handle_established_inbound_connection(
+            connection_id,
+            peer,
+            local_addr,
+            remote_addr,
+        )
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        addr: &libp2p::Multiaddr,
+        role_override: libp2p::core::Endpoint,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.inner
+            .handle_established_outbound_connection(connection_id, peer, addr, role_override)
+    }
+
+    fn handle_pending_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        local_addr: &libp2p::Multiaddr,
+        remote_addr: &libp2p::Multiaddr,
+    ) -> Result<(), ConnectionDenied> {
+        self.inner
+            .handle_pending_inbound_connection(connection_id, local_addr, remote_addr)
+    }
+
+    fn handle_pending_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        maybe_peer: Option<PeerId>,
+        addresses: &[libp2p::Multiaddr],
+        effective_role: libp2p::core::Endpoint,
+    ) -> Result<Vec<libp2p::Multiaddr>, ConnectionDenied> {
+        self.inner.handle_pending_outbound_connection(
+            connection_id,
+            maybe_peer,
+            addresses,
+            effective_role,
+        )
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        self.inner
+            .on_connection_handler_event(peer_id, connection_id, event)
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm<Self::ConnectionHandler>) {
+        self.inner.on_swarm_event(event)
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+        params: &mut impl PollParameters,
+    ) -> std::task::Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        self.inner.poll(cx, params)
+    }
+}
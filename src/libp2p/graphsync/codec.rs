@@ -0,0 +1,110 @@
+// Copyright 2019-2023 ChainSafe Systems
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+use std::io;
+
+use async_trait::async_trait;
+use futures::prelude::*;
+use libp2p::{core::upgrade, request_response};
+
+use super::{message::GraphsyncMessageRoot, GraphsyncMessage};
+use crate::libp2p::bandwidth;
+
+/// Maximum size of a message. Responses are split into messages carrying at
+/// most [`MAX_MESSAGE_BLOCK_BYTES`](super::MAX_MESSAGE_BLOCK_BYTES) of blocks,
+/// plus a block of at most 2MB.
+const MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Reads and writes a single length-prefixed DAG-CBOR message per stream.
+/// Requests and responses are both sent as outbound "requests", the
+/// request-response "responses" are empty.
+#[derive(Default, Debug, Clone)]
+pub struct GraphsyncCodec;
+
+#[async_trait]
+impl request_response::Codec for GraphsyncCodec {
+    type Protocol = &'static str;
+    type Request = GraphsyncMessage;
+    type Response = ();
+
+    async fn read_request<T>(
+        &mut self,
+        protocol: &Self::Protocol,
+        io: &mut T,
+    ) -> io::Result<Self::Request>
+    where
+        T: AsyncRead + Send + Unpin,
+    {
+        let data = upgrade::read_length_prefixed(io, MAX_MESSAGE_SIZE).await?;
+        bandwidth::record_inbound(protocol, data.len());
+        let root: GraphsyncMessageRoot = fvm_ipld_encoding::from_slice(&data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(root.message)
+    }
+
+    /// Just close the outbound stream, the responses come on inbound streams
+    /// and are received in `read_request`
+    async fn read_response<T>(&mut self, _: &Self::Protocol, _: &mut T) -> io::Result<()>
+    where
+        T: AsyncRead + Send + Unpin,
+    {
+        Ok(())
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        protocol: &Self::Protocol,
+        io: &mut T,
+        message: Self::Request,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Send + Unpin,
+    {
+        let bytes = fvm_ipld_encoding::to_vec(&GraphsyncMessageRoot { message })
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        bandwidth::record_outbound(protocol, bytes.len());
+        upgrade::write_length_prefixed(io, bytes).await
+    }
+
+    async fn write_response<T>(&mut self, _: &Self::Protocol, _: &mut T, _: ()) -> io::Result<()>
+    where
+        T: AsyncWrite + Send + Unpin,
+    {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipld::selector::Selector;
+    use crate::libp2p::graphsync::{GraphsyncRequest, GRAPHSYNC_PROTOCOL_NAME};
+    use request_response::Codec as _;
+
+    #[tokio::test]
+    async fn graphsync_codec_roundtrip() {
+        let message = GraphsyncMessage {
+            requests: vec![GraphsyncRequest::new(
+                Default::default(),
+                Selector::explore_all_recursively(),
+            )],
+            ..Default::default()
+        };
+        let mut io = futures::io::Cursor::new(vec![]);
+        GraphsyncCodec
+            .write_request(&GRAPHSYNC_PROTOCOL_NAME, &mut io, message.clone())
+            .await
+            .unwrap();
+        // Messages are prefixed with their length as an unsigned varint
+        let bytes = io.into_inner();
+        let (len, rest) = unsigned_varint::decode::usize(&bytes).unwrap();
+        assert_eq!(len, rest.len());
+
+        let mut io = futures::io::Cursor::new(bytes);
+        let read = GraphsyncCodec
+            .read_request(&GRAPHSYNC_PROTOCOL_NAME, &mut io)
+            .await
+            .unwrap();
+        assert_eq!(read, message);
+    }
+}
@@ -0,0 +1,460 @@
+// Copyright 2019-2023 ChainSafe Systems
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+use std::collections::BTreeMap;
+
+use crate::ipld::{selector::Selector, Ipld};
+use anyhow::ensure;
+use cid::{
+    multihash::{Code, MultihashDigest},
+    Cid, Version,
+};
+use fvm_ipld_encoding::{BytesDe, BytesSer};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_tuple::{self, Deserialize_tuple, Serialize_tuple};
+use unsigned_varint::{decode as varint_decode, encode as varint_encode};
+
+/// Identifier of a request, chosen by the requester and echoed in every
+/// response to the request. Encoded as the 16 bytes of a UUID.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct GraphsyncRequestId(pub [u8; 16]);
+
+impl GraphsyncRequestId {
+    pub fn random() -> Self {
+        Self(uuid::Uuid::new_v4().into_bytes())
+    }
+}
+
+impl Serialize for GraphsyncRequestId {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        BytesSer(&self.0).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for GraphsyncRequestId {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let BytesDe(bytes) = Deserialize::deserialize(deserializer)?;
+        bytes
+            .try_into()
+            .map(Self)
+            .map_err(|bytes: Vec<u8>| de::Error::invalid_length(bytes.len(), &"16 bytes"))
+    }
+}
+
+/// Type of a request: new requests start a traversal, cancellations stop it
+/// and updates carry extensions for a traversal in progress.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GraphsyncRequestType {
+    #[serde(rename = "n")]
+    New,
+    #[serde(rename = "c")]
+    Cancel,
+    #[serde(rename = "u")]
+    Update,
+}
+
+/// A request of a graphsync message. Fields are declared in the canonical
+/// DAG-CBOR order of their keys.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GraphsyncRequest {
+    #[serde(rename = "id")]
+    pub id: GraphsyncRequestId,
+    /// Extensions of the request, which are ignored.
+    #[serde(rename = "ext", default, skip_serializing_if = "Option::is_none")]
+    pub extensions: Option<BTreeMap<String, Ipld>>,
+    #[serde(rename = "pri", default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<i32>,
+    /// Selector walked from the root, every block loaded along the way is
+    /// sent in the response. Only set on new requests.
+    #[serde(rename = "sel", default, skip_serializing_if = "Option::is_none")]
+    pub selector: Option<Selector>,
+    /// Root of the DAG to traverse. Only set on new requests.
+    #[serde(rename = "root", default, skip_serializing_if = "Option::is_none")]
+    pub root: Option<Cid>,
+    #[serde(rename = "type")]
+    pub ty: GraphsyncRequestType,
+}
+
+impl GraphsyncRequest {
+    /// A new request for the blocks traversed by `selector` from `root`.
+    pub fn new(root: Cid, selector: Selector) -> Self {
+        Self {
+            id: GraphsyncRequestId::random(),
+            extensions: None,
+            priority: None,
+            selector: Some(selector),
+            root: Some(root),
+            ty: GraphsyncRequestType::New,
+        }
+    }
+
+    /// Cancels the request `id`.
+    pub fn cancel(id: GraphsyncRequestId) -> Self {
+        Self {
+            id,
+            extensions: None,
+            priority: None,
+            selector: None,
+            root: None,
+            ty: GraphsyncRequestType::Cancel,
+        }
+    }
+}
+
+/// Status codes of a graphsync response.
+#[derive(Clone, Debug, PartialEq, Eq, Copy)]
+pub enum GraphsyncResponseStatus {
+    /// Request was received and is being worked on.
+    RequestAcknowledged,
+    /// Responder has additional peers to request the blocks from.
+    AdditionalPeers,
+    /// Responder needs more payment to continue.
+    NotEnoughGas,
+    /// Responder asks to use another protocol.
+    OtherProtocol,
+    /// More blocks of the response are coming in following messages.
+    PartialResponse,
+    /// Responder paused the traversal.
+    RequestPaused,
+    /// All blocks traversed by the selector were sent.
+    RequestCompletedFull,
+    /// The traversal completed but some blocks were missing.
+    RequestCompletedPartial,
+    /// Responder refuses to serve the request.
+    RequestRejected,
+    /// Responder is serving too many requests.
+    RequestFailedBusy,
+    /// Traversal failed for another reason.
+    RequestFailedUnknown,
+    /// Responder is not allowed to serve the content.
+    RequestFailedLegal,
+    /// Root of the request was not found.
+    RequestFailedContentNotFound,
+    /// Request was cancelled by the requester.
+    RequestCancelled,
+    /// Other undefined response code.
+    Other(i32),
+}
+
+impl GraphsyncResponseStatus {
+    /// Returns `true` if no more messages follow for the request.
+    pub fn is_terminal(&self) -> bool {
+        i32::from(*self) >= 20
+    }
+
+    /// Returns `true` if the request failed.
+    pub fn is_failure(&self) -> bool {
+        i32::from(*self) >= 30
+    }
+}
+
+impl From<GraphsyncResponseStatus> for i32 {
+    fn from(status: GraphsyncResponseStatus) -> Self {
+        use GraphsyncResponseStatus::*;
+        match status {
+            RequestAcknowledged => 10,
+            AdditionalPeers => 11,
+            NotEnoughGas => 12,
+            OtherProtocol => 13,
+            PartialResponse => 14,
+            RequestPaused => 15,
+            RequestCompletedFull => 20,
+            RequestCompletedPartial => 21,
+            RequestRejected => 30,
+            RequestFailedBusy => 31,
+            RequestFailedUnknown => 32,
+            RequestFailedLegal => 33,
+            RequestFailedContentNotFound => 34,
+            RequestCancelled => 35,
+            Other(i) => i,
+        }
+    }
+}
+
+impl From<i32> for GraphsyncResponseStatus {
+    fn from(code: i32) -> Self {
+        use GraphsyncResponseStatus::*;
+        match code {
+            10 => RequestAcknowledged,
+            11 => AdditionalPeers,
+            12 => NotEnoughGas,
+            13 => OtherProtocol,
+            14 => PartialResponse,
+            15 => RequestPaused,
+            20 => RequestCompletedFull,
+            21 => RequestCompletedPartial,
+            30 => RequestRejected,
+            31 => RequestFailedBusy,
+            32 => RequestFailedUnknown,
+            33 => RequestFailedLegal,
+            34 => RequestFailedContentNotFound,
+            35 => RequestCancelled,
+            x => Other(x),
+        }
+    }
+}
+
+impl Serialize for GraphsyncResponseStatus {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        i32::from(*self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for GraphsyncResponseStatus {
+    fn deserialize<D>(deserializer: D) -> Result<Self, <D as Deserializer<'de>>::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        i32::deserialize(deserializer).map(Into::into)
+    }
+}
+
+/// What the responder did with a block loaded by the traversal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GraphsyncLinkAction {
+    /// The block is in the message.
+    #[serde(rename = "p")]
+    Present,
+    /// The block was already sent for the request.
+    #[serde(rename = "d")]
+    DuplicateNotSent,
+    /// The responder doesn't have the block.
+    #[serde(rename = "m")]
+    Missing,
+    /// The block was already sent and its DAG wasn't traversed again.
+    #[serde(rename = "s")]
+    DuplicateDagSkipped,
+}
+
+/// A link loaded by the traversal, in traversal order.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize_tuple, Deserialize_tuple)]
+pub struct GraphsyncMetadatum {
+    pub link: Cid,
+    pub action: GraphsyncLinkAction,
+}
+
+/// A response of a graphsync message. Fields are declared in the canonical
+/// DAG-CBOR order of their keys.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GraphsyncResponse {
+    /// Extensions of the response, which are ignored.
+    #[serde(rename = "ext", default, skip_serializing_if = "Option::is_none")]
+    pub extensions: Option<BTreeMap<String, Ipld>>,
+    /// Links loaded by the traversal since the previous message.
+    #[serde(rename = "meta", default, skip_serializing_if = "Vec::is_empty")]
+    pub metadata: Vec<GraphsyncMetadatum>,
+    #[serde(rename = "stat")]
+    pub status: GraphsyncResponseStatus,
+    #[serde(rename = "reqid")]
+    pub id: GraphsyncRequestId,
+}
+
+/// A block of a graphsync message, encoded as the prefix of its CID and its
+/// data, from which the CID is computed when decoding.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GraphsyncBlock {
+    pub cid: Cid,
+    pub data: Vec<u8>,
+}
+
+impl Serialize for GraphsyncBlock {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        (BytesSer(&cid_prefix(&self.cid)), BytesSer(&self.data)).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for GraphsyncBlock {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let (BytesDe(prefix), BytesDe(data)) = Deserialize::deserialize(deserializer)?;
+        let cid = cid_from_prefix(&prefix, &data).map_err(de::Error::custom)?;
+        Ok(Self { cid, data })
+    }
+}
+
+/// Encodes the version, codec, hash function and hash length of a CID.
+fn cid_prefix(cid: &Cid) -> Vec<u8> {
+    let mut prefix = vec![];
+    for n in [
+        cid.version().into(),
+        cid.codec(),
+        cid.hash().code(),
+        cid.hash().size().into(),
+    ] {
+        prefix.extend_from_slice(varint_encode::u64(n, &mut varint_encode::u64_buffer()));
+    }
+    prefix
+}
+
+/// Computes the CID of `data` from the prefix of its CID.
+fn cid_from_prefix(prefix: &[u8], data: &[u8]) -> anyhow::Result<Cid> {
+    let (version, prefix) = varint_decode::u64(prefix)?;
+    let (codec, prefix) = varint_decode::u64(prefix)?;
+    let (code, prefix) = varint_decode::u64(prefix)?;
+    let (size, _) = varint_decode::u64(prefix)?;
+    let hash = Code::try_from(code)?.digest(data);
+    ensure!(
+        u64::from(hash.size()) == size,
+        "unsupported hash length {size}"
+    );
+    Ok(Cid::new(Version::try_from(version)?, codec, hash)?)
+}
+
+/// A graphsync message. Requests and responses are sent in messages of their
+/// own, on streams that are closed after each message. Fields are declared in
+/// the canonical DAG-CBOR order of their keys.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct GraphsyncMessage {
+    /// Blocks of the responses, listed as present in their metadata.
+    #[serde(rename = "blk", default, skip_serializing_if = "Vec::is_empty")]
+    pub blocks: Vec<GraphsyncBlock>,
+    #[serde(rename = "req", default, skip_serializing_if = "Vec::is_empty")]
+    pub requests: Vec<GraphsyncRequest>,
+    #[serde(rename = "rsp", default, skip_serializing_if = "Vec::is_empty")]
+    pub responses: Vec<GraphsyncResponse>,
+}
+
+/// Versioned envelope of the messages on the wire.
+#[derive(Serialize, Deserialize)]
+pub(super) struct GraphsyncMessageRoot {
+    #[serde(rename = "gs2")]
+    pub message: GraphsyncMessage,
+}
+
+/// A response received for a request, with the blocks of its message listed
+/// as present in its metadata.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GraphsyncReply {
+    pub response: GraphsyncResponse,
+    pub blocks: Vec<GraphsyncBlock>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MemoryDB;
+    use crate::utils::db::CborStoreExt;
+    use fvm_ipld_blockstore::Blockstore;
+
+    fn message() -> GraphsyncMessage {
+        let db = MemoryDB::default();
+        let cid = db.put_cbor_default(&"block").unwrap();
+        let id = GraphsyncRequestId::random();
+        GraphsyncMessage {
+            blocks: vec![GraphsyncBlock {
+                cid,
+                data: db.get(&cid).unwrap().unwrap(),
+            }],
+            requests: vec![
+                GraphsyncRequest::new(cid, Selector::explore_all_recursively()),
+                GraphsyncRequest::cancel(id),
+            ],
+            responses: vec![GraphsyncResponse {
+                extensions: None,
+                metadata: vec![GraphsyncMetadatum {
+                    link: cid,
+                    action: GraphsyncLinkAction::Present,
+                }],
+                status: GraphsyncResponseStatus::PartialResponse,
+                id,
+            }],
+        }
+    }
+
+    #[test]
+    fn graphsync_message_roundtrip() {
+        let message = message();
+        let bytes = fvm_ipld_encoding::to_vec(&GraphsyncMessageRoot {
+            message: message.clone(),
+        })
+        .unwrap();
+        let root: GraphsyncMessageRoot = fvm_ipld_encoding::from_slice(&bytes).unwrap();
+        assert_eq!(root.message, message);
+    }
+
+    #[test]
+    fn graphsync_message_encoding_is_canonical() {
+        let bytes =
+            fvm_ipld_encoding::to_vec(&GraphsyncMessageRoot { message: message() }).unwrap();
+        // Maps of the generic IPLD data model are encoded with sorted keys
+        let ipld: Ipld = fvm_ipld_encoding::from_slice(&bytes).unwrap();
+        assert_eq!(fvm_ipld_encoding::to_vec(&ipld).unwrap(), bytes);
+
+        let Ipld::Map(root) = ipld else {
+            panic!("message root is not a map");
+        };
+        let Some(Ipld::Map(message)) = root.get("gs2") else {
+            panic!("message is not a map");
+        };
+        let Some(Ipld::List(blocks)) = message.get("blk") else {
+            panic!("blocks are not a list");
+        };
+        // Blocks are a prefix of CIDv1, DAG-CBOR, Blake2b-256 and 32 bytes
+        // followed by the data
+        let Ipld::List(block) = &blocks[0] else {
+            panic!("block is not a list");
+        };
+        assert_eq!(
+            block[0],
+            Ipld::Bytes(vec![0x01, 0x71, 0xa0, 0xe4, 0x02, 0x20])
+        );
+    }
+
+    #[test]
+    fn graphsync_response_status_roundtrip() {
+        for code in [10, 11, 12, 13, 14, 15, 20, 21, 30, 31, 32, 33, 34, 35, 1] {
+            let status = GraphsyncResponseStatus::from(code);
+            assert_eq!(i32::from(status), code);
+            let bytes = fvm_ipld_encoding::to_vec(&status).unwrap();
+            assert_eq!(
+                fvm_ipld_encoding::from_slice::<GraphsyncResponseStatus>(&bytes).unwrap(),
+                status
+            );
+        }
+        assert_eq!(
+            GraphsyncResponseStatus::from(32),
+            GraphsyncResponseStatus::RequestFailedUnknown
+        );
+        assert_eq!(
+            GraphsyncResponseStatus::from(33),
+            GraphsyncResponseStatus::RequestFailedLegal
+        );
+        assert!(!GraphsyncResponseStatus::PartialResponse.is_terminal());
+        assert!(GraphsyncResponseStatus::RequestCompletedPartial.is_terminal());
+        assert!(!GraphsyncResponseStatus::RequestCompletedPartial.is_failure());
+        assert!(GraphsyncResponseStatus::RequestFailedBusy.is_failure());
+    }
+
+    #[test]
+    fn graphsync_block_cid_is_computed() {
+        let message = message();
+        let block = &message.blocks[0];
+        let bytes = fvm_ipld_encoding::to_vec(block).unwrap();
+        assert_eq!(
+            fvm_ipld_encoding::from_slice::<GraphsyncBlock>(&bytes).unwrap(),
+            *block
+        );
+
+        // Data not matching the prefix gives another CID
+        let forged =
+            fvm_ipld_encoding::to_vec(&(BytesSer(&cid_prefix(&block.cid)), BytesSer(b"forged")))
+                .unwrap();
+        let decoded = fvm_ipld_encoding::from_slice::<GraphsyncBlock>(&forged).unwrap();
+        assert_ne!(decoded.cid, block.cid);
+        assert_eq!(decoded.cid.codec(), block.cid.codec());
+    }
+}
@@ -0,0 +1,23 @@
+// Copyright 2019-2023 ChainSafe Systems
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+//! [Graphsync](https://github.com/ipfs/go-graphsync/blob/main/docs/architecture.md)
+//! `2.0.0`: fetches DAGs by walking an IPLD selector from a root, as an
+//! alternative to requesting blocks one at a time over `bitswap`.
+//!
+//! Messages are length-prefixed DAG-CBOR, as specified by the
+//! [schema](https://github.com/ipfs/go-graphsync/blob/main/message/ipldbind/schema.ipldsch)
+//! of go-graphsync. Responses are streamed over as many messages as needed,
+//! each one listing the links the traversal loaded since the previous one, and
+//! the last one carrying the final status of the request.
+
+mod behaviour;
+mod codec;
+mod message;
+mod requester;
+mod server;
+
+pub use self::{behaviour::*, codec::*, message::*, requester::*, server::*};
+
+/// Libp2p protocol name for graphsync.
+pub const GRAPHSYNC_PROTOCOL_NAME: &str = "/ipfs/graphsync/2.0.0";
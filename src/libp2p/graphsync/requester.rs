@@ -0,0 +1,323 @@
+// Copyright 2019-2023 ChainSafe Systems
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+use std::{sync::Arc, time::Duration};
+
+use crate::ipld::{
+    selector::{LinkResolver, Selector},
+    Ipld,
+};
+use crate::libp2p::rpc::RequestResponseError;
+use ahash::{HashMap, HashSet};
+use async_trait::async_trait;
+use cid::Cid;
+use fvm_ipld_blockstore::Blockstore;
+use parking_lot::Mutex;
+
+use super::{server::decode_block, GraphsyncReply, GraphsyncResponseStatus};
+
+/// Time given to a responder to send each message of a response.
+pub const GRAPHSYNC_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Replies to a request, forwarded by the network service.
+pub type GraphsyncReplyReceiver = flume::Receiver<Result<GraphsyncReply, RequestResponseError>>;
+
+/// Failure of a graphsync request.
+#[derive(Debug, thiserror::Error)]
+pub enum GraphsyncError {
+    /// The request couldn't be sent to the peer.
+    #[error("{0:?}")]
+    Network(RequestResponseError),
+    /// The response is incomplete or carries blocks that weren't requested.
+    #[error("{0}")]
+    Response(String),
+}
+
+/// Blocks and links received so far for a request.
+#[derive(Default)]
+struct Received {
+    /// Blocks received but not yet reached by the traversal.
+    blocks: HashMap<Cid, Vec<u8>>,
+    /// Links reached by the traversal.
+    loaded: HashSet<Cid>,
+    /// Links the responder doesn't have.
+    missing: HashSet<Cid>,
+    /// Whether the traversal reached links that neither the responder nor the
+    /// store have.
+    incomplete: bool,
+    /// Number of blocks put in the store.
+    stored: usize,
+    /// Final status of the response.
+    status: Option<GraphsyncResponseStatus>,
+    network_error: Option<RequestResponseError>,
+}
+
+impl Received {
+    fn add(&mut self, reply: Result<GraphsyncReply, RequestResponseError>) -> Result<(), String> {
+        let reply = match reply {
+            Ok(reply) => reply,
+            Err(e) => {
+                let message = format!("{e:?}");
+                self.network_error = Some(e);
+                return Err(message);
+            }
+        };
+        let status = reply.response.status;
+        if status.is_failure() {
+            return Err(format!("Status {status:?}"));
+        }
+        for metadatum in reply.response.metadata {
+            if metadatum.action == super::GraphsyncLinkAction::Missing {
+                self.missing.insert(metadatum.link);
+            }
+        }
+        for block in reply.blocks {
+            if !self.loaded.contains(&block.cid) {
+                self.blocks.insert(block.cid, block.data);
+            }
+        }
+        if status.is_terminal() {
+            self.status = Some(status);
+        }
+        Ok(())
+    }
+}
+
+async fn next_reply(
+    replies: &GraphsyncReplyReceiver,
+    timeout: Duration,
+) -> Result<Result<GraphsyncReply, RequestResponseError>, String> {
+    match tokio::time::timeout(timeout, replies.recv_async()).await {
+        Ok(Ok(reply)) => Ok(reply),
+        Ok(Err(_)) => Err("request dropped by the network service".into()),
+        Err(_) => Err("timed out".into()),
+    }
+}
+
+/// [`LinkResolver`] loading blocks from the replies to a request, and from the
+/// store for the blocks it already has.
+struct ReplyResolver<DB> {
+    db: Arc<DB>,
+    replies: GraphsyncReplyReceiver,
+    received: Arc<Mutex<Received>>,
+    timeout: Duration,
+}
+
+impl<DB: Blockstore> ReplyResolver<DB> {
+    /// Puts the block `link` in the store if it was received.
+    fn take_received(&self, link: &Cid) -> Result<Option<Vec<u8>>, String> {
+        let mut received = self.received.lock();
+        let Some(data) = received.blocks.remove(link) else {
+            return Ok(None);
+        };
+        self.db.put_keyed(link, &data).map_err(|e| e.to_string())?;
+        received.loaded.insert(*link);
+        received.stored += 1;
+        Ok(Some(data))
+    }
+}
+
+#[async_trait]
+impl<DB> LinkResolver for ReplyResolver<DB>
+where
+    DB: Blockstore + Send + Sync,
+{
+    async fn load_link(&mut self, link: &Cid) -> Result<Option<Ipld>, String> {
+        if let Some(data) = self.take_received(link)? {
+            return decode_block(link, &data).map(Some);
+        }
+        if let Some(data) = self.db.get(link).map_err(|e| e.to_string())? {
+            self.received.lock().loaded.insert(*link);
+            return decode_block(link, &data).map(Some);
+        }
+        loop {
+            {
+                let mut received = self.received.lock();
+                if received.missing.contains(link) || received.status.is_some() {
+                    received.incomplete = true;
+                    return Ok(None);
+                }
+            }
+            let reply = next_reply(&self.replies, self.timeout).await?;
+            self.received.lock().add(reply)?;
+            if let Some(data) = self.take_received(link)? {
+                return decode_block(link, &data).map(Some);
+            }
+        }
+    }
+}
+
+/// Walks `selector` from `root` over the replies to a request, putting every
+/// block the traversal reaches in the store as soon as it is received. Blocks
+/// the store already has are loaded from it instead. Fails if a block the
+/// traversal reaches is missing, or if the response carries blocks that it
+/// doesn't reach, so that peers can't have unrelated blocks stored.
+/// Returns the number of blocks put in the store.
+pub async fn store_graphsync_response<DB>(
+    db: Arc<DB>,
+    root: Cid,
+    selector: Selector,
+    replies: GraphsyncReplyReceiver,
+    timeout: Duration,
+) -> Result<usize, GraphsyncError>
+where
+    DB: Blockstore + Send + Sync,
+{
+    let received = Arc::new(Mutex::new(Received::default()));
+    let resolver = ReplyResolver {
+        db,
+        replies: replies.clone(),
+        received: received.clone(),
+        timeout,
+    };
+    let failure =
+        |received: &Arc<Mutex<Received>>, e: String| match received.lock().network_error.take() {
+            Some(e) => GraphsyncError::Network(e),
+            None => GraphsyncError::Response(e),
+        };
+    if let Err(e) = selector
+        .walk_all(&Ipld::Link(root), Some(resolver), |_, _, _| Ok(()))
+        .await
+    {
+        return Err(failure(&received, e.to_string()));
+    }
+    // The traversal may end before the final status is received
+    while received.lock().status.is_none() {
+        let reply = next_reply(&replies, timeout)
+            .await
+            .map_err(GraphsyncError::Response)?;
+        let added = received.lock().add(reply);
+        added.map_err(|e| failure(&received, e))?;
+    }
+
+    let received = received.lock();
+    if received.incomplete {
+        return Err(GraphsyncError::Response(format!(
+            "Incomplete response, status {:?}",
+            received.status
+        )));
+    }
+    if !received.blocks.is_empty() {
+        return Err(GraphsyncError::Response(format!(
+            "{} blocks are not traversed by the selector",
+            received.blocks.len()
+        )));
+    }
+    Ok(received.stored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MemoryDB;
+    use crate::libp2p::graphsync::{
+        make_graphsync_response, GraphsyncBlock, GraphsyncMessage, GraphsyncRequestId,
+    };
+    use crate::utils::db::CborStoreExt;
+
+    /// Sends the replies of the response of `db` to the request.
+    async fn replies(db: Arc<MemoryDB>, root: Cid, selector: Selector) -> GraphsyncReplyReceiver {
+        let (tx, rx) = flume::unbounded();
+        make_graphsync_response(db, GraphsyncRequestId::random(), root, selector, move |m| {
+            let GraphsyncMessage {
+                blocks,
+                mut responses,
+                ..
+            } = m;
+            let _ = tx.send(Ok(GraphsyncReply {
+                response: responses.remove(0),
+                blocks,
+            }));
+            Ok(())
+        })
+        .await;
+        rx
+    }
+
+    fn dag() -> (Arc<MemoryDB>, Cid) {
+        let db = Arc::new(MemoryDB::default());
+        let leaf = db.put_cbor_default(&"leaf").unwrap();
+        let other = db.put_cbor_default(&"other").unwrap();
+        let root = db.put_cbor_default(&(leaf, other, leaf)).unwrap();
+        (db, root)
+    }
+
+    #[tokio::test]
+    async fn stores_traversed_blocks() {
+        let (remote, root) = dag();
+        let selector = Selector::explore_all_recursively();
+        let rx = replies(remote.clone(), root, selector.clone()).await;
+        let local = Arc::new(MemoryDB::default());
+        let stored =
+            store_graphsync_response(local.clone(), root, selector.clone(), rx, GRAPHSYNC_TIMEOUT)
+                .await
+                .unwrap();
+        assert_eq!(stored, 3);
+        assert!(local.has(&root).unwrap());
+
+        // Blocks already in the store aren't stored again
+        let rx = replies(remote, root, selector.clone()).await;
+        let stored = store_graphsync_response(local, root, selector, rx, GRAPHSYNC_TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(stored, 0);
+    }
+
+    #[tokio::test]
+    async fn rejects_unrelated_blocks() {
+        let (remote, root) = dag();
+        let selector = Selector::explore_all_recursively();
+        let rx = replies(remote.clone(), root, selector.clone()).await;
+        let mut reply = rx.recv().unwrap().unwrap();
+        let unrelated = remote.put_cbor_default(&"unrelated").unwrap();
+        reply.blocks.push(GraphsyncBlock {
+            cid: unrelated,
+            data: remote.get(&unrelated).unwrap().unwrap(),
+        });
+        let (tx, rx) = flume::unbounded();
+        tx.send(Ok(reply)).unwrap();
+        let local = Arc::new(MemoryDB::default());
+        assert!(matches!(
+            store_graphsync_response(local.clone(), root, selector, rx, GRAPHSYNC_TIMEOUT).await,
+            Err(GraphsyncError::Response(_))
+        ));
+        assert!(!local.has(&unrelated).unwrap());
+    }
+
+    #[tokio::test]
+    async fn rejects_incomplete_responses() {
+        let (remote, root) = dag();
+        let selector = Selector::explore_all_recursively();
+        let rx = replies(remote, root, selector.clone()).await;
+        let mut reply = rx.recv().unwrap().unwrap();
+        reply.blocks.pop();
+        let (tx, rx) = flume::unbounded();
+        tx.send(Ok(reply)).unwrap();
+        assert!(store_graphsync_response(
+            Arc::new(MemoryDB::default()),
+            root,
+            selector.clone(),
+            rx,
+            GRAPHSYNC_TIMEOUT
+        )
+        .await
+        .is_err());
+
+        let (tx, rx) = flume::unbounded();
+        tx.send(Err(RequestResponseError::UnsupportedProtocols))
+            .unwrap();
+        assert!(matches!(
+            store_graphsync_response(
+                Arc::new(MemoryDB::default()),
+                root,
+                selector,
+                rx,
+                GRAPHSYNC_TIMEOUT
+            )
+            .await,
+            Err(GraphsyncError::Network(
+                RequestResponseError::UnsupportedProtocols
+            ))
+        ));
+    }
+}
@@ -0,0 +1,407 @@
+// Copyright 2019-2023 ChainSafe Systems
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use crate::cid_collections::CidHashSet;
+use crate::ipld::{
+    selector::{LinkResolver, Selector},
+    Ipld,
+};
+use crate::utils::encoding::from_slice_with_fallback;
+use ahash::HashMap;
+use async_trait::async_trait;
+use cid::Cid;
+use fvm_ipld_blockstore::Blockstore;
+use fvm_ipld_encoding::{DAG_CBOR, IPLD_RAW};
+use libp2p::PeerId;
+use parking_lot::Mutex;
+use tokio::sync::Semaphore;
+use tracing::{debug, trace};
+
+use super::*;
+
+/// Maximum number of bytes of blocks in a message, messages reaching it are
+/// sent and the response continues in a new message.
+pub const MAX_MESSAGE_BLOCK_BYTES: usize = 512 * 1024;
+/// Maximum number of responses served concurrently.
+const MAX_CONCURRENT_RESPONSES: usize = 4;
+/// Maximum number of messages of a response being written to the network. The
+/// traversal waits for the oldest one to be written before sending more.
+const MAX_MESSAGES_IN_FLIGHT: usize = 4;
+
+/// Channel of the messages to send to peers, each one with a channel told
+/// whether it was written.
+pub type GraphsyncMessageSender = flume::Sender<(PeerId, GraphsyncMessage, flume::Sender<bool>)>;
+
+/// Answers graphsync requests by walking their selector over the blockstore.
+pub struct GraphsyncServer {
+    concurrency: Semaphore,
+    /// Cancellation flags of the requests being served.
+    active: Mutex<HashMap<(PeerId, GraphsyncRequestId), Arc<AtomicBool>>>,
+}
+
+impl Default for GraphsyncServer {
+    fn default() -> Self {
+        Self {
+            concurrency: Semaphore::new(MAX_CONCURRENT_RESPONSES),
+            active: Default::default(),
+        }
+    }
+}
+
+impl GraphsyncServer {
+    /// Serves a new request from `peer`, sending the messages of the response
+    /// to `sender`. Requests arriving while [`MAX_CONCURRENT_RESPONSES`]
+    /// responses are being served are answered with
+    /// [`GraphsyncResponseStatus::RequestFailedBusy`].
+    pub async fn serve<DB>(
+        &self,
+        db: Arc<DB>,
+        peer: PeerId,
+        request: GraphsyncRequest,
+        sender: GraphsyncMessageSender,
+    ) where
+        DB: Blockstore + Send + Sync + 'static,
+    {
+        let id = request.id;
+        let (Some(root), Some(selector)) = (request.root, request.selector) else {
+            send_status(&sender, peer, id, GraphsyncResponseStatus::RequestRejected);
+            return;
+        };
+        let Ok(_permit) = self.concurrency.try_acquire() else {
+            send_status(
+                &sender,
+                peer,
+                id,
+                GraphsyncResponseStatus::RequestFailedBusy,
+            );
+            return;
+        };
+        let cancelled = Arc::new(AtomicBool::new(false));
+        self.active.lock().insert((peer, id), cancelled.clone());
+
+        let mut in_flight = VecDeque::new();
+        let send = move |message| {
+            if cancelled.load(Ordering::Relaxed) {
+                return Err("request cancelled".to_string());
+            }
+            if in_flight.len() >= MAX_MESSAGES_IN_FLIGHT {
+                let written: flume::Receiver<bool> = in_flight.pop_front().expect("not empty");
+                if !written.recv().unwrap_or(false) {
+                    return Err("failed to send message".to_string());
+                }
+            }
+            let (tx, rx) = flume::bounded(1);
+            sender
+                .send((peer, message, tx))
+                .map_err(|_| "network service stopped".to_string())?;
+            in_flight.push_back(rx);
+            Ok(())
+        };
+        if let Err(e) = tokio::task::spawn_blocking(move || {
+            futures::executor::block_on(make_graphsync_response(db, id, root, selector, send))
+        })
+        .await
+        {
+            debug!("Failed to serve graphsync request: {e}");
+        }
+        self.active.lock().remove(&(peer, id));
+    }
+
+    /// Stops serving the request `id` of `peer`.
+    pub fn cancel(&self, peer: PeerId, id: GraphsyncRequestId) {
+        if let Some(cancelled) = self.active.lock().get(&(peer, id)) {
+            cancelled.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+fn send_status(
+    sender: &GraphsyncMessageSender,
+    peer: PeerId,
+    id: GraphsyncRequestId,
+    status: GraphsyncResponseStatus,
+) {
+    let message = GraphsyncMessage {
+        responses: vec![GraphsyncResponse {
+            extensions: None,
+            metadata: vec![],
+            status,
+            id,
+        }],
+        ..Default::default()
+    };
+    let (tx, _) = flume::bounded(1);
+    if sender.send((peer, message, tx)).is_err() {
+        debug!("Failed to send graphsync response: network service stopped");
+    }
+}
+
+/// Decodes a block loaded by a traversal. Links to raw blocks, such as the
+/// code of actors, are leaves.
+pub(super) fn decode_block(cid: &Cid, data: &[u8]) -> Result<Ipld, String> {
+    match cid.codec() {
+        DAG_CBOR => from_slice_with_fallback(data).map_err(|e| e.to_string()),
+        IPLD_RAW => Ok(Ipld::Bytes(data.to_vec())),
+        codec => Err(format!("unsupported codec {codec:#x} of {cid}")),
+    }
+}
+
+/// The message of a response being built.
+struct ResponseMessage<F> {
+    id: GraphsyncRequestId,
+    metadata: Vec<GraphsyncMetadatum>,
+    blocks: Vec<GraphsyncBlock>,
+    bytes: usize,
+    sent: CidHashSet,
+    missing: bool,
+    send: F,
+}
+
+impl<F> ResponseMessage<F>
+where
+    F: FnMut(GraphsyncMessage) -> Result<(), String>,
+{
+    fn record(&mut self, link: &Cid, data: Option<&[u8]>) -> Result<(), String> {
+        let action = match data {
+            None => {
+                self.missing = true;
+                GraphsyncLinkAction::Missing
+            }
+            Some(data) if self.sent.insert(*link) => {
+                if !self.blocks.is_empty() && self.bytes + data.len() > MAX_MESSAGE_BLOCK_BYTES {
+                    self.flush(GraphsyncResponseStatus::PartialResponse)?;
+                }
+                self.bytes += data.len();
+                self.blocks.push(GraphsyncBlock {
+                    cid: *link,
+                    data: data.to_vec(),
+                });
+                GraphsyncLinkAction::Present
+            }
+            Some(_) => GraphsyncLinkAction::DuplicateNotSent,
+        };
+        self.metadata.push(GraphsyncMetadatum {
+            link: *link,
+            action,
+        });
+        Ok(())
+    }
+
+    fn flush(&mut self, status: GraphsyncResponseStatus) -> Result<(), String> {
+        self.bytes = 0;
+        (self.send)(GraphsyncMessage {
+            blocks: std::mem::take(&mut self.blocks),
+            responses: vec![GraphsyncResponse {
+                extensions: None,
+                metadata: std::mem::take(&mut self.metadata),
+                status,
+                id: self.id,
+            }],
+            ..Default::default()
+        })
+    }
+}
+
+/// [`LinkResolver`] loading blocks from a blockstore and adding them to the
+/// response.
+struct BlockstoreResolver<DB, F> {
+    db: Arc<DB>,
+    message: Arc<Mutex<ResponseMessage<F>>>,
+}
+
+#[async_trait]
+impl<DB, F> LinkResolver for BlockstoreResolver<DB, F>
+where
+    DB: Blockstore + Send + Sync,
+    F: FnMut(GraphsyncMessage) -> Result<(), String> + Send,
+{
+    async fn load_link(&mut self, link: &Cid) -> Result<Option<Ipld>, String> {
+        let data = self.db.get(link).map_err(|e| e.to_string())?;
+        self.message.lock().record(link, data.as_deref())?;
+        data.map(|data| decode_block(link, &data)).transpose()
+    }
+}
+
+/// Walks `selector` from `root` and sends the response to the request `id`
+/// with `send`, in messages carrying at most [`MAX_MESSAGE_BLOCK_BYTES`] of
+/// blocks. The response is abandoned if `send` fails.
+pub async fn make_graphsync_response<DB, F>(
+    db: Arc<DB>,
+    id: GraphsyncRequestId,
+    root: Cid,
+    selector: Selector,
+    send: F,
+) where
+    DB: Blockstore + Send + Sync,
+    F: FnMut(GraphsyncMessage) -> Result<(), String> + Send,
+{
+    let message = Arc::new(Mutex::new(ResponseMessage {
+        id,
+        metadata: vec![],
+        blocks: vec![],
+        bytes: 0,
+        sent: CidHashSet::default(),
+        missing: false,
+        send,
+    }));
+    let resolver = BlockstoreResolver {
+        db,
+        message: message.clone(),
+    };
+    let result = selector
+        .walk_all(&Ipld::Link(root), Some(resolver), |progress, _, _| {
+            trace!(
+                "Graphsync traversal of {root} at /{} in block {:?}",
+                progress.path().to_string(),
+                progress.last_block().map(|block| block.link)
+            );
+            Ok(())
+        })
+        .await;
+
+    let mut message = message.lock();
+    let status = if message.sent.len() == 0 {
+        GraphsyncResponseStatus::RequestFailedContentNotFound
+    } else if let Err(e) = result {
+        debug!("Graphsync traversal of {root} failed: {e}");
+        GraphsyncResponseStatus::RequestFailedUnknown
+    } else if message.missing {
+        GraphsyncResponseStatus::RequestCompletedPartial
+    } else {
+        GraphsyncResponseStatus::RequestCompletedFull
+    };
+    if let Err(e) = message.flush(status) {
+        debug!("Failed to send graphsync response: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MemoryDB;
+    use crate::utils::db::CborStoreExt;
+
+    /// Returns the messages of the response to a request.
+    async fn response(
+        db: Arc<MemoryDB>,
+        root: Cid,
+        selector: Selector,
+    ) -> (GraphsyncRequestId, Vec<GraphsyncMessage>) {
+        let id = GraphsyncRequestId::random();
+        let messages = Arc::new(Mutex::new(vec![]));
+        let sink = messages.clone();
+        make_graphsync_response(db, id, root, selector, move |message| {
+            sink.lock().push(message);
+            Ok(())
+        })
+        .await;
+        let messages = std::mem::take(&mut *messages.lock());
+        (id, messages)
+    }
+
+    fn metadata(messages: &[GraphsyncMessage]) -> Vec<(Cid, GraphsyncLinkAction)> {
+        messages
+            .iter()
+            .flat_map(|message| &message.responses[0].metadata)
+            .map(|metadatum| (metadatum.link, metadatum.action))
+            .collect()
+    }
+
+    fn status(messages: &[GraphsyncMessage]) -> GraphsyncResponseStatus {
+        messages.last().unwrap().responses[0].status
+    }
+
+    #[tokio::test]
+    async fn serves_selected_blocks() {
+        let db = Arc::new(MemoryDB::default());
+        let leaf = db.put_cbor_default(&"leaf").unwrap();
+        let other = db.put_cbor_default(&"other").unwrap();
+        let root = db.put_cbor_default(&(leaf, other, leaf)).unwrap();
+
+        let (_, messages) = response(db.clone(), root, Selector::Matcher).await;
+        assert_eq!(
+            status(&messages),
+            GraphsyncResponseStatus::RequestCompletedFull
+        );
+        assert_eq!(messages[0].blocks.len(), 1);
+
+        let (id, messages) = response(db.clone(), root, Selector::explore_all_recursively()).await;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].responses[0].id, id);
+        assert_eq!(
+            status(&messages),
+            GraphsyncResponseStatus::RequestCompletedFull
+        );
+        use GraphsyncLinkAction::*;
+        assert_eq!(
+            metadata(&messages),
+            vec![
+                (root, Present),
+                (leaf, Present),
+                (other, Present),
+                (leaf, DuplicateNotSent)
+            ]
+        );
+        let cids: Vec<_> = messages[0].blocks.iter().map(|block| block.cid).collect();
+        assert_eq!(cids, vec![root, leaf, other]);
+    }
+
+    #[tokio::test]
+    async fn splits_large_responses() {
+        let db = Arc::new(MemoryDB::default());
+        let data = vec![0; MAX_MESSAGE_BLOCK_BYTES / 3];
+        let leaves: Vec<_> = (0..4u8)
+            .map(|i| db.put_cbor_default(&(i, &data)).unwrap())
+            .collect();
+        let root = db.put_cbor_default(&leaves).unwrap();
+
+        let (_, messages) = response(db, root, Selector::explore_all_recursively()).await;
+        // The root and two leaves, then the last two leaves
+        assert_eq!(messages.len(), 2);
+        assert_eq!(
+            messages[0].responses[0].status,
+            GraphsyncResponseStatus::PartialResponse
+        );
+        assert_eq!(messages[0].blocks.len(), 3);
+        for message in &messages {
+            let bytes: usize = message.blocks.iter().map(|block| block.data.len()).sum();
+            assert!(bytes <= MAX_MESSAGE_BLOCK_BYTES);
+        }
+        assert_eq!(
+            status(&messages),
+            GraphsyncResponseStatus::RequestCompletedFull
+        );
+        assert_eq!(metadata(&messages).len(), 5);
+    }
+
+    #[tokio::test]
+    async fn reports_missing_blocks() {
+        let db = Arc::new(MemoryDB::default());
+        let missing = MemoryDB::default().put_cbor_default(&"missing").unwrap();
+        let root = db.put_cbor_default(&vec![missing]).unwrap();
+
+        let (_, messages) = response(db.clone(), root, Selector::explore_all_recursively()).await;
+        assert_eq!(
+            status(&messages),
+            GraphsyncResponseStatus::RequestCompletedPartial
+        );
+        assert_eq!(
+            metadata(&messages).last(),
+            Some(&(missing, GraphsyncLinkAction::Missing))
+        );
+
+        let (_, messages) = response(db, missing, Selector::Matcher).await;
+        assert_eq!(
+            status(&messages),
+            GraphsyncResponseStatus::RequestFailedContentNotFound
+        );
+    }
+}
@@ -1,15 +1,20 @@
 // Copyright 2019-2023 ChainSafe Systems
 // SPDX-License-Identifier: Apache-2.0, MIT
 
+pub mod bandwidth;
 mod behaviour;
+mod block_list;
 pub mod chain_exchange;
 mod config;
+mod dag_fetch;
 mod discovery;
 mod gossip_params;
+pub mod graphsync;
 pub mod hello;
 pub mod keypair;
 mod metrics;
 mod peer_manager;
+mod protection;
 pub mod rpc;
 mod service;
 
@@ -21,7 +26,13 @@ pub use libp2p::{
 };
 
 pub(in crate::libp2p) use self::behaviour::*;
-pub use self::{config::*, peer_manager::*, service::*};
+pub use self::{
+    block_list::BlockList,
+    config::*,
+    dag_fetch::{fetch_dag, DagFetchStats},
+    peer_manager::*,
+    service::*,
+};
 #[cfg(test)]
 mod tests {
     mod decode_test;
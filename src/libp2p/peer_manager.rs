@@ -8,13 +8,15 @@ use std::{
 };
 
 use crate::blocks::Tipset;
+use crate::shim::clock::ChainEpoch;
 use ahash::{HashMap, HashSet};
 use flume::{Receiver, Sender};
+use parking_lot::RwLock as SyncRwLock;
 use rand::seq::SliceRandom;
 use tokio::sync::RwLock;
 use tracing::{debug, trace, warn};
 
-use crate::libp2p::*;
+use crate::libp2p::{protection::ProtectedPeers, *};
 
 /// New peer multiplier slightly less than 1 to incentivize choosing new peers.
 const NEW_PEER_MUL: f64 = 0.9;
@@ -51,6 +53,49 @@ impl PeerInfo {
     }
 }
 
+/// Direction of the first connection established with a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionDirection {
+    /// The peer dialed us.
+    Inbound,
+    /// We dialed the peer.
+    Outbound,
+}
+
+/// What is known about a connected peer from the swarm and `identify`.
+#[derive(Debug, Default, Clone)]
+struct PeerConnectionInfo {
+    agent_version: Option<String>,
+    protocols: Vec<String>,
+    direction: Option<ConnectionDirection>,
+}
+
+/// Snapshot of the statistics the peer manager keeps about a peer.
+#[derive(Debug, Clone)]
+pub struct PeerStats {
+    /// Epoch of the peer's head tipset, from the hello protocol.
+    pub head_epoch: Option<ChainEpoch>,
+    /// Number of successful requests.
+    pub successes: u32,
+    /// Number of failed requests.
+    pub failures: u32,
+    /// Average response time for the peer.
+    pub average_time: Duration,
+    /// Cost used to rank `ChainExchange` peers, lower is better. `None` if
+    /// the peer is not ranked or has failed without ever succeeding.
+    pub cost: Option<f64>,
+    /// Whether the peer is marked as bad.
+    pub bad: bool,
+    /// Whether the peer is protected from being banned.
+    pub protected: bool,
+    /// Agent version reported by `identify`.
+    pub agent_version: Option<String>,
+    /// Protocols reported by `identify`.
+    pub protocols: Vec<String>,
+    /// Direction of the first established connection.
+    pub direction: Option<ConnectionDirection>,
+}
+
 /// Peer tracking sets, these are handled together to avoid race conditions or
 /// deadlocks when updating state.
 #[derive(Default)]
@@ -75,6 +120,10 @@ pub struct PeerManager {
     peer_ops_rx: Receiver<PeerOperation>,
     /// Peer ban list, key is peer id, value is expiration time
     peer_ban_list: RwLock<HashMap<PeerId, Option<Instant>>>,
+    /// Peers that are never banned, disconnected or removed
+    protected_peers: ProtectedPeers,
+    /// Identity and connection direction of connected peers
+    connections: SyncRwLock<HashMap<PeerId, PeerConnectionInfo>>,
 }
 
 impl Default for PeerManager {
@@ -86,6 +135,8 @@ impl Default for PeerManager {
             peer_ops_tx,
             peer_ops_rx,
             peer_ban_list: Default::default(),
+            protected_peers: Default::default(),
+            connections: Default::default(),
         }
     }
 }
@@ -118,17 +169,7 @@ impl PeerManager {
         let mut peers: Vec<_> = peer_lk
             .full_peers
             .iter()
-            .map(|(p, info)| {
-                let cost = if (info.successes + info.failures) > 0 {
-                    // Calculate cost based on fail rate and latency
-                    let fail_rate = f64::from(info.failures) / f64::from(info.successes);
-                    info.average_time.as_secs_f64() + fail_rate * average_time.as_secs_f64()
-                } else {
-                    // There have been no failures or successes
-                    average_time.as_secs_f64() * NEW_PEER_MUL
-                };
-                (p, cost)
-            })
+            .map(|(p, info)| (p, peer_cost(info, *average_time)))
             .collect();
 
         // Unstable sort because hashmap iter order doesn't need to be preserved.
@@ -203,8 +244,12 @@ impl PeerManager {
     }
 
     /// Removes a peer from the set and returns true if the value was present
-    /// previously
+    /// previously. Protected peers are neither removed nor marked as bad.
     pub async fn mark_peer_bad(&self, peer_id: PeerId) -> bool {
+        if self.is_peer_protected(&peer_id) {
+            debug!("Not marking protected peer {peer_id} bad");
+            return false;
+        }
         let mut peers = self.peers.write().await;
         let removed = remove_peer(&mut peers, &peer_id);
         if removed {
@@ -220,8 +265,12 @@ impl PeerManager {
         removed
     }
 
-    /// Remove peer from managed set, does not mark as bad
+    /// Remove peer from managed set, does not mark as bad. Protected peers are
+    /// kept.
     pub async fn remove_peer(&self, peer_id: &PeerId) -> bool {
+        if self.is_peer_protected(peer_id) {
+            return false;
+        }
         let mut peers = self.peers.write().await;
         debug!("removed peer {}", peer_id);
         let removed = remove_peer(&mut peers, peer_id);
@@ -236,13 +285,102 @@ impl PeerManager {
         &self.peer_ops_rx
     }
 
-    /// Bans a peer with an optional duration
+    /// Records the direction of the first connection with a peer.
+    pub fn peer_connected(&self, peer: PeerId, direction: ConnectionDirection) {
+        self.connections
+            .write()
+            .entry(peer)
+            .or_default()
+            .direction
+            .get_or_insert(direction);
+    }
+
+    /// Forgets the connection information of a peer once all connections with
+    /// it are closed.
+    pub fn peer_disconnected(&self, peer: &PeerId) {
+        self.connections.write().remove(peer);
+    }
+
+    /// Records the agent version and protocols reported by `identify`.
+    pub fn update_peer_identity(
+        &self,
+        peer: PeerId,
+        agent_version: String,
+        protocols: Vec<String>,
+    ) {
+        let mut connections = self.connections.write();
+        let info = connections.entry(peer).or_default();
+        info.agent_version = Some(agent_version);
+        info.protocols = protocols;
+    }
+
+    /// Returns a snapshot of the statistics of a peer, or `None` if nothing is
+    /// known about it.
+    pub async fn peer_stats(&self, peer: &PeerId) -> Option<PeerStats> {
+        let peers = self.peers.read().await;
+        let average_time = *self.avg_global_time.read().await;
+        let info = peers.full_peers.get(peer);
+        let bad = peers.bad_peers.contains(peer);
+        let connection = self.connections.read().get(peer).cloned();
+        if info.is_none() && !bad && connection.is_none() {
+            return None;
+        }
+        let connection = connection.unwrap_or_default();
+        Some(PeerStats {
+            head_epoch: info
+                .and_then(|info| info.head.as_ref())
+                .map(|ts| ts.epoch()),
+            successes: info.map(|info| info.successes).unwrap_or_default(),
+            failures: info.map(|info| info.failures).unwrap_or_default(),
+            average_time: info.map(|info| info.average_time).unwrap_or_default(),
+            cost: info
+                .map(|info| peer_cost(info, average_time))
+                .filter(|cost| cost.is_finite()),
+            bad,
+            protected: self.is_peer_protected(peer),
+            agent_version: connection.agent_version,
+            protocols: connection.protocols,
+            direction: connection.direction,
+        })
+    }
+
+    /// Protects a peer from being banned, disconnected or removed. Returns true
+    /// if the peer was not protected already.
+    pub fn protect_peer(&self, peer: PeerId) -> bool {
+        self.protected_peers.write().insert(peer)
+    }
+
+    /// Lifts the protection of a peer. Returns true if the peer was protected.
+    pub fn unprotect_peer(&self, peer: &PeerId) -> bool {
+        self.protected_peers.write().remove(peer)
+    }
+
+    /// Returns true if the peer is protected.
+    pub fn is_peer_protected(&self, peer: &PeerId) -> bool {
+        self.protected_peers.read().contains(peer)
+    }
+
+    /// Returns the set of protected peers, shared with the swarm behaviour.
+    pub(in crate::libp2p) fn protected_peers(&self) -> ProtectedPeers {
+        self.protected_peers.clone()
+    }
+
+    /// Returns the protected peers.
+    pub fn list_protected_peers(&self) -> Vec<PeerId> {
+        self.protected_peers.read().iter().copied().collect()
+    }
+
+    /// Bans a peer with an optional duration. Protected peers are never banned.
     pub async fn ban_peer(
         &self,
         peer: PeerId,
         reason: impl Into<String>,
         duration: Option<Duration>,
     ) {
+        if self.is_peer_protected(&peer) {
+            debug!("Not banning protected peer {peer}: {}", reason.into());
+            return;
+        }
         let mut locked = self.peer_ban_list.write().await;
         locked.insert(peer, duration.and_then(|d| Instant::now().checked_add(d)));
         if let Err(e) = self
@@ -289,6 +427,18 @@ impl PeerManager {
     }
 }
 
+/// Cost of sending requests to a peer, based on its success rate and latency.
+fn peer_cost(info: &PeerInfo, average_time: Duration) -> f64 {
+    if (info.successes + info.failures) > 0 {
+        // Calculate cost based on fail rate and latency
+        let fail_rate = f64::from(info.failures) / f64::from(info.successes);
+        info.average_time.as_secs_f64() + fail_rate * average_time.as_secs_f64()
+    } else {
+        // There have been no failures or successes
+        average_time.as_secs_f64() * NEW_PEER_MUL
+    }
+}
+
 fn remove_peer(peers: &mut PeerSets, peer_id: &PeerId) -> bool {
     debug!(
         "removing peer {:?}, remaining chain exchange peers: {}",
@@ -315,3 +465,29 @@ pub enum PeerOperation {
     Ban(PeerId, String),
     Unban(PeerId),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn protected_peers_are_not_pruned() {
+        let peer_manager = PeerManager::default();
+        let (protected, other) = (PeerId::random(), PeerId::random());
+        for peer in [protected, other] {
+            peer_manager.log_success(peer, Duration::from_secs(1)).await;
+        }
+        peer_manager.protect_peer(protected);
+
+        assert!(!peer_manager.mark_peer_bad(protected).await);
+        assert!(!peer_manager.remove_peer(&protected).await);
+        assert!(peer_manager.mark_peer_bad(other).await);
+        assert_eq!(peer_manager.sorted_peers().await, vec![protected]);
+        assert!(!peer_manager
+            .peers
+            .read()
+            .await
+            .bad_peers
+            .contains(&protected));
+    }
+}
@@ -0,0 +1,276 @@
+// Copyright 2019-2023 ChainSafe Systems
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+use std::{
+    convert::Infallible,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use ahash::HashSet;
+use libp2p::{
+    connection_limits,
+    core::{upgrade::DeniedUpgrade, Endpoint},
+    swarm::{
+        behaviour::ConnectionClosed,
+        dummy,
+        handler::{
+            ConnectionEvent, ConnectionHandlerEvent, FullyNegotiatedInbound,
+            FullyNegotiatedOutbound, KeepAlive, SubstreamProtocol,
+        },
+        ConnectionDenied, ConnectionHandler, ConnectionId, FromSwarm, NetworkBehaviour,
+        PollParameters, THandler, THandlerInEvent, THandlerOutEvent, ToSwarm,
+    },
+    Multiaddr, PeerId,
+};
+use parking_lot::RwLock;
+
+/// Peers the node never disconnects from, shared by the
+/// [`crate::libp2p::PeerManager`] and the [`ProtectionBehaviour`].
+pub(in crate::libp2p) type ProtectedPeers = Arc<RwLock<HashSet<PeerId>>>;
+
+/// Enforces the connection limits, except on protected peers, and keeps the
+/// connections with protected peers alive, so they are not closed when idle.
+pub(in crate::libp2p) struct ProtectionBehaviour {
+    limits: connection_limits::Behaviour,
+    protected: ProtectedPeers,
+}
+
+impl ProtectionBehaviour {
+    pub fn new(limits: connection_limits::ConnectionLimits, protected: ProtectedPeers) -> Self {
+        Self {
+            limits: connection_limits::Behaviour::new(limits),
+            protected,
+        }
+    }
+
+    fn is_protected(&self, peer: &PeerId) -> bool {
+        self.protected.read().contains(peer)
+    }
+
+    /// Lets the connections with protected peers through the limits. The
+    /// limits are still checked, for them to account for these connections.
+    fn exempt<T>(
+        &self,
+        peer: Option<&PeerId>,
+        checked: Result<T, ConnectionDenied>,
+    ) -> Result<(), ConnectionDenied> {
+        match checked {
+            Err(_) if peer.is_some_and(|peer| self.is_protected(peer)) => Ok(()),
+            checked => checked.map(|_| ()),
+        }
+    }
+
+    fn handler(&self, peer: PeerId) -> ProtectionHandler {
+        ProtectionHandler {
+            peer,
+            protected: self.protected.clone(),
+        }
+    }
+}
+
+impl NetworkBehaviour for ProtectionBehaviour {
+    type ConnectionHandler = ProtectionHandler;
+    type ToSwarm = Infallible;
+
+    fn handle_pending_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<(), ConnectionDenied> {
+        // The peer is not known yet
+        self.limits
+            .handle_pending_inbound_connection(connection_id, local_addr, remote_addr)
+    }
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        let checked = self.limits.handle_established_inbound_connection(
+            connection_id,
+            peer,
+            local_addr,
+            remote_addr,
+        );
+        self.exempt(Some(&peer), checked)?;
+        Ok(self.handler(peer))
+    }
+
+    fn handle_pending_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: Option<PeerId>,
+        addresses: &[Multiaddr],
+        effective_role: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        let checked = self.limits.handle_pending_outbound_connection(
+            connection_id,
+            peer,
+            addresses,
+            effective_role,
+        );
+        self.exempt(peer.as_ref(), checked)?;
+        Ok(vec![])
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        addr: &Multiaddr,
+        role_override: Endpoint,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        let checked = self.limits.handle_established_outbound_connection(
+            connection_id,
+            peer,
+            addr,
+            role_override,
+        );
+        self.exempt(Some(&peer), checked)?;
+        Ok(self.handler(peer))
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm<Self::ConnectionHandler>) {
+        // Only the events the limits are counted from are forwarded
+        let event = match event {
+            FromSwarm::ConnectionEstablished(e) => FromSwarm::ConnectionEstablished(e),
+            FromSwarm::ConnectionClosed(ConnectionClosed {
+                peer_id,
+                connection_id,
+                endpoint,
+                remaining_established,
+                ..
+            }) => FromSwarm::ConnectionClosed(ConnectionClosed {
+                peer_id,
+                connection_id,
+                endpoint,
+                handler: dummy::ConnectionHandler,
+                remaining_established,
+            }),
+            FromSwarm::DialFailure(e) => FromSwarm::DialFailure(e),
+            FromSwarm::ListenFailure(e) => FromSwarm::ListenFailure(e),
+            _ => return,
+        };
+        self.limits.on_swarm_event(event);
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        _: PeerId,
+        _: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        match event {}
+    }
+
+    fn poll(
+        &mut self,
+        _: &mut Context<'_>,
+        _: &mut impl PollParameters,
+    ) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        Poll::Pending
+    }
+}
+
+/// Keeps a connection alive while its peer is protected.
+pub(in crate::libp2p) struct ProtectionHandler {
+    peer: PeerId,
+    protected: ProtectedPeers,
+}
+
+impl ConnectionHandler for ProtectionHandler {
+    type FromBehaviour = Infallible;
+    type ToBehaviour = Infallible;
+    type Error = Infallible;
+    type InboundProtocol = DeniedUpgrade;
+    type OutboundProtocol = DeniedUpgrade;
+    type InboundOpenInfo = ();
+    type OutboundOpenInfo = Infallible;
+
+    fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol, Self::InboundOpenInfo> {
+        SubstreamProtocol::new(DeniedUpgrade, ())
+    }
+
+    fn on_behaviour_event(&mut self, event: Self::FromBehaviour) {
+        match event {}
+    }
+
+    fn connection_keep_alive(&self) -> KeepAlive {
+        if self.protected.read().contains(&self.peer) {
+            KeepAlive::Yes
+        } else {
+            KeepAlive::No
+        }
+    }
+
+    fn poll(
+        &mut self,
+        _: &mut Context<'_>,
+    ) -> Poll<
+        ConnectionHandlerEvent<
+            Self::OutboundProtocol,
+            Self::OutboundOpenInfo,
+            Self::ToBehaviour,
+            Self::Error,
+        >,
+    > {
+        Poll::Pending
+    }
+
+    fn on_connection_event(
+        &mut self,
+        event: ConnectionEvent<
+            Self::InboundProtocol,
+            Self::OutboundProtocol,
+            Self::InboundOpenInfo,
+            Self::OutboundOpenInfo,
+        >,
+    ) {
+        match event {
+            ConnectionEvent::FullyNegotiatedInbound(FullyNegotiatedInbound {
+                protocol, ..
+            }) => match protocol {},
+            ConnectionEvent::FullyNegotiatedOutbound(FullyNegotiatedOutbound {
+                protocol, ..
+            }) => match protocol {},
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn protected_peers_are_exempt_from_limits_and_kept_alive() {
+        let protected = ProtectedPeers::default();
+        let mut behaviour = ProtectionBehaviour::new(
+            connection_limits::ConnectionLimits::default().with_max_established_incoming(Some(0)),
+            protected.clone(),
+        );
+        let (peer, other) = (PeerId::random(), PeerId::random());
+        protected.write().insert(peer);
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/1234".parse().unwrap();
+        let mut connect = |peer| {
+            behaviour.handle_established_inbound_connection(
+                ConnectionId::new_unchecked(0),
+                peer,
+                &addr,
+                &addr,
+            )
+        };
+
+        assert!(connect(other).is_err());
+        let handler = connect(peer).unwrap();
+        assert_eq!(handler.connection_keep_alive(), KeepAlive::Yes);
+        // Connections are closed when idle once the protection is lifted
+        protected.write().remove(&peer);
+        assert_eq!(handler.connection_keep_alive(), KeepAlive::No);
+    }
+}
@@ -38,7 +38,8 @@ where
     B: AsyncRead,
     T: serde::de::DeserializeOwned,
 {
-    type Output = io::Result<T>;
+    /// The decoded item and the number of bytes read.
+    type Output = io::Result<(T, usize)>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Self::Output> {
         // https://github.com/mxinden/asynchronous-codec/blob/master/src/framed_read.rs#L161
@@ -50,7 +51,7 @@ where
             if n == 0 {
                 let item = serde_ipld_dagcbor::de::from_reader(&self.bytes[..])
                     .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()));
-                return Poll::Ready(item);
+                return Poll::Ready(item.map(|item| (item, self.bytes_read)));
             }
             *this.bytes_read += n;
             if *this.max_bytes_allowed > 0 && *this.bytes_read > *this.max_bytes_allowed {
@@ -70,7 +71,7 @@ where
             //
             // Note: `from_reader` ensures no trailing data left in `bytes`
             if let Ok(r) = serde_ipld_dagcbor::de::from_reader(&this.bytes[..]) {
-                return Poll::Ready(Ok(r));
+                return Poll::Ready(Ok((r, *this.bytes_read)));
             }
         }
     }
@@ -8,8 +8,14 @@ use async_trait::async_trait;
 use decoder::DagCborDecodingReader;
 use futures::prelude::*;
 use libp2p::request_response::{self, OutboundFailure};
+
+use crate::libp2p::bandwidth;
 use serde::{de::DeserializeOwned, Serialize};
 
+/// Maximum size of a response, so that peers can't make us buffer responses
+/// without bounds.
+const MAX_RESPONSE_BYTES: u64 = 128 * 1024 * 1024;
+
 /// Generic `Cbor` `RequestResponse` type. This is just needed to satisfy
 /// [`request_response::Codec`] for Hello and `ChainExchange` protocols without
 /// duplication.
@@ -68,7 +74,7 @@ impl From<OutboundFailure> for RequestResponseError {
 #[async_trait]
 impl<P, RQ, RS> request_response::Codec for CborRequestResponse<P, RQ, RS>
 where
-    P: AsRef<str> + Send + Clone,
+    P: AsRef<str> + Send + Sync + Clone,
     RQ: Serialize + DeserializeOwned + Send + Sync,
     RS: Serialize + DeserializeOwned + Send + Sync,
 {
@@ -76,49 +82,68 @@ where
     type Request = RQ;
     type Response = RS;
 
-    async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Request>
+    async fn read_request<T>(
+        &mut self,
+        protocol: &Self::Protocol,
+        io: &mut T,
+    ) -> io::Result<Self::Request>
     where
         T: AsyncRead + Unpin + Send,
     {
-        read_request_and_decode(io).await
+        let (request, bytes_read) = read_request_and_decode(io).await?;
+        bandwidth::record_inbound(protocol.as_ref(), bytes_read);
+        Ok(request)
     }
 
     async fn read_response<T>(
         &mut self,
-        _: &Self::Protocol,
+        protocol: &Self::Protocol,
         io: &mut T,
     ) -> io::Result<Self::Response>
     where
         T: AsyncRead + Unpin + Send,
     {
         let mut bytes = vec![];
-        io.read_to_end(&mut bytes).await?;
+        io.take(MAX_RESPONSE_BYTES + 1)
+            .read_to_end(&mut bytes)
+            .await?;
+        bandwidth::record_inbound(protocol.as_ref(), bytes.len());
+        if bytes.len() as u64 > MAX_RESPONSE_BYTES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("response larger than {MAX_RESPONSE_BYTES} bytes"),
+            ));
+        }
         serde_ipld_dagcbor::de::from_reader(bytes.as_slice())
             .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
     }
 
     async fn write_request<T>(
         &mut self,
-        _: &Self::Protocol,
+        protocol: &Self::Protocol,
         io: &mut T,
         req: Self::Request,
     ) -> io::Result<()>
     where
         T: AsyncWrite + Unpin + Send,
     {
-        encode_and_write(io, req).await
+        let bytes_written = encode_and_write(io, req).await?;
+        bandwidth::record_outbound(protocol.as_ref(), bytes_written);
+        Ok(())
     }
 
     async fn write_response<T>(
         &mut self,
-        _: &Self::Protocol,
+        protocol: &Self::Protocol,
         io: &mut T,
         res: Self::Response,
     ) -> io::Result<()>
     where
         T: AsyncWrite + Unpin + Send,
     {
-        encode_and_write(io, res).await
+        let bytes_written = encode_and_write(io, res).await?;
+        bandwidth::record_outbound(protocol.as_ref(), bytes_written);
+        Ok(())
     }
 }
 
@@ -142,7 +167,8 @@ where
 //
 // `io` is essentially [yamux::Stream](https://docs.rs/yamux/0.11.0/yamux/struct.Stream.html)
 //
-async fn read_request_and_decode<IO, T>(io: &mut IO) -> io::Result<T>
+/// Returns the decoded request and the number of bytes read.
+async fn read_request_and_decode<IO, T>(io: &mut IO) -> io::Result<(T, usize)>
 where
     IO: AsyncRead + Unpin,
     T: serde::de::DeserializeOwned,
@@ -164,7 +190,8 @@ where
     }
 }
 
-async fn encode_and_write<IO, T>(io: &mut IO, data: T) -> io::Result<()>
+/// Returns the number of bytes written.
+async fn encode_and_write<IO, T>(io: &mut IO, data: T) -> io::Result<usize>
 where
     IO: AsyncWrite + Unpin,
     T: serde::Serialize,
@@ -173,5 +200,5 @@ where
         .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
     io.write_all(&bytes).await?;
     io.close().await?;
-    Ok(())
+    Ok(bytes.len())
 }
@@ -6,29 +6,44 @@ use std::{
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+use crate::beacon::GossipSource;
+use crate::db::{
+    setting_keys::{NET_BLOCK_LIST_KEY, NET_PROTECTED_PEERS_KEY},
+    SettingsStore, SettingsStoreExt,
+};
 use crate::libp2p_bitswap::{
-    request_manager::BitswapRequestManager, BitswapStoreRead, BitswapStoreReadWrite,
+    request_manager::{BitswapRequestManager, BitswapSession},
+    BitswapStoreRead, BitswapStoreReadWrite,
 };
 use crate::message::SignedMessage;
-use crate::{blocks::GossipBlock, rpc_api::net_api::NetInfoResult};
+use crate::{
+    blocks::GossipBlock,
+    rpc_api::net_api::{NetBlockList, NetInfoResult},
+};
 use crate::{chain::ChainStore, utils::encoding::from_slice_with_fallback};
 use ahash::{HashMap, HashSet};
 use anyhow::Context as _;
 use cid::Cid;
 use flume::Sender;
 use futures::stream::StreamExt;
-use futures::{channel::oneshot::Sender as OneShotSender, select};
+use futures::{channel::oneshot::Sender as OneShotSender, future::Either, select};
 use fvm_ipld_blockstore::Blockstore;
 use libp2p::connection_limits::Exceeded;
 pub use libp2p::gossipsub::{IdentTopic, Topic};
 use libp2p::swarm::DialError;
 use libp2p::{
-    core::{self, muxing::StreamMuxerBox, transport::Boxed, Multiaddr},
-    gossipsub,
+    autonat,
+    core::{
+        self,
+        muxing::StreamMuxerBox,
+        transport::{Boxed, OrTransport},
+        Multiaddr,
+    },
+    dcutr, gossipsub, identify,
     identity::Keypair,
     metrics::{Metrics, Recorder},
     multiaddr::Protocol,
-    noise, ping,
+    noise, ping, relay,
     request_response::{self, RequestId, ResponseChannel},
     swarm::{SwarmBuilder, SwarmEvent},
     yamux, PeerId, Swarm, Transport,
@@ -37,20 +52,26 @@ use tokio_stream::wrappers::IntervalStream;
 use tracing::{debug, error, info, trace, warn};
 
 use super::{
-    chain_exchange::{make_chain_exchange_response, ChainExchangeRequest, ChainExchangeResponse},
+    chain_exchange::{ChainExchangeRequest, ChainExchangeResponse},
     ForestBehaviour, ForestBehaviourEvent, Libp2pConfig,
 };
 use crate::libp2p::{
+    bandwidth,
+    block_list::BlockList,
     chain_exchange::ChainExchangeBehaviour,
     discovery::DiscoveryEvent,
+    graphsync::{
+        GraphsyncBehaviour, GraphsyncMessage, GraphsyncMessageSender, GraphsyncReply,
+        GraphsyncRequest,
+    },
     hello::{HelloBehaviour, HelloRequest, HelloResponse},
     rpc::RequestResponseError,
-    PeerManager, PeerOperation,
+    ConnectionDirection, PeerManager, PeerOperation, PeerStats,
 };
 
 pub(in crate::libp2p) mod metrics {
     use once_cell::sync::Lazy;
-    use prometheus::core::{AtomicU64, GenericGaugeVec, Opts};
+    use prometheus::core::{AtomicU64, GenericCounterVec, GenericGaugeVec, Opts};
     pub static NETWORK_CONTAINER_CAPACITIES: Lazy<Box<GenericGaugeVec<AtomicU64>>> = {
         Lazy::new(|| {
             let network_container_capacities = Box::new(
@@ -70,13 +91,64 @@ pub(in crate::libp2p) mod metrics {
         })
     };
 
+    pub static NETWORK_CONNECTIONS: Lazy<Box<GenericGaugeVec<AtomicU64>>> = {
+        Lazy::new(|| {
+            let network_connections = Box::new(
+                GenericGaugeVec::<AtomicU64>::new(
+                    Opts::new(
+                        "network_connections",
+                        "Number of established connections per transport",
+                    ),
+                    &[labels::TRANSPORT],
+                )
+                .expect("Defining the network_connections metric must succeed"),
+            );
+            prometheus::default_registry().register(network_connections.clone()).expect(
+            "Registering the network_connections metric with the metrics registry must succeed"
+        );
+            network_connections
+        })
+    };
+
+    pub static CHAIN_EXCHANGE_INBOUND_REQUESTS: Lazy<Box<GenericCounterVec<AtomicU64>>> = {
+        Lazy::new(|| {
+            let chain_exchange_inbound_requests = Box::new(
+                GenericCounterVec::<AtomicU64>::new(
+                    Opts::new(
+                        "chain_exchange_inbound_requests",
+                        "Number of inbound chain exchange requests by outcome",
+                    ),
+                    &[labels::RESULT],
+                )
+                .expect("Defining the chain_exchange_inbound_requests metric must succeed"),
+            );
+            prometheus::default_registry().register(chain_exchange_inbound_requests.clone()).expect(
+            "Registering the chain_exchange_inbound_requests metric with the metrics registry must succeed"
+        );
+            chain_exchange_inbound_requests
+        })
+    };
+
     pub mod values {
         pub const HELLO_REQUEST_TABLE: &str = "hello_request_table";
         pub const CHAIN_EXCHANGE_REQUEST_TABLE: &str = "cx_request_table";
+        pub const GRAPHSYNC_REQUEST_TABLE: &str = "graphsync_request_table";
+        pub const TCP: &str = "tcp";
+        pub const QUIC: &str = "quic";
+        /// Chain exchange request served from the chain store.
+        pub const SERVED: &str = "served";
+        /// Chain exchange request served from the response cache.
+        pub const CACHED: &str = "cached";
+        /// Chain exchange request rejected because the peer exceeded its quota.
+        pub const QUOTA_EXCEEDED: &str = "quota_exceeded";
+        /// Malformed chain exchange request.
+        pub const BAD_REQUEST: &str = "bad_request";
     }
 
     pub mod labels {
         pub const KIND: &str = "kind";
+        pub const TRANSPORT: &str = "transport";
+        pub const RESULT: &str = "result";
     }
 }
 
@@ -91,6 +163,14 @@ pub const BITSWAP_TIMEOUT: Duration = Duration::from_secs(10);
 
 const BAN_PEER_DURATION: Duration = Duration::from_secs(60 * 60); //1h
 
+/// Interval at which the node announces itself on the DHT as a provider of
+/// the head blocks and state root.
+const PROVIDE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Maximum number of circuit relays to hold reservations with when the node is
+/// not publicly reachable.
+const MAX_RELAY_RESERVATIONS: usize = 2;
+
 /// Events emitted by this Service.
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
@@ -160,6 +240,23 @@ pub enum NetworkMessage {
         cid: Cid,
         response_channel: flume::Sender<bool>,
     },
+    /// Starts a [`BitswapSession`] fetching blocks into the store, each block
+    /// being given `timeout` to be fetched.
+    BitswapSession {
+        timeout: Duration,
+        response_channel: flume::Sender<BitswapSession>,
+    },
+    /// Looks up the providers of a block on the DHT and connects to them.
+    FindProviders {
+        cid: Cid,
+        response_channel: flume::Sender<HashSet<PeerId>>,
+    },
+    /// Sends a graphsync request, whose replies are forwarded to the channel.
+    GraphsyncRequest {
+        peer_id: PeerId,
+        request: GraphsyncRequest,
+        response_channel: flume::Sender<Result<GraphsyncReply, RequestResponseError>>,
+    },
     JSONRPCRequest {
         method: NetRPCMethods,
     },
@@ -173,6 +270,17 @@ pub enum NetRPCMethods {
     Info(OneShotSender<NetInfoResult>),
     Connect(OneShotSender<bool>, PeerId, HashSet<Multiaddr>),
     Disconnect(OneShotSender<()>, PeerId),
+    AutoNatStatus(OneShotSender<autonat::NatStatus>),
+    BlockAdd(OneShotSender<anyhow::Result<()>>, BlockList),
+    BlockRemove(OneShotSender<anyhow::Result<()>>, BlockList),
+    BlockList(OneShotSender<BlockList>),
+    ProtectAdd(OneShotSender<anyhow::Result<()>>, Vec<PeerId>),
+    ProtectRemove(OneShotSender<anyhow::Result<()>>, Vec<PeerId>),
+    ProtectList(OneShotSender<Vec<PeerId>>),
+    PeerInfo(
+        OneShotSender<Option<(PeerStats, HashSet<Multiaddr>)>>,
+        PeerId,
+    ),
 }
 
 /// The `Libp2pService` listens to events from the libp2p swarm.
@@ -187,6 +295,8 @@ pub struct Libp2pService<DB> {
     network_sender_out: Sender<NetworkEvent>,
     network_name: String,
     genesis_cid: Cid,
+    /// Sources of the `drand` beacon chains, by gossipsub topic.
+    drand_topics: HashMap<String, Arc<GossipSource>>,
 }
 
 impl<DB> Libp2pService<DB>
@@ -203,12 +313,18 @@ where
     ) -> anyhow::Result<Self> {
         let peer_id = PeerId::from(net_keypair.public());
 
-        let transport =
+        let (transport, relay_client) =
             build_transport(net_keypair.clone()).expect("Failed to build libp2p transport");
 
         let mut swarm = SwarmBuilder::with_tokio_executor(
             transport,
-            ForestBehaviour::new(&net_keypair, &config, network_name)?,
+            ForestBehaviour::new(
+                &net_keypair,
+                &config,
+                network_name,
+                relay_client,
+                peer_manager.protected_peers(),
+            )?,
             peer_id,
         )
         .notify_handler_buffer_size(std::num::NonZeroUsize::new(20).expect("Not zero"))
@@ -216,6 +332,26 @@ where
         .idle_connection_timeout(Duration::from_secs(60 * 10))
         .build();
 
+        // Re-apply the block list and protected peers persisted by the operator
+        let settings = cs.settings();
+        if let Some(block_list) = settings.read_obj::<NetBlockList>(NET_BLOCK_LIST_KEY)? {
+            swarm
+                .behaviour_mut()
+                .block_list
+                .block(BlockList::from_persisted(block_list));
+        }
+        for peer in settings
+            .read_obj::<Vec<String>>(NET_PROTECTED_PEERS_KEY)?
+            .unwrap_or_default()
+        {
+            match peer.parse() {
+                Ok(peer) => {
+                    peer_manager.protect_peer(peer);
+                }
+                Err(_) => warn!("Skipping invalid protected peer ID {peer}"),
+            }
+        }
+
         // Subscribe to gossipsub topics with the network name suffix
         for topic in PUBSUB_TOPICS.iter() {
             let t = Topic::new(format!("{topic}/{network_name}"));
@@ -236,9 +372,24 @@ where
             network_sender_out,
             network_name: network_name.into(),
             genesis_cid,
+            drand_topics: HashMap::default(),
         })
     }
 
+    /// Subscribes to the gossipsub topics of the `drand` beacon chains with hashes
+    /// `chain_hashes`, feeding their [`GossipSource`].
+    pub fn subscribe_drand(&mut self, chain_hashes: &[String]) -> anyhow::Result<()> {
+        for chain_hash in chain_hashes {
+            let topic = GossipSource::topic(chain_hash);
+            self.swarm
+                .behaviour_mut()
+                .subscribe(&Topic::new(topic.clone()))?;
+            self.drand_topics
+                .insert(topic, GossipSource::for_chain(chain_hash));
+        }
+        Ok(())
+    }
+
     /// Starts the libp2p service networking stack. This Future resolves when
     /// shutdown occurs.
     pub async fn run(mut self) -> anyhow::Result<()> {
@@ -259,12 +410,16 @@ where
         let mut network_stream = self.network_receiver_in.stream().fuse();
         let mut interval =
             IntervalStream::new(tokio::time::interval(Duration::from_secs(15))).fuse();
+        let mut provide_interval =
+            IntervalStream::new(tokio::time::interval(PROVIDE_INTERVAL)).fuse();
         let pubsub_block_str = format!("{}/{}", PUBSUB_BLOCK_STR, self.network_name);
         let pubsub_msg_str = format!("{}/{}", PUBSUB_MSG_STR, self.network_name);
 
         let (cx_response_tx, cx_response_rx) = flume::unbounded();
 
         let mut cx_response_rx_stream = cx_response_rx.stream().fuse();
+        let (gs_message_tx, gs_message_rx) = flume::unbounded();
+        let mut gs_message_rx_stream = gs_message_rx.stream().fuse();
         let mut bitswap_outbound_request_rx_stream = bitswap_request_manager
             .outbound_request_rx()
             .stream()
@@ -288,8 +443,31 @@ where
                             &self.genesis_cid,
                             &self.network_sender_out,
                             cx_response_tx.clone(),
+                            gs_message_tx.clone(),
                             &pubsub_block_str,
-                            &pubsub_msg_str,).await;
+                            &pubsub_msg_str,
+                            &self.drand_topics,).await;
+                    },
+                    Some(SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. }) => {
+                        metrics::NETWORK_CONNECTIONS
+                            .with_label_values(&[transport_name(endpoint.get_remote_address())])
+                            .inc();
+                        let direction = if endpoint.is_dialer() {
+                            ConnectionDirection::Outbound
+                        } else {
+                            ConnectionDirection::Inbound
+                        };
+                        self.peer_manager.peer_connected(peer_id, direction);
+                    },
+                    Some(SwarmEvent::ConnectionClosed {
+                        peer_id, endpoint, num_established, ..
+                    }) => {
+                        metrics::NETWORK_CONNECTIONS
+                            .with_label_values(&[transport_name(endpoint.get_remote_address())])
+                            .dec();
+                        if num_established == 0 {
+                            self.peer_manager.peer_disconnected(&peer_id);
+                        }
                     },
                     None => { break; },
                     _ => { },
@@ -300,6 +478,7 @@ where
                         handle_network_message(
                             swarm_stream.get_mut(),
                             self.cs.clone(),
+                            &self.peer_manager,
                             bitswap_request_manager.clone(),
                             message,
                             &self.network_sender_out).await;
@@ -307,9 +486,22 @@ where
                     None => { break; }
                 },
                 interval_event = interval.next() => if interval_event.is_some() {
+                    bandwidth::tick();
                     // Print peer count on an interval.
                     debug!("Peers connected: {}", swarm_stream.get_mut().behaviour_mut().peers().len());
                 },
+                provide_event = provide_interval.next() => if provide_event.is_some() {
+                    // Announce the blocks and state root of the head, which right after a
+                    // snapshot import are the roots of the snapshot.
+                    let head = self.cs.heaviest_tipset();
+                    let behaviour = swarm_stream.get_mut().behaviour_mut();
+                    for cid in head.cids().into_iter().chain([*head.parent_state()]) {
+                        if let Err(e) = behaviour.start_providing(cid) {
+                            debug!("Failed to provide {cid}: {e}");
+                            break;
+                        }
+                    }
+                },
                 cs_pair_opt = cx_response_rx_stream.next() => {
                     if let Some((_request_id, channel, cx_response)) = cs_pair_opt {
                         let behaviour = swarm_stream.get_mut().behaviour_mut();
@@ -318,10 +510,16 @@ where
                         }
                     }
                 },
+                gs_message_opt = gs_message_rx_stream.next() => {
+                    if let Some((peer, message, written)) = gs_message_opt {
+                        let graphsync = &mut swarm_stream.get_mut().behaviour_mut().graphsync;
+                        graphsync.send_message(&peer, message, written);
+                    }
+                },
                 bitswap_outbound_request_opt = bitswap_outbound_request_rx_stream.next() => {
-                    if let Some((peer, request)) = bitswap_outbound_request_opt {
+                    if let Some((peer, requests)) = bitswap_outbound_request_opt {
                         let bitswap = &mut swarm_stream.get_mut().behaviour_mut().bitswap;
-                        bitswap.send_request(&peer, request);
+                        bitswap.send_requests(&peer, requests);
                     }
                 }
                 peer_ops_opt = peer_ops_rx_stream.next() => {
@@ -359,13 +557,16 @@ fn handle_peer_ops(swarm: &mut Swarm<ForestBehaviour>, peer_ops: PeerOperation)
     }
 }
 
-async fn handle_network_message(
+async fn handle_network_message<DB>(
     swarm: &mut Swarm<ForestBehaviour>,
-    store: Arc<impl BitswapStoreReadWrite>,
+    cs: Arc<ChainStore<DB>>,
+    peer_manager: &PeerManager,
     bitswap_request_manager: Arc<BitswapRequestManager>,
     message: NetworkMessage,
     network_sender_out: &Sender<NetworkEvent>,
-) {
+) where
+    DB: Blockstore + BitswapStoreReadWrite + Sync + Send + 'static,
+{
     match message {
         NetworkMessage::PubsubMessage { topic, message } => {
             if let Err(e) = swarm.behaviour_mut().publish(topic, message) {
@@ -408,7 +609,32 @@ async fn handle_network_message(
             cid,
             response_channel,
         } => {
-            bitswap_request_manager.get_block(store, cid, BITSWAP_TIMEOUT, Some(response_channel));
+            bitswap_request_manager.get_block(cs, cid, BITSWAP_TIMEOUT, Some(response_channel));
+        }
+        NetworkMessage::BitswapSession {
+            timeout,
+            response_channel,
+        } => {
+            let session = bitswap_request_manager.session(cs, timeout);
+            if let Err(e) = response_channel.send_async(session).await {
+                warn!("Failed to send bitswap session: {e}");
+            }
+        }
+        NetworkMessage::FindProviders {
+            cid,
+            response_channel,
+        } => {
+            swarm.behaviour_mut().get_providers(cid, response_channel);
+        }
+        NetworkMessage::GraphsyncRequest {
+            peer_id,
+            request,
+            response_channel,
+        } => {
+            swarm
+                .behaviour_mut()
+                .graphsync
+                .send_request(&peer_id, request, response_channel);
         }
         NetworkMessage::JSONRPCRequest { method } => {
             match method {
@@ -471,11 +697,102 @@ async fn handle_network_message(
                         warn!("Failed to disconnect from a peer");
                     }
                 }
+                NetRPCMethods::AutoNatStatus(response_channel) => {
+                    let nat_status = swarm.behaviour().autonat.nat_status();
+                    if response_channel.send(nat_status).is_err() {
+                        warn!("Failed to get AutoNAT status");
+                    }
+                }
+                NetRPCMethods::BlockAdd(response_channel, block_list) => {
+                    let behaviour = swarm.behaviour_mut();
+                    behaviour.block_list.block(block_list);
+                    let result =
+                        persist_block_list(&*cs.settings(), behaviour.block_list.block_list());
+                    if response_channel.send(result).is_err() {
+                        warn!("Failed to add to the block list");
+                    }
+                }
+                NetRPCMethods::BlockRemove(response_channel, block_list) => {
+                    let behaviour = swarm.behaviour_mut();
+                    behaviour.block_list.unblock(&block_list);
+                    let result =
+                        persist_block_list(&*cs.settings(), behaviour.block_list.block_list());
+                    if response_channel.send(result).is_err() {
+                        warn!("Failed to remove from the block list");
+                    }
+                }
+                NetRPCMethods::BlockList(response_channel) => {
+                    let block_list = swarm.behaviour().block_list.block_list().clone();
+                    if response_channel.send(block_list).is_err() {
+                        warn!("Failed to get the block list");
+                    }
+                }
+                NetRPCMethods::ProtectAdd(response_channel, peers) => {
+                    for peer in peers {
+                        peer_manager.protect_peer(peer);
+                        // Lift any ban issued before the peer was protected
+                        swarm.behaviour_mut().blocked_peers.unblock_peer(peer);
+                    }
+                    let result = persist_protected_peers(&*cs.settings(), peer_manager);
+                    if response_channel.send(result).is_err() {
+                        warn!("Failed to protect peers");
+                    }
+                }
+                NetRPCMethods::ProtectRemove(response_channel, peers) => {
+                    for peer in peers {
+                        peer_manager.unprotect_peer(&peer);
+                    }
+                    let result = persist_protected_peers(&*cs.settings(), peer_manager);
+                    if response_channel.send(result).is_err() {
+                        warn!("Failed to unprotect peers");
+                    }
+                }
+                NetRPCMethods::PeerInfo(response_channel, peer_id) => {
+                    let peer_info = match peer_manager.peer_stats(&peer_id).await {
+                        Some(stats) => {
+                            let addrs = swarm
+                                .behaviour_mut()
+                                .peer_addresses()
+                                .get(&peer_id)
+                                .cloned()
+                                .unwrap_or_default();
+                            Some((stats, addrs))
+                        }
+                        None => None,
+                    };
+                    if response_channel.send(peer_info).is_err() {
+                        warn!("Failed to get peer info");
+                    }
+                }
+                NetRPCMethods::ProtectList(response_channel) => {
+                    if response_channel
+                        .send(peer_manager.list_protected_peers())
+                        .is_err()
+                    {
+                        warn!("Failed to get protected peers");
+                    }
+                }
             }
         }
     }
 }
 
+fn persist_block_list(settings: &dyn SettingsStore, block_list: &BlockList) -> anyhow::Result<()> {
+    settings.write_obj(NET_BLOCK_LIST_KEY, &NetBlockList::from(block_list))
+}
+
+fn persist_protected_peers(
+    settings: &dyn SettingsStore,
+    peer_manager: &PeerManager,
+) -> anyhow::Result<()> {
+    let peers: Vec<String> = peer_manager
+        .list_protected_peers()
+        .iter()
+        .map(PeerId::to_string)
+        .collect();
+    settings.write_obj(NET_PROTECTED_PEERS_KEY, &peers)
+}
+
 async fn handle_discovery_event(
     discovery_out: DiscoveryEvent,
     network_sender_out: &Sender<NetworkEvent>,
@@ -497,6 +814,7 @@ async fn handle_gossip_event(
     network_sender_out: &Sender<NetworkEvent>,
     pubsub_block_str: &str,
     pubsub_msg_str: &str,
+    drand_topics: &HashMap<String, Arc<GossipSource>>,
 ) {
     if let gossipsub::Event::Message {
         propagation_source: source,
@@ -506,6 +824,7 @@ async fn handle_gossip_event(
     {
         let topic = message.topic.as_str();
         let message = message.data;
+        bandwidth::record_inbound(bandwidth::GOSSIPSUB_PROTOCOL_NAME, message.len());
         trace!("Got a Gossip Message from {:?}", source);
         if topic == pubsub_block_str {
             match from_slice_with_fallback::<GossipBlock>(&message) {
@@ -539,6 +858,17 @@ async fn handle_gossip_event(
                     warn!("Gossip Message from peer {source:?} could not be deserialized: {e}");
                 }
             }
+        } else if let Some(gossip_source) = drand_topics.get(topic) {
+            match GossipSource::decode(&message) {
+                Ok(entry) => {
+                    if let Err(e) = gossip_source.insert(entry) {
+                        debug!("Gossip drand entry from peer {source:?} rejected: {e}");
+                    }
+                }
+                Err(e) => {
+                    warn!("Gossip drand entry from peer {source:?} could not be decoded: {e}");
+                }
+            }
         } else {
             warn!("Getting gossip messages from unknown topic: {topic}");
         }
@@ -697,11 +1027,12 @@ async fn handle_chain_exchange_event<DB>(
                     )
                     .await;
                     let db = db.clone();
+                    let server = chain_exchange.server();
                     tokio::task::spawn(async move {
                         if let Err(e) = cx_response_tx.send((
                             request_id,
                             channel,
-                            make_chain_exchange_response(&db, &request),
+                            server.serve(db, peer, request).await,
                         )) {
                             debug!("Failed to send ChainExchangeResponse: {e:?}");
                         }
@@ -749,6 +1080,125 @@ async fn handle_chain_exchange_event<DB>(
     }
 }
 
+fn handle_graphsync_event<DB>(
+    graphsync: &mut GraphsyncBehaviour,
+    gs_event: request_response::Event<GraphsyncMessage, ()>,
+    db: &Arc<ChainStore<DB>>,
+    gs_message_tx: GraphsyncMessageSender,
+) where
+    DB: Blockstore + Sync + Send + 'static,
+{
+    match gs_event {
+        request_response::Event::Message { peer, message } => match message {
+            request_response::Message::Request {
+                request, channel, ..
+            } => {
+                graphsync.close_inbound_stream(channel);
+                for request in graphsync.handle_inbound_message(&peer, request) {
+                    trace!(
+                        "Received graphsync request for {:?} from {peer}",
+                        request.root
+                    );
+                    let db = db.db.clone();
+                    let server = graphsync.server();
+                    let gs_message_tx = gs_message_tx.clone();
+                    tokio::task::spawn(async move {
+                        server.serve(db, peer, request, gs_message_tx).await
+                    });
+                }
+            }
+            request_response::Message::Response { request_id, .. } => {
+                graphsync.on_outbound_success(&request_id);
+            }
+        },
+        request_response::Event::OutboundFailure {
+            request_id, error, ..
+        } => {
+            graphsync.on_outbound_error(&request_id, error);
+        }
+        request_response::Event::InboundFailure { peer, error, .. } => {
+            debug!("Graphsync inbound error (peer: {peer:?}): {error:?}");
+        }
+        request_response::Event::ResponseSent { .. } => {}
+    }
+}
+
+/// Records the identity of the peer. Once `AutoNAT` has concluded that the
+/// node is not publicly reachable, also asks identified peers that speak the
+/// circuit relay v2 `hop` protocol for a reservation, so that other nodes can
+/// reach this one through them. At most [`MAX_RELAY_RESERVATIONS`] relays are
+/// used at any time.
+fn handle_identify_event(
+    swarm: &mut Swarm<ForestBehaviour>,
+    peer_manager: &PeerManager,
+    event: identify::Event,
+) {
+    let identify::Event::Received { peer_id, info } = event else {
+        return;
+    };
+    peer_manager.update_peer_identity(
+        peer_id,
+        info.agent_version,
+        info.protocols.iter().map(ToString::to_string).collect(),
+    );
+    if swarm.behaviour().autonat.nat_status() != autonat::NatStatus::Private
+        || !info.protocols.contains(&relay::HOP_PROTOCOL_NAME)
+    {
+        return;
+    }
+
+    let relays: HashSet<PeerId> = swarm.listeners().filter_map(relay_peer_id).collect();
+    if relays.len() >= MAX_RELAY_RESERVATIONS || relays.contains(&peer_id) {
+        return;
+    }
+
+    let Some(relay_addr) = info.listen_addrs.into_iter().find(|addr| !is_relayed(addr)) else {
+        return;
+    };
+    let circuit_addr = relay_addr
+        .with(Protocol::P2p(peer_id))
+        .with(Protocol::P2pCircuit);
+    match swarm.listen_on(circuit_addr.clone()) {
+        Ok(_) => info!("Requesting relay reservation on {circuit_addr}"),
+        Err(e) => warn!("Failed to listen on relay address {circuit_addr}: {e}"),
+    }
+}
+
+fn handle_autonat_event(event: autonat::Event) {
+    if let autonat::Event::StatusChanged { old, new } = event {
+        info!("AutoNAT status changed from {old:?} to {new:?}");
+    }
+}
+
+fn handle_relay_client_event(event: relay::client::Event) {
+    match event {
+        relay::client::Event::ReservationReqAccepted {
+            relay_peer_id,
+            renewal: false,
+            ..
+        } => info!("Relay reservation accepted by {relay_peer_id}"),
+        relay::client::Event::ReservationReqFailed {
+            relay_peer_id,
+            error,
+            ..
+        } => debug!("Relay reservation with {relay_peer_id} failed: {error}"),
+        event => trace!("Relay client event: {event:?}"),
+    }
+}
+
+fn handle_dcutr_event(event: dcutr::Event) {
+    match event {
+        dcutr::Event::DirectConnectionUpgradeSucceeded { remote_peer_id } => {
+            debug!("Upgraded relayed connection with {remote_peer_id} to a direct one")
+        }
+        dcutr::Event::DirectConnectionUpgradeFailed {
+            remote_peer_id,
+            error,
+        } => debug!("Failed to upgrade relayed connection with {remote_peer_id}: {error}"),
+        event => trace!("DCUtR event: {event:?}"),
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
 async fn handle_forest_behaviour_event<DB>(
     swarm: &mut Swarm<ForestBehaviour>,
@@ -763,8 +1213,10 @@ async fn handle_forest_behaviour_event<DB>(
         ResponseChannel<ChainExchangeResponse>,
         ChainExchangeResponse,
     )>,
+    gs_message_tx: GraphsyncMessageSender,
     pubsub_block_str: &str,
     pubsub_msg_str: &str,
+    drand_topics: &HashMap<String, Arc<GossipSource>>,
 ) where
     DB: Blockstore + BitswapStoreRead + Sync + Send + 'static,
 {
@@ -773,7 +1225,14 @@ async fn handle_forest_behaviour_event<DB>(
             handle_discovery_event(discovery_out, network_sender_out).await
         }
         ForestBehaviourEvent::Gossipsub(e) => {
-            handle_gossip_event(e, network_sender_out, pubsub_block_str, pubsub_msg_str).await
+            handle_gossip_event(
+                e,
+                network_sender_out,
+                pubsub_block_str,
+                pubsub_msg_str,
+                drand_topics,
+            )
+            .await
         }
         ForestBehaviourEvent::Hello(rr_event) => {
             handle_hello_event(
@@ -795,9 +1254,15 @@ async fn handle_forest_behaviour_event<DB>(
             }
         }
         ForestBehaviourEvent::Ping(ping_event) => handle_ping_event(ping_event, peer_manager).await,
-        ForestBehaviourEvent::Identify(_) => {}
+        ForestBehaviourEvent::Identify(identify_event) => {
+            handle_identify_event(swarm, peer_manager, identify_event)
+        }
+        ForestBehaviourEvent::Autonat(autonat_event) => handle_autonat_event(autonat_event),
+        ForestBehaviourEvent::RelayClient(relay_event) => handle_relay_client_event(relay_event),
+        ForestBehaviourEvent::Dcutr(dcutr_event) => handle_dcutr_event(dcutr_event),
         ForestBehaviourEvent::ConnectionLimits(_) => {}
         ForestBehaviourEvent::BlockedPeers(_) => {}
+        ForestBehaviourEvent::BlockList(_) => {}
         ForestBehaviourEvent::ChainExchange(ce_event) => {
             handle_chain_exchange_event(
                 &mut swarm.behaviour_mut().chain_exchange,
@@ -808,6 +1273,12 @@ async fn handle_forest_behaviour_event<DB>(
             )
             .await
         }
+        ForestBehaviourEvent::Graphsync(gs_event) => handle_graphsync_event(
+            &mut swarm.behaviour_mut().graphsync,
+            gs_event,
+            db,
+            gs_message_tx,
+        ),
     }
 }
 
@@ -817,23 +1288,129 @@ async fn emit_event(sender: &Sender<NetworkEvent>, event: NetworkEvent) {
     }
 }
 
-/// Builds the transport stack that libp2p will communicate over. When support
-/// of other protocols like `udp`, `quic`, `http` are added, remember to update
-/// code comment in [`Libp2pConfig`].
+/// Builds the transport stack that libp2p will communicate over. TCP and
+/// circuit relay v2 connections are secured with `noise` and multiplexed with
+/// `yamux`, while QUIC (`/quic-v1`) brings its own TLS 1.3 handshake and stream
+/// multiplexing. All are wrapped with DNS resolution. The relay client
+/// behaviour returned alongside must be part of the swarm behaviour. When
+/// support of other protocols like `websocket` or `webtransport` is added,
+/// remember to update code comment in [`Libp2pConfig`].
 ///
 /// As a reference `lotus` uses the default `go-libp2p` transport builder which
 /// has all above protocols enabled.
-pub fn build_transport(local_key: Keypair) -> anyhow::Result<Boxed<(PeerId, StreamMuxerBox)>> {
-    let build_tcp = || libp2p::tcp::tokio::Transport::new(libp2p::tcp::Config::new().nodelay(true));
-    let build_dns_tcp = || libp2p::dns::TokioDnsConfig::system(build_tcp());
-    let transport = build_dns_tcp()?;
-
-    let auth_config = noise::Config::new(&local_key).context("Noise key generation failed")?;
-
-    Ok(transport
-        .upgrade(core::upgrade::Version::V1)
-        .authenticate(auth_config)
-        .multiplex(yamux::Config::default())
-        .timeout(Duration::from_secs(20))
-        .boxed())
+pub fn build_transport(
+    local_key: Keypair,
+) -> anyhow::Result<(Boxed<(PeerId, StreamMuxerBox)>, relay::client::Behaviour)> {
+    let (relay_transport, relay_client) = relay::client::new(local_key.public().to_peer_id());
+    let tcp_transport = OrTransport::new(
+        relay_transport,
+        libp2p::tcp::tokio::Transport::new(libp2p::tcp::Config::new().nodelay(true)),
+    )
+    .upgrade(core::upgrade::Version::V1)
+    .authenticate(noise::Config::new(&local_key).context("Noise key generation failed")?)
+    .multiplex(yamux::Config::default())
+    .timeout(Duration::from_secs(20));
+    let quic_transport = libp2p::quic::tokio::Transport::new(libp2p::quic::Config::new(&local_key));
+    let transport = OrTransport::new(quic_transport, tcp_transport).map(|either, _| match either {
+        Either::Left((peer_id, muxer)) => (peer_id, StreamMuxerBox::new(muxer)),
+        Either::Right((peer_id, muxer)) => (peer_id, StreamMuxerBox::new(muxer)),
+    });
+
+    Ok((
+        libp2p::dns::TokioDnsConfig::system(transport)?.boxed(),
+        relay_client,
+    ))
+}
+
+/// Whether the address goes through a circuit relay.
+fn is_relayed(address: &Multiaddr) -> bool {
+    address.iter().any(|p| p == Protocol::P2pCircuit)
+}
+
+/// Peer ID of the relay a circuit address goes through, if any.
+fn relay_peer_id(address: &Multiaddr) -> Option<PeerId> {
+    let mut relay = None;
+    for protocol in address.iter() {
+        match protocol {
+            Protocol::P2p(peer_id) => relay = Some(peer_id),
+            Protocol::P2pCircuit => return relay,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Name of the transport a connection was established over, used as a metrics
+/// label.
+fn transport_name(address: &Multiaddr) -> &'static str {
+    if address
+        .iter()
+        .any(|p| matches!(p, Protocol::QuicV1 | Protocol::Quic))
+    {
+        metrics::values::QUIC
+    } else {
+        metrics::values::TCP
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::core::transport::{ListenerId, TransportEvent};
+
+    #[tokio::test]
+    async fn quic_transport_connects() {
+        let listener_key = Keypair::generate_ed25519();
+        let (mut listener, _) = build_transport(listener_key.clone()).unwrap();
+        listener
+            .listen_on(
+                ListenerId::next(),
+                "/ip4/127.0.0.1/udp/0/quic-v1".parse().unwrap(),
+            )
+            .unwrap();
+        let listen_addr = loop {
+            if let TransportEvent::NewAddress { listen_addr, .. } =
+                listener.select_next_some().await
+            {
+                break listen_addr;
+            }
+        };
+        assert_eq!(transport_name(&listen_addr), metrics::values::QUIC);
+
+        let (mut dialer, _) = build_transport(Keypair::generate_ed25519()).unwrap();
+        let dial = dialer.dial(listen_addr).unwrap();
+        let accept = async {
+            loop {
+                if let TransportEvent::Incoming { upgrade, .. } = listener.select_next_some().await
+                {
+                    break upgrade.await;
+                }
+            }
+        };
+        let (dialed, accepted) = futures::join!(dial, accept);
+        let (remote_peer_id, _) = dialed.unwrap();
+        assert_eq!(remote_peer_id, PeerId::from(listener_key.public()));
+        assert!(accepted.is_ok());
+    }
+
+    #[test]
+    fn transport_name_from_address() {
+        let tcp: Multiaddr = "/ip4/1.2.3.4/tcp/1347".parse().unwrap();
+        let quic: Multiaddr = "/ip4/1.2.3.4/udp/1347/quic-v1".parse().unwrap();
+        assert_eq!(transport_name(&tcp), metrics::values::TCP);
+        assert_eq!(transport_name(&quic), metrics::values::QUIC);
+    }
+
+    #[test]
+    fn relay_peer_id_from_address() {
+        let relay = PeerId::random();
+        let direct: Multiaddr = format!("/ip4/1.2.3.4/tcp/1347/p2p/{relay}")
+            .parse()
+            .unwrap();
+        let circuit = direct.clone().with(Protocol::P2pCircuit);
+        assert!(!is_relayed(&direct));
+        assert!(is_relayed(&circuit));
+        assert_eq!(relay_peer_id(&direct), None);
+        assert_eq!(relay_peer_id(&circuit), Some(relay));
+    }
 }
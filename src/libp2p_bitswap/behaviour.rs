@@ -41,18 +41,22 @@ impl BitswapBehaviour {
         &mut self.inner
     }
 
-    /// Sends a [`BitswapRequest`] to a peer
-    pub fn send_request(&mut self, peer: &PeerId, request: BitswapRequest) -> RequestId {
-        if request.cancel {
-            metrics::message_counter_outbound_request_cancel().inc();
-        } else {
-            match request.ty {
-                RequestType::Have => metrics::message_counter_outbound_request_have().inc(),
-                RequestType::Block => metrics::message_counter_outbound_request_block().inc(),
+    /// Sends [`BitswapRequest`]s to a peer as a single wantlist
+    pub fn send_requests(&mut self, peer: &PeerId, requests: Vec<BitswapRequest>) -> RequestId {
+        for request in &requests {
+            if request.cancel {
+                metrics::message_counter_outbound_request_cancel().inc();
+            } else {
+                match request.ty {
+                    RequestType::Have => metrics::message_counter_outbound_request_have().inc(),
+                    RequestType::Block => metrics::message_counter_outbound_request_block().inc(),
+                }
             }
         }
-        self.inner
-            .send_request(peer, vec![BitswapMessage::Request(request)])
+        self.inner.send_request(
+            peer,
+            requests.into_iter().map(BitswapMessage::Request).collect(),
+        )
     }
 
     /// Sends a [`BitswapResponse`] to a peer
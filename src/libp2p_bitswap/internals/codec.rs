@@ -80,7 +80,8 @@ impl request_response::Codec for BitswapRequestResponseCodec {
         Ok(())
     }
 
-    /// Sending both `bitswap` requests and responses
+    /// Sending both `bitswap` requests and responses, all messages are batched
+    /// into a single protobuf message
     async fn write_request<T>(
         &mut self,
         _: &Self::Protocol,
@@ -90,13 +91,7 @@ impl request_response::Codec for BitswapRequestResponseCodec {
     where
         T: AsyncWrite + Send + Unpin,
     {
-        assert_eq!(
-            messages.len(),
-            1,
-            "It's only supported to send a single message" // libp2p-bitswap doesn't support batch sending
-        );
-
-        let bytes = messages[0].to_bytes()?;
+        let bytes = BitswapMessage::batch_to_bytes(&messages)?;
 
         metrics::outbound_stream_count().inc();
         metrics::outbound_bytes().inc_by(bytes.len() as _);
@@ -109,51 +109,55 @@ pub enum BitswapMessage {
 }
 
 impl BitswapMessage {
-    pub fn to_bytes(&self) -> IOResult<Vec<u8>> {
+    /// Encodes the messages into a single protobuf message, requests are sent
+    /// as the entries of one wantlist.
+    pub fn batch_to_bytes(messages: &[Self]) -> IOResult<Vec<u8>> {
         let mut msg = bitswap_pb::Message::new();
-        match self {
-            Self::Request(BitswapRequest {
-                ty,
-                cid,
-                send_dont_have,
-                cancel,
-            }) => {
-                let mut wantlist = bitswap_pb::message::Wantlist::new();
-
-                wantlist.entries.push({
-                    let mut entry = bitswap_pb::message::wantlist::Entry::new();
-                    entry.block = cid.to_bytes();
-                    entry.wantType = (*ty).into();
-                    entry.sendDontHave = *send_dont_have;
-                    entry.cancel = *cancel;
-                    entry.priority = 1;
-                    entry
-                });
-
-                msg.wantlist = Some(wantlist).into();
-            }
-            Self::Response(cid, BitswapResponse::Have(have)) => {
-                let mut block_presence = bitswap_pb::message::BlockPresence::new();
-
-                block_presence.cid = cid.to_bytes();
-                block_presence.type_ = if *have {
-                    bitswap_pb::message::BlockPresenceType::Have
-                } else {
-                    bitswap_pb::message::BlockPresenceType::DontHave
+        let mut wantlist = bitswap_pb::message::Wantlist::new();
+        for message in messages {
+            match message {
+                Self::Request(BitswapRequest {
+                    ty,
+                    cid,
+                    send_dont_have,
+                    cancel,
+                }) => {
+                    wantlist.entries.push({
+                        let mut entry = bitswap_pb::message::wantlist::Entry::new();
+                        entry.block = cid.to_bytes();
+                        entry.wantType = (*ty).into();
+                        entry.sendDontHave = *send_dont_have;
+                        entry.cancel = *cancel;
+                        entry.priority = 1;
+                        entry
+                    });
                 }
-                .into();
-
-                msg.blockPresences.push(block_presence);
-            }
-            Self::Response(cid, BitswapResponse::Block(bytes)) => {
-                let mut payload = bitswap_pb::message::Block::new();
+                Self::Response(cid, BitswapResponse::Have(have)) => {
+                    let mut block_presence = bitswap_pb::message::BlockPresence::new();
+
+                    block_presence.cid = cid.to_bytes();
+                    block_presence.type_ = if *have {
+                        bitswap_pb::message::BlockPresenceType::Have
+                    } else {
+                        bitswap_pb::message::BlockPresenceType::DontHave
+                    }
+                    .into();
+
+                    msg.blockPresences.push(block_presence);
+                }
+                Self::Response(cid, BitswapResponse::Block(bytes)) => {
+                    let mut payload = bitswap_pb::message::Block::new();
 
-                payload.prefix = Prefix::from(cid).to_bytes();
-                payload.data = bytes.to_vec();
+                    payload.prefix = Prefix::from(cid).to_bytes();
+                    payload.data = bytes.to_vec();
 
-                msg.payload.push(payload);
+                    msg.payload.push(payload);
+                }
             }
         }
+        if !wantlist.entries.is_empty() {
+            msg.wantlist = Some(wantlist).into();
+        }
         msg.write_to_bytes().map_err(map_io_err)
     }
 }
@@ -56,6 +56,11 @@ pub(in crate::libp2p_bitswap) fn outbound_bytes() -> GenericCounter<AtomicU64> {
     MESSAGE_SIZE.with_label_values(&["outbound_bytes"])
 }
 
+/// Returns the total sizes of the inbound and outbound messages, in bytes.
+pub fn message_bytes() -> (u64, u64) {
+    (inbound_bytes().get(), outbound_bytes().get())
+}
+
 pub(in crate::libp2p_bitswap) fn inbound_stream_count() -> GenericCounter<AtomicU64> {
     MESSAGE_COUNTER.with_label_values(&["inbound_stream_count"])
 }
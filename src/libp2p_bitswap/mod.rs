@@ -37,7 +37,7 @@ mod message;
 pub use message::*;
 
 mod metrics;
-pub use metrics::register_metrics;
+pub use metrics::{message_bytes, register_metrics};
 
 pub mod request_manager;
 
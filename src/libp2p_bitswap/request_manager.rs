@@ -10,38 +10,54 @@ use std::{
 };
 
 use crate::cid_collections::CidHashMap;
-use ahash::{HashSet, HashSetExt};
-use flume::TryRecvError;
+use ahash::{HashMap, HashSet, HashSetExt};
+use flume::RecvTimeoutError;
 use libipld::{Block, Cid};
 use libp2p::PeerId;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 
 use crate::libp2p_bitswap::{event_handlers::*, *};
 
 const BITSWAP_BLOCK_REQUEST_INTERVAL: Duration = Duration::from_millis(500);
+/// Time given to a peer to send a block before it is requested from another
+/// peer that has it.
+const BITSWAP_BLOCK_RESPONSE_TIMEOUT: Duration = Duration::from_secs(2);
+/// Maximum number of entries in a wantlist sent to a peer.
+const MAX_WANTLIST_SIZE: usize = 256;
+/// Maximum number of blocks requested from a peer and not yet received,
+/// across all sessions.
+const MAX_IN_FLIGHT_BLOCKS_PER_PEER: usize = 32;
 
-#[derive(Debug, Clone)]
-struct ResponseChannels {
-    block_have: flume::Sender<PeerId>,
-    block_received: flume::Sender<Option<Vec<u8>>>,
+/// Inputs of a session: blocks wanted by its owner, the peers known to have
+/// them, and the responses of peers to the blocks it registered.
+#[derive(Debug)]
+enum SessionEvent {
+    Want(Cid),
+    Providers(Vec<PeerId>),
+    Have(PeerId, Cid),
+    Block(Cid, Option<Vec<u8>>),
 }
 
 /// Request manager implementation that is optimized for Filecoin network
 /// usage
 #[derive(Debug)]
 pub struct BitswapRequestManager {
-    outbound_request_tx: flume::Sender<(PeerId, BitswapRequest)>,
-    outbound_request_rx: flume::Receiver<(PeerId, BitswapRequest)>,
+    outbound_request_tx: flume::Sender<(PeerId, Vec<BitswapRequest>)>,
+    outbound_request_rx: flume::Receiver<(PeerId, Vec<BitswapRequest>)>,
     peers: RwLock<HashSet<PeerId>>,
-    response_channels: RwLock<CidHashMap<ResponseChannels>>,
+    /// The session fetching each block.
+    response_channels: RwLock<CidHashMap<flume::Sender<SessionEvent>>>,
+    /// Number of blocks requested from each peer and not yet received.
+    in_flight: Mutex<HashMap<PeerId, usize>>,
 }
 
 impl BitswapRequestManager {
     /// A receiver channel of the outbound `bitswap` network events that the
-    /// [`BitswapRequestManager`] emits. The messages from this channel need
-    /// to be sent with [`BitswapBehaviour::send_request`] to make
-    /// [`BitswapRequestManager::get_block`] work.
-    pub fn outbound_request_rx(&self) -> &flume::Receiver<(PeerId, BitswapRequest)> {
+    /// [`BitswapRequestManager`] emits. The wantlists from this channel need
+    /// to be sent with [`BitswapBehaviour::send_requests`] to make
+    /// [`BitswapRequestManager::get_block`] and
+    /// [`BitswapRequestManager::session`] work.
+    pub fn outbound_request_rx(&self) -> &flume::Receiver<(PeerId, Vec<BitswapRequest>)> {
         &self.outbound_request_rx
     }
 }
@@ -54,10 +70,64 @@ impl Default for BitswapRequestManager {
             outbound_request_rx,
             peers: RwLock::new(HashSet::new()),
             response_channels: RwLock::new(CidHashMap::new()),
+            in_flight: Default::default(),
         }
     }
 }
 
+/// A stream of blocks fetched over `bitswap`. Blocks are requested with
+/// [`BitswapSession::want`] and reported, in the order they are fetched or
+/// time out, by [`BitswapSession::next`]. Wanted blocks are first asked for
+/// with `want-have` entries batched into a wantlist per peer, then requested
+/// with `want-block` from the peers that answered `HAVE`. Fetching stops when
+/// the session is dropped.
+#[derive(Debug)]
+pub struct BitswapSession {
+    events: flume::Sender<SessionEvent>,
+    results: flume::Receiver<(Cid, bool)>,
+}
+
+impl BitswapSession {
+    /// Adds a block to fetch. Blocks that are already in the store are
+    /// reported as fetched right away. A block wanted again while it is being
+    /// fetched is fetched once, and reported once for each time it was wanted.
+    pub fn want(&self, cid: Cid) {
+        _ = self.events.send(SessionEvent::Want(cid));
+    }
+
+    /// Restricts the `want-have` broadcasts of the session to the given
+    /// peers, e.g. the providers of the DAG found on the DHT, as long as any
+    /// of them is connected.
+    pub fn add_providers(&self, peers: impl IntoIterator<Item = PeerId>) {
+        _ = self
+            .events
+            .send(SessionEvent::Providers(peers.into_iter().collect()));
+    }
+
+    /// Waits for the next block to be fetched or to time out, and returns
+    /// its `Cid` with whether it is now in the store.
+    pub async fn next(&self) -> Option<(Cid, bool)> {
+        self.results.recv_async().await.ok()
+    }
+}
+
+/// State of a block wanted by a session.
+struct Want {
+    deadline: Instant,
+    /// Whether the block is fetched by this session, or by another session
+    /// that wanted it first.
+    owned: bool,
+    /// Number of times the block was wanted, each reported when it is done.
+    waiters: usize,
+    /// When `want-have` was last sent for the block.
+    asked: Option<Instant>,
+    /// Peers that answered `HAVE`.
+    have: Vec<PeerId>,
+    /// Peers the block was requested from.
+    tried: Vec<PeerId>,
+    requested: Option<(PeerId, Instant)>,
+}
+
 impl BitswapRequestManager {
     /// Hook the `bitswap` network event into the [`BitswapRequestManager`]
     pub fn handle_event<S: BitswapStoreRead>(
@@ -80,24 +150,15 @@ impl BitswapRequestManager {
         timeout: Duration,
         responder: Option<flume::Sender<bool>>,
     ) {
-        let start = Instant::now();
         let timer = metrics::GET_BLOCK_TIME.start_timer();
-        let store_cloned = store.clone();
+        let session = self.session(store, timeout);
+        session.want(cid);
         task::spawn(async move {
-            let mut success = store.contains(&cid).unwrap_or_default();
-            if !success {
-                let deadline = start.checked_add(timeout).expect("Infallible");
-                success =
-                    task::spawn_blocking(move || self.get_block_sync(store_cloned, cid, deadline))
-                        .await
-                        .unwrap_or_default();
-                // Spin check db when `get_block_sync` fails fast,
-                // which means there is other task actually processing the same `cid`
-                while !success && Instant::now() < deadline {
-                    task::sleep(BITSWAP_BLOCK_REQUEST_INTERVAL).await;
-                    success = store.contains(&cid).unwrap_or_default();
-                }
-            }
+            let success = session
+                .next()
+                .await
+                .map(|(_, success)| success)
+                .unwrap_or_default();
 
             if success {
                 metrics::message_counter_get_block_success().inc();
@@ -115,93 +176,239 @@ impl BitswapRequestManager {
         });
     }
 
-    fn get_block_sync(
-        &self,
+    /// Starts a [`BitswapSession`] writing the blocks it fetches to the given
+    /// block store. Each block is given `timeout` from the time it is wanted
+    /// to be fetched.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn session(
+        self: Arc<Self>,
         store: Arc<impl BitswapStoreReadWrite>,
-        cid: Cid,
-        deadline: Instant,
-    ) -> bool {
-        // Fail fast here when the given `cid` is being processed by other tasks
-        if self.response_channels.read().contains_key(&cid) {
-            return false;
+        timeout: Duration,
+    ) -> BitswapSession {
+        let (events_tx, events_rx) = flume::unbounded();
+        let (results_tx, results_rx) = flume::unbounded();
+        let session_events_tx = events_tx.clone();
+        task::spawn_blocking(move || {
+            self.run_session(store, timeout, session_events_tx, events_rx, results_tx)
+        });
+        BitswapSession {
+            events: events_tx,
+            results: results_rx,
         }
+    }
 
-        let (block_have_tx, block_have_rx) = flume::unbounded();
-        let (block_saved_tx, block_saved_rx) = flume::unbounded();
-        let channels = ResponseChannels {
-            block_have: block_have_tx,
-            block_received: block_saved_tx,
-        };
-        {
-            self.response_channels.write().insert(cid, channels);
-        }
+    fn run_session(
+        &self,
+        store: Arc<impl BitswapStoreReadWrite>,
+        timeout: Duration,
+        events_tx: flume::Sender<SessionEvent>,
+        events_rx: flume::Receiver<SessionEvent>,
+        results_tx: flume::Sender<(Cid, bool)>,
+    ) {
+        let mut wants: HashMap<Cid, Want> = HashMap::default();
+        let mut providers: HashSet<PeerId> = HashSet::new();
+        // The session is dropped once no one listens to its results
+        while !results_tx.is_disconnected() {
+            let first = match events_rx.recv_timeout(BITSWAP_BLOCK_REQUEST_INTERVAL) {
+                Ok(event) => Some(event),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
 
-        let have_request = BitswapRequest::new_have(cid).send_dont_have(false);
-        for &peer in self.peers.read().iter() {
-            if let Err(e) = self.outbound_request_tx.send((peer, have_request.clone())) {
-                warn!("{e}");
+            let mut done = vec![];
+            for event in first.into_iter().chain(events_rx.try_iter()) {
+                match event {
+                    SessionEvent::Want(cid) => {
+                        if let Some(want) = wants.get_mut(&cid) {
+                            want.waiters += 1;
+                            continue;
+                        }
+                        if store.contains(&cid).unwrap_or_default() {
+                            _ = results_tx.send((cid, true));
+                            continue;
+                        }
+                        let owned = {
+                            let mut response_channels = self.response_channels.write();
+                            if response_channels.contains_key(&cid) {
+                                false
+                            } else {
+                                response_channels.insert(cid, events_tx.clone());
+                                true
+                            }
+                        };
+                        wants.insert(
+                            cid,
+                            Want {
+                                deadline: Instant::now() + timeout,
+                                owned,
+                                waiters: 1,
+                                asked: None,
+                                have: vec![],
+                                tried: vec![],
+                                requested: None,
+                            },
+                        );
+                    }
+                    SessionEvent::Providers(peers) => providers.extend(peers),
+                    SessionEvent::Have(peer, cid) => {
+                        if let Some(want) = wants.get_mut(&cid) {
+                            if !want.have.contains(&peer) {
+                                want.have.push(peer);
+                            }
+                        }
+                    }
+                    SessionEvent::Block(cid, data) => {
+                        if wants.contains_key(&cid) {
+                            let success = match data {
+                                Some(data) => insert_block(store.as_ref(), cid, data),
+                                None => true,
+                            };
+                            done.push((cid, success));
+                        }
+                    }
+                }
             }
-        }
 
-        let mut success = false;
-        let mut block_data = None;
-        let block_request = BitswapRequest::new_block(cid).send_dont_have(false);
-        while !success && Instant::now() < deadline {
-            match block_have_rx.try_recv() {
-                Ok(peer) => {
-                    _ = self.outbound_request_tx.send((peer, block_request.clone()));
+            let now = Instant::now();
+            let peers: Vec<_> = self.peers.read().iter().copied().collect();
+            let connected_providers: Vec<_> = peers
+                .iter()
+                .filter(|peer| providers.contains(peer))
+                .copied()
+                .collect();
+            let mut ask_providers = vec![];
+            let mut ask_all = vec![];
+            let mut block_requests: HashMap<PeerId, Vec<BitswapRequest>> = HashMap::default();
+            for (&cid, want) in wants.iter_mut() {
+                if done.iter().any(|(done_cid, _)| done_cid == &cid) {
+                    continue;
                 }
-                Err(TryRecvError::Empty) => {}
-                Err(TryRecvError::Disconnected) => {
-                    break;
+                if !want.owned {
+                    // Wait for the session that wanted the block first
+                    if store.contains(&cid).unwrap_or_default() {
+                        done.push((cid, true));
+                    } else if now >= want.deadline {
+                        done.push((cid, false));
+                    }
+                    continue;
+                }
+                if now >= want.deadline {
+                    done.push((cid, false));
+                    continue;
+                }
+                // Ask the providers first, and everyone when no one answered in time as
+                // peers may have connected since
+                let ask_all_peers = match want.asked {
+                    _ if !want.have.is_empty() => None,
+                    None => Some(connected_providers.is_empty()),
+                    Some(asked_at) => (now.duration_since(asked_at)
+                        >= BITSWAP_BLOCK_RESPONSE_TIMEOUT)
+                        .then_some(true),
+                };
+                if let Some(all) = ask_all_peers {
+                    want.asked = Some(now);
+                    if all {
+                        ask_all.push(cid);
+                    } else {
+                        ask_providers.push(cid);
+                    }
+                }
+                if let Some((peer, requested_at)) = want.requested {
+                    if now.duration_since(requested_at) < BITSWAP_BLOCK_RESPONSE_TIMEOUT {
+                        continue;
+                    }
+                    self.release(&peer);
+                    want.requested = None;
+                }
+                if let Some(peer) = self.reserve_peer(want) {
+                    want.requested = Some((peer, now));
+                    block_requests
+                        .entry(peer)
+                        .or_default()
+                        .push(BitswapRequest::new_block(cid).send_dont_have(false));
+                }
+            }
+            self.send_have_requests(&connected_providers, &ask_providers);
+            self.send_have_requests(&peers, &ask_all);
+            for (peer, requests) in block_requests {
+                for chunk in requests.chunks(MAX_WANTLIST_SIZE) {
+                    _ = self.outbound_request_tx.send((peer, chunk.to_vec()));
                 }
             }
 
-            if let Ok(data) = block_saved_rx.recv_timeout(BITSWAP_BLOCK_REQUEST_INTERVAL) {
-                success = true;
-                block_data = data;
+            for (cid, success) in done {
+                // A block may be done more than once in an iteration, e.g. when delivered twice
+                if let Some(want) = wants.remove(&cid) {
+                    for _ in 0..want.waiters {
+                        _ = results_tx.send((cid, success));
+                    }
+                    self.finish(cid, want);
+                }
             }
         }
 
-        if !success {
-            if let Ok(data) = block_saved_rx.recv_deadline(deadline) {
-                success = true;
-                block_data = data;
-            }
+        // Cleanup
+        for (cid, want) in wants {
+            self.finish(cid, want);
         }
+    }
 
-        if let Some(data) = block_data {
-            success = match Block::new(cid, data) {
-                Ok(block) => match store.insert(&block) {
-                    Ok(()) => {
-                        metrics::message_counter_inbound_response_block_update_db().inc();
-                        true
-                    }
-                    Err(e) => {
-                        metrics::message_counter_inbound_response_block_update_db_failure().inc();
-                        warn!(
-                            "Failed to update db: {e}, cid: {cid}, data: {:?}",
-                            block.data()
-                        );
-                        false
-                    }
-                },
-                Err(e) => {
-                    warn!("Failed to construct block: {e}, cid: {cid}");
-                    false
+    fn send_have_requests(&self, peers: &[PeerId], cids: &[Cid]) {
+        for chunk in cids.chunks(MAX_WANTLIST_SIZE) {
+            let wantlist: Vec<_> = chunk
+                .iter()
+                .map(|&cid| BitswapRequest::new_have(cid).send_dont_have(false))
+                .collect();
+            for &peer in peers {
+                if let Err(e) = self.outbound_request_tx.send((peer, wantlist.clone())) {
+                    warn!("{e}");
                 }
-            };
+            }
         }
+    }
 
-        // Cleanup
-        {
+    /// Picks the peer to request a block from among those that have it,
+    /// preferring the ones with the fewest blocks in flight, and accounts for
+    /// the request.
+    fn reserve_peer(&self, want: &mut Want) -> Option<PeerId> {
+        if !want.have.is_empty() && want.have.iter().all(|peer| want.tried.contains(peer)) {
+            // Every peer has been tried, start over
+            want.tried.clear();
+        }
+        let mut in_flight = self.in_flight.lock();
+        let peer = want
+            .have
+            .iter()
+            .filter(|peer| !want.tried.contains(peer))
+            .map(|peer| (*peer, in_flight.get(peer).copied().unwrap_or_default()))
+            .filter(|(_, count)| *count < MAX_IN_FLIGHT_BLOCKS_PER_PEER)
+            .min_by_key(|(_, count)| *count)
+            .map(|(peer, _)| peer)?;
+        *in_flight.entry(peer).or_default() += 1;
+        want.tried.push(peer);
+        Some(peer)
+    }
+
+    fn release(&self, peer: &PeerId) {
+        let mut in_flight = self.in_flight.lock();
+        if let Some(count) = in_flight.get_mut(peer) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                in_flight.remove(peer);
+            }
+        }
+    }
+
+    fn finish(&self, cid: Cid, want: Want) {
+        if let Some((peer, _)) = want.requested {
+            self.release(&peer);
+        }
+        if want.owned {
             let mut response_channels = self.response_channels.write();
             response_channels.remove(&cid);
             metrics::response_channel_container_capacity()
                 .set(response_channels.total_capacity() as _);
         }
-
-        success
     }
 
     pub(in crate::libp2p_bitswap) fn on_inbound_response_event<S: BitswapStoreRead>(
@@ -214,7 +421,7 @@ impl BitswapRequestManager {
         match response {
             HaveBlock(peer, cid) => {
                 if let Some(chans) = self.response_channels.read().get(&cid) {
-                    _ = chans.block_have.send(peer);
+                    _ = chans.send(SessionEvent::Have(peer, cid));
                 }
             }
             DataBlock(_peer, cid, data) => {
@@ -223,9 +430,9 @@ impl BitswapRequestManager {
                         // Avoid duplicate writes, still notify the receiver
                         metrics::message_counter_inbound_response_block_already_exists_in_db()
                             .inc();
-                        _ = chans.block_received.send(None);
+                        _ = chans.send(SessionEvent::Block(cid, None));
                     } else {
-                        _ = chans.block_received.send(Some(data));
+                        _ = chans.send(SessionEvent::Block(cid, Some(data)));
                     }
 
                     // <https://github.com/ipfs/go-libipfs/tree/main/bitswap#background>
@@ -236,7 +443,7 @@ impl BitswapRequestManager {
                     for &peer in self.peers.read().iter() {
                         if let Err(e) = self
                             .outbound_request_tx
-                            .send((peer, cancel_request.clone()))
+                            .send((peer, vec![cancel_request.clone()]))
                         {
                             warn!("{e}");
                         }
@@ -263,6 +470,30 @@ impl BitswapRequestManager {
         if success {
             metrics::peer_container_capacity().set(peers.capacity() as _);
         }
+        self.in_flight.lock().remove(peer);
         success
     }
 }
+
+fn insert_block(store: &impl BitswapStoreReadWrite, cid: Cid, data: Vec<u8>) -> bool {
+    match Block::new(cid, data) {
+        Ok(block) => match store.insert(&block) {
+            Ok(()) => {
+                metrics::message_counter_inbound_response_block_update_db().inc();
+                true
+            }
+            Err(e) => {
+                metrics::message_counter_inbound_response_block_update_db_failure().inc();
+                warn!(
+                    "Failed to update db: {e}, cid: {cid}, data: {:?}",
+                    block.data()
+                );
+                false
+            }
+        },
+        Err(e) => {
+            warn!("Failed to construct block: {e}, cid: {cid}");
+            false
+        }
+    }
+}
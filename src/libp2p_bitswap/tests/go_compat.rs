@@ -83,10 +83,12 @@ mod tests {
                                             if r.cid == expected_inbound_request_cid {
                                                 inbound_request_tx.send_async(peer).await.unwrap();
                                                 // Send a request to the go app
-                                                bitswap.send_request(
+                                                bitswap.send_requests(
                                                     &peer,
-                                                    BitswapRequest::new_have(outbound_request_cid)
-                                                        .send_dont_have(true),
+                                                    vec![BitswapRequest::new_have(
+                                                        outbound_request_cid,
+                                                    )
+                                                    .send_dont_have(true)],
                                                 );
                                             }
                                         }
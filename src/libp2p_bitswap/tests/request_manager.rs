@@ -36,12 +36,15 @@ mod tests {
     async fn request_manager_e2e_test_mpl() -> anyhow::Result<()> {
         let block_exist = new_random_block()?;
         let block_not_exist = new_random_block()?;
+        let blocks_batch = (0..N_SERVER)
+            .map(|_| new_random_block())
+            .collect::<anyhow::Result<Vec<_>>>()?;
 
         // 1. Set up N servers, one of them have `block_exist` in its store
         let mut joinset = JoinSet::new();
         let mut server_addr_vec = vec![];
         let server_index_with_block = OsRng.gen_range(0..N_SERVER);
-        for i in 0..N_SERVER {
+        for (i, batch_block) in blocks_batch.iter().enumerate() {
             let (server, server_peer_id, server_peer_addr) = create_swarm().await?;
             println!("Server peer id: {server_peer_id}, address: {server_peer_addr}");
             server_addr_vec.push(server_peer_addr.with(Protocol::P2p(server_peer_id)));
@@ -50,6 +53,8 @@ mod tests {
             if i == server_index_with_block {
                 server_store.insert(&block_exist)?;
             }
+            // Spread the blocks of the batch across the servers
+            server_store.insert(batch_block)?;
             joinset.spawn(run_swarm_loop(server, server_store));
         }
 
@@ -87,7 +92,7 @@ mod tests {
         // 4. Get a block that exists on one of the servers
         {
             let (request_tx, request_rx) = flume::unbounded();
-            client_request_manager.get_block(
+            client_request_manager.clone().get_block(
                 client_store.clone(),
                 *block_exist.cid(),
                 TIMEOUT,
@@ -99,6 +104,42 @@ mod tests {
             assert!(client_store.contains(block_exist.cid())?);
         }
 
+        // 5. Get a batch of blocks from different servers in one session
+        {
+            let session = client_request_manager.session(client_store.clone(), TIMEOUT);
+            for block in &blocks_batch {
+                session.want(*block.cid());
+            }
+            session.want(*block_not_exist.cid());
+            // Blocks already in the store are reported right away
+            session.want(*block_exist.cid());
+            // Blocks wanted again while they are fetched are reported for each want
+            session.want(*blocks_batch[0].cid());
+
+            let mut fetched = HashMap::default();
+            let mut reported = vec![];
+            for _ in 0..blocks_batch.len() + 3 {
+                let (cid, success) = tokio::time::timeout(TIMEOUT * 2, session.next())
+                    .await?
+                    .expect("session should be running");
+                fetched.insert(cid, success);
+                reported.push(cid);
+            }
+            assert_eq!(
+                reported
+                    .iter()
+                    .filter(|cid| *cid == blocks_batch[0].cid())
+                    .count(),
+                2
+            );
+            for block in &blocks_batch {
+                assert_eq!(fetched.get(block.cid()), Some(&true));
+                assert!(client_store.contains(block.cid())?);
+            }
+            assert_eq!(fetched.get(block_exist.cid()), Some(&true));
+            assert_eq!(fetched.get(block_not_exist.cid()), Some(&false));
+        }
+
         Ok(())
     }
 
@@ -143,8 +184,8 @@ mod tests {
                         store.as_ref(),
                     );
                 },
-                request_opt = outbound_request_rx_stream.next() => if let Some((peer, request)) = request_opt {
-                    swarm_stream.get_mut().behaviour_mut().send_request(&peer, request);
+                request_opt = outbound_request_rx_stream.next() => if let Some((peer, requests)) = request_opt {
+                    swarm_stream.get_mut().behaviour_mut().send_requests(&peer, requests);
                 },
             }
         }
@@ -182,6 +182,7 @@ decl_and_test!(
     gossip_block for crate::blocks::GossipBlock,
     key_info for crate::key_management::KeyInfo,
     message for crate::shim::message::Message,
+    mpool_update for crate::message_pool::MpoolUpdate,
     po_st_proof for crate::shim::sector::PoStProof,
     registered_po_st_proof for crate::shim::sector::RegisteredPoStProof,
     registered_seal_proof for crate::shim::sector::RegisteredSealProof,
@@ -0,0 +1,100 @@
+// Copyright 2019-2023 ChainSafe Systems
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+use crate::message::SignedMessage;
+use crate::message_pool::MpoolUpdate;
+
+use super::*;
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct MpoolUpdateLotusJson {
+    #[serde(rename = "Type")]
+    kind: MpoolUpdateKind,
+    message: LotusJson<SignedMessage>,
+}
+
+/// Lotus encodes the update type as `0` for additions and `1` for removals.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(try_from = "u8", into = "u8")]
+enum MpoolUpdateKind {
+    Add,
+    Remove,
+}
+
+impl From<MpoolUpdateKind> for u8 {
+    fn from(kind: MpoolUpdateKind) -> Self {
+        match kind {
+            MpoolUpdateKind::Add => 0,
+            MpoolUpdateKind::Remove => 1,
+        }
+    }
+}
+
+impl TryFrom<u8> for MpoolUpdateKind {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Add),
+            1 => Ok(Self::Remove),
+            other => Err(format!("unknown mpool update type: {other}")),
+        }
+    }
+}
+
+impl HasLotusJson for MpoolUpdate {
+    type LotusJson = MpoolUpdateLotusJson;
+
+    fn snapshots() -> Vec<(serde_json::Value, Self)> {
+        vec![(
+            json!({
+                "Type": 1,
+                "Message": {
+                    "Message": {
+                        "From": "f00",
+                        "GasFeeCap": "0",
+                        "GasLimit": 0,
+                        "GasPremium": "0",
+                        "Method": 0,
+                        "Nonce": 0,
+                        "Params": null,
+                        "To": "f00",
+                        "Value": "0",
+                        "Version": 0,
+                        "CID": {
+                            "/": "bafy2bzaced3xdk2uf6azekyxgcttujvy3fzyeqmibtpjf2fxcpfdx2zcx4s3g"
+                        },
+                    },
+                    "Signature": {"Type": "bls", "Data": "aGVsbG8gd29ybGQh"}
+                }
+            }),
+            MpoolUpdate::Remove(SignedMessage {
+                message: crate::shim::message::Message::default(),
+                signature: crate::shim::crypto::Signature {
+                    sig_type: crate::shim::crypto::SignatureType::Bls,
+                    bytes: Vec::from_iter(*b"hello world!"),
+                },
+            }),
+        )]
+    }
+
+    fn into_lotus_json(self) -> Self::LotusJson {
+        let (kind, message) = match self {
+            MpoolUpdate::Add(message) => (MpoolUpdateKind::Add, message),
+            MpoolUpdate::Remove(message) => (MpoolUpdateKind::Remove, message),
+        };
+        Self::LotusJson {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    fn from_lotus_json(lotus_json: Self::LotusJson) -> Self {
+        let Self::LotusJson { kind, message } = lotus_json;
+        match kind {
+            MpoolUpdateKind::Add => Self::Add(message.into_inner()),
+            MpoolUpdateKind::Remove => Self::Remove(message.into_inner()),
+        }
+    }
+}
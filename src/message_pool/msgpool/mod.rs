@@ -22,13 +22,14 @@ use cid::Cid;
 use fvm_ipld_encoding::to_vec;
 use lru::LruCache;
 use parking_lot::{Mutex, RwLock as SyncRwLock};
+use tokio::sync::broadcast::Sender as Publisher;
 use tracing::error;
 use utils::{get_base_fee_lower_bound, recover_sig};
 
 use super::errors::Error;
 use crate::message_pool::{
     msg_chain::{create_message_chains, Chains},
-    msg_pool::{add_helper, remove, MsgSet},
+    msg_pool::{add_helper, remove, MpoolUpdate, MsgSet},
     provider::Provider,
 };
 
@@ -212,6 +213,7 @@ pub async fn head_change<T>(
     repub_trigger: Arc<flume::Sender<()>>,
     republished: &SyncRwLock<HashSet<Cid>>,
     pending: &SyncRwLock<HashMap<Address, MsgSet>>,
+    changes: &Publisher<MpoolUpdate>,
     cur_tipset: &Mutex<Arc<Tipset>>,
     revert: Vec<Tipset>,
     apply: Vec<Tipset>,
@@ -248,6 +250,7 @@ where
                 remove_from_selected_msgs(
                     &msg.from(),
                     pending,
+                    changes,
                     msg.sequence(),
                     rmsgs.borrow_mut(),
                 )?;
@@ -256,7 +259,13 @@ where
                 }
             }
             for msg in msgs {
-                remove_from_selected_msgs(&msg.from, pending, msg.sequence, rmsgs.borrow_mut())?;
+                remove_from_selected_msgs(
+                    &msg.from,
+                    pending,
+                    changes,
+                    msg.sequence,
+                    rmsgs.borrow_mut(),
+                )?;
                 if !repub && republished.write().insert(msg.cid()?) {
                     repub = true;
                 }
@@ -273,7 +282,7 @@ where
     for (_, hm) in rmsgs {
         for (_, msg) in hm {
             let sequence = get_state_sequence(api, &msg.from(), &cur_tipset.lock().clone())?;
-            if let Err(e) = add_helper(api, bls_sig_cache, pending, msg, sequence) {
+            if let Err(e) = add_helper(api, bls_sig_cache, pending, changes, msg, sequence) {
                 error!("Failed to read message from reorg to mpool: {}", e);
             }
         }
@@ -287,6 +296,7 @@ where
 pub(in crate::message_pool) fn remove_from_selected_msgs(
     from: &Address,
     pending: &SyncRwLock<HashMap<Address, MsgSet>>,
+    changes: &Publisher<MpoolUpdate>,
     sequence: u64,
     rmsgs: &mut HashMap<Address, HashMap<u64, SignedMessage>>,
 ) -> Result<(), Error> {
@@ -294,10 +304,10 @@ pub(in crate::message_pool) fn remove_from_selected_msgs(
         if temp.get_mut(&sequence).is_some() {
             temp.remove(&sequence);
         } else {
-            remove(from, pending, sequence, true)?;
+            remove(from, pending, changes, sequence, true)?;
         }
     } else {
-        remove(from, pending, sequence, true)?;
+        remove(from, pending, changes, sequence, true)?;
     }
     Ok(())
 }
@@ -476,6 +486,7 @@ pub mod tests {
             repub_trigger,
             republished.as_ref(),
             pending.as_ref(),
+            &mpool.changes,
             cur_tipset.as_ref(),
             Vec::new(),
             vec![Tipset::from(a)],
@@ -486,6 +497,70 @@ pub mod tests {
         assert_eq!(mpool.get_sequence(&sender).unwrap(), 2);
     }
 
+    #[tokio::test]
+    async fn test_mpool_changes() {
+        let keystore = KeyStore::new(KeyStoreConfig::Memory).unwrap();
+        let mut wallet = Wallet::new(keystore);
+        let sender = wallet.generate_addr(SignatureType::Secp256k1).unwrap();
+        let target = wallet.generate_addr(SignatureType::Secp256k1).unwrap();
+        let tma = TestApi::default();
+        tma.set_state_sequence(&sender, 0);
+
+        let (tx, _rx) = flume::bounded(50);
+        let mut services = JoinSet::new();
+        let mpool = MessagePool::new(
+            tma,
+            "mptest".to_string(),
+            tx,
+            Default::default(),
+            Arc::default(),
+            &mut services,
+        )
+        .unwrap();
+        let mut changes = mpool.changes.subscribe();
+
+        let original = create_smsg(&target, &sender, wallet.borrow_mut(), 0, 1000000, 100);
+        mpool.add(original.clone()).unwrap();
+        assert_eq!(
+            changes.try_recv().unwrap(),
+            MpoolUpdate::Add(original.clone())
+        );
+
+        // Replace by fee
+        let replacement = create_smsg(&target, &sender, wallet.borrow_mut(), 0, 1000000, 200);
+        mpool.add(replacement.clone()).unwrap();
+        assert_eq!(changes.try_recv().unwrap(), MpoolUpdate::Remove(original));
+        assert_eq!(
+            changes.try_recv().unwrap(),
+            MpoolUpdate::Add(replacement.clone())
+        );
+
+        let a = mock_block(1, 1);
+        mpool
+            .api
+            .inner
+            .lock()
+            .set_block_messages(&a, vec![replacement.clone()]);
+        head_change(
+            mpool.api.as_ref(),
+            mpool.bls_sig_cache.as_ref(),
+            Arc::new(mpool.repub_trigger.clone()),
+            mpool.republished.as_ref(),
+            mpool.pending.as_ref(),
+            &mpool.changes,
+            mpool.cur_tipset.as_ref(),
+            Vec::new(),
+            vec![Tipset::from(a)],
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            changes.try_recv().unwrap(),
+            MpoolUpdate::Remove(replacement)
+        );
+        assert!(changes.try_recv().is_err());
+    }
+
     #[tokio::test]
     async fn test_revert_messages() {
         let tma = TestApi::default();
@@ -544,6 +619,7 @@ pub mod tests {
             repub_trigger.clone(),
             republished.as_ref(),
             pending.as_ref(),
+            &mpool.changes,
             cur_tipset.as_ref(),
             Vec::new(),
             vec![Tipset::from(a)],
@@ -566,6 +642,7 @@ pub mod tests {
             repub_trigger.clone(),
             republished.as_ref(),
             pending.as_ref(),
+            &mpool.changes,
             cur_tipset.as_ref(),
             Vec::new(),
             vec![Tipset::from(&b)],
@@ -583,6 +660,7 @@ pub mod tests {
             repub_trigger.clone(),
             republished.as_ref(),
             pending.as_ref(),
+            &mpool.changes,
             cur_tipset.as_ref(),
             vec![Tipset::from(b)],
             Vec::new(),
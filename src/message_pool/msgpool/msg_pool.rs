@@ -31,7 +31,11 @@ use lru::LruCache;
 use nonzero_ext::nonzero;
 use num::BigInt;
 use parking_lot::{Mutex, RwLock as SyncRwLock};
-use tokio::{sync::broadcast::error::RecvError, task::JoinSet, time::interval};
+use tokio::{
+    sync::broadcast::{self, error::RecvError, Sender as Publisher},
+    task::JoinSet,
+    time::interval,
+};
 use tracing::warn;
 
 use crate::message_pool::{
@@ -49,10 +53,22 @@ use crate::message_pool::{
 // LruCache sizes have been taken from the lotus implementation
 const BLS_SIG_CACHE_SIZE: NonZeroUsize = nonzero!(40000usize);
 const SIG_VAL_CACHE_SIZE: NonZeroUsize = nonzero!(32000usize);
+// A cap on the number of buffered, not yet consumed, pool update events
+const CHANGES_CAP: usize = 1024;
 
 pub const MAX_ACTOR_PENDING_MESSAGES: u64 = 1000;
 pub const MAX_UNTRUSTED_ACTOR_PENDING_MESSAGES: u64 = 10;
 
+/// Event published whenever a message enters or leaves the set of pending
+/// messages. Replacing a message by fee emits a `Remove` of the old message
+/// followed by an `Add` of the new one.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(test, derive(derive_quickcheck_arbitrary::Arbitrary))]
+pub enum MpoolUpdate {
+    Add(SignedMessage),
+    Remove(SignedMessage),
+}
+
 /// Simple structure that contains a hash-map of messages where k: a message
 /// from address, v: a message which corresponds to that address.
 #[derive(Clone, Default, Debug)]
@@ -133,17 +149,17 @@ impl MsgSet {
     }
 
     /// Removes message with the given sequence. If applied, update the set's
-    /// next sequence.
-    pub fn rm(&mut self, sequence: u64, applied: bool) {
-        if self.msgs.remove(&sequence).is_none() {
+    /// next sequence. Returns the removed message, if any.
+    pub fn rm(&mut self, sequence: u64, applied: bool) -> Option<SignedMessage> {
+        let Some(removed) = self.msgs.remove(&sequence) else {
             if applied && sequence >= self.next_sequence {
                 self.next_sequence = sequence + 1;
                 while self.msgs.get(&self.next_sequence).is_some() {
                     self.next_sequence += 1;
                 }
             }
-            return;
-        }
+            return None;
+        };
         metrics::MPOOL_MESSAGE_TOTAL.dec();
 
         // adjust next sequence
@@ -153,13 +169,14 @@ impl MsgSet {
             if sequence >= self.next_sequence {
                 self.next_sequence = sequence + 1;
             }
-            return;
+            return Some(removed);
         }
         // we removed a message because it was pruned
         // we have to adjust the sequence if it creates a gap or rewinds state
         if sequence < self.next_sequence {
             self.next_sequence = sequence;
         }
+        Some(removed)
     }
 }
 
@@ -195,6 +212,8 @@ pub struct MessagePool<T> {
     pub config: MpoolConfig,
     /// Chain configuration
     pub chain_config: Arc<ChainConfig>,
+    /// Publisher for pending message updates
+    pub changes: Publisher<MpoolUpdate>,
 }
 
 impl<T> MessagePool<T>
@@ -314,6 +333,7 @@ where
             self.api.as_ref(),
             self.bls_sig_cache.as_ref(),
             self.pending.as_ref(),
+            &self.changes,
             msg,
             self.get_state_sequence(&from, &cur_ts)?,
         )
@@ -485,6 +505,7 @@ where
         let block_delay = chain_config.block_delay_secs;
 
         let (repub_trigger, repub_trigger_rx) = flume::bounded::<()>(4);
+        let (changes, _) = broadcast::channel(CHANGES_CAP);
         let mut mp = MessagePool {
             local_addrs,
             pending,
@@ -500,6 +521,7 @@ where
             network_sender,
             repub_trigger,
             chain_config: Arc::clone(&chain_config),
+            changes,
         };
 
         mp.load_local()?;
@@ -509,6 +531,7 @@ where
         let api = mp.api.clone();
         let bls_sig_cache = mp.bls_sig_cache.clone();
         let pending = mp.pending.clone();
+        let changes = mp.changes.clone();
         let republished = mp.republished.clone();
 
         let cur_tipset = mp.cur_tipset.clone();
@@ -532,6 +555,7 @@ where
                             repub_trigger.clone(),
                             republished.as_ref(),
                             pending.as_ref(),
+                            &changes,
                             cur.as_ref(),
                             rev,
                             app,
@@ -596,6 +620,7 @@ pub(in crate::message_pool) fn add_helper<T>(
     api: &T,
     bls_sig_cache: &Mutex<LruCache<Cid, Signature>>,
     pending: &SyncRwLock<HashMap<Address, MsgSet>>,
+    changes: &Publisher<MpoolUpdate>,
     msg: SignedMessage,
     sequence: u64,
 ) -> Result<(), Error>
@@ -620,14 +645,22 @@ where
     let mut pending = pending.write();
     let msett = pending.get_mut(&msg.from());
     match msett {
-        Some(mset) => mset.add_trusted(api, msg)?,
+        Some(mset) => {
+            let replaced = mset.msgs.get(&msg.sequence()).cloned();
+            mset.add_trusted(api, msg.clone())?;
+            if let Some(replaced) = replaced {
+                // There are no subscribers most of the time, which is fine.
+                let _ = changes.send(MpoolUpdate::Remove(replaced));
+            }
+        }
         None => {
             let mut mset = MsgSet::new(sequence);
             let from = msg.from();
-            mset.add_trusted(api, msg)?;
+            mset.add_trusted(api, msg.clone())?;
             pending.insert(from, mset);
         }
     }
+    let _ = changes.send(MpoolUpdate::Add(msg));
 
     Ok(())
 }
@@ -662,6 +695,7 @@ fn verify_msg_before_add(
 pub fn remove(
     from: &Address,
     pending: &SyncRwLock<HashMap<Address, MsgSet>>,
+    changes: &Publisher<MpoolUpdate>,
     sequence: u64,
     applied: bool,
 ) -> Result<(), Error> {
@@ -672,7 +706,9 @@ pub fn remove(
         return Ok(());
     };
 
-    mset.rm(sequence, applied);
+    if let Some(removed) = mset.rm(sequence, applied) {
+        let _ = changes.send(MpoolUpdate::Remove(removed));
+    }
 
     if mset.msgs.is_empty() {
         pending.remove(from);
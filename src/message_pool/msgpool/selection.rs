@@ -14,12 +14,13 @@ use crate::shim::{address::Address, econ::TokenAmount};
 use ahash::{HashMap, HashMapExt};
 use parking_lot::RwLock;
 use rand::{prelude::SliceRandom, thread_rng};
+use tokio::sync::broadcast::Sender as Publisher;
 
 use super::{msg_pool::MessagePool, provider::Provider};
 use crate::message_pool::{
     add_to_selected_msgs,
     msg_chain::{create_message_chains, Chains, NodeKey},
-    msg_pool::MsgSet,
+    msg_pool::{MpoolUpdate, MsgSet},
     msgpool::MIN_GAS,
     remove_from_selected_msgs, Error,
 };
@@ -480,6 +481,7 @@ where
         run_head_change(
             self.api.as_ref(),
             &self.pending,
+            &self.changes,
             cur_ts.clone(),
             ts.clone(),
             &mut result,
@@ -631,6 +633,7 @@ fn merge_and_trim(
 pub(in crate::message_pool) fn run_head_change<T>(
     api: &T,
     pending: &RwLock<HashMap<Address, MsgSet>>,
+    changes: &Publisher<MpoolUpdate>,
     from: Tipset,
     to: Tipset,
     rmsgs: &mut HashMap<Address, HashMap<u64, SignedMessage>>,
@@ -672,12 +675,19 @@ where
                 remove_from_selected_msgs(
                     &msg.from(),
                     pending,
+                    changes,
                     msg.sequence(),
                     rmsgs.borrow_mut(),
                 )?;
             }
             for msg in msgs {
-                remove_from_selected_msgs(&msg.from, pending, msg.sequence, rmsgs.borrow_mut())?;
+                remove_from_selected_msgs(
+                    &msg.from,
+                    pending,
+                    changes,
+                    msg.sequence,
+                    rmsgs.borrow_mut(),
+                )?;
             }
         }
     }
@@ -747,6 +757,7 @@ mod test_selection {
             repub_trigger.clone(),
             republished.as_ref(),
             pending.as_ref(),
+            &mpool.changes,
             cur_tipset.as_ref(),
             Vec::new(),
             vec![Tipset::from(b1)],
@@ -805,6 +816,7 @@ mod test_selection {
             repub_trigger.clone(),
             republished.as_ref(),
             pending.as_ref(),
+            &mpool.changes,
             cur_tipset.as_ref(),
             Vec::new(),
             vec![Tipset::from(b2)],
@@ -909,6 +921,7 @@ mod test_selection {
             repub_trigger.clone(),
             republished.as_ref(),
             pending.as_ref(),
+            &mpool.changes,
             cur_tipset.as_ref(),
             Vec::new(),
             vec![Tipset::from(b1)],
@@ -989,6 +1002,7 @@ mod test_selection {
             repub_trigger.clone(),
             republished.as_ref(),
             pending.as_ref(),
+            &mpool.changes,
             cur_tipset.as_ref(),
             Vec::new(),
             vec![Tipset::from(b1)],
@@ -1083,6 +1097,7 @@ mod test_selection {
             repub_trigger.clone(),
             republished.as_ref(),
             pending.as_ref(),
+            &mpool.changes,
             cur_tipset.as_ref(),
             Vec::new(),
             vec![Tipset::from(b1)],
@@ -1162,6 +1177,7 @@ mod test_selection {
             repub_trigger.clone(),
             republished.as_ref(),
             pending.as_ref(),
+            &mpool.changes,
             cur_tipset.as_ref(),
             Vec::new(),
             vec![Tipset::from(b1)],
@@ -1279,6 +1295,7 @@ mod test_selection {
             repub_trigger.clone(),
             republished.as_ref(),
             pending.as_ref(),
+            &mpool.changes,
             cur_tipset.as_ref(),
             Vec::new(),
             vec![Tipset::from(block)],
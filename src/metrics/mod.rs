@@ -126,4 +126,6 @@ pub mod values {
     pub const TIPSET: &str = "tipset";
     /// tipset cache in state manager
     pub const STATE_MANAGER_TIPSET: &str = "sm_tipset";
+    /// tipset states persisted in the database by the state manager
+    pub const STATE_MANAGER_TIPSET_DB: &str = "sm_tipset_db";
 }
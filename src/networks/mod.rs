@@ -11,7 +11,7 @@ use reqwest::Url;
 use serde::{Deserialize, Serialize};
 use strum_macros::Display;
 
-use crate::beacon::{BeaconPoint, BeaconSchedule, DrandBeacon, DrandConfig};
+use crate::beacon::{BeaconPoint, BeaconSchedule, DrandBeacon, DrandConfig, DrandSources};
 use crate::shim::clock::{ChainEpoch, EPOCH_DURATION_SECONDS};
 use crate::shim::sector::{RegisteredPoStProofV3, RegisteredSealProofV3};
 use crate::shim::version::NetworkVersion;
@@ -200,6 +200,10 @@ pub struct ChainConfig {
     /// Number of default recent state roots to keep in memory and include in
     /// the exported snapshot.
     pub recent_state_roots: i64,
+    /// Sources of `drand` entries, set at runtime from the node configuration.
+    #[serde(skip)]
+    #[cfg_attr(test, arbitrary(gen(|_g| DrandSources::default())))]
+    pub drand: DrandSources,
     pub request_window: u32,
 }
 
@@ -217,6 +221,7 @@ impl ChainConfig {
             eth_chain_id: ETH_CHAIN_ID as u32,
             recent_state_roots: DEFAULT_RECENT_STATE_ROOTS,
             request_window: DEFAULT_REQUEST_WINDOW as u32,
+            drand: DrandSources::default(),
         }
     }
 
@@ -233,6 +238,7 @@ impl ChainConfig {
             eth_chain_id: ETH_CHAIN_ID as u32,
             recent_state_roots: DEFAULT_RECENT_STATE_ROOTS,
             request_window: DEFAULT_REQUEST_WINDOW as u32,
+            drand: DrandSources::default(),
         }
     }
 
@@ -267,6 +273,7 @@ impl ChainConfig {
             eth_chain_id: ETH_CHAIN_ID as u32,
             recent_state_roots: DEFAULT_RECENT_STATE_ROOTS,
             request_window: DEFAULT_REQUEST_WINDOW as u32,
+            drand: DrandSources::default(),
         }
     }
 
@@ -307,12 +314,25 @@ impl ChainConfig {
                         genesis_ts,
                         self.block_delay_secs as u64,
                         dc.config,
+                        &self.drand,
                     )),
                 })
                 .collect(),
         )
     }
 
+    /// Hashes of the `drand` beacon chains of the network.
+    pub fn drand_chain_hashes(&self) -> Vec<String> {
+        let ds_iter = match self.network {
+            NetworkChain::Mainnet => mainnet::DRAND_SCHEDULE.iter(),
+            NetworkChain::Calibnet => calibnet::DRAND_SCHEDULE.iter(),
+            NetworkChain::Devnet(_) => devnet::DRAND_SCHEDULE.iter(),
+        };
+        ds_iter
+            .map(|dc| dc.config.chain_info.hash.to_string())
+            .collect()
+    }
+
     pub fn epoch(&self, height: Height) -> ChainEpoch {
         sort_by_epoch(&self.height_infos)
             .iter()
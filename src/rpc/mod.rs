@@ -20,15 +20,30 @@ mod wallet_api;
 
 use std::{net::TcpListener, sync::Arc};
 
+use crate::message_pool::MpoolUpdate;
 use crate::rpc_api::{
-    auth_api::*, beacon_api::*, chain_api::*, common_api::*, data_types::RPCState, db_api::*,
-    gas_api::*, mpool_api::*, net_api::*, node_api::NODE_STATUS, progress_api::GET_PROGRESS,
-    state_api::*, sync_api::*, wallet_api::*,
+    auth_api::*,
+    beacon_api::*,
+    chain_api::*,
+    common_api::*,
+    data_types::{JsonRpcServerState, RPCState},
+    db_api::*,
+    gas_api::*,
+    mpool_api::*,
+    net_api::*,
+    node_api::NODE_STATUS,
+    progress_api::GET_PROGRESS,
+    state_api::*,
+    sync_api::*,
+    wallet_api::*,
+};
+use axum::{
+    extract::FromRef,
+    routing::{get, post},
 };
-use axum::routing::{get, post};
 use fvm_ipld_blockstore::Blockstore;
 use jsonrpc_v2::{Data, Error as JSONRPCError, Params, Server};
-use tokio::sync::mpsc::Sender;
+use tokio::sync::{broadcast::Sender as Publisher, mpsc::Sender};
 use tracing::info;
 
 use crate::rpc::{
@@ -41,6 +56,20 @@ use crate::rpc::{
 
 pub type RpcResult<T> = Result<T, JSONRPCError>;
 
+/// State shared by the HTTP and WebSocket RPC handlers.
+#[derive(Clone)]
+pub struct RpcServerState {
+    rpc_server: JsonRpcServerState,
+    /// Source of the `Filecoin.MpoolSub` subscription events
+    mpool_changes: Publisher<MpoolUpdate>,
+}
+
+impl FromRef<RpcServerState> for JsonRpcServerState {
+    fn from_ref(state: &RpcServerState) -> Self {
+        state.rpc_server.clone()
+    }
+}
+
 pub async fn start_rpc<DB>(
     state: Arc<RPCState<DB>>,
     rpc_endpoint: TcpListener,
@@ -58,6 +87,7 @@ where
     use wallet_api::*;
 
     let block_delay = state.state_manager.chain_config().block_delay_secs as u64;
+    let mpool_changes = state.mpool.changes.clone();
     let rpc_server = Arc::new(
         Server::new()
             .with_data(Data(state))
@@ -128,6 +158,19 @@ where
             .with_method(NET_INFO, net_api::net_info::<DB>)
             .with_method(NET_CONNECT, net_api::net_connect::<DB>)
             .with_method(NET_DISCONNECT, net_api::net_disconnect::<DB>)
+            .with_method(NET_AUTO_NAT_STATUS, net_api::net_auto_nat_status::<DB>)
+            .with_method(NET_BLOCK_ADD, net_api::net_block_add::<DB>)
+            .with_method(NET_BLOCK_REMOVE, net_api::net_block_remove::<DB>)
+            .with_method(NET_BLOCK_LIST, net_api::net_block_list::<DB>)
+            .with_method(NET_PROTECT_ADD, net_api::net_protect_add::<DB>)
+            .with_method(NET_PROTECT_REMOVE, net_api::net_protect_remove::<DB>)
+            .with_method(NET_PROTECT_LIST, net_api::net_protect_list::<DB>)
+            .with_method(NET_PEER_INFO, net_api::net_peer_info::<DB>)
+            .with_method(NET_BANDWIDTH_STATS, net_api::net_bandwidth_stats)
+            .with_method(
+                NET_BANDWIDTH_STATS_BY_PROTOCOL,
+                net_api::net_bandwidth_stats_by_protocol,
+            )
             // DB API
             .with_method(DB_GC, db_api::db_gc::<DB>)
             // Progress API
@@ -140,7 +183,10 @@ where
     let app = axum::Router::new()
         .route("/rpc/v0", get(rpc_ws_handler))
         .route("/rpc/v0", post(rpc_http_handler))
-        .with_state(rpc_server);
+        .with_state(RpcServerState {
+            rpc_server,
+            mpool_changes,
+        });
 
     info!("Ready for RPC connections");
     let server = axum::Server::from_tcp(rpc_endpoint)?.serve(app.into_make_service());
@@ -3,7 +3,7 @@
 
 use std::str::FromStr;
 
-use crate::libp2p::{NetRPCMethods, NetworkMessage, PeerId};
+use crate::libp2p::{bandwidth, BlockList, NetRPCMethods, NetworkMessage, PeerId};
 use crate::rpc_api::{
     data_types::{AddrInfo, RPCState},
     net_api::*,
@@ -11,6 +11,7 @@ use crate::rpc_api::{
 use cid::multibase;
 use futures::channel::oneshot;
 use fvm_ipld_blockstore::Blockstore;
+use itertools::Itertools;
 use jsonrpc_v2::{Data, Error as JsonRpcError, Params};
 use tracing::error;
 
@@ -106,3 +107,141 @@ pub(in crate::rpc) async fn net_disconnect<DB: Blockstore>(
 
     Ok(())
 }
+
+pub(in crate::rpc) async fn net_auto_nat_status<DB: Blockstore>(
+    data: Data<RPCState<DB>>,
+) -> Result<NetAutoNatStatusResult, JsonRpcError> {
+    let (tx, rx) = oneshot::channel();
+    let req = NetworkMessage::JSONRPCRequest {
+        method: NetRPCMethods::AutoNatStatus(tx),
+    };
+
+    data.network_send.send_async(req).await?;
+    let nat_status = rx.await?;
+
+    Ok(nat_status.into())
+}
+
+pub(in crate::rpc) async fn net_block_add<DB: Blockstore>(
+    data: Data<RPCState<DB>>,
+    Params((block_list,)): Params<NetBlockAddParams>,
+) -> Result<NetBlockAddResult, JsonRpcError> {
+    let (tx, rx) = oneshot::channel();
+    let req = NetworkMessage::JSONRPCRequest {
+        method: NetRPCMethods::BlockAdd(tx, BlockList::try_from(block_list)?),
+    };
+
+    data.network_send.send_async(req).await?;
+    Ok(rx.await??)
+}
+
+pub(in crate::rpc) async fn net_block_remove<DB: Blockstore>(
+    data: Data<RPCState<DB>>,
+    Params((block_list,)): Params<NetBlockRemoveParams>,
+) -> Result<NetBlockRemoveResult, JsonRpcError> {
+    let (tx, rx) = oneshot::channel();
+    let req = NetworkMessage::JSONRPCRequest {
+        method: NetRPCMethods::BlockRemove(tx, BlockList::try_from(block_list)?),
+    };
+
+    data.network_send.send_async(req).await?;
+    Ok(rx.await??)
+}
+
+pub(in crate::rpc) async fn net_block_list<DB: Blockstore>(
+    data: Data<RPCState<DB>>,
+) -> Result<NetBlockListResult, JsonRpcError> {
+    let (tx, rx) = oneshot::channel();
+    let req = NetworkMessage::JSONRPCRequest {
+        method: NetRPCMethods::BlockList(tx),
+    };
+
+    data.network_send.send_async(req).await?;
+    Ok(NetBlockList::from(&rx.await?))
+}
+
+pub(in crate::rpc) async fn net_protect_add<DB: Blockstore>(
+    data: Data<RPCState<DB>>,
+    Params((peers,)): Params<NetProtectAddParams>,
+) -> Result<NetProtectAddResult, JsonRpcError> {
+    let peers = peers.iter().map(|peer| peer.parse()).try_collect()?;
+    let (tx, rx) = oneshot::channel();
+    let req = NetworkMessage::JSONRPCRequest {
+        method: NetRPCMethods::ProtectAdd(tx, peers),
+    };
+
+    data.network_send.send_async(req).await?;
+    Ok(rx.await??)
+}
+
+pub(in crate::rpc) async fn net_protect_remove<DB: Blockstore>(
+    data: Data<RPCState<DB>>,
+    Params((peers,)): Params<NetProtectRemoveParams>,
+) -> Result<NetProtectRemoveResult, JsonRpcError> {
+    let peers = peers.iter().map(|peer| peer.parse()).try_collect()?;
+    let (tx, rx) = oneshot::channel();
+    let req = NetworkMessage::JSONRPCRequest {
+        method: NetRPCMethods::ProtectRemove(tx, peers),
+    };
+
+    data.network_send.send_async(req).await?;
+    Ok(rx.await??)
+}
+
+pub(in crate::rpc) async fn net_protect_list<DB: Blockstore>(
+    data: Data<RPCState<DB>>,
+) -> Result<NetProtectListResult, JsonRpcError> {
+    let (tx, rx) = oneshot::channel();
+    let req = NetworkMessage::JSONRPCRequest {
+        method: NetRPCMethods::ProtectList(tx),
+    };
+
+    data.network_send.send_async(req).await?;
+    Ok(rx.await?.iter().map(PeerId::to_string).sorted().collect())
+}
+
+pub(in crate::rpc) async fn net_peer_info<DB: Blockstore>(
+    data: Data<RPCState<DB>>,
+    Params((peer_id,)): Params<NetPeerInfoParams>,
+) -> Result<NetPeerInfoResult, JsonRpcError> {
+    let peer_id: PeerId = peer_id.parse()?;
+    let (tx, rx) = oneshot::channel();
+    let req = NetworkMessage::JSONRPCRequest {
+        method: NetRPCMethods::PeerInfo(tx, peer_id),
+    };
+
+    data.network_send.send_async(req).await?;
+    let (stats, addrs) = rx
+        .await?
+        .ok_or_else(|| format!("peer {peer_id} not found"))?;
+
+    Ok(ExtendedPeerInfo {
+        id: peer_id.to_string(),
+        agent: stats.agent_version.unwrap_or_default(),
+        addrs: addrs.iter().map(ToString::to_string).sorted().collect(),
+        protocols: stats.protocols,
+        direction: stats
+            .direction
+            .map(|direction| format!("{direction:?}"))
+            .unwrap_or_default(),
+        head_epoch: stats.head_epoch,
+        successes: stats.successes,
+        failures: stats.failures,
+        average_time_ms: stats.average_time.as_millis() as u64,
+        cost: stats.cost,
+        bad: stats.bad,
+        protected: stats.protected,
+    })
+}
+
+pub(in crate::rpc) async fn net_bandwidth_stats() -> Result<NetBandwidthStatsResult, JsonRpcError> {
+    Ok(bandwidth::total_stats().into())
+}
+
+pub(in crate::rpc) async fn net_bandwidth_stats_by_protocol(
+) -> Result<NetBandwidthStatsByProtocolResult, JsonRpcError> {
+    Ok(bandwidth::stats_by_protocol()
+        .into_iter()
+        .map(|(protocol, stats)| (protocol, stats.into()))
+        .collect())
+}
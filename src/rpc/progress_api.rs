@@ -7,6 +7,7 @@ use std::sync::atomic;
 
 use crate::ipld::{ProgressBarCurrentTotalPair, WALK_SNAPSHOT_PROGRESS_DB_GC};
 use crate::rpc_api::progress_api::{GetProgressParams, GetProgressResult, GetProgressType};
+use crate::state_manager::STATE_RECOMPUTATION_PROGRESS;
 
 use crate::rpc::*;
 
@@ -15,6 +16,7 @@ pub(in crate::rpc) async fn get_progress(
 ) -> RpcResult<GetProgressResult> {
     let tracker: &ProgressBarCurrentTotalPair = match typ {
         GetProgressType::DatabaseGarbageCollection => &WALK_SNAPSHOT_PROGRESS_DB_GC,
+        GetProgressType::StateRecomputation => &STATE_RECOMPUTATION_PROGRESS,
     };
 
     Ok((
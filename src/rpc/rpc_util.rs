@@ -1,7 +1,9 @@
 // Copyright 2019-2023 ChainSafe Systems
 // SPDX-License-Identifier: Apache-2.0, MIT
 
-use crate::rpc_api::{auth_api::*, check_access, data_types::JsonRpcServerState, ACCESS_MAP};
+use crate::rpc_api::{
+    auth_api::*, check_access, data_types::JsonRpcServerState, mpool_api::MPOOL_SUB, ACCESS_MAP,
+};
 use http::{HeaderMap, HeaderValue, StatusCode};
 use serde::de::DeserializeOwned;
 use tracing::{debug, error};
@@ -33,7 +35,7 @@ pub fn get_error_str(code: i64, message: String) -> String {
     }
 }
 
-const STREAMING_METHODS: [&str; 0] = [];
+const STREAMING_METHODS: [&str; 1] = [MPOOL_SUB];
 
 pub fn is_streaming_method(method_name: &str) -> bool {
     STREAMING_METHODS.contains(&method_name)
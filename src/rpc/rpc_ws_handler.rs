@@ -1,9 +1,13 @@
 // Copyright 2019-2023 ChainSafe Systems
 // SPDX-License-Identifier: Apache-2.0, MIT
 
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
 
-use crate::rpc_api::data_types::JsonRpcServerState;
+use crate::lotus_json::LotusJson;
+use crate::rpc_api::mpool_api::MPOOL_SUB;
 use axum::{
     extract::{
         ws::{Message, WebSocket},
@@ -11,30 +15,48 @@ use axum::{
     },
     response::IntoResponse,
 };
-use crossbeam::atomic::AtomicCell;
 use futures::{stream::SplitSink, SinkExt, StreamExt};
 use http::{HeaderMap, HeaderValue};
-use tokio::sync::RwLock;
+use serde_json::json;
+use tokio::sync::{broadcast::error::RecvError, RwLock};
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, warn};
 
-use crate::rpc::rpc_util::{call_rpc_str, check_permissions, get_auth_header, get_error_str};
+use crate::rpc::{
+    rpc_util::{
+        call_rpc_str, check_permissions, get_auth_header, get_error_str, is_streaming_method,
+    },
+    RpcServerState,
+};
+
+/// Identifies a subscription on the websocket it was opened on, as in the
+/// Lotus `xrpc.ch.val` notifications.
+static NEXT_CHANNEL_ID: AtomicU64 = AtomicU64::new(1);
 
 async fn rpc_ws_task(
     authorization_header: Option<HeaderValue>,
     rpc_call: jsonrpc_v2::RequestObject,
-    rpc_server: JsonRpcServerState,
-    _is_socket_active: Arc<AtomicCell<bool>>,
+    rpc_server: RpcServerState,
+    socket_closed: CancellationToken,
     ws_sender: Arc<RwLock<SplitSink<WebSocket, Message>>>,
 ) -> anyhow::Result<()> {
     let call_method = rpc_call.method_ref();
-    let _call_id = rpc_call.id_ref();
+    let call_id = rpc_call.id_ref().cloned().unwrap_or(jsonrpc_v2::Id::Null);
 
-    check_permissions(rpc_server.clone(), call_method, authorization_header)
-        .await
-        .map_err(|(_, e)| anyhow::Error::msg(e))?;
+    check_permissions(
+        rpc_server.rpc_server.clone(),
+        call_method,
+        authorization_header,
+    )
+    .await
+    .map_err(|(_, e)| anyhow::Error::msg(e))?;
 
     info!("RPC WS called method: {}", call_method);
-    let response = call_rpc_str(rpc_server.clone(), rpc_call).await?;
+    if is_streaming_method(call_method) {
+        return rpc_ws_stream(call_method, call_id, rpc_server, socket_closed, ws_sender).await;
+    }
+
+    let response = call_rpc_str(rpc_server.rpc_server.clone(), rpc_call).await?;
     ws_sender
         .write()
         .await
@@ -44,9 +66,74 @@ async fn rpc_ws_task(
     Ok(())
 }
 
+/// Opens a channel for a streaming method and forwards its values to the
+/// socket until either side goes away. The channel identifier is returned as
+/// the call result, and every value is sent as an `xrpc.ch.val` notification.
+async fn rpc_ws_stream(
+    call_method: &str,
+    call_id: jsonrpc_v2::Id,
+    rpc_server: RpcServerState,
+    socket_closed: CancellationToken,
+    ws_sender: Arc<RwLock<SplitSink<WebSocket, Message>>>,
+) -> anyhow::Result<()> {
+    let mut subscriber = match call_method {
+        MPOOL_SUB => rpc_server.mpool_changes.subscribe(),
+        _ => anyhow::bail!("Unsupported streaming method: {call_method}"),
+    };
+    let channel_id = NEXT_CHANNEL_ID.fetch_add(1, Ordering::Relaxed);
+
+    let response = json!({ "jsonrpc": "2.0", "result": channel_id, "id": call_id });
+    ws_sender
+        .write()
+        .await
+        .send(Message::Text(response.to_string()))
+        .await?;
+
+    loop {
+        // Stop waiting for updates as soon as the socket is closed, so that
+        // the subscription doesn't outlive it
+        let update = tokio::select! {
+            _ = socket_closed.cancelled() => break,
+            update = subscriber.recv() => match update {
+                Ok(update) => update,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("{call_method} subscriber lagged: skipping {skipped} events");
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            },
+        };
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": "xrpc.ch.val",
+            "params": [channel_id, LotusJson(update)],
+        });
+        ws_sender
+            .write()
+            .await
+            .send(Message::Text(notification.to_string()))
+            .await?;
+    }
+
+    let notification = json!({
+        "jsonrpc": "2.0",
+        "method": "xrpc.ch.close",
+        "params": [channel_id],
+    });
+    if !socket_closed.is_cancelled() {
+        ws_sender
+            .write()
+            .await
+            .send(Message::Text(notification.to_string()))
+            .await?;
+    }
+
+    Ok(())
+}
+
 pub async fn rpc_ws_handler(
     headers: HeaderMap,
-    axum::extract::State(rpc_server): axum::extract::State<JsonRpcServerState>,
+    axum::extract::State(rpc_server): axum::extract::State<RpcServerState>,
     ws: WebSocketUpgrade,
 ) -> impl IntoResponse {
     let authorization_header = get_auth_header(headers);
@@ -58,12 +145,12 @@ pub async fn rpc_ws_handler(
 async fn rpc_ws_handler_inner(
     socket: WebSocket,
     authorization_header: Option<HeaderValue>,
-    rpc_server: JsonRpcServerState,
+    rpc_server: RpcServerState,
 ) {
     info!("Accepted WS connection!");
     let (sender, mut receiver) = socket.split();
     let ws_sender = Arc::new(RwLock::new(sender));
-    let socket_active = Arc::new(AtomicCell::new(true));
+    let socket_closed = CancellationToken::new();
     while let Some(Ok(message)) = receiver.next().await {
         debug!("Received new WS RPC message: {:?}", message);
         if let Message::Text(request_text) = message {
@@ -72,7 +159,7 @@ async fn rpc_ws_handler_inner(
                 info!("RPC Request Received: {:?}", &request_text);
                 let authorization_header = authorization_header.clone();
                 let task_rpc_server = rpc_server.clone();
-                let task_socket_active = socket_active.clone();
+                let task_socket_closed = socket_closed.clone();
                 let task_ws_sender = ws_sender.clone();
                 match serde_json::from_str(&request_text)
                     as Result<jsonrpc_v2::RequestObject, serde_json::Error>
@@ -83,7 +170,7 @@ async fn rpc_ws_handler_inner(
                                 authorization_header,
                                 rpc_call,
                                 task_rpc_server,
-                                task_socket_active,
+                                task_socket_closed,
                                 task_ws_sender.clone(),
                             )
                             .await
@@ -94,12 +181,15 @@ async fn rpc_ws_handler_inner(
                                 Err(e) => {
                                     let msg = format!("WS RPC task error: {e}");
                                     error!("{}", msg);
-                                    task_ws_sender
+                                    // The client may be gone already
+                                    if let Err(e) = task_ws_sender
                                         .write()
                                         .await
                                         .send(Message::Text(get_error_str(3, msg)))
                                         .await
-                                        .unwrap();
+                                    {
+                                        warn!("Failed to send WS RPC error: {e}");
+                                    }
                                 }
                             }
                         });
@@ -120,5 +210,5 @@ async fn rpc_ws_handler_inner(
             }
         }
     }
-    socket_active.store(false);
+    socket_closed.cancel();
 }
@@ -2,9 +2,8 @@
 // SPDX-License-Identifier: Apache-2.0, MIT
 #![allow(clippy::unused_async)]
 
-use crate::cid_collections::CidHashSet;
 use crate::ipld::json::IpldJson;
-use crate::libp2p::NetworkMessage;
+use crate::libp2p::{fetch_dag, DagFetchStats};
 use crate::lotus_json::LotusJson;
 use crate::rpc_api::{
     data_types::{MarketDeal, MessageLookup, RPCState},
@@ -18,12 +17,8 @@ use anyhow::Context as _;
 use fil_actor_interface::market;
 use futures::StreamExt;
 use fvm_ipld_blockstore::Blockstore;
-use fvm_ipld_encoding::{CborStore, DAG_CBOR};
 use jsonrpc_v2::{Data, Error as JsonRpcError, Params};
 use libipld_core::ipld::Ipld;
-use parking_lot::Mutex;
-use std::{sync::Arc, time::Duration};
-use tokio::task::JoinSet;
 
 /// runs the given message and returns its result without any persisted changes.
 pub(in crate::rpc) async fn state_call<DB: Blockstore + Send + Sync + 'static>(
@@ -33,7 +28,7 @@ pub(in crate::rpc) async fn state_call<DB: Blockstore + Send + Sync + 'static>(
     let state_manager = &data.state_manager;
     let (message_json, LotusJson(key)) = params;
     let mut message = message_json.into_inner();
-    let tipset = data.state_manager.chain_store().tipset_from_keys(&key)?;
+    let tipset = state_manager.load_tipset_with_state(&key).await?;
     Ok(state_manager.call(&mut message, Some(tipset))?)
 }
 
@@ -76,12 +71,12 @@ pub(in crate::rpc) async fn state_get_network_version<DB: Blockstore>(
     Ok(data.state_manager.get_network_version(ts.epoch()))
 }
 
-pub(crate) async fn state_get_actor<DB: Blockstore>(
+pub(crate) async fn state_get_actor<DB: Blockstore + Send + Sync + 'static>(
     data: Data<RPCState<DB>>,
     Params(params): Params<StateGetActorParams>,
 ) -> Result<StateGetActorResult, JsonRpcError> {
     let (LotusJson(addr), LotusJson(tsk)) = params;
-    let ts = data.chain_store.tipset_from_keys(&tsk)?;
+    let ts = data.state_manager.load_tipset_with_state(&tsk).await?;
     let state = data.state_manager.get_actor(&addr, *ts.parent_state());
     state.map(Into::into).map_err(|e| e.into())
 }
@@ -94,18 +89,18 @@ pub(in crate::rpc) async fn state_market_balance<DB: Blockstore + Send + Sync +
 ) -> Result<StateMarketBalanceResult, JsonRpcError> {
     let (address, LotusJson(key)) = params;
     let address = address.into_inner();
-    let tipset = data.state_manager.chain_store().tipset_from_keys(&key)?;
+    let tipset = data.state_manager.load_tipset_with_state(&key).await?;
     data.state_manager
         .market_balance(&address, &tipset)
         .map_err(|e| e.into())
 }
 
-pub(in crate::rpc) async fn state_market_deals<DB: Blockstore>(
+pub(in crate::rpc) async fn state_market_deals<DB: Blockstore + Send + Sync + 'static>(
     data: Data<RPCState<DB>>,
     Params(params): Params<StateMarketDealsParams>,
 ) -> Result<StateMarketDealsResult, JsonRpcError> {
     let (LotusJson(tsk),) = params;
-    let ts = data.chain_store.tipset_from_keys(&tsk)?;
+    let ts = data.state_manager.load_tipset_with_state(&tsk).await?;
     let actor = data
         .state_manager
         .get_actor(&Address::MARKET_ACTOR, *ts.parent_state())?
@@ -213,121 +208,22 @@ pub(in crate::rpc) async fn state_fetch_root<DB: Blockstore + Sync + Send + 'sta
         (None, None)
     };
 
-    const MAX_CONCURRENT_REQUESTS: usize = 64;
-    const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
-
-    let mut seen: CidHashSet = CidHashSet::default();
-    let mut counter: usize = 0;
-    let mut fetched: usize = 0;
-    let mut failures: usize = 0;
-    let mut task_set = JoinSet::new();
-
-    fn handle_worker(fetched: &mut usize, failures: &mut usize, ret: anyhow::Result<()>) {
-        match ret {
-            Ok(()) => *fetched += 1,
-            Err(msg) => {
-                *failures += 1;
-                tracing::debug!("Request failed: {msg}");
-            }
-        }
-    }
-
-    // When walking an Ipld graph, we're only interested in the DAG_CBOR encoded nodes.
-    let mut get_ipld_link = |ipld: &Ipld| match ipld {
-        &Ipld::Link(cid) if cid.codec() == DAG_CBOR && seen.insert(cid) => Some(cid),
-        _ => None,
-    };
-
-    // Do a depth-first-search of the IPLD graph (DAG). Nodes that are _not_ present in our database
-    // are fetched in background tasks. If the number of tasks reaches MAX_CONCURRENT_REQUESTS, the
-    // depth-first-search pauses until one of the work tasks returns. The memory usage of this
-    // algorithm is dominated by the set of seen CIDs and the 'dfs' stack is not expected to grow to
-    // more than 1000 elements (even when walking tens of millions of nodes).
-    let dfs = Arc::new(Mutex::new(vec![Ipld::Link(root_cid)]));
-    let mut to_be_fetched = vec![];
-
-    // Loop until: No more items in `dfs` AND no running worker tasks.
-    loop {
-        while let Some(ipld) = lock_pop(&dfs) {
-            {
-                let mut dfs_guard = dfs.lock();
-                // Scan for unseen CIDs. Available IPLD nodes are pushed to the depth-first-search
-                // stack, unavailable nodes will be requested in worker tasks.
-                for new_cid in ipld.iter().filter_map(&mut get_ipld_link) {
-                    counter += 1;
-                    if counter % 1_000 == 0 {
-                        // set RUST_LOG=forest_filecoin::rpc::state_api=debug to enable these printouts.
-                        tracing::debug!(
-                                "Graph walk: CIDs: {counter}, Fetched: {fetched}, Failures: {failures}, dfs: {}, Concurrent: {}",
-                                dfs_guard.len(), task_set.len()
-                            );
-                    }
-
-                    if let Some(next_ipld) = db.get_cbor(&new_cid)? {
-                        dfs_guard.push(next_ipld);
-                        if let Some(car_tx) = &car_tx {
-                            car_tx.send(CarBlock {
-                                cid: new_cid,
-                                data: db.get(&new_cid)?.with_context(|| {
-                                    format!("Failed to get cid {new_cid} from block store")
-                                })?,
-                            })?;
-                        }
-                    } else {
-                        to_be_fetched.push(new_cid);
-                    }
-                }
-            }
-
-            while let Some(cid) = to_be_fetched.pop() {
-                if task_set.len() == MAX_CONCURRENT_REQUESTS {
-                    if let Some(ret) = task_set.join_next().await {
-                        handle_worker(&mut fetched, &mut failures, ret?)
-                    }
-                }
-                task_set.spawn_blocking({
-                    let network_send = network_send.clone();
-                    let db = db.clone();
-                    let dfs_vec = Arc::clone(&dfs);
-                    let car_tx = car_tx.clone();
-                    move || {
-                        let (tx, rx) = flume::bounded(1);
-                        network_send.send(NetworkMessage::BitswapRequest {
-                            cid,
-                            response_channel: tx,
-                        })?;
-                        // Bitswap requests do not fail. They are just ignored if no-one has
-                        // the requested data. Here we arbitrary decide to only wait for
-                        // REQUEST_TIMEOUT before judging that the data is unavailable.
-                        let _ignore = rx.recv_timeout(REQUEST_TIMEOUT);
-
-                        let new_ipld = db
-                            .get_cbor::<Ipld>(&cid)?
-                            .with_context(|| format!("Request failed: {cid}"))?;
-                        dfs_vec.lock().push(new_ipld);
-                        if let Some(car_tx) = &car_tx {
-                            car_tx.send(CarBlock {
-                                cid,
-                                data: db.get(&cid)?.with_context(|| {
-                                    format!("Failed to get cid {cid} from block store")
-                                })?,
-                            })?;
-                        }
-
-                        Ok(())
-                    }
-                });
-            }
-            tokio::task::yield_now().await;
-        }
-        if let Some(ret) = task_set.join_next().await {
-            handle_worker(&mut fetched, &mut failures, ret?)
-        } else {
-            // We are out of work items (dfs) and all worker threads have finished, this means
-            // the entire graph has been walked and fetched.
-            break;
+    let DagFetchStats {
+        cids,
+        fetched,
+        failures,
+    } = fetch_dag(&network_send, &db, [root_cid], |cid| {
+        if let Some(car_tx) = &car_tx {
+            car_tx.send(CarBlock {
+                cid,
+                data: db
+                    .get(&cid)?
+                    .with_context(|| format!("Failed to get cid {cid} from block store"))?,
+            })?;
         }
-    }
+        Ok(())
+    })
+    .await?;
 
     drop(car_tx);
     if let Some(car_handle) = car_handle {
@@ -335,12 +231,6 @@ pub(in crate::rpc) async fn state_fetch_root<DB: Blockstore + Sync + Send + 'sta
     }
 
     Ok(format!(
-        "IPLD graph traversed! CIDs: {counter}, fetched: {fetched}, failures: {failures}."
+        "IPLD graph traversed! CIDs: {cids}, fetched: {fetched}, failures: {failures}."
     ))
 }
-
-// Convenience function for locking and popping a value out of a vector. If this function is
-// inlined, the mutex guard isn't dropped early enough.
-fn lock_pop<T>(mutex: &Mutex<Vec<T>>) -> Option<T> {
-    mutex.lock().pop()
-}
@@ -52,6 +52,7 @@ pub static ACCESS_MAP: Lazy<HashMap<&str, Access>> = Lazy::new(|| {
     access.insert(mpool_api::MPOOL_PENDING, Access::Read);
     access.insert(mpool_api::MPOOL_PUSH, Access::Write);
     access.insert(mpool_api::MPOOL_PUSH_MESSAGE, Access::Sign);
+    access.insert(mpool_api::MPOOL_SUB, Access::Read);
 
     // Sync API
     access.insert(sync_api::SYNC_CHECK_BAD, Access::Read);
@@ -101,6 +102,16 @@ pub static ACCESS_MAP: Lazy<HashMap<&str, Access>> = Lazy::new(|| {
     access.insert(net_api::NET_INFO, Access::Read);
     access.insert(net_api::NET_CONNECT, Access::Write);
     access.insert(net_api::NET_DISCONNECT, Access::Write);
+    access.insert(net_api::NET_AUTO_NAT_STATUS, Access::Read);
+    access.insert(net_api::NET_BLOCK_ADD, Access::Admin);
+    access.insert(net_api::NET_BLOCK_REMOVE, Access::Admin);
+    access.insert(net_api::NET_BLOCK_LIST, Access::Read);
+    access.insert(net_api::NET_PROTECT_ADD, Access::Admin);
+    access.insert(net_api::NET_PROTECT_REMOVE, Access::Admin);
+    access.insert(net_api::NET_PROTECT_LIST, Access::Read);
+    access.insert(net_api::NET_PEER_INFO, Access::Read);
+    access.insert(net_api::NET_BANDWIDTH_STATS, Access::Read);
+    access.insert(net_api::NET_BANDWIDTH_STATS_BY_PROTOCOL, Access::Read);
 
     // DB API
     access.insert(db_api::DB_GC, Access::Write);
@@ -236,6 +247,7 @@ pub mod chain_api {
 pub mod mpool_api {
     use cid::Cid;
 
+    use crate::message_pool::MpoolUpdate;
     use crate::rpc_api::data_types::MessageSendSpec;
     use crate::shim::message::Message;
     use crate::{lotus_json::LotusJson, message::SignedMessage};
@@ -251,6 +263,14 @@ pub mod mpool_api {
     pub const MPOOL_PUSH_MESSAGE: &str = "Filecoin.MpoolPushMessage";
     pub type MpoolPushMessageParams = (LotusJson<Message>, Option<MessageSendSpec>);
     pub type MpoolPushMessageResult = LotusJson<SignedMessage>;
+
+    /// Streaming method, only available over WebSocket. Each notification
+    /// carries one update.
+    pub const MPOOL_SUB: &str = "Filecoin.MpoolSub";
+    #[allow(unused)] // https://github.com/ChainSafe/forest/issues/3029
+    pub type MpoolSubParams = ();
+    #[allow(unused)] // https://github.com/ChainSafe/forest/issues/3029
+    pub type MpoolSubResult = LotusJson<MpoolUpdate>;
 }
 
 /// Sync API
@@ -434,6 +454,7 @@ pub mod common_api {
 
 /// Net API
 pub mod net_api {
+    use ahash::HashMap;
     use serde::{Deserialize, Serialize};
 
     use crate::rpc_api::data_types::AddrInfo;
@@ -480,6 +501,141 @@ pub mod net_api {
     pub const NET_DISCONNECT: &str = "Filecoin.NetDisconnect";
     pub type NetDisconnectParams = (String,);
     pub type NetDisconnectResult = ();
+
+    pub const NET_AUTO_NAT_STATUS: &str = "Filecoin.NetAutoNatStatus";
+    pub type NetAutoNatStatusParams = ();
+
+    /// Reachability of the node as determined by `AutoNAT`. The numeric values
+    /// follow `go-libp2p`'s `network.Reachability`.
+    #[derive(Debug, Default, Serialize, Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    pub struct NetAutoNatStatusResult {
+        pub reachability: i32,
+        pub public_addrs: Option<Vec<String>>,
+    }
+
+    impl NetAutoNatStatusResult {
+        pub fn reachability_as_str(&self) -> &'static str {
+            match self.reachability {
+                0 => "Unknown",
+                1 => "Public",
+                2 => "Private",
+                _ => "(unrecognized)",
+            }
+        }
+    }
+
+    impl From<libp2p::autonat::NatStatus> for NetAutoNatStatusResult {
+        fn from(nat: libp2p::autonat::NatStatus) -> Self {
+            use libp2p::autonat::NatStatus;
+            match nat {
+                NatStatus::Unknown => Self::default(),
+                NatStatus::Public(addr) => Self {
+                    reachability: 1,
+                    public_addrs: Some(vec![addr.to_string()]),
+                },
+                NatStatus::Private => Self {
+                    reachability: 2,
+                    public_addrs: None,
+                },
+            }
+        }
+    }
+
+    /// Peers, IP addresses and IP subnets blocked by the node operator.
+    #[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    pub struct NetBlockList {
+        #[serde(with = "crate::lotus_json")]
+        pub peers: Vec<String>,
+        #[serde(rename = "IPAddrs", with = "crate::lotus_json")]
+        pub ip_addrs: Vec<String>,
+        #[serde(rename = "IPSubnets", with = "crate::lotus_json")]
+        pub ip_subnets: Vec<String>,
+    }
+
+    pub const NET_BLOCK_ADD: &str = "Filecoin.NetBlockAdd";
+    pub type NetBlockAddParams = (NetBlockList,);
+    pub type NetBlockAddResult = ();
+
+    pub const NET_BLOCK_REMOVE: &str = "Filecoin.NetBlockRemove";
+    pub type NetBlockRemoveParams = (NetBlockList,);
+    pub type NetBlockRemoveResult = ();
+
+    pub const NET_BLOCK_LIST: &str = "Filecoin.NetBlockList";
+    pub type NetBlockListParams = ();
+    pub type NetBlockListResult = NetBlockList;
+
+    pub const NET_PROTECT_ADD: &str = "Filecoin.NetProtectAdd";
+    pub type NetProtectAddParams = (Vec<String>,);
+    pub type NetProtectAddResult = ();
+
+    pub const NET_PROTECT_REMOVE: &str = "Filecoin.NetProtectRemove";
+    pub type NetProtectRemoveParams = (Vec<String>,);
+    pub type NetProtectRemoveResult = ();
+
+    pub const NET_PROTECT_LIST: &str = "Filecoin.NetProtectList";
+    pub type NetProtectListParams = ();
+    pub type NetProtectListResult = Vec<String>;
+
+    pub const NET_PEER_INFO: &str = "Filecoin.NetPeerInfo";
+    pub type NetPeerInfoParams = (String,);
+    pub type NetPeerInfoResult = ExtendedPeerInfo;
+
+    /// Identity, connection and request statistics of a peer.
+    #[derive(Debug, Default, Clone, Serialize, Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    pub struct ExtendedPeerInfo {
+        #[serde(rename = "ID")]
+        pub id: String,
+        pub agent: String,
+        #[serde(with = "crate::lotus_json")]
+        pub addrs: Vec<String>,
+        #[serde(with = "crate::lotus_json")]
+        pub protocols: Vec<String>,
+        /// `Inbound` or `Outbound`, empty if unknown.
+        pub direction: String,
+        pub head_epoch: Option<i64>,
+        pub successes: u32,
+        pub failures: u32,
+        /// Average response time, in milliseconds.
+        pub average_time_ms: u64,
+        /// Cost used to rank `ChainExchange` peers, lower is better. `None` if
+        /// the peer is not ranked or has failed without ever succeeding.
+        pub cost: Option<f64>,
+        pub bad: bool,
+        pub protected: bool,
+    }
+
+    pub const NET_BANDWIDTH_STATS: &str = "Filecoin.NetBandwidthStats";
+    pub type NetBandwidthStatsParams = ();
+    pub type NetBandwidthStatsResult = NetBandwidthStats;
+
+    pub const NET_BANDWIDTH_STATS_BY_PROTOCOL: &str = "Filecoin.NetBandwidthStatsByProtocol";
+    pub type NetBandwidthStatsByProtocolParams = ();
+    pub type NetBandwidthStatsByProtocolResult = HashMap<String, NetBandwidthStats>;
+
+    /// Bytes received and sent by the node over all peers, and the current
+    /// rates in bytes per second.
+    #[derive(Debug, Default, Clone, Serialize, Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    pub struct NetBandwidthStats {
+        pub total_in: u64,
+        pub total_out: u64,
+        pub rate_in: f64,
+        pub rate_out: f64,
+    }
+
+    impl From<crate::libp2p::bandwidth::BandwidthStats> for NetBandwidthStats {
+        fn from(stats: crate::libp2p::bandwidth::BandwidthStats) -> Self {
+            Self {
+                total_in: stats.total_in,
+                total_out: stats.total_out,
+                rate_in: stats.rate_in,
+                rate_out: stats.rate_out,
+            }
+        }
+    }
 }
 
 /// DB API
@@ -500,6 +656,7 @@ pub mod progress_api {
     #[derive(Serialize, Deserialize)]
     pub enum GetProgressType {
         DatabaseGarbageCollection,
+        StateRecomputation,
     }
 }
 
@@ -40,3 +40,73 @@ pub async fn net_disconnect(
 ) -> Result<NetDisconnectResult, Error> {
     call(NET_DISCONNECT, params, auth_token).await
 }
+
+pub async fn net_auto_nat_status(
+    (): NetAutoNatStatusParams,
+    auth_token: &Option<String>,
+) -> Result<NetAutoNatStatusResult, Error> {
+    call(NET_AUTO_NAT_STATUS, (), auth_token).await
+}
+
+pub async fn net_block_add(
+    params: NetBlockAddParams,
+    auth_token: &Option<String>,
+) -> Result<NetBlockAddResult, Error> {
+    call(NET_BLOCK_ADD, params, auth_token).await
+}
+
+pub async fn net_block_remove(
+    params: NetBlockRemoveParams,
+    auth_token: &Option<String>,
+) -> Result<NetBlockRemoveResult, Error> {
+    call(NET_BLOCK_REMOVE, params, auth_token).await
+}
+
+pub async fn net_block_list(
+    (): NetBlockListParams,
+    auth_token: &Option<String>,
+) -> Result<NetBlockListResult, Error> {
+    call(NET_BLOCK_LIST, (), auth_token).await
+}
+
+pub async fn net_protect_add(
+    params: NetProtectAddParams,
+    auth_token: &Option<String>,
+) -> Result<NetProtectAddResult, Error> {
+    call(NET_PROTECT_ADD, params, auth_token).await
+}
+
+pub async fn net_protect_remove(
+    params: NetProtectRemoveParams,
+    auth_token: &Option<String>,
+) -> Result<NetProtectRemoveResult, Error> {
+    call(NET_PROTECT_REMOVE, params, auth_token).await
+}
+
+pub async fn net_protect_list(
+    (): NetProtectListParams,
+    auth_token: &Option<String>,
+) -> Result<NetProtectListResult, Error> {
+    call(NET_PROTECT_LIST, (), auth_token).await
+}
+
+pub async fn net_peer_info(
+    params: NetPeerInfoParams,
+    auth_token: &Option<String>,
+) -> Result<NetPeerInfoResult, Error> {
+    call(NET_PEER_INFO, params, auth_token).await
+}
+
+pub async fn net_bandwidth_stats(
+    (): NetBandwidthStatsParams,
+    auth_token: &Option<String>,
+) -> Result<NetBandwidthStatsResult, Error> {
+    call(NET_BANDWIDTH_STATS, (), auth_token).await
+}
+
+pub async fn net_bandwidth_stats_by_protocol(
+    (): NetBandwidthStatsByProtocolParams,
+    auth_token: &Option<String>,
+) -> Result<NetBandwidthStatsByProtocolResult, Error> {
+    call(NET_BANDWIDTH_STATS_BY_PROTOCOL, (), auth_token).await
+}
@@ -7,7 +7,7 @@ mod metrics;
 mod utils;
 use crate::interpreter::{MessageCallbackCtx, VMTrace};
 use crate::state_migration::run_state_migrations;
-use anyhow::{bail, Context as _};
+use anyhow::{bail, ensure, Context as _};
 use fil_actor_interface::init::{self, State};
 use rayon::prelude::ParallelBridge;
 pub use utils::is_valid_for_sending;
@@ -15,12 +15,15 @@ mod vm_circ_supply;
 pub use self::errors::*;
 use crate::beacon::BeaconSchedule;
 use crate::blocks::{Tipset, TipsetKeys};
+use crate::chain::ChainEpochDelta;
 use crate::chain::{
     index::{ChainIndex, ResolveNullTipset},
     ChainStore, HeadChange,
 };
+use crate::db::tipset_state::{read_tipset_state, write_tipset_state};
 use crate::interpreter::{resolve_to_key_addr, ExecutionContext, VM};
 use crate::interpreter::{BlockMessages, CalledAt};
+use crate::ipld::ProgressBarCurrentTotalPair;
 use crate::message::{ChainMessage, Message as MessageTrait};
 use crate::networks::ChainConfig;
 use crate::shim::clock::ChainEpoch;
@@ -45,9 +48,11 @@ use lru::LruCache;
 use nonzero_ext::nonzero;
 use num::BigInt;
 use num_traits::identities::Zero;
+use once_cell::sync::Lazy;
 use parking_lot::Mutex as SyncMutex;
 use serde::{Deserialize, Serialize};
 use std::ops::RangeInclusive;
+use std::sync::atomic;
 use std::{num::NonZeroUsize, sync::Arc};
 use tokio::sync::{broadcast::error::RecvError, Mutex as TokioMutex, RwLock};
 use tracing::{debug, error, info, instrument, trace, warn};
@@ -55,6 +60,15 @@ use vm_circ_supply::GenesisInfo;
 
 const DEFAULT_TIPSET_CACHE_SIZE: NonZeroUsize = nonzero!(1024usize);
 
+/// Longest run of tipsets replayed to recompute a state missing from the blockstore, one day of
+/// epochs on mainnet.
+const MAX_STATE_REPLAY: ChainEpochDelta = 2880;
+
+/// Progress of the current state recomputation, in tipsets. See
+/// [`StateManager::ensure_parent_state`].
+pub static STATE_RECOMPUTATION_PROGRESS: Lazy<ProgressBarCurrentTotalPair> =
+    Lazy::new(Default::default);
+
 /// Intermediary for retrieving state objects and updating actor states.
 type CidPair = (Cid, Cid);
 
@@ -205,6 +219,8 @@ pub struct StateManager<DB> {
     beacon: Arc<crate::beacon::BeaconSchedule>,
     chain_config: Arc<ChainConfig>,
     engine: crate::shim::machine::MultiEngine,
+    /// Held while states are recomputed, so replays of overlapping ranges aren't run twice.
+    replay_lock: TokioMutex<()>,
 }
 
 #[allow(clippy::type_complexity)]
@@ -227,6 +243,7 @@ where
             beacon,
             chain_config,
             engine: crate::shim::machine::MultiEngine::default(),
+            replay_lock: TokioMutex::new(()),
         })
     }
 
@@ -349,18 +366,159 @@ where
     /// state for a given tipset is guaranteed not to be computed twice.
     #[instrument(skip(self))]
     pub async fn tipset_state(self: &Arc<Self>, tipset: &Arc<Tipset>) -> anyhow::Result<CidPair> {
-        let key = tipset.key();
+        self.tipset_state_with(tipset, |tipset| async move {
+            Ok(self
+                .compute_tipset_state(tipset, NO_CALLBACK, VMTrace::NotTraced)
+                .await?)
+        })
+        .await
+    }
+
+    /// [`StateManager::tipset_state`], computing the state of `tipset` with `compute` when it is
+    /// neither cached nor persisted.
+    async fn tipset_state_with<F, Fut>(
+        self: &Arc<Self>,
+        tipset: &Arc<Tipset>,
+        compute: F,
+    ) -> anyhow::Result<CidPair>
+    where
+        F: Fn(Arc<Tipset>) -> Fut,
+        Fut: std::future::Future<Output = anyhow::Result<CidPair>>,
+    {
+        let (key, compute) = (tipset.key(), &compute);
         self.cache
             .get_or_else(key, || async move {
-                let ts_state = self
-                    .compute_tipset_state(Arc::clone(tipset), NO_CALLBACK, VMTrace::NotTraced)
-                    .await?;
+                if let Some(ts_state) = self.persisted_tipset_state(tipset) {
+                    crate::metrics::LRU_CACHE_HIT
+                        .with_label_values(&[crate::metrics::values::STATE_MANAGER_TIPSET_DB])
+                        .inc();
+                    return Ok(ts_state);
+                }
+                crate::metrics::LRU_CACHE_MISS
+                    .with_label_values(&[crate::metrics::values::STATE_MANAGER_TIPSET_DB])
+                    .inc();
+                self.ensure_parent_state(tipset).await?;
+                let ts_state = compute(Arc::clone(tipset)).await?;
                 debug!("Completed tipset state calculation {:?}", tipset.cids());
+                self.persist_tipset_state(tipset, ts_state);
                 Ok(ts_state)
             })
             .await
     }
 
+    /// Loads the tipset of `key` and makes sure its parent state is in the blockstore. State
+    /// lookups at a tipset given by the user should load it with this function.
+    pub async fn load_tipset_with_state(
+        self: &Arc<Self>,
+        key: &TipsetKeys,
+    ) -> anyhow::Result<Arc<Tipset>> {
+        let tipset = self.cs.tipset_from_keys(key)?;
+        self.ensure_parent_state(&tipset).await?;
+        Ok(tipset)
+    }
+
+    /// Makes sure the parent state of `tipset` is in the blockstore. Lite snapshots only contain
+    /// the most recent state roots, older ones are recomputed by replaying the messages of the
+    /// ancestors of `tipset` from the nearest one whose parent state is available, at most
+    /// [`MAX_STATE_REPLAY`] epochs away. The recomputed states are cached.
+    pub async fn ensure_parent_state(self: &Arc<Self>, tipset: &Arc<Tipset>) -> anyhow::Result<()> {
+        self.ensure_parent_state_with(tipset, |ancestor| async move {
+            Ok(self
+                .compute_tipset_state(ancestor, NO_CALLBACK, VMTrace::NotTraced)
+                .await?)
+        })
+        .await
+    }
+
+    /// [`StateManager::ensure_parent_state`], computing the state of each replayed ancestor with
+    /// `compute`.
+    async fn ensure_parent_state_with<F, Fut>(
+        self: &Arc<Self>,
+        tipset: &Arc<Tipset>,
+        compute: F,
+    ) -> anyhow::Result<()>
+    where
+        F: Fn(Arc<Tipset>) -> Fut,
+        Fut: std::future::Future<Output = anyhow::Result<CidPair>>,
+    {
+        let db = self.blockstore();
+        if tipset.epoch() == 0 || db.has(tipset.parent_state())? {
+            return Ok(());
+        }
+        let _guard = self.replay_lock.lock().await;
+        // Another replay may have recomputed the state meanwhile
+        if db.has(tipset.parent_state())? {
+            return Ok(());
+        }
+
+        // Ancestors to replay, from the parent of `tipset` down to the nearest one with a state
+        let mut replay = vec![];
+        let mut ancestor = self.cs.tipset_from_keys(tipset.parents())?;
+        loop {
+            if tipset.epoch() - ancestor.epoch() > MAX_STATE_REPLAY {
+                bail!(
+                    "the state at epoch {} isn't available, and no ancestor with a state is within {MAX_STATE_REPLAY} epochs",
+                    tipset.epoch()
+                );
+            }
+            let parent_state_available =
+                ancestor.epoch() == 0 || db.has(ancestor.parent_state())?;
+            let parents = ancestor.parents().clone();
+            replay.push(ancestor);
+            if parent_state_available {
+                break;
+            }
+            ancestor = self.cs.tipset_from_keys(&parents)?;
+        }
+
+        info!(
+            "Recomputing the state at epoch {} from epoch {}",
+            tipset.epoch(),
+            replay.last().map(|ts| ts.epoch()).unwrap_or_default()
+        );
+        let progress = STATE_RECOMPUTATION_PROGRESS.clone();
+        progress.0.store(0, atomic::Ordering::Relaxed);
+        progress
+            .1
+            .store(replay.len() as u64, atomic::Ordering::Relaxed);
+        for ancestor in replay.into_iter().rev() {
+            let ts_state = compute(Arc::clone(&ancestor)).await?;
+            self.cache.insert(ancestor.key().clone(), ts_state);
+            self.persist_tipset_state(&ancestor, ts_state);
+            progress.0.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+        ensure!(
+            db.has(tipset.parent_state())?,
+            "the recomputed state at epoch {} doesn't match the parent state of the tipset",
+            tipset.epoch()
+        );
+        Ok(())
+    }
+
+    /// Returns the state of `tipset` persisted in the database, if its state and receipts are
+    /// still in the blockstore. A record that can't be read is a miss, the state can be
+    /// recomputed.
+    fn persisted_tipset_state(&self, tipset: &Tipset) -> Option<CidPair> {
+        let db = self.blockstore();
+        let key = tipset.key();
+        let ts_state = read_tipset_state(self.cs.settings().as_ref(), tipset)
+            .map_err(|e| warn!("Failed to read the persisted tipset state of {key}: {e}"))
+            .ok()??;
+        let (state_root, receipt_root) = &ts_state;
+        (db.has(state_root).unwrap_or(false) && db.has(receipt_root).unwrap_or(false))
+            .then_some(ts_state)
+    }
+
+    /// Persists the state of `tipset`. Failures are only logged, the state can be recomputed.
+    fn persist_tipset_state(&self, tipset: &Tipset, ts_state: CidPair) {
+        if let Err(e) = write_tipset_state(self.cs.settings().as_ref(), tipset, ts_state) {
+            warn!(
+                "Failed to persist the tipset state of {}: {e}",
+                tipset.key()
+            );
+        }
+    }
+
     #[instrument(skip(self, rand))]
     fn call_raw(
         self: &Arc<Self>,
@@ -1067,16 +1225,34 @@ where
         T: Iterator<Item = Arc<Tipset>> + Send,
     {
         let genesis_timestamp = self.chain_store().genesis().timestamp();
-        validate_tipsets(
+        validate_tipsets_with(
             genesis_timestamp,
             self.chain_store().chain_index.clone(),
             self.chain_config(),
             self.beacon_schedule(),
             &self.engine,
             tipsets,
+            |_, tipset, ts_state, mismatch| self.validated(tipset, ts_state, mismatch),
         )
     }
 
+    /// Persists the state of a tipset validated by [`Self::validate_tipsets`], unless it
+    /// mismatches.
+    fn validated(
+        &self,
+        tipset: &Tipset,
+        ts_state: CidPair,
+        mismatch: Option<StateMismatch>,
+    ) -> anyhow::Result<()> {
+        match mismatch {
+            None => {
+                self.persist_tipset_state(tipset, ts_state);
+                Ok(())
+            }
+            Some(_) => bail!("state mismatch"),
+        }
+    }
+
     fn chain_rand(&self, tipset: Arc<Tipset>) -> ChainRand<DB> {
         ChainRand::new(
             self.chain_config.clone(),
@@ -1087,23 +1263,46 @@ where
     }
 }
 
-pub fn validate_tipsets<DB, T>(
+/// A tipset whose computed state root or receipt root differs from the one
+/// recorded in its child.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateMismatch {
+    pub epoch: ChainEpoch,
+    #[serde(with = "crate::lotus_json")]
+    pub expected_state: Cid,
+    #[serde(with = "crate::lotus_json")]
+    pub expected_receipt: Cid,
+    #[serde(with = "crate::lotus_json")]
+    pub actual_state: Cid,
+    #[serde(with = "crate::lotus_json")]
+    pub actual_receipt: Cid,
+}
+
+/// Computes the states of the parents of the consecutive `tipsets`
+/// concurrently, and calls `on_validated` with the index of each pair of
+/// tipsets, the parent, its computed state and receipt roots and the mismatch
+/// of its state, if any. Validation stops
+/// at the first error returned by `on_validated`.
+pub fn validate_tipsets_with<DB, T, F>(
     genesis_timestamp: u64,
     chain_index: Arc<ChainIndex<Arc<DB>>>,
     chain_config: Arc<ChainConfig>,
     beacon: Arc<BeaconSchedule>,
     engine: &crate::shim::machine::MultiEngine,
     tipsets: T,
+    on_validated: F,
 ) -> anyhow::Result<()>
 where
     DB: Blockstore + Send + Sync + 'static,
     T: Iterator<Item = Arc<Tipset>> + Send,
+    F: Fn(usize, &Tipset, CidPair, Option<StateMismatch>) -> anyhow::Result<()> + Sync,
 {
     use rayon::iter::ParallelIterator as _;
     tipsets
         .tuple_windows()
+        .enumerate()
         .par_bridge()
-        .try_for_each(|(child, parent)| {
+        .try_for_each(|(index, (child, parent))| {
             info!(height = parent.epoch(), "compute parent state");
             let (actual_state, actual_receipt) = apply_block_messages(
                 genesis_timestamp,
@@ -1111,27 +1310,35 @@ where
                 chain_config.clone(),
                 beacon.clone(),
                 engine,
-                parent,
+                parent.clone(),
                 NO_CALLBACK,
                 VMTrace::NotTraced,
             )
             .context("couldn't compute tipset state")?;
             let expected_receipt = child.min_ticket_block().message_receipts();
             let expected_state = child.parent_state();
-            match (expected_state, expected_receipt) == (&actual_state, &actual_receipt) {
-                true => Ok(()),
-                false => {
-                    error!(
-                        height = child.epoch(),
-                        ?expected_state,
-                        ?expected_receipt,
-                        ?actual_state,
-                        ?actual_receipt,
-                        "state mismatch"
-                    );
-                    bail!("state mismatch");
-                }
-            }
+            let mismatch =
+                match (expected_state, expected_receipt) == (&actual_state, &actual_receipt) {
+                    true => None,
+                    false => {
+                        error!(
+                            height = child.epoch(),
+                            ?expected_state,
+                            ?expected_receipt,
+                            ?actual_state,
+                            ?actual_receipt,
+                            "state mismatch"
+                        );
+                        Some(StateMismatch {
+                            epoch: parent.epoch(),
+                            expected_state: *expected_state,
+                            expected_receipt: *expected_receipt,
+                            actual_state,
+                            actual_receipt,
+                        })
+                    }
+                };
+            on_validated(index, &parent, (actual_state, actual_receipt), mismatch)
         })
 }
 
@@ -1312,3 +1519,198 @@ where
 
     Ok((state_root, receipt_root))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocks::BlockHeader;
+    use crate::db::tipset_state::tipset_state_key;
+    use crate::db::MemoryDB;
+    use crate::utils::cid::CidCborExt as _;
+    use crate::utils::db::CborStoreExt as _;
+
+    fn state_manager() -> (Arc<StateManager<MemoryDB>>, Arc<Tipset>) {
+        let db = Arc::new(MemoryDB::default());
+        let genesis_header = BlockHeader::builder()
+            .miner_address(Address::new_id(0))
+            .timestamp(7777)
+            .build()
+            .unwrap();
+        let chain_config = Arc::new(ChainConfig::default());
+        let cs = Arc::new(
+            ChainStore::new(db.clone(), db, chain_config.clone(), genesis_header.clone()).unwrap(),
+        );
+        cs.blockstore().put_cbor_default(&genesis_header).unwrap();
+        let sm = Arc::new(StateManager::new(cs, chain_config).unwrap());
+        (sm, Arc::new(Tipset::from(genesis_header)))
+    }
+
+    fn child(parent: &Tipset, epoch: ChainEpoch, state_root: Cid) -> Arc<Tipset> {
+        let header = BlockHeader::builder()
+            .miner_address(Address::new_id(0))
+            .parents(parent.key().clone())
+            .epoch(epoch)
+            .state_root(state_root)
+            .build()
+            .unwrap();
+        Arc::new(Tipset::from(header))
+    }
+
+    #[tokio::test]
+    async fn ensure_available_parent_state() {
+        let (sm, genesis) = state_manager();
+        let state_root = sm.blockstore().put_cbor_default(&"state").unwrap();
+        let tipset = child(&genesis, 10, state_root);
+        sm.ensure_parent_state(&tipset).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn ensure_missing_parent_state() {
+        let (sm, genesis) = state_manager();
+        let db = sm.blockstore();
+        // The states of epochs 1 and 2 are missing, the state of epoch 0 is available
+        let state = |epoch: ChainEpoch| format!("state {epoch}");
+        let receipts = |epoch: ChainEpoch| format!("receipts {epoch}");
+        let state_0 = db.put_cbor_default(&state(0)).unwrap();
+        let mut chain = vec![child(&genesis, 1, state_0)];
+        for epoch in 2..=3 {
+            let state_root = Cid::from_cbor_blake2b256(&state(epoch - 1)).unwrap();
+            chain.push(child(chain.last().unwrap(), epoch, state_root));
+        }
+        for tipset in &chain {
+            db.put_cbor_default(tipset.min_ticket_block()).unwrap();
+        }
+        let [tipset_1, tipset_2, tipset_3] = &chain[..] else {
+            unreachable!()
+        };
+
+        // Computes the state of `tipset` at its epoch, as a replay of its messages would
+        let replayed = Arc::new(SyncMutex::new(vec![]));
+        let compute = |tipset: Arc<Tipset>| {
+            let (replayed, sm) = (replayed.clone(), sm.clone());
+            async move {
+                replayed.lock().push(tipset.epoch());
+                let db = sm.blockstore();
+                Ok((
+                    db.put_cbor_default(&state(tipset.epoch()))?,
+                    db.put_cbor_default(&receipts(tipset.epoch()))?,
+                ))
+            }
+        };
+        sm.ensure_parent_state_with(tipset_3, compute)
+            .await
+            .unwrap();
+
+        assert_eq!(*replayed.lock(), vec![1, 2]);
+        assert!(db.has(tipset_3.parent_state()).unwrap());
+        let progress = &STATE_RECOMPUTATION_PROGRESS;
+        assert_eq!(progress.0.load(atomic::Ordering::Relaxed), 2);
+        assert_eq!(progress.1.load(atomic::Ordering::Relaxed), 2);
+        // The replayed states are persisted
+        for tipset in [tipset_1, tipset_2] {
+            assert_eq!(
+                sm.persisted_tipset_state(tipset),
+                Some((
+                    Cid::from_cbor_blake2b256(&state(tipset.epoch())).unwrap(),
+                    Cid::from_cbor_blake2b256(&receipts(tipset.epoch())).unwrap(),
+                ))
+            );
+        }
+
+        // Nothing is replayed once the state is available
+        sm.ensure_parent_state_with(tipset_3, compute)
+            .await
+            .unwrap();
+        assert_eq!(replayed.lock().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn tipset_state_is_persisted() {
+        let (sm, genesis) = state_manager();
+        let db = sm.blockstore();
+        let state_0 = db.put_cbor_default(&"state 0").unwrap();
+        let tipset = child(&genesis, 1, state_0);
+        let computed = Arc::new(atomic::AtomicUsize::new(0));
+        let compute = |_| {
+            let computed = computed.clone();
+            async move {
+                computed.fetch_add(1, atomic::Ordering::Relaxed);
+                Ok((
+                    db.put_cbor_default(&"state 1")?,
+                    db.put_cbor_default(&"receipts 1")?,
+                ))
+            }
+        };
+
+        // A miss computes the state and persists it
+        let ts_state = sm.tipset_state_with(&tipset, compute).await.unwrap();
+        assert_eq!(computed.load(atomic::Ordering::Relaxed), 1);
+        assert_eq!(sm.persisted_tipset_state(&tipset), Some(ts_state));
+
+        // A state manager with an empty cache hits the persisted state
+        let sm = Arc::new(
+            StateManager::new(sm.chain_store().clone(), sm.chain_config().clone()).unwrap(),
+        );
+        assert_eq!(
+            sm.tipset_state_with(&tipset, compute).await.unwrap(),
+            ts_state
+        );
+        assert_eq!(computed.load(atomic::Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn unusable_persisted_tipset_states_are_misses() {
+        let (sm, genesis) = state_manager();
+        // The state and receipts of the record were garbage collected
+        let collected = child(&genesis, 1, Cid::default());
+        let settings = sm.chain_store().settings();
+        write_tipset_state(
+            settings.as_ref(),
+            &collected,
+            (Cid::default(), Cid::default()),
+        )
+        .unwrap();
+        assert_eq!(sm.persisted_tipset_state(&collected), None);
+        // The record doesn't decode
+        let invalid = child(&genesis, 2, Cid::default());
+        settings
+            .write_bin(&tipset_state_key(&invalid).unwrap(), b"invalid")
+            .unwrap();
+        assert_eq!(sm.persisted_tipset_state(&invalid), None);
+    }
+
+    #[test]
+    fn validated_tipset_states_are_persisted() {
+        let (sm, genesis) = state_manager();
+        let db = sm.blockstore();
+        let ts_state = (
+            db.put_cbor_default(&"state").unwrap(),
+            db.put_cbor_default(&"receipts").unwrap(),
+        );
+        let valid = child(&genesis, 1, Cid::default());
+        sm.validated(&valid, ts_state, None).unwrap();
+        assert_eq!(sm.persisted_tipset_state(&valid), Some(ts_state));
+
+        let mismatching = child(&genesis, 2, Cid::default());
+        let mismatch = StateMismatch {
+            epoch: 2,
+            expected_state: Cid::default(),
+            expected_receipt: Cid::default(),
+            actual_state: ts_state.0,
+            actual_receipt: ts_state.1,
+        };
+        assert!(sm
+            .validated(&mismatching, ts_state, Some(mismatch))
+            .is_err());
+        assert_eq!(sm.persisted_tipset_state(&mismatching), None);
+    }
+
+    #[tokio::test]
+    async fn ensure_parent_state_beyond_replay_bound() {
+        let (sm, genesis) = state_manager();
+        let missing_state = Cid::default();
+        let tipset = child(&genesis, MAX_STATE_REPLAY + 1, missing_state);
+        let error = sm.ensure_parent_state(&tipset).await.unwrap_err();
+        assert!(error.to_string().contains("isn't available"), "{error}");
+    }
+}
@@ -0,0 +1,274 @@
+// Copyright 2019-2023 ChainSafe Systems
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Context as _};
+use cid::Cid;
+use fil_actors_shared::fvm_ipld_amt::{self, Amt};
+use fvm_ipld_blockstore::Blockstore;
+use fvm_ipld_encoding::CborStore;
+use libipld_core::ipld::Ipld;
+
+/// An entry of a HAMT or AMT that differs between two roots.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntryChange<K> {
+    pub key: K,
+    pub pre: Option<Ipld>,
+    pub post: Option<Ipld>,
+}
+
+/// A pointer of a HAMT node, either to a child node or to a bucket of entries.
+/// Links of `KAMT` nodes carry the extension of the key bits they skip.
+enum Pointer {
+    Link(Cid, Option<Ipld>),
+    Bucket(Vec<(Vec<u8>, Ipld)>),
+}
+
+/// Returns the changed entries between the HAMTs at `pre` and `post`. Both
+/// HAMTs are walked together, skipping the subtrees with the same CID on both
+/// sides. Works on the IPLD of the nodes, so any HAMT (or `KAMT`) version and
+/// value type is supported.
+pub fn diff_hamt<BS: Blockstore>(
+    bs: &BS,
+    pre: &Cid,
+    post: &Cid,
+) -> anyhow::Result<Vec<EntryChange<Vec<u8>>>> {
+    let mut changes = vec![];
+    if pre != post {
+        diff_nodes(bs, load_node(bs, pre)?, load_node(bs, post)?, &mut changes)?;
+    }
+    Ok(changes)
+}
+
+/// Returns the changed entries between the AMTs at `pre` and `post`.
+pub fn diff_amt<BS: Blockstore>(
+    bs: &BS,
+    pre: &Cid,
+    post: &Cid,
+) -> anyhow::Result<Vec<EntryChange<u64>>> {
+    if pre == post {
+        return Ok(vec![]);
+    }
+    let pre = Amt::<Ipld, _>::load(pre, bs)?;
+    let post = Amt::<Ipld, _>::load(post, bs)?;
+    Ok(fvm_ipld_amt::diff(&pre, &post)?
+        .into_iter()
+        .map(|change| EntryChange {
+            key: change.key,
+            pre: change.before,
+            post: change.after,
+        })
+        .collect())
+}
+
+fn diff_nodes<BS: Blockstore>(
+    bs: &BS,
+    pre: Vec<(usize, Pointer)>,
+    post: Vec<(usize, Pointer)>,
+    changes: &mut Vec<EntryChange<Vec<u8>>>,
+) -> anyhow::Result<()> {
+    // Pointers at the same index of both nodes hold the keys with the same hash
+    // prefix
+    let mut pointers: BTreeMap<usize, (Option<Pointer>, Option<Pointer>)> = BTreeMap::new();
+    for (index, pointer) in pre {
+        pointers.entry(index).or_default().0 = Some(pointer);
+    }
+    for (index, pointer) in post {
+        pointers.entry(index).or_default().1 = Some(pointer);
+    }
+    for (pre, post) in pointers.into_values() {
+        match (pre, post) {
+            (Some(Pointer::Link(pre, pre_ext)), Some(Pointer::Link(post, post_ext)))
+                if pre_ext == post_ext =>
+            {
+                if pre != post {
+                    diff_nodes(bs, load_node(bs, &pre)?, load_node(bs, &post)?, changes)?;
+                }
+            }
+            (pre, post) => {
+                let mut entries: BTreeMap<Vec<u8>, (Option<Ipld>, Option<Ipld>)> = BTreeMap::new();
+                for (key, value) in flatten(bs, pre)? {
+                    entries.entry(key).or_default().0 = Some(value);
+                }
+                for (key, value) in flatten(bs, post)? {
+                    entries.entry(key).or_default().1 = Some(value);
+                }
+                changes.extend(
+                    entries
+                        .into_iter()
+                        .filter(|(_, (pre, post))| pre != post)
+                        .map(|(key, (pre, post))| EntryChange { key, pre, post }),
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns all the entries under a pointer.
+fn flatten<BS: Blockstore>(
+    bs: &BS,
+    pointer: Option<Pointer>,
+) -> anyhow::Result<Vec<(Vec<u8>, Ipld)>> {
+    let mut entries = vec![];
+    let mut stack: Vec<Pointer> = pointer.into_iter().collect();
+    while let Some(pointer) = stack.pop() {
+        match pointer {
+            Pointer::Link(cid, _) => {
+                stack.extend(load_node(bs, &cid)?.into_iter().map(|(_, pointer)| pointer))
+            }
+            Pointer::Bucket(bucket) => entries.extend(bucket),
+        }
+    }
+    Ok(entries)
+}
+
+/// Loads a HAMT node, returning its pointers with their index in the node.
+fn load_node<BS: Blockstore>(bs: &BS, cid: &Cid) -> anyhow::Result<Vec<(usize, Pointer)>> {
+    let node: Ipld = bs
+        .get_cbor(cid)?
+        .with_context(|| format!("HAMT node {cid} does not exist in blockstore"))?;
+    let (bitfield, pointers) = match node {
+        Ipld::List(fields) => match <[Ipld; 2]>::try_from(fields) {
+            Ok([Ipld::Bytes(bitfield), Ipld::List(pointers)]) => (bitfield, pointers),
+            _ => bail!("Invalid HAMT node {cid}"),
+        },
+        _ => bail!("Invalid HAMT node {cid}"),
+    };
+    // The bitfield is a big-endian integer with its leading zero bytes trimmed
+    let indices = (0..bitfield.len() * 8)
+        .filter(|i| bitfield[bitfield.len() - 1 - i / 8] & (1 << (i % 8)) != 0);
+    let pointers = pointers
+        .into_iter()
+        .map(parse_pointer)
+        .collect::<anyhow::Result<Vec<_>>>()
+        .with_context(|| format!("Invalid HAMT node {cid}"))?;
+    if indices.clone().count() != pointers.len() {
+        bail!("Invalid HAMT node {cid}: bitfield does not match the pointers");
+    }
+    Ok(indices.zip(pointers).collect())
+}
+
+fn parse_pointer(pointer: Ipld) -> anyhow::Result<Pointer> {
+    match pointer {
+        Ipld::Link(cid) => Ok(Pointer::Link(cid, None)),
+        // Version 0 pointers are maps with a single field, `0` for links and
+        // `1` for buckets
+        Ipld::Map(mut map) => match (map.remove("0"), map.remove("1")) {
+            (Some(Ipld::Link(cid)), None) => Ok(Pointer::Link(cid, None)),
+            (None, Some(bucket)) => parse_bucket(bucket),
+            _ => bail!("Invalid version 0 HAMT pointer"),
+        },
+        Ipld::List(mut fields) if matches!(fields.first(), Some(Ipld::Link(_))) => {
+            let ext = (fields.len() > 1).then(|| fields.swap_remove(1));
+            match fields.swap_remove(0) {
+                Ipld::Link(cid) => Ok(Pointer::Link(cid, ext)),
+                _ => unreachable!("the first field is a link"),
+            }
+        }
+        bucket => parse_bucket(bucket),
+    }
+}
+
+fn parse_bucket(bucket: Ipld) -> anyhow::Result<Pointer> {
+    let Ipld::List(entries) = bucket else {
+        bail!("Invalid HAMT bucket");
+    };
+    entries
+        .into_iter()
+        .map(|entry| match entry {
+            Ipld::List(fields) => match <[Ipld; 2]>::try_from(fields) {
+                Ok([Ipld::Bytes(key), value]) => Ok((key, value)),
+                _ => bail!("Invalid HAMT bucket entry"),
+            },
+            _ => bail!("Invalid HAMT bucket entry"),
+        })
+        .collect::<anyhow::Result<_>>()
+        .map(Pointer::Bucket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MemoryDB;
+    use fil_actors_shared::fvm_ipld_hamt::{BytesKey, Hamt};
+
+    #[test]
+    fn diff_hamt_entries() {
+        let db = MemoryDB::default();
+        let mut hamt: Hamt<_, u64> = Hamt::new_with_bit_width(&db, 5);
+        for i in 0..1000u64 {
+            hamt.set(BytesKey(i.to_be_bytes().to_vec()), i).unwrap();
+        }
+        let pre = hamt.flush().unwrap();
+        hamt.set(BytesKey(1u64.to_be_bytes().to_vec()), 42).unwrap();
+        hamt.delete(&BytesKey(2u64.to_be_bytes().to_vec())).unwrap();
+        hamt.set(BytesKey(1000u64.to_be_bytes().to_vec()), 1000)
+            .unwrap();
+        let post = hamt.flush().unwrap();
+
+        let mut changes = diff_hamt(&db, &pre, &post).unwrap();
+        changes.sort_by(|a, b| a.key.cmp(&b.key));
+        let key = |i: u64| i.to_be_bytes().to_vec();
+        assert_eq!(
+            changes,
+            vec![
+                EntryChange {
+                    key: key(1),
+                    pre: Some(Ipld::Integer(1)),
+                    post: Some(Ipld::Integer(42)),
+                },
+                EntryChange {
+                    key: key(2),
+                    pre: Some(Ipld::Integer(2)),
+                    post: None,
+                },
+                EntryChange {
+                    key: key(1000),
+                    pre: None,
+                    post: Some(Ipld::Integer(1000)),
+                },
+            ]
+        );
+        assert!(diff_hamt(&db, &pre, &pre).unwrap().is_empty());
+    }
+
+    #[test]
+    fn diff_amt_entries() {
+        let db = MemoryDB::default();
+        let mut amt: Amt<u64, _> = Amt::new(&db);
+        for i in 0..1000u64 {
+            amt.set(i, i).unwrap();
+        }
+        let pre = amt.flush().unwrap();
+        amt.set(1, 42).unwrap();
+        amt.delete(2).unwrap();
+        amt.set(1000, 1000).unwrap();
+        let post = amt.flush().unwrap();
+
+        let mut changes = diff_amt(&db, &pre, &post).unwrap();
+        changes.sort_by_key(|change| change.key);
+        assert_eq!(
+            changes,
+            vec![
+                EntryChange {
+                    key: 1,
+                    pre: Some(Ipld::Integer(1)),
+                    post: Some(Ipld::Integer(42)),
+                },
+                EntryChange {
+                    key: 2,
+                    pre: Some(Ipld::Integer(2)),
+                    post: None,
+                },
+                EntryChange {
+                    key: 1000,
+                    pre: None,
+                    post: Some(Ipld::Integer(1000)),
+                },
+            ]
+        );
+        assert!(diff_amt(&db, &pre, &pre).unwrap().is_empty());
+    }
+}
@@ -1,7 +1,9 @@
 // Copyright 2019-2023 ChainSafe Systems
 // SPDX-License-Identifier: Apache-2.0, MIT
 
+mod collections;
 mod resolve;
+mod structured;
 
 use std::{
     fmt::Write as FmtWrite,
@@ -28,6 +30,7 @@ use libipld_core::ipld::Ipld;
 use resolve::resolve_cids_recursive;
 use serde::{Deserialize, Serialize};
 use similar::{ChangeTag, TextDiff};
+pub use structured::state_diff;
 
 #[derive(Serialize, Deserialize)]
 struct ActorStateResolved {
@@ -0,0 +1,722 @@
+// Copyright 2019-2023 ChainSafe Systems
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+//! Machine-readable state diffs. The actors HAMTs of both state trees are
+//! walked together to find the changed actors, whose states are then decoded
+//! field by field, down to the entries of the HAMTs and AMTs they hold.
+
+use anyhow::{bail, Context as _};
+use cid::Cid;
+use fil_actor_interface::{evm, market, miner, power, verifreg};
+use fil_actor_verifreg_state as verifreg_state;
+use fvm_ipld_blockstore::Blockstore;
+use fvm_ipld_encoding::CborStore;
+use libipld_core::ipld::Ipld;
+use rayon::prelude::*;
+use serde::Serialize;
+use serde_json::Value;
+
+use super::collections::{diff_amt, diff_hamt};
+use crate::ipld::{from_ipld, json::IpldJsonRef, to_ipld};
+use crate::shim::{
+    address::Address,
+    bigint::{BigIntDe, BigIntSer},
+    state_tree::{ActorStateV2, ActorStateV4},
+};
+
+/// The changes between two state trees.
+#[derive(Debug, Serialize)]
+pub struct StateDiff {
+    #[serde(with = "crate::lotus_json")]
+    pub pre: Cid,
+    #[serde(with = "crate::lotus_json")]
+    pub post: Cid,
+    pub actors: Vec<ActorDiff>,
+}
+
+/// The changes of an actor added, removed or changed between two state trees.
+#[derive(Debug, Serialize)]
+pub struct ActorDiff {
+    pub address: String,
+    /// The kind of actor, if its state could be decoded.
+    pub actor: Option<&'static str>,
+    pub changes: Vec<FieldChange>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct FieldChange {
+    /// A JSON pointer to the changed field of the actor, empty when the whole
+    /// actor is added or removed.
+    pub path: String,
+    #[serde(flatten)]
+    pub change: Change,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum Change {
+    Add { post: Value },
+    Remove { pre: Value },
+    Change { pre: Value, post: Value },
+}
+
+/// How a field of an actor state is decoded.
+#[derive(Debug, Clone, Copy)]
+enum Field {
+    /// Any value, compared as a whole.
+    Value,
+    /// A `BigInt`, like a token amount or a storage power.
+    BigInt,
+    Address,
+    /// A link to a node compared field by field, without following its links.
+    Object,
+    Hamt(Key),
+    Amt,
+}
+
+/// How the keys of a HAMT are decoded.
+#[derive(Debug, Clone, Copy)]
+enum Key {
+    Address,
+    /// A varint-encoded integer, like an actor ID, a deal ID or an epoch.
+    Int,
+    Bytes,
+}
+
+/// A field of a typed state, by name, with how it is decoded.
+type NamedField = (&'static str, Field, Ipld);
+
+/// Reads the fields of a typed state by name, so that a field renamed or
+/// removed in a version of an actor fails to compile instead of being compared
+/// with another field. Fields serialized `with` a module in the state are
+/// wrapped in the matching serializer.
+macro_rules! named_fields {
+    ($state:expr; $($name:ident: $field:expr $(=> $ser:path)?),* $(,)?) => {
+        vec![$((stringify!($name), $field, to_ipld(&$($ser)?(&$state.$name))?)),*]
+    };
+}
+
+macro_rules! miner_fields {
+    ($state:expr) => {
+        named_fields!($state;
+            info: Field::Object,
+            pre_commit_deposits: Field::BigInt,
+            locked_funds: Field::BigInt,
+            vesting_funds: Field::Object,
+            fee_debt: Field::BigInt,
+            initial_pledge: Field::BigInt,
+            pre_committed_sectors: Field::Hamt(Key::Int),
+            pre_committed_sectors_cleanup: Field::Amt,
+            allocated_sectors: Field::Value,
+            sectors: Field::Amt,
+            proving_period_start: Field::Value,
+            current_deadline: Field::Value,
+            deadlines: Field::Object,
+            early_terminations: Field::Value,
+            deadline_cron_active: Field::Value,
+        )
+    };
+}
+
+macro_rules! market_fields {
+    ($state:expr $(, $name:ident: $field:expr)*) => {
+        named_fields!($state;
+            proposals: Field::Amt,
+            states: Field::Amt,
+            pending_proposals: Field::Hamt(Key::Bytes),
+            escrow_table: Field::Hamt(Key::Address),
+            locked_table: Field::Hamt(Key::Address),
+            next_id: Field::Value,
+            deal_ops_by_epoch: Field::Hamt(Key::Int),
+            last_cron: Field::Value,
+            total_client_locked_collateral: Field::BigInt,
+            total_provider_locked_collateral: Field::BigInt,
+            total_client_storage_fee: Field::BigInt,
+            $($name: $field,)*
+        )
+    };
+}
+
+macro_rules! power_fields {
+    ($state:expr) => {
+        named_fields!($state;
+            total_raw_byte_power: Field::BigInt => BigIntSer,
+            total_bytes_committed: Field::BigInt => BigIntSer,
+            total_quality_adj_power: Field::BigInt => BigIntSer,
+            total_qa_bytes_committed: Field::BigInt => BigIntSer,
+            total_pledge_collateral: Field::BigInt,
+            this_epoch_raw_byte_power: Field::BigInt => BigIntSer,
+            this_epoch_quality_adj_power: Field::BigInt => BigIntSer,
+            this_epoch_pledge_collateral: Field::BigInt,
+            this_epoch_qa_power_smoothed: Field::Value,
+            miner_count: Field::Value,
+            miner_above_min_power_count: Field::Value,
+            cron_event_queue: Field::Hamt(Key::Int),
+            first_cron_epoch: Field::Value,
+            claims: Field::Hamt(Key::Address),
+            proof_validation_batch: Field::Hamt(Key::Address),
+        )
+    };
+}
+
+macro_rules! verifreg_fields {
+    ($state:expr) => {
+        named_fields!($state;
+            root_key: Field::Address,
+            verifiers: Field::Hamt(Key::Address),
+            remove_data_cap_proposal_ids: Field::Hamt(Key::Bytes),
+            allocations: Field::Hamt(Key::Int),
+            next_allocation_id: Field::Value,
+            claims: Field::Hamt(Key::Int),
+        )
+    };
+}
+
+macro_rules! evm_fields {
+    ($state:expr) => {
+        named_fields!($state;
+            bytecode: Field::Value,
+            bytecode_hash: Field::Value,
+            contract_state: Field::Hamt(Key::Bytes),
+            nonce: Field::Value,
+            tombstone: Field::Value,
+        )
+    };
+}
+
+/// The code, the state and the fields of an actor of a state tree, whose
+/// fields depend on the version of the state tree.
+fn actor_fields(actor: &Ipld) -> anyhow::Result<(Cid, Cid, Vec<NamedField>)> {
+    if let Ok(actor) = from_ipld::<ActorStateV4>(actor.clone()) {
+        let fields = named_fields!(actor;
+            code: Field::Value,
+            state: Field::Value,
+            sequence: Field::Value,
+            balance: Field::BigInt,
+            delegated_address: Field::Address,
+        );
+        return Ok((actor.code, actor.state, fields));
+    }
+    let actor = from_ipld::<ActorStateV2>(actor.clone()).context("Invalid actor")?;
+    let fields = named_fields!(actor;
+        code: Field::Value,
+        state: Field::Value,
+        sequence: Field::Value,
+        balance: Field::BigInt,
+    );
+    Ok((actor.code, actor.state, fields))
+}
+
+/// Returns the kind of actor and the fields of its state, if the state loads
+/// as the typed state of a known actor.
+fn typed_state(
+    bs: &impl Blockstore,
+    code: Cid,
+    state: Cid,
+) -> anyhow::Result<Option<(&'static str, Vec<NamedField>)>> {
+    if let Ok(state) = miner::State::load(bs, code, state) {
+        let fields = match state {
+            miner::State::V8(state) => miner_fields!(state),
+            miner::State::V9(state) => miner_fields!(state),
+            miner::State::V10(state) => miner_fields!(state),
+            miner::State::V11(state) => miner_fields!(state),
+            miner::State::V12(state) => miner_fields!(state),
+        };
+        return Ok(Some(("miner", fields)));
+    }
+    if let Ok(state) = market::State::load(bs, code, state) {
+        let allocation_ids = Field::Hamt(Key::Int);
+        let fields = match state {
+            market::State::V8(state) => market_fields!(state),
+            market::State::V9(state) => {
+                market_fields!(state, pending_deal_allocation_ids: allocation_ids)
+            }
+            market::State::V10(state) => {
+                market_fields!(state, pending_deal_allocation_ids: allocation_ids)
+            }
+            market::State::V11(state) => {
+                market_fields!(state, pending_deal_allocation_ids: allocation_ids)
+            }
+            market::State::V12(state) => {
+                market_fields!(state, pending_deal_allocation_ids: allocation_ids)
+            }
+        };
+        return Ok(Some(("market", fields)));
+    }
+    if let Ok(state) = power::State::load(bs, code, state) {
+        let fields = match state {
+            power::State::V8(state) => power_fields!(state),
+            power::State::V9(state) => power_fields!(state),
+            power::State::V10(state) => power_fields!(state),
+            power::State::V11(state) => power_fields!(state),
+            power::State::V12(state) => power_fields!(state),
+        };
+        return Ok(Some(("power", fields)));
+    }
+    if verifreg::is_v8_verifreg_cid(&code) {
+        if let Some(state) = bs.get_cbor::<verifreg_state::v8::State>(&state)? {
+            let fields = named_fields!(state;
+                root_key: Field::Address,
+                verifiers: Field::Hamt(Key::Address),
+                verified_clients: Field::Hamt(Key::Address),
+                remove_data_cap_proposal_ids: Field::Hamt(Key::Bytes),
+            );
+            return Ok(Some(("verifreg", fields)));
+        }
+    }
+    if verifreg::is_v9_verifreg_cid(&code) {
+        if let Some(state) = bs.get_cbor::<verifreg_state::v9::State>(&state)? {
+            return Ok(Some(("verifreg", verifreg_fields!(state))));
+        }
+    }
+    if verifreg::is_v10_verifreg_cid(&code) {
+        if let Some(state) = bs.get_cbor::<verifreg_state::v10::State>(&state)? {
+            return Ok(Some(("verifreg", verifreg_fields!(state))));
+        }
+    }
+    if verifreg::is_v11_verifreg_cid(&code) {
+        if let Some(state) = bs.get_cbor::<verifreg_state::v11::State>(&state)? {
+            return Ok(Some(("verifreg", verifreg_fields!(state))));
+        }
+    }
+    if verifreg::is_v12_verifreg_cid(&code) {
+        if let Some(state) = bs.get_cbor::<verifreg_state::v12::State>(&state)? {
+            return Ok(Some(("verifreg", verifreg_fields!(state))));
+        }
+    }
+    if let Ok(state) = evm::State::load(bs, code, state) {
+        let fields = match state {
+            evm::State::V10(state) => evm_fields!(state),
+            evm::State::V11(state) => evm_fields!(state),
+            evm::State::V12(state) => evm_fields!(state),
+        };
+        return Ok(Some(("evm", fields)));
+    }
+    Ok(None)
+}
+
+/// Returns the changes between the state trees at `pre` and `post`. Only the
+/// subtrees of the state trees that differ are loaded.
+pub fn state_diff<BS>(bs: &BS, pre: &Cid, post: &Cid) -> anyhow::Result<StateDiff>
+where
+    BS: Blockstore + Sync,
+{
+    let actors = diff_hamt(bs, &actors_root(bs, pre)?, &actors_root(bs, post)?)?
+        .into_par_iter()
+        .map(|change| {
+            let address = Address::from_bytes(&change.key)
+                .map(|address| address.to_string())
+                .unwrap_or_else(|_| hex::encode(&change.key));
+            match (change.pre, change.post) {
+                (pre, post) if pre.is_none() || post.is_none() => {
+                    let actor = post.as_ref().or(pre.as_ref()).and_then(|actor| {
+                        let (code, state, _) = actor_fields(actor).ok()?;
+                        typed_state(bs, code, state).ok()?.map(|(actor, _)| actor)
+                    });
+                    Ok(ActorDiff {
+                        address,
+                        actor,
+                        changes: vec![FieldChange {
+                            path: String::new(),
+                            change: change_of(
+                                pre.as_ref().map(decode_actor),
+                                post.as_ref().map(decode_actor),
+                            ),
+                        }],
+                    })
+                }
+                (Some(pre), Some(post)) => actor_diff(bs, address, &pre, &post),
+                _ => unreachable!("an added or removed actor has no pre or post state"),
+            }
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(StateDiff {
+        pre: *pre,
+        post: *post,
+        actors,
+    })
+}
+
+/// Returns the root of the actors HAMT of a state tree, which is the state root
+/// itself for version 0 state trees.
+fn actors_root(bs: &impl Blockstore, root: &Cid) -> anyhow::Result<Cid> {
+    match bs
+        .get_cbor::<Ipld>(root)?
+        .with_context(|| format!("State root {root} does not exist in blockstore"))?
+    {
+        // StateRoot { version, actors, info }
+        Ipld::List(fields) if fields.len() == 3 => match fields[1] {
+            Ipld::Link(actors) => Ok(actors),
+            _ => bail!("Invalid state root {root}"),
+        },
+        Ipld::List(_) => Ok(*root),
+        _ => bail!("Invalid state root {root}"),
+    }
+}
+
+fn actor_diff(
+    bs: &impl Blockstore,
+    address: String,
+    pre: &Ipld,
+    post: &Ipld,
+) -> anyhow::Result<ActorDiff> {
+    let invalid = || format!("Invalid actor state of {address}");
+    let (pre_code, pre_state, pre_actor) = actor_fields(pre).with_context(invalid)?;
+    let (post_code, post_state, post_actor) = actor_fields(post).with_context(invalid)?;
+    let mut changes = vec![];
+    diff_fields(bs, "", &pre_actor, &post_actor, &mut changes)?;
+
+    let pre_typed = typed_state(bs, pre_code, pre_state)?;
+    let post_typed = typed_state(bs, post_code, post_state)?;
+    let actor = post_typed.as_ref().map(|(actor, _)| *actor);
+    if pre_state != post_state {
+        match (pre_typed, post_typed) {
+            (Some((_, pre_fields)), Some((_, post_fields))) => {
+                diff_fields(bs, "/state", &pre_fields, &post_fields, &mut changes)?
+            }
+            // Unknown actors are compared without following their links
+            _ => {
+                if let (Some(pre), Some(post)) =
+                    (bs.get_cbor(&pre_state)?, bs.get_cbor(&post_state)?)
+                {
+                    diff_ipld("/state", &pre, &post, &mut changes)
+                }
+            }
+        }
+    }
+    Ok(ActorDiff {
+        address,
+        actor,
+        changes,
+    })
+}
+
+fn value<'a>(fields: &'a [NamedField], name: &str) -> Option<&'a Ipld> {
+    fields
+        .iter()
+        .find(|(field_name, ..)| *field_name == name)
+        .map(|(_, _, value)| value)
+}
+
+/// Compares the fields of two typed states by name, so that states of
+/// different versions of an actor are compared too.
+fn diff_fields(
+    bs: &impl Blockstore,
+    path: &str,
+    pre: &[NamedField],
+    post: &[NamedField],
+    changes: &mut Vec<FieldChange>,
+) -> anyhow::Result<()> {
+    let removed = pre.iter().filter(|(name, ..)| value(post, name).is_none());
+    for &(name, field, _) in post.iter().chain(removed) {
+        let path = format!("{path}/{name}");
+        match (value(pre, name), value(post, name)) {
+            (Some(pre), Some(post)) if pre == post => (),
+            (Some(Ipld::Link(pre)), Some(Ipld::Link(post))) => {
+                diff_linked(bs, &path, field, pre, post, changes)?
+            }
+            (pre, post) => changes.push(FieldChange {
+                path,
+                change: change_of(
+                    pre.map(|pre| decode(field, pre)),
+                    post.map(|post| decode(field, post)),
+                ),
+            }),
+        }
+    }
+    Ok(())
+}
+
+/// Compares the HAMTs, AMTs or objects behind two different links.
+fn diff_linked(
+    bs: &impl Blockstore,
+    path: &str,
+    field: Field,
+    pre: &Cid,
+    post: &Cid,
+    changes: &mut Vec<FieldChange>,
+) -> anyhow::Result<()> {
+    match field {
+        Field::Hamt(key) => {
+            for change in diff_hamt(bs, pre, post)? {
+                changes.push(FieldChange {
+                    path: format!("{path}/{}", decode_key(key, &change.key)),
+                    change: change_of(
+                        change.pre.as_ref().map(ipld_to_json),
+                        change.post.as_ref().map(ipld_to_json),
+                    ),
+                });
+            }
+        }
+        Field::Amt => {
+            for change in diff_amt(bs, pre, post)? {
+                changes.push(FieldChange {
+                    path: format!("{path}/{}", change.key),
+                    change: change_of(
+                        change.pre.as_ref().map(ipld_to_json),
+                        change.post.as_ref().map(ipld_to_json),
+                    ),
+                });
+            }
+        }
+        Field::Object => match (bs.get_cbor(pre)?, bs.get_cbor(post)?) {
+            (Some(pre), Some(post)) => diff_ipld(path, &pre, &post, changes),
+            _ => changes.push(link_change(path, pre, post)),
+        },
+        Field::Value | Field::BigInt | Field::Address => changes.push(link_change(path, pre, post)),
+    }
+    Ok(())
+}
+
+/// Compares two IPLD values structurally, without following links.
+fn diff_ipld(path: &str, pre: &Ipld, post: &Ipld, changes: &mut Vec<FieldChange>) {
+    match (pre, post) {
+        _ if pre == post => (),
+        (Ipld::List(pre), Ipld::List(post)) => {
+            for i in 0..pre.len().max(post.len()) {
+                diff_optional_ipld(&format!("{path}/{i}"), pre.get(i), post.get(i), changes);
+            }
+        }
+        (Ipld::Map(pre), Ipld::Map(post)) => {
+            for key in pre
+                .keys()
+                .chain(post.keys().filter(|key| !pre.contains_key(*key)))
+            {
+                let path = format!("{path}/{}", escape_json_pointer(key));
+                diff_optional_ipld(&path, pre.get(key), post.get(key), changes);
+            }
+        }
+        _ => changes.push(FieldChange {
+            path: path.to_owned(),
+            change: Change::Change {
+                pre: ipld_to_json(pre),
+                post: ipld_to_json(post),
+            },
+        }),
+    }
+}
+
+fn diff_optional_ipld(
+    path: &str,
+    pre: Option<&Ipld>,
+    post: Option<&Ipld>,
+    changes: &mut Vec<FieldChange>,
+) {
+    match (pre, post) {
+        (Some(pre), Some(post)) => diff_ipld(path, pre, post, changes),
+        (pre, post) => changes.push(FieldChange {
+            path: path.to_owned(),
+            change: change_of(pre.map(ipld_to_json), post.map(ipld_to_json)),
+        }),
+    }
+}
+
+fn change_of(pre: Option<Value>, post: Option<Value>) -> Change {
+    match (pre, post) {
+        (Some(pre), Some(post)) => Change::Change { pre, post },
+        (Some(pre), None) => Change::Remove { pre },
+        (None, Some(post)) => Change::Add { post },
+        (None, None) => unreachable!("a change has a pre or a post value"),
+    }
+}
+
+fn link_change(path: &str, pre: &Cid, post: &Cid) -> FieldChange {
+    FieldChange {
+        path: path.to_owned(),
+        change: Change::Change {
+            pre: ipld_to_json(&Ipld::Link(*pre)),
+            post: ipld_to_json(&Ipld::Link(*post)),
+        },
+    }
+}
+
+/// Decodes an actor of a state tree as a JSON object with named fields.
+fn decode_actor(actor: &Ipld) -> Value {
+    match actor_fields(actor) {
+        Ok((_, _, fields)) => Value::Object(
+            fields
+                .iter()
+                .map(|(name, field, value)| (name.to_string(), decode(*field, value)))
+                .collect(),
+        ),
+        Err(_) => ipld_to_json(actor),
+    }
+}
+
+fn decode(field: Field, ipld: &Ipld) -> Value {
+    let decoded = match (field, ipld) {
+        (Field::BigInt, _) => from_ipld::<BigIntDe>(ipld.clone())
+            .ok()
+            .map(|BigIntDe(n)| Value::String(n.to_string())),
+        (Field::Address, Ipld::Bytes(bytes)) => Address::from_bytes(bytes)
+            .ok()
+            .map(|address| Value::String(address.to_string())),
+        _ => None,
+    };
+    decoded.unwrap_or_else(|| ipld_to_json(ipld))
+}
+
+fn decode_key(key: Key, bytes: &[u8]) -> String {
+    let decoded = match key {
+        Key::Address => Address::from_bytes(bytes)
+            .ok()
+            .map(|address| address.to_string()),
+        Key::Int => unsigned_varint::decode::u64(bytes)
+            .ok()
+            .filter(|(_, rest)| rest.is_empty())
+            .map(|(n, _)| n.to_string()),
+        Key::Bytes => None,
+    };
+    decoded.unwrap_or_else(|| hex::encode(bytes))
+}
+
+fn ipld_to_json(ipld: &Ipld) -> Value {
+    serde_json::to_value(IpldJsonRef(ipld)).unwrap_or(Value::Null)
+}
+
+fn escape_json_pointer(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MemoryDB;
+    use crate::shim::{econ::TokenAmount, state_tree::StateTree, state_tree::StateTreeVersion};
+    use crate::utils::db::CborStoreExt;
+    use fil_actor_account_state::v10::State as AccountState;
+    use fil_actor_power_state::v10::State as PowerState;
+    use fil_actors_shared::fvm_ipld_hamt::{BytesKey, Hamt};
+    use std::sync::Arc;
+
+    fn account(db: &MemoryDB, id: u64, balance: u64) -> crate::shim::state_tree::ActorState {
+        // mainnet v10 account actor cid
+        let code = Cid::try_from("bafk2bzaceampw4romta75hyz5p4cqriypmpbgnkxncgxgqn6zptv5lsp2w2bo")
+            .unwrap();
+        let state = db
+            .put_cbor_default(&AccountState {
+                address: Address::new_id(id).into(),
+            })
+            .unwrap();
+        crate::shim::state_tree::ActorState::new(
+            code,
+            state,
+            TokenAmount::from_atto(balance),
+            0,
+            None,
+        )
+    }
+
+    #[test]
+    fn state_diff_changed_actors() {
+        let db = Arc::new(MemoryDB::default());
+        let mut tree = StateTree::new(db.clone(), StateTreeVersion::V5).unwrap();
+        for id in 100..200 {
+            tree.set_actor(&Address::new_id(id), account(&db, id, id))
+                .unwrap();
+        }
+        let pre = tree.flush().unwrap();
+        tree.set_actor(&Address::new_id(100), account(&db, 100, 42))
+            .unwrap();
+        tree.set_actor(&Address::new_id(200), account(&db, 200, 200))
+            .unwrap();
+        let post = tree.flush().unwrap();
+
+        let mut diff = state_diff(db.as_ref(), &pre, &post).unwrap();
+        diff.actors.sort_by(|a, b| a.address.cmp(&b.address));
+        assert_eq!(diff.actors.len(), 2);
+
+        let changed = &diff.actors[0];
+        assert_eq!(changed.address, "f0100");
+        assert_eq!(
+            changed.changes,
+            vec![FieldChange {
+                path: "/balance".into(),
+                change: Change::Change {
+                    pre: "100".into(),
+                    post: "42".into(),
+                },
+            }]
+        );
+
+        let added = &diff.actors[1];
+        assert_eq!(added.address, "f0200");
+        assert!(matches!(
+            &added.changes[..],
+            [FieldChange { path, change: Change::Add { post } }]
+                if path.is_empty() && post["balance"] == "200"
+        ));
+
+        assert!(state_diff(db.as_ref(), &pre, &pre)
+            .unwrap()
+            .actors
+            .is_empty());
+    }
+
+    #[test]
+    fn state_diff_typed_actor() {
+        let db = Arc::new(MemoryDB::default());
+        // mainnet v10 power actor cid
+        let code = Cid::try_from("bafk2bzacec4ay4crzo73ypmh7o3fjendhbqrxake46bprabw67fvwjz5q6ixq")
+            .unwrap();
+        let power = |state: &PowerState| {
+            let state = db.put_cbor_default(state).unwrap();
+            crate::shim::state_tree::ActorState::new(
+                code,
+                state,
+                TokenAmount::from_atto(0),
+                0,
+                None,
+            )
+        };
+        let mut tree = StateTree::new(db.clone(), StateTreeVersion::V5).unwrap();
+        let mut state = PowerState::new(db.as_ref()).unwrap();
+        tree.set_actor(&Address::POWER_ACTOR, power(&state))
+            .unwrap();
+        let pre = tree.flush().unwrap();
+
+        state.total_raw_byte_power = 100.into();
+        let mut claims: Hamt<_, u64> =
+            Hamt::load_with_bit_width(&state.claims, db.as_ref(), 5).unwrap();
+        claims
+            .set(BytesKey(Address::new_id(1000).to_bytes()), 7)
+            .unwrap();
+        state.claims = claims.flush().unwrap();
+        tree.set_actor(&Address::POWER_ACTOR, power(&state))
+            .unwrap();
+        let post = tree.flush().unwrap();
+
+        let diff = state_diff(db.as_ref(), &pre, &post).unwrap();
+        let [actor] = &diff.actors[..] else {
+            panic!("expected one changed actor, got {:?}", diff.actors);
+        };
+        assert_eq!(actor.address, "f04");
+        assert_eq!(actor.actor, Some("power"));
+        let paths: Vec<_> = actor
+            .changes
+            .iter()
+            .map(|change| &change.path[..])
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                "/state",
+                "/state/total_raw_byte_power",
+                "/state/claims/f01000"
+            ]
+        );
+        assert_eq!(
+            actor.changes[1].change,
+            Change::Change {
+                pre: "0".into(),
+                post: "100".into(),
+            }
+        );
+        assert_eq!(
+            actor.changes[2].change,
+            Change::Add {
+                post: ipld_to_json(&Ipld::Integer(7)),
+            }
+        );
+    }
+}
@@ -1,23 +1,37 @@
 // Copyright 2019-2023 ChainSafe Systems
 // SPDX-License-Identifier: Apache-2.0, MIT
 
+use std::collections::BTreeMap;
+use std::io::Write as _;
 use std::path::{Path, PathBuf};
 
+use anyhow::Context as _;
+use async_trait::async_trait;
+use cid::Cid;
 use clap::Subcommand;
-use futures::{StreamExt, TryStreamExt};
+use futures::{StreamExt, TryStream, TryStreamExt};
 use fvm_ipld_blockstore::Blockstore;
+use fvm_ipld_encoding::{CBOR, DAG_CBOR, IPLD_RAW};
+use human_repr::HumanCount;
 use indicatif::{ProgressBar, ProgressStyle};
 use itertools::Itertools;
+use libipld_core::ipld::Ipld;
 use tokio::{
     fs::File,
     io::{AsyncWriteExt, BufReader},
 };
 
-use crate::db::car::ForestCar;
+use crate::cid_collections::CidHashSet;
+use crate::db::car::{forest, v2, AnyCar, CarV2, ForestCar};
+use crate::ipld::{
+    selector::{LinkResolver, Selector},
+    DfsIter,
+};
 use crate::utils::db::{
-    car_stream::CarStream,
+    car_stream::{CarBlock, CarStream},
     car_util::{dedup_block_stream, merge_car_streams},
 };
+use crate::utils::io::EitherMmapOrRandomAccessFile;
 
 #[derive(Debug, Subcommand)]
 pub enum CarCommands {
@@ -30,15 +44,79 @@ pub enum CarCommands {
         #[arg(short, long)]
         output: PathBuf,
     },
+    /// Convert a CAR archive between the CARv2 and `.forest.car.zst` formats
+    Convert {
+        /// CAR archive. Supported extensions: `.car` (CARv1 or CARv2), `.car.zst`,
+        /// `.forest.car.zst`
+        car_file: PathBuf,
+        /// The output file path. A `.forest.car.zst` archive is written if the path has this
+        /// extension, and a CARv2 archive otherwise
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// List the blocks of a CAR archive with their codec and size
+    Ls {
+        /// CAR archive. Supported extensions: `.car` (CARv1 or CARv2), `.car.zst`,
+        /// `.forest.car.zst`
+        car_file: PathBuf,
+    },
+    /// Write the data of a block of a CAR archive to stdout
+    Get {
+        /// CAR archive. Supported extensions: `.car` (CARv1 or CARv2), `.car.zst`,
+        /// `.forest.car.zst`
+        car_file: PathBuf,
+        /// CID of the block
+        cid: Cid,
+    },
+    /// Show the roots of a CAR archive, or copy the archive with new roots
+    Roots {
+        /// CAR archive. Supported extensions: `.car` (CARv1 or CARv2), `.car.zst`,
+        /// `.forest.car.zst`
+        car_file: PathBuf,
+        /// The new roots of the archive
+        #[arg(long, num_args = 1.., requires = "output")]
+        set: Vec<Cid>,
+        /// The output file path of the archive with new roots. A `.forest.car.zst` archive is
+        /// written if the path has this extension, and a CARv2 archive otherwise
+        #[arg(short, long, requires = "set")]
+        output: Option<PathBuf>,
+    },
+    /// Print the number and size of the blocks of a CAR archive by codec, and a histogram of
+    /// the block sizes
+    Stats {
+        /// CAR archive. Supported extensions: `.car` (CARv1 or CARv2), `.car.zst`,
+        /// `.forest.car.zst`
+        car_file: PathBuf,
+    },
+    /// Copy the DAG reachable from a root into a new CAR archive. Blocks missing from the
+    /// archive are skipped
+    Filter {
+        /// CAR archive. Supported extensions: `.car` (CARv1 or CARv2), `.car.zst`,
+        /// `.forest.car.zst`
+        car_file: PathBuf,
+        /// Root of the DAG, also the root of the new archive
+        #[arg(long)]
+        root: Cid,
+        /// Only copy the blocks traversed by this IPLD selector, in its `DAG-JSON`
+        /// representation, e.g. `{"R":{"l":{"depth":1},":>":{"a":{">":{"@":{}}}}}}`
+        #[arg(long)]
+        selector: Option<String>,
+        /// The output file path. A `.forest.car.zst` archive is written if the path has this
+        /// extension, and a CARv2 archive otherwise
+        #[arg(short, long)]
+        output: PathBuf,
+    },
     /// Check the validity of a CAR archive. For Filecoin-specific checks, see
     /// `forest-tool snapshot validate`.
     Validate {
-        /// CAR archive. Supported extensions: `.car`, `.car.zst`, `.forest.car.zst`
+        /// CAR archive. Supported extensions: `.car` (CARv1 or CARv2), `.car.zst`,
+        /// `.forest.car.zst`
         car_file: PathBuf,
         /// Skip verifying that blocks are hashed correctly
         #[arg(long)]
         ignore_block_validity: bool,
-        /// Skip verifying the integrity of the on-disk index
+        /// Skip verifying the integrity of the on-disk index of `.forest.car.zst` and CARv2
+        /// archives
         #[arg(long)]
         ignore_forest_index: bool,
     },
@@ -62,13 +140,47 @@ impl CarCommands {
                     .cloned()
                     .collect::<Vec<_>>();
 
-                let frames = crate::db::car::forest::Encoder::compress_stream_default(
+                let frames = forest::Encoder::compress_stream_default(
                     dedup_block_stream(merge_car_streams(car_streams)).map_err(anyhow::Error::from),
                 );
                 let mut writer = tokio::io::BufWriter::new(tokio::fs::File::create(&output).await?);
-                crate::db::car::forest::Encoder::write(&mut writer, all_roots, frames).await?;
+                forest::Encoder::write(&mut writer, all_roots, frames).await?;
                 writer.flush().await?;
             }
+            Self::Convert { car_file, output } => convert(&car_file, &output).await?,
+            Self::Ls { car_file } => ls(&car_file, &mut std::io::stdout().lock()).await?,
+            Self::Get { car_file, cid } => {
+                let data = get(&car_file, cid)?;
+                let mut stdout = std::io::stdout().lock();
+                stdout.write_all(&data)?;
+                stdout.flush()?;
+            }
+            Self::Roots {
+                car_file,
+                set,
+                output,
+            } => match output {
+                Some(output) => set_roots(&car_file, set, &output).await?,
+                None => {
+                    for root in open_car_stream(&car_file).await?.header.roots {
+                        println!("{root}");
+                    }
+                }
+            },
+            Self::Stats { car_file } => print!("{}", stats(&car_file).await?),
+            Self::Filter {
+                car_file,
+                root,
+                selector,
+                output,
+            } => {
+                let selector = selector
+                    .map(|selector| serde_json::from_str(&selector))
+                    .transpose()
+                    .context("Invalid selector")?;
+                let copied = filter(&car_file, root, selector, &output).await?;
+                println!("Copied {copied} blocks to {}", output.display());
+            }
             Self::Validate {
                 car_file,
                 ignore_block_validity,
@@ -79,10 +191,242 @@ impl CarCommands {
     }
 }
 
+async fn open_car_stream(car_file: &Path) -> anyhow::Result<CarStream<BufReader<File>>> {
+    Ok(CarStream::new(BufReader::new(File::open(car_file).await?)).await?)
+}
+
+/// Writes the CID, codec and size of the blocks of `car_file`, one block per line.
+async fn ls(car_file: &Path, writer: &mut impl std::io::Write) -> anyhow::Result<()> {
+    let mut stream = open_car_stream(car_file).await?;
+    while let Some(block) = stream.try_next().await? {
+        writeln!(
+            writer,
+            "{}\t{}\t{}",
+            block.cid,
+            codec_name(block.cid.codec()),
+            block.data.len()
+        )?;
+    }
+    Ok(())
+}
+
+/// Returns the data of the block `cid` of `car_file`.
+fn get(car_file: &Path, cid: Cid) -> anyhow::Result<Vec<u8>> {
+    AnyCar::try_from(car_file)?
+        .get(&cid)?
+        .with_context(|| format!("Block {cid} not found in {}", car_file.display()))
+}
+
+async fn stats(car_file: &Path) -> anyhow::Result<CarStats> {
+    let mut stream = open_car_stream(car_file).await?;
+    let mut stats = CarStats::default();
+    while let Some(block) = stream.try_next().await? {
+        stats.add(&block);
+    }
+    Ok(stats)
+}
+
+/// Streams the blocks of `car_file` into a `.forest.car.zst` archive if `output` has this
+/// extension, and into a CARv2 archive otherwise.
+async fn convert(car_file: &Path, output: &Path) -> anyhow::Result<()> {
+    let stream = open_car_stream(car_file).await?;
+    let roots = stream.header.roots.clone();
+    write_car(output, roots, stream.map_err(anyhow::Error::from)).await
+}
+
+/// Copies the blocks of `car_file` into a new archive with `roots`.
+async fn set_roots(car_file: &Path, roots: Vec<Cid>, output: &Path) -> anyhow::Result<()> {
+    let stream = open_car_stream(car_file).await?;
+    write_car(output, roots, stream.map_err(anyhow::Error::from)).await
+}
+
+/// Number of blocks loaded by a selector walk ahead of the encoder.
+const FILTER_BUFFER: usize = 64;
+
+/// Copies the blocks of the DAG under `root` into a new archive, in depth-first order. With a
+/// selector, only the blocks it traverses are copied. Blocks are streamed into the archive as
+/// they are loaded. Returns the number of copied blocks.
+async fn filter(
+    car_file: &Path,
+    root: Cid,
+    selector: Option<Selector>,
+    output: &Path,
+) -> anyhow::Result<usize> {
+    let store = AnyCar::try_from(car_file)?;
+    let mut copied = 0;
+    match selector {
+        Some(selector) => {
+            let (sender, receiver) = flume::bounded(FILTER_BUFFER);
+            let resolver = BlockSender {
+                store: &store,
+                seen: CidHashSet::default(),
+                sender,
+            };
+            // The resolver, and so the sender, is dropped once the walk is over, which ends
+            // the stream of blocks
+            let walk = async move {
+                selector
+                    .walk_all(&Ipld::Link(root), Some(resolver), |_, _, _| Ok(()))
+                    .await
+                    .map_err(anyhow::Error::from)
+            };
+            let blocks = receiver.into_stream().map(Ok).inspect_ok(|_| copied += 1);
+            futures::try_join!(walk, write_car(output, vec![root], blocks))?;
+        }
+        None => {
+            let mut seen = CidHashSet::default();
+            let mut dfs = DfsIter::from(root);
+            let blocks = std::iter::from_fn(|| next_block(&store, &mut dfs, &mut seen).transpose());
+            let blocks = futures::stream::iter(blocks).inspect_ok(|_| copied += 1);
+            write_car(output, vec![root], blocks).await?;
+        }
+    }
+    Ok(copied)
+}
+
+/// Returns the next block of a depth-first traversal that is in `store` and not `seen` yet.
+fn next_block(
+    store: &impl Blockstore,
+    dfs: &mut DfsIter,
+    seen: &mut CidHashSet,
+) -> anyhow::Result<Option<CarBlock>> {
+    while let Some(ipld) = dfs.next() {
+        if let Ipld::Link(cid) = ipld {
+            if !seen.insert(cid) {
+                continue;
+            }
+            if let Some(data) = store.get(&cid)? {
+                if cid.codec() == DAG_CBOR {
+                    dfs.walk_next(fvm_ipld_encoding::from_slice(&data)?);
+                }
+                return Ok(Some(CarBlock { cid, data }));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Resolves the links of a selector walk from a block store, sending the loaded blocks the
+/// first time they are loaded.
+struct BlockSender<'a, DB> {
+    store: &'a DB,
+    seen: CidHashSet,
+    sender: flume::Sender<CarBlock>,
+}
+
+#[async_trait]
+impl<DB: Blockstore + Send + Sync> LinkResolver for BlockSender<'_, DB> {
+    async fn load_link(&mut self, link: &Cid) -> Result<Option<Ipld>, String> {
+        let Some(data) = self.store.get(link).map_err(|e| e.to_string())? else {
+            return Ok(None);
+        };
+        let ipld = if link.codec() == DAG_CBOR {
+            fvm_ipld_encoding::from_slice(&data).map_err(|e| e.to_string())?
+        } else {
+            Ipld::Bytes(data.clone())
+        };
+        if self.seen.insert(*link) {
+            self.sender
+                .send_async(CarBlock { cid: *link, data })
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(Some(ipld))
+    }
+}
+
+/// Writes a `.forest.car.zst` archive if `output` has this extension, and a CARv2 archive
+/// otherwise.
+async fn write_car(
+    output: &Path,
+    roots: Vec<Cid>,
+    blocks: impl TryStream<Ok = CarBlock, Error = anyhow::Error> + Unpin,
+) -> anyhow::Result<()> {
+    let mut writer = tokio::io::BufWriter::new(File::create(output).await?);
+    if output
+        .to_string_lossy()
+        .ends_with(forest::FOREST_CAR_FILE_EXTENSION)
+    {
+        let frames = forest::Encoder::compress_stream_default(blocks);
+        forest::Encoder::write(&mut writer, roots, frames).await?;
+    } else {
+        v2::Encoder::write(&mut writer, roots, blocks).await?;
+    }
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Name of a multicodec, or its code if it's not one of the common IPLD codecs.
+fn codec_name(codec: u64) -> String {
+    match codec {
+        DAG_CBOR => "dag-cbor".into(),
+        CBOR => "cbor".into(),
+        IPLD_RAW => "raw".into(),
+        0x70 => "dag-pb".into(),
+        0x0129 => "dag-json".into(),
+        other => format!("{other:#x}"),
+    }
+}
+
+/// Number and size of the blocks of an archive, by codec and by size bucket.
+#[derive(Default)]
+struct CarStats {
+    /// Number and total size of the blocks by codec.
+    codecs: BTreeMap<u64, (usize, usize)>,
+    /// Number of blocks by the log2 of the smallest power of two not less than their size.
+    sizes: BTreeMap<u32, usize>,
+}
+
+impl CarStats {
+    fn add(&mut self, block: &CarBlock) {
+        let (count, size) = self.codecs.entry(block.cid.codec()).or_default();
+        *count += 1;
+        *size += block.data.len();
+        *self
+            .sizes
+            .entry(block.data.len().next_power_of_two().trailing_zeros())
+            .or_default() += 1;
+    }
+}
+
+impl std::fmt::Display for CarStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{:<12}{:>12}{:>14}", "Codec", "Blocks", "Size")?;
+        let (mut total_count, mut total_size) = (0, 0);
+        for (codec, (count, size)) in &self.codecs {
+            writeln!(
+                f,
+                "{:<12}{count:>12}{:>14}",
+                codec_name(*codec),
+                size.human_count_bytes().to_string()
+            )?;
+            total_count += count;
+            total_size += size;
+        }
+        writeln!(
+            f,
+            "{:<12}{total_count:>12}{:>14}",
+            "Total",
+            total_size.human_count_bytes().to_string()
+        )?;
+        writeln!(f)?;
+        writeln!(f, "{:<12}{:>12}", "Size", "Blocks")?;
+        for (log2, count) in &self.sizes {
+            writeln!(
+                f,
+                "{:<12}{count:>12}",
+                format!("<= {}", (1u64 << log2).human_count_bytes())
+            )?;
+        }
+        Ok(())
+    }
+}
+
 /// At present, three properties are checked:
 /// - The CAR file is syntactically valid and all blocks can be streamed.
 /// - Each block CID is checked against the hash of the block.
-/// - Each block CID is looked-up in the on-disk index.
+/// - Each block CID is looked-up in the on-disk index. The entries of a CARv2
+///   index are also checked to point at the blocks they index.
 ///
 /// Properties related to Filecoin are not checked. For those, see `forest-tool
 /// snapshot validate`.
@@ -94,8 +438,15 @@ async fn validate(
     ignore_block_validity: bool,
     ignore_forest_index: bool,
 ) -> anyhow::Result<()> {
-    let optional_db = if !ignore_forest_index {
-        Some(ForestCar::try_from(car_file)?)
+    let optional_db: Option<AnyCar<_>> = if !ignore_forest_index {
+        let reader = EitherMmapOrRandomAccessFile::open(car_file)?;
+        if CarV2::is_valid(&reader) {
+            let car = CarV2::new(reader)?;
+            car.validate_index()?;
+            Some(car.into())
+        } else {
+            Some(ForestCar::new(reader)?.into())
+        }
     } else {
         None
     };
@@ -120,13 +471,14 @@ async fn validate(
 
 #[cfg(test)]
 mod tests {
-    use super::validate;
-    use crate::db::car::forest;
+    use super::{convert, filter, get, ls, set_roots, stats, validate};
+    use crate::db::car::{forest, AnyCar};
     use crate::networks::{calibnet, mainnet};
     use crate::utils::db::car_stream::CarBlock;
     use cid::multihash::{Code, MultihashDigest};
     use cid::Cid;
     use futures::{stream::iter, StreamExt, TryStreamExt};
+    use fvm_ipld_blockstore::Blockstore as _;
     use std::io::Write;
     use tempfile::{Builder, TempPath};
     use tokio::io::AsyncWriteExt;
@@ -166,6 +518,95 @@ mod tests {
             .is_ok());
     }
 
+    #[tokio::test]
+    async fn convert_calibnet_genesis() {
+        let mut genesis = Builder::new().tempfile().unwrap();
+        genesis.write_all(calibnet::DEFAULT_GENESIS).unwrap();
+        let genesis = genesis.into_temp_path();
+
+        let carv2 = Builder::new().suffix(".car").tempfile().unwrap();
+        let carv2 = carv2.into_temp_path();
+        convert(&genesis, &carv2).await.unwrap();
+        assert_eq!(AnyCar::try_from(&*carv2).unwrap().variant(), "CARv2");
+        assert!(validate(&carv2, false, false).await.is_ok());
+
+        let forest_car = Builder::new().suffix(".forest.car.zst").tempfile().unwrap();
+        let forest_car = forest_car.into_temp_path();
+        convert(&carv2, &forest_car).await.unwrap();
+        let store = AnyCar::try_from(&*forest_car).unwrap();
+        assert_eq!(store.variant(), "ForestCARv1.zst");
+        assert_eq!(
+            store.heaviest_tipset().unwrap(),
+            AnyCar::try_from(calibnet::DEFAULT_GENESIS)
+                .unwrap()
+                .heaviest_tipset()
+                .unwrap()
+        );
+        assert!(validate(&forest_car, false, false).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn filter_calibnet_genesis() {
+        let mut genesis = Builder::new().tempfile().unwrap();
+        genesis.write_all(calibnet::DEFAULT_GENESIS).unwrap();
+        let genesis = genesis.into_temp_path();
+        let output = Builder::new().suffix(".car").tempfile().unwrap();
+        let output = output.into_temp_path();
+
+        let copied = filter(&genesis, *calibnet::GENESIS_CID, None, &output)
+            .await
+            .unwrap();
+        assert!(copied > 1);
+        let store = AnyCar::try_from(&*output).unwrap();
+        assert!(store.has(&calibnet::GENESIS_CID).unwrap());
+        assert!(validate(&output, false, false).await.is_ok());
+
+        // The matcher selector only selects the root
+        let selector = serde_json::from_str(r#"{".":{}}"#).unwrap();
+        let copied = filter(&genesis, *calibnet::GENESIS_CID, Some(selector), &output)
+            .await
+            .unwrap();
+        assert_eq!(copied, 1);
+    }
+
+    #[tokio::test]
+    async fn ls_get_and_stats() {
+        let blocks = vec![valid_block("first block"), valid_block("second block")];
+        let car = create_raw_car_file(blocks.clone(), vec![]).await;
+
+        let mut listing = vec![];
+        ls(&car, &mut listing).await.unwrap();
+        let listing = String::from_utf8(listing).unwrap();
+        let expected = blocks
+            .iter()
+            .map(|block| format!("{}\t0x0\t{}", block.cid, block.data.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(listing.lines().collect::<Vec<_>>(), expected);
+
+        assert_eq!(get(&car, blocks[1].cid).unwrap(), blocks[1].data);
+        assert!(get(&car, valid_block("missing block").cid).is_err());
+
+        let stats = stats(&car).await.unwrap();
+        // 11 and 12 bytes, both in the 16-byte bucket
+        assert_eq!(stats.codecs.into_iter().collect::<Vec<_>>(), [(0, (2, 23))]);
+        assert_eq!(stats.sizes.into_iter().collect::<Vec<_>>(), [(4, 2)]);
+    }
+
+    #[tokio::test]
+    async fn set_roots_calibnet_genesis() {
+        let mut genesis = Builder::new().tempfile().unwrap();
+        genesis.write_all(calibnet::DEFAULT_GENESIS).unwrap();
+        let genesis = genesis.into_temp_path();
+        let output = Builder::new().suffix(".forest.car.zst").tempfile().unwrap();
+        let output = output.into_temp_path();
+
+        let root = valid_block("new root").cid;
+        set_roots(&genesis, vec![root], &output).await.unwrap();
+        let store = forest::ForestCar::try_from(&*output).unwrap();
+        assert_eq!(store.roots(), vec![root]);
+        assert!(store.has(&calibnet::GENESIS_CID).unwrap());
+    }
+
     fn valid_block(msg: &str) -> CarBlock {
         let data = msg.as_bytes().to_vec();
         CarBlock {
@@ -2,7 +2,10 @@
 // SPDX-License-Identifier: Apache-2.0, MIT
 
 use crate::shim::sector::SectorSize;
-use crate::utils::proofs_api::paramfetch::{get_params_default, SectorSizeOpt};
+use crate::utils::proofs_api::paramfetch::{
+    get_params_default, verify_params_default, FetchOptions, ParamStatus, ParamsMirror,
+    SectorSizeOpt,
+};
 
 use super::read_config;
 use crate::cli::subcommands::cli_error_and_die;
@@ -19,6 +22,21 @@ pub struct FetchCommands {
     /// Print out download location instead of downloading files
     #[arg(short, long)]
     dry_run: bool,
+    /// Re-hash the parameter files of the cache and report corrupt ones instead of downloading
+    /// files. Checks all the files of the cache unless parameters are selected
+    #[arg(long, conflicts_with = "dry_run")]
+    verify_only: bool,
+    /// Directory, `file://` or HTTP URL laid out like the parameter cache to download from
+    /// instead of the IPFS gateway. Defaults to the `FOREST_PROOFS_PARAMS_MIRROR` environment
+    /// variable
+    #[arg(long)]
+    mirror: Option<ParamsMirror>,
+    /// Maximum number of files downloaded at once
+    #[arg(long, default_value_t = FetchOptions::DEFAULT_CONCURRENCY)]
+    concurrency: usize,
+    /// Maximum combined download rate from the mirror, in bytes per second
+    #[arg(long)]
+    max_bandwidth: Option<u64>,
     /// Size in bytes
     params_size: Option<String>,
     /// Optional TOML file containing forest daemon configuration
@@ -37,6 +55,8 @@ impl FetchCommands {
             SectorSizeOpt::Size(sector_size)
         } else if self.keys {
             SectorSizeOpt::Keys
+        } else if self.verify_only {
+            return verify(&config.client.data_dir, SectorSizeOpt::All, true).await;
         } else {
             cli_error_and_die(
                 "Sector size option must be chosen. Choose between --all, --keys, or <size>",
@@ -44,8 +64,46 @@ impl FetchCommands {
             );
         };
 
-        get_params_default(&config.client.data_dir, sizes, self.dry_run).await
+        if self.verify_only {
+            return verify(&config.client.data_dir, sizes, false).await;
+        }
+
+        let mut options = FetchOptions::from_env()?;
+        if let Some(mirror) = &self.mirror {
+            options.mirror = Some(mirror.clone());
+        }
+        options.concurrency = self.concurrency;
+        options.max_bandwidth = self.max_bandwidth;
+        get_params_default(&config.client.data_dir, sizes, self.dry_run, &options).await
+    }
+}
+
+/// Prints the status of the parameter files, failing if any of them is corrupt or missing.
+async fn verify(
+    data_dir: &std::path::Path,
+    sizes: SectorSizeOpt,
+    skip_missing: bool,
+) -> anyhow::Result<()> {
+    let mut failures = 0;
+    for (name, status) in verify_params_default(data_dir, sizes).await? {
+        match status {
+            ParamStatus::Ok => println!("ok       {name}"),
+            ParamStatus::Missing if skip_missing => {}
+            ParamStatus::Missing => {
+                failures += 1;
+                println!("missing  {name}");
+            }
+            ParamStatus::Corrupt { expected, actual } => {
+                failures += 1;
+                println!("corrupt  {name} (expected {expected}, got {actual})");
+            }
+        }
     }
+    anyhow::ensure!(
+        failures == 0,
+        "{failures} parameter files failed verification"
+    );
+    Ok(())
 }
 
 /// Converts a human readable string to a `u64` size.
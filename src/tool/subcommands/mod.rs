@@ -69,6 +69,7 @@ fn read_config(config: &Option<String>, chain: &Option<NetworkChain>) -> anyhow:
         }
         None => Config::default(),
     };
+    cfg.drand.validate()?;
 
     // Override config with chain if some
     match chain {
@@ -2,6 +2,7 @@
 // SPDX-License-Identifier: Apache-2.0, MIT
 
 use super::*;
+use crate::beacon::BeaconSchedule;
 use crate::blocks::Tipset;
 use crate::chain::index::{ChainIndex, ResolveNullTipset};
 use crate::cid_collections::CidHashSet;
@@ -12,21 +13,29 @@ use crate::db::car::{AnyCar, ManyCar};
 use crate::interpreter::{MessageCallbackCtx, VMTrace};
 use crate::ipld::recurse_links_hash;
 use crate::networks::{calibnet, mainnet, ChainConfig, NetworkChain};
-use crate::shim::address::CurrentNetwork;
+use crate::shim::address::{Address, CurrentNetwork};
 use crate::shim::clock::ChainEpoch;
 use crate::shim::fvm_shared_latest::address::Network;
-use crate::shim::machine::MultiEngine;
-use crate::state_manager::apply_block_messages;
+use crate::shim::machine::{BuiltinActorManifest, MultiEngine};
+use crate::shim::state_tree::StateTree;
+use crate::state_manager::{apply_block_messages, StateMismatch};
 use crate::utils::db::car_stream::CarStream;
+use crate::utils::io::{write_atomically, EitherMmapOrRandomAccessFile};
 use crate::utils::proofs_api::paramfetch::ensure_params_downloaded;
+use crate::utils::reqwest_resume::DEFAULT_CONNECTIONS;
+use ahash::HashMap;
 use anyhow::{bail, Context as _};
 use cid::Cid;
 use clap::Subcommand;
 use dialoguer::{theme::ColorfulTheme, Confirm};
 use futures::TryStreamExt;
 use fvm_ipld_blockstore::Blockstore;
+use fvm_ipld_encoding::CborStore as _;
 use indicatif::{ProgressBar, ProgressStyle};
-use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
@@ -40,9 +49,21 @@ pub enum SnapshotCommands {
         /// Network chain the snapshot will belong to
         #[arg(long, default_value_t = NetworkChain::Mainnet)]
         chain: NetworkChain,
-        /// Vendor to fetch the snapshot from
-        #[arg(short, long, value_enum, default_value_t = snapshot::TrustedVendor::default())]
-        vendor: snapshot::TrustedVendor,
+        /// Vendors to fetch the snapshot from, in order of preference. Either `forest`,
+        /// `filops` or a vendor of the configuration file. Defaults to the vendors of the
+        /// configuration file followed by `forest` and `filops`
+        #[arg(short, long)]
+        vendor: Vec<String>,
+        /// Fetch the snapshot at this epoch instead of the latest one
+        #[arg(long)]
+        epoch: Option<ChainEpoch>,
+        /// Number of parallel connections used to download the snapshot when the server accepts
+        /// byte ranges
+        #[arg(long, default_value_t = DEFAULT_CONNECTIONS)]
+        connections: usize,
+        /// Optional TOML file containing forest daemon configuration
+        #[arg(short, long)]
+        config: Option<String>,
     },
 
     /// Validates the snapshot.
@@ -57,6 +78,16 @@ pub enum SnapshotCommands {
         /// Number of recent epochs to scan for bad messages/transactions
         #[arg(long, default_value_t = 60)]
         check_stateroots: u32,
+        /// File recording the progress of the state root validation, to resume
+        /// it if interrupted. Defaults to the name of the first snapshot file
+        /// with a `.validation.json` suffix, in the `snapshot_validation`
+        /// directory of the default data directory.
+        #[arg(long)]
+        progress_file: Option<PathBuf>,
+        /// Write a JSON report of the mismatching state roots and receipts to
+        /// this file
+        #[arg(long)]
+        report: Option<PathBuf>,
         /// Path to a snapshot CAR, which may be zstd compressed
         #[arg(required = true)]
         snapshot_files: Vec<PathBuf>,
@@ -108,6 +139,30 @@ pub enum SnapshotCommands {
         #[arg(long)]
         json: bool,
     },
+    /// Executes the messages of a range of epochs and profiles the gas they use, by actor code,
+    /// method number and gas charge.
+    ///
+    /// Writes the gas of each call stack as folded stacks, which can be rendered with e.g.
+    /// `inferno-flamegraph` or `flamegraph.pl`, and the top gas consumers as CSV.
+    Profile {
+        /// Path to a snapshot CAR, which may be zstd compressed
+        snapshot: PathBuf,
+        /// First epoch to execute the messages of
+        #[arg(long)]
+        from: ChainEpoch,
+        /// Last epoch to execute the messages of
+        #[arg(long)]
+        to: ChainEpoch,
+        /// Write the folded stacks to this file
+        #[arg(long, default_value = "gas.folded")]
+        folded: PathBuf,
+        /// Write the top gas consumers to this CSV file
+        #[arg(long, default_value = "gas.csv")]
+        csv: PathBuf,
+        /// Number of gas consumers in the CSV file
+        #[arg(long, default_value_t = 100)]
+        top: usize,
+    },
 }
 
 impl SnapshotCommands {
@@ -117,7 +172,16 @@ impl SnapshotCommands {
                 directory,
                 chain,
                 vendor,
-            } => match snapshot::fetch(&directory, &chain, vendor).await {
+                epoch,
+                connections,
+                config,
+            } => match async {
+                let config = read_config(&config, &Some(chain.clone()))?;
+                let vendors = snapshot::vendors(&vendor, &config.client.snapshot_vendors, &chain)?;
+                snapshot::fetch(&directory, &chain, &vendors, epoch, connections).await
+            }
+            .await
+            {
                 Ok(out) => {
                     println!("{}", out.display());
                     Ok(())
@@ -128,8 +192,26 @@ impl SnapshotCommands {
                 check_links,
                 check_network,
                 check_stateroots,
+                progress_file,
+                report,
                 snapshot_files,
             } => {
+                let progress_file = match progress_file {
+                    Some(progress_file) => progress_file,
+                    None => {
+                        let dir = Config::default()
+                            .client
+                            .data_dir
+                            .join("snapshot_validation");
+                        std::fs::create_dir_all(&dir)?;
+                        let mut name = snapshot_files[0]
+                            .file_name()
+                            .context("Invalid snapshot file name")?
+                            .to_owned();
+                        name.push(".validation.json");
+                        dir.join(name)
+                    }
+                };
                 let store = ManyCar::try_from(snapshot_files)?;
                 validate_with_blockstore(
                     store.heaviest_tipset()?,
@@ -137,6 +219,8 @@ impl SnapshotCommands {
                     check_links,
                     check_network,
                     check_stateroots,
+                    &progress_file,
+                    report.as_deref(),
                 )
                 .await
             }
@@ -207,6 +291,14 @@ impl SnapshotCommands {
                 epoch,
                 json,
             } => print_computed_state(snapshot, epoch, json),
+            SnapshotCommands::Profile {
+                snapshot,
+                from,
+                to,
+                folded,
+                csv,
+                top,
+            } => profile_gas(snapshot, from, to, &folded, &csv, top),
         }
     }
 }
@@ -233,6 +325,8 @@ async fn validate_with_blockstore<BlockstoreT>(
     check_links: u32,
     check_network: Option<NetworkChain>,
     check_stateroots: u32,
+    progress_file: &Path,
+    report: Option<&Path>,
 ) -> anyhow::Result<()>
 where
     BlockstoreT: Blockstore + Send + Sync + 'static,
@@ -257,7 +351,15 @@ where
         let network = check_network
             .map(anyhow::Ok)
             .unwrap_or_else(|| query_network(&root, &store))?;
-        validate_stateroots(root, &store, network, check_stateroots).await?;
+        validate_stateroots(
+            root,
+            &store,
+            network,
+            check_stateroots,
+            progress_file,
+            report,
+        )
+        .await?;
     }
 
     println!("Snapshot is valid");
@@ -336,11 +438,16 @@ fn query_network(ts: &Tipset, db: impl Blockstore) -> anyhow::Result<NetworkChai
 // Note: Messages may access state-trees 900 epochs in the past. So, if a
 // snapshot has state-trees for 2000 epochs, one can only validate the messages
 // for the last 1100 epochs.
+// Validating takes hours on mainnet, so the progress is recorded in
+// `progress_file` to resume an interrupted validation. All the mismatches are
+// collected, and written to `report` before failing.
 async fn validate_stateroots<DB>(
     ts: Tipset,
     db: &Arc<DB>,
     network: NetworkChain,
     epochs: u32,
+    progress_file: &Path,
+    report: Option<&Path>,
 ) -> anyhow::Result<()>
 where
     DB: Blockstore + Send + Sync + 'static,
@@ -368,32 +475,203 @@ where
 
     let chain_index = Arc::new(ChainIndex::new(Arc::new(db.clone())));
 
-    // Prepare tipsets for validation
-    let tipsets = chain_index
-        .chain(Arc::new(ts))
-        .take_while(|tipset| tipset.epoch() >= last_epoch)
-        .inspect(|tipset| {
-            pb.set_message(format!("epoch queue: {}", tipset.epoch() - last_epoch));
-        });
+    let progress = ValidationProgress::load(progress_file, &chain_index, &ts)?;
+    if let Some((epoch, _)) = progress.validated {
+        pb.println(format!(
+            "Resuming validation below epoch {epoch} ({})",
+            progress_file.display()
+        ));
+    }
+    let resume_epoch = progress.validated.map(|(epoch, _)| epoch);
+    let tracker = ValidationTracker::new(progress_file, progress);
+
+    let tipsets = match stateroot_tipsets(db, &chain_index, &ts, last_epoch, resume_epoch) {
+        Ok(tipsets) => tipsets,
+        Err(e) => {
+            pb.abandon_with_message("❌ Missing parent state!");
+            return Err(e);
+        }
+    };
+    let tipsets = tipsets.into_iter().inspect(|tipset| {
+        pb.set_message(format!("epoch queue: {}", tipset.epoch() - last_epoch));
+    });
 
     let beacon = Arc::new(chain_config.get_beacon_schedule(genesis.timestamp()));
 
     // ProgressBar::wrap_iter believes the progress has been abandoned once the
     // iterator is consumed.
-    crate::state_manager::validate_tipsets(
+    crate::state_manager::validate_tipsets_with(
         genesis.timestamp(),
         chain_index.clone(),
         chain_config,
         beacon,
         &MultiEngine::default(),
         tipsets,
+        |index, tipset, _, mismatch| tracker.validated(index, tipset, mismatch),
     )?;
 
+    // The validation is complete, the next one starts over
+    let mismatches = tracker.into_mismatches();
+    std::fs::remove_file(progress_file).or_else(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => Ok(()),
+        _ => Err(e),
+    })?;
+    if let Some(report) = report {
+        let report_json = serde_json::json!({
+            "HeadEpoch": ts.epoch(),
+            "LastEpoch": last_epoch,
+            "Mismatches": mismatches,
+        });
+        std::fs::write(report, serde_json::to_vec_pretty(&report_json)?)
+            .with_context(|| format!("Failed to write the report to {}", report.display()))?;
+    }
+    if !mismatches.is_empty() {
+        bail!(
+            "{} tipsets have mismatching state roots or receipts",
+            mismatches.len()
+        );
+    }
+
     pb.finish_with_message("✅ verified!");
     drop(pb);
     Ok(())
 }
 
+/// Returns the tipsets from `ts` down to `last_epoch` to validate, skipping the
+/// ones validated down to `resume_epoch` included. Fails if a parent state is
+/// missing, rather than validating fewer epochs than requested.
+fn stateroot_tipsets<DB: Blockstore>(
+    db: &DB,
+    chain_index: &ChainIndex<Arc<DB>>,
+    ts: &Tipset,
+    last_epoch: ChainEpoch,
+    resume_epoch: Option<ChainEpoch>,
+) -> anyhow::Result<Vec<Arc<Tipset>>> {
+    let tipsets = chain_index
+        .chain(Arc::new(ts.clone()))
+        .take_while(|tipset| tipset.epoch() >= last_epoch)
+        .skip_while(|tipset| resume_epoch.is_some_and(|epoch| tipset.epoch() >= epoch))
+        .collect::<Vec<_>>();
+    if let Some(tipset) = tipsets
+        .iter()
+        .find(|tipset| !db.has(tipset.parent_state()).unwrap_or(false))
+    {
+        bail!(
+            "The parent state of epoch {} is missing, state roots can only be validated above \
+             this epoch: lower --check-stateroots to at most {}",
+            tipset.epoch(),
+            (ts.epoch() - tipset.epoch() - 1).max(0)
+        );
+    }
+    Ok(tipsets)
+}
+
+/// The progress of a state root validation, going down from the heaviest
+/// tipset of the snapshot.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ValidationProgress {
+    /// Epoch and parent state root of the last tipset validated, all the
+    /// tipsets above it being validated too.
+    validated: Option<(ChainEpoch, Cid)>,
+    mismatches: Vec<StateMismatch>,
+}
+
+impl ValidationProgress {
+    /// Loads the progress from `path`, if it matches the chain being
+    /// validated.
+    fn load<DB: Blockstore>(
+        path: &Path,
+        chain_index: &ChainIndex<Arc<DB>>,
+        head: &Tipset,
+    ) -> anyhow::Result<Self> {
+        let progress: Self = match std::fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .with_context(|| format!("Invalid progress file {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e.into()),
+        };
+        if let Some((epoch, state_root)) = progress.validated {
+            let tipset = chain_index.tipset_by_height(
+                epoch,
+                Arc::new(head.clone()),
+                ResolveNullTipset::TakeOlder,
+            )?;
+            if tipset.epoch() != epoch || tipset.parent_state() != &state_root {
+                tracing::warn!(
+                    "Progress file {} belongs to another chain, starting over",
+                    path.display()
+                );
+                return Ok(Self::default());
+            }
+        }
+        Ok(progress)
+    }
+
+    fn save(&self, path: &Path) -> anyhow::Result<()> {
+        Ok(write_atomically(path, serde_json::to_vec(self)?)?)
+    }
+}
+
+/// Tracks the tipsets validated concurrently, saving the progress every time
+/// all the tipsets above a validated one are validated too.
+struct ValidationTracker<'a> {
+    path: &'a Path,
+    state: parking_lot::Mutex<ValidationTrackerState>,
+}
+
+struct ValidationTrackerState {
+    progress: ValidationProgress,
+    /// Validated tipsets by index, with tipsets above them not validated yet
+    done: BTreeMap<usize, (ChainEpoch, Cid)>,
+    /// Index of the first tipset not validated yet
+    next: usize,
+}
+
+impl<'a> ValidationTracker<'a> {
+    fn new(path: &'a Path, progress: ValidationProgress) -> Self {
+        Self {
+            path,
+            state: parking_lot::Mutex::new(ValidationTrackerState {
+                progress,
+                done: BTreeMap::new(),
+                next: 0,
+            }),
+        }
+    }
+
+    /// Records the validation of the tipset at `index` in the chain of
+    /// tipsets validated.
+    fn validated(
+        &self,
+        index: usize,
+        tipset: &Tipset,
+        mismatch: Option<StateMismatch>,
+    ) -> anyhow::Result<()> {
+        let mut state = self.state.lock();
+        let state = &mut *state;
+        state.progress.mismatches.extend(mismatch);
+        state
+            .done
+            .insert(index, (tipset.epoch(), *tipset.parent_state()));
+        let mut advanced = false;
+        while let Some(validated) = state.done.remove(&state.next) {
+            state.progress.validated = Some(validated);
+            state.next += 1;
+            advanced = true;
+        }
+        if advanced {
+            state.progress.save(self.path)?;
+        }
+        Ok(())
+    }
+
+    fn into_mismatches(self) -> Vec<StateMismatch> {
+        let mut mismatches = self.state.into_inner().progress.mismatches;
+        mismatches.sort_by_key(|mismatch| -mismatch.epoch);
+        mismatches
+    }
+}
+
 fn validation_spinner(prefix: &'static str) -> indicatif::ProgressBar {
     let pb = indicatif::ProgressBar::new_spinner()
         .with_style(
@@ -405,34 +683,105 @@ fn validation_spinner(prefix: &'static str) -> indicatif::ProgressBar {
     pb
 }
 
-fn print_computed_state(snapshot: PathBuf, epoch: ChainEpoch, json: bool) -> anyhow::Result<()> {
-    // Initialize Blockstore
-    let store = Arc::new(AnyCar::try_from(snapshot.as_path())?);
+/// A snapshot, with what executing the messages of its tipsets requires.
+struct SnapshotChain {
+    store: Arc<AnyCar<EitherMmapOrRandomAccessFile>>,
+    head: Arc<Tipset>,
+    genesis_timestamp: u64,
+    chain_index: Arc<ChainIndex<Arc<AnyCar<EitherMmapOrRandomAccessFile>>>>,
+    chain_config: Arc<ChainConfig>,
+    beacon: Arc<BeaconSchedule>,
+}
+
+impl SnapshotChain {
+    fn open(snapshot: &Path) -> anyhow::Result<Self> {
+        let store = Arc::new(AnyCar::try_from(snapshot)?);
+        let head = store.heaviest_tipset()?;
+
+        let genesis = head.genesis(&store)?;
+        let network = NetworkChain::from_genesis_or_devnet_placeholder(genesis.cid());
 
-    // Prepare call to apply_block_messages
-    let ts = store.heaviest_tipset()?;
+        let genesis_timestamp = genesis.timestamp();
+        let chain_config = ChainConfig::from_chain(&network);
+        if chain_config.is_testnet() {
+            CurrentNetwork::set_global(Network::Testnet);
+        }
+        let beacon = Arc::new(chain_config.get_beacon_schedule(genesis_timestamp));
+        Ok(SnapshotChain {
+            chain_index: Arc::new(ChainIndex::new(Arc::clone(&store))),
+            store,
+            head: Arc::new(head),
+            genesis_timestamp,
+            chain_config: Arc::new(chain_config),
+            beacon,
+        })
+    }
 
-    let genesis = ts.genesis(&store)?;
-    let network = NetworkChain::from_genesis_or_devnet_placeholder(genesis.cid());
+    fn tipset_by_height(&self, epoch: ChainEpoch) -> anyhow::Result<Arc<Tipset>> {
+        self.chain_index
+            .tipset_by_height(epoch, Arc::clone(&self.head), ResolveNullTipset::TakeOlder)
+            .context(format!("couldn't get a tipset at height {}", epoch))
+    }
 
-    let timestamp = genesis.timestamp();
-    let chain_index = ChainIndex::new(Arc::clone(&store));
-    let chain_config = ChainConfig::from_chain(&network);
-    if chain_config.is_testnet() {
-        CurrentNetwork::set_global(Network::Testnet);
+    fn ensure_parent_state(&self, tipset: &Tipset) -> anyhow::Result<()> {
+        ensure_parent_state(self.store.as_ref(), &self.chain_index, &self.head, tipset)
     }
-    let beacon = Arc::new(chain_config.get_beacon_schedule(timestamp));
-    let tipset = chain_index
-        .tipset_by_height(epoch, Arc::new(ts), ResolveNullTipset::TakeOlder)
-        .context(format!("couldn't get a tipset at height {}", epoch))?;
+
+    fn apply_block_messages(
+        &self,
+        engine: &MultiEngine,
+        tipset: Arc<Tipset>,
+        callback: Option<impl FnMut(&MessageCallbackCtx) -> anyhow::Result<()>>,
+        enable_tracing: VMTrace,
+    ) -> anyhow::Result<Cid> {
+        let (state_root, _) = apply_block_messages(
+            self.genesis_timestamp,
+            Arc::clone(&self.chain_index),
+            Arc::clone(&self.chain_config),
+            Arc::clone(&self.beacon),
+            engine,
+            tipset,
+            callback,
+            enable_tracing,
+        )?;
+        Ok(state_root)
+    }
+}
+
+/// Fails unless the parent state of `tipset`, which its messages are applied to, is in `store`.
+/// The error reports the earliest epoch below `head` whose messages can be applied.
+fn ensure_parent_state<DB: Blockstore>(
+    store: &DB,
+    chain_index: &ChainIndex<Arc<DB>>,
+    head: &Arc<Tipset>,
+    tipset: &Tipset,
+) -> anyhow::Result<()> {
+    if store.has(tipset.parent_state())? {
+        return Ok(());
+    }
+    let earliest = chain_index
+        .chain(Arc::clone(head))
+        .take_while(|tipset| store.has(tipset.parent_state()).unwrap_or(false))
+        .last();
+    match earliest {
+        Some(earliest) => bail!(
+            "the state at epoch {} isn't in the snapshot, \
+             the earliest epoch that can be executed is {}",
+            tipset.epoch(),
+            earliest.epoch()
+        ),
+        None => bail!("the snapshot has no state its tipsets can be executed against"),
+    }
+}
+
+fn print_computed_state(snapshot: PathBuf, epoch: ChainEpoch, json: bool) -> anyhow::Result<()> {
+    let chain = SnapshotChain::open(&snapshot)?;
+    let tipset = chain.tipset_by_height(epoch)?;
+    chain.ensure_parent_state(&tipset)?;
 
     let mut message_calls = vec![];
 
-    let (state_root, _) = apply_block_messages(
-        timestamp,
-        Arc::new(chain_index),
-        Arc::new(chain_config),
-        beacon,
+    let state_root = chain.apply_block_messages(
         &MultiEngine::default(),
         tipset,
         Some(|ctx: &MessageCallbackCtx| {
@@ -454,10 +803,113 @@ fn print_computed_state(snapshot: PathBuf, epoch: ChainEpoch, json: bool) -> any
     Ok(())
 }
 
+/// Executes the messages of the tipsets from epoch `from` to epoch `to`, and writes the gas they
+/// use as folded stacks to `folded` and the `top` gas consumers to `csv`.
+fn profile_gas(
+    snapshot: PathBuf,
+    from: ChainEpoch,
+    to: ChainEpoch,
+    folded: &Path,
+    csv: &Path,
+    top: usize,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(from <= to, "--from must not be after --to");
+    let chain = SnapshotChain::open(&snapshot)?;
+    let last = chain.tipset_by_height(to)?;
+    let mut tipsets = chain
+        .chain_index
+        .chain(last)
+        .take_while(|tipset| tipset.epoch() >= from)
+        .collect::<Vec<_>>();
+    tipsets.reverse();
+    // The messages of each tipset are applied to its parent state. The computed states are kept
+    // in the store, so only the parent state of the first tipset has to be in the snapshot, the
+    // later ones are computed by the previous iterations.
+    let first = tipsets.first().context("no tipsets in the epoch range")?;
+    chain.ensure_parent_state(first)?;
+
+    let store = &chain.store;
+    let engine = MultiEngine::default();
+    let mut profile = structured::GasProfile::default();
+    let pb = ProgressBar::new(tipsets.len() as u64).with_style(
+        ProgressStyle::with_template("{bar} {pos}/{len} tipsets, eta: {eta}").expect("infallible"),
+    );
+    for tipset in tipsets {
+        let mut traces = vec![];
+        let state_root = chain.apply_block_messages(
+            &engine,
+            tipset,
+            Some(|ctx: &MessageCallbackCtx| {
+                traces.push(ctx.apply_ret.exec_trace());
+                anyhow::Ok(())
+            }),
+            VMTrace::Traced,
+        )?;
+
+        // Resolve the actors against the resulting state, which includes the actors created by
+        // the messages
+        let state_tree = StateTree::new_from_root(Arc::clone(store), &state_root)?;
+        let code_names = builtin_actor_names(store, &state_tree)?;
+        let mut actor_names = HashMap::default();
+        let mut actor_name = |address: &Address| {
+            actor_names
+                .entry(*address)
+                .or_insert_with(|| {
+                    state_tree
+                        .get_actor(address)
+                        .ok()
+                        .flatten()
+                        .and_then(|actor| code_names.get(&actor.code).copied())
+                        .unwrap_or("unknown")
+                })
+                .to_string()
+        };
+        for trace in traces {
+            profile.add(trace, &mut actor_name)?;
+        }
+        pb.inc(1);
+    }
+    pb.finish_and_clear();
+
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(folded)?);
+    profile.write_folded(&mut writer)?;
+    writer.flush()?;
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(csv)?);
+    profile.write_csv(&mut writer, top)?;
+    writer.flush()?;
+    println!(
+        "Wrote the folded stacks to {} and the top gas consumers to {}",
+        folded.display(),
+        csv.display()
+    );
+    Ok(())
+}
+
+/// Names of the code CIDs of the built-in actors of a state tree.
+fn builtin_actor_names(
+    store: impl Blockstore,
+    state_tree: &StateTree<impl Blockstore>,
+) -> anyhow::Result<HashMap<Cid, &'static str>> {
+    let system_actor = state_tree
+        .get_actor(&Address::SYSTEM_ACTOR)?
+        .context("system actor not found")?;
+    // The layout of the system actor state is the same in all versions
+    let system_state = store
+        .get_cbor::<fil_actor_system_state::v11::State>(&system_actor.state)?
+        .context("system actor state not found")?;
+    let manifest = BuiltinActorManifest::load_v1_actor_list(&store, &system_state.builtin_actors)?;
+    Ok(manifest
+        .builtin_actors()
+        .map(|(actor, code)| (code, actor.name()))
+        .collect())
+}
+
 /// Parsed tree of [`fvm3::trace::ExecutionEvent`]s
 mod structured {
-    use std::collections::VecDeque;
+    use std::collections::{BTreeMap, VecDeque};
+    use std::io::Write;
 
+    use ahash::HashMap;
     use cid::Cid;
     use serde_json::json;
 
@@ -742,6 +1194,77 @@ mod structured {
         }
     }
 
+    /// Gas used by executions, aggregated by call stack and by consumer.
+    #[derive(Default)]
+    pub struct GasProfile {
+        /// Gas by folded stack: the `actor::method` frames of the calls, then the gas charge.
+        stacks: BTreeMap<String, u64>,
+        /// Gas and number of charges by actor, method number and gas charge.
+        consumers: HashMap<(String, u64, String), (u64, u64)>,
+    }
+
+    impl GasProfile {
+        /// Adds the gas charges of the trace of an execution. Called actors are named by
+        /// `actor_name`.
+        pub fn add(
+            &mut self,
+            events: Vec<ExecutionEvent>,
+            actor_name: &mut impl FnMut(&Address) -> String,
+        ) -> anyhow::Result<()> {
+            if let Some(call_tree) = parse_events(events)? {
+                self.add_call(&call_tree, &mut vec![], actor_name);
+            }
+            Ok(())
+        }
+
+        fn add_call(
+            &mut self,
+            call_tree: &CallTree,
+            stack: &mut Vec<String>,
+            actor_name: &mut impl FnMut(&Address) -> String,
+        ) {
+            let actor = actor_name(&call_tree.call.to);
+            let method_num = call_tree.call.method_num;
+            stack.push(format!("{actor}::{method_num}"));
+            for gc in &call_tree.gas_charges {
+                let gas = gc.total().round_up();
+                *self
+                    .stacks
+                    .entry(format!("{};{}", stack.join(";"), gc.name()))
+                    .or_default() += gas;
+                let (total, charges) = self
+                    .consumers
+                    .entry((actor.clone(), method_num, gc.name().to_owned()))
+                    .or_default();
+                *total += gas;
+                *charges += 1;
+            }
+            for sub_call in &call_tree.sub_calls {
+                self.add_call(sub_call, stack, actor_name);
+            }
+            stack.pop();
+        }
+
+        /// Writes the folded stacks, one `frame;frame;charge gas` line per stack.
+        pub fn write_folded(&self, mut writer: impl Write) -> std::io::Result<()> {
+            for (stack, gas) in &self.stacks {
+                writeln!(writer, "{stack} {gas}")?;
+            }
+            Ok(())
+        }
+
+        /// Writes the `top` consumers using the most gas as CSV.
+        pub fn write_csv(&self, mut writer: impl Write, top: usize) -> std::io::Result<()> {
+            let mut consumers = self.consumers.iter().collect::<Vec<_>>();
+            consumers.sort_by(|(a, (a_gas, _)), (b, (b_gas, _))| b_gas.cmp(a_gas).then(a.cmp(b)));
+            writeln!(writer, "actor,method,charge,gas,charges")?;
+            for ((actor, method_num, charge), (gas, charges)) in consumers.into_iter().take(top) {
+                writeln!(writer, "{actor},{method_num},{charge},{gas},{charges}")?;
+            }
+            Ok(())
+        }
+    }
+
     fn gas_charge_json(gc: GasCharge) -> serde_json::Value {
         json!({
             "Name": gc.name(),
@@ -769,3 +1292,193 @@ mod structured {
         UnrecognisedEvent(Box<dyn std::fmt::Debug + Send + Sync + 'static>),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocks::BlockHeader;
+    use crate::shim::address::Address;
+    use crate::utils::cid::CidCborExt;
+    use crate::utils::db::CborStoreExt as _;
+
+    fn tipset(epoch: ChainEpoch) -> Tipset {
+        BlockHeader::builder()
+            .miner_address(Address::new_id(0))
+            .epoch(epoch)
+            .state_root(Cid::from_cbor_blake2b256(&epoch).unwrap())
+            .build()
+            .unwrap()
+            .into()
+    }
+
+    #[test]
+    fn validation_tracker_saves_contiguous_progress() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snapshot.car.validation.json");
+        let tracker = ValidationTracker::new(&path, ValidationProgress::default());
+        let saved = || -> ValidationProgress {
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap()
+        };
+
+        // Tipsets are validated going down from the head, out of order
+        tracker.validated(1, &tipset(9), None).unwrap();
+        assert!(!path.exists());
+        tracker.validated(0, &tipset(10), None).unwrap();
+        assert_eq!(saved().validated.map(|(epoch, _)| epoch), Some(9));
+
+        let mismatch = StateMismatch {
+            epoch: 7,
+            expected_state: Cid::default(),
+            expected_receipt: Cid::default(),
+            actual_state: Cid::default(),
+            actual_receipt: Cid::default(),
+        };
+        tracker
+            .validated(3, &tipset(7), Some(mismatch.clone()))
+            .unwrap();
+        tracker.validated(2, &tipset(8), None).unwrap();
+        let progress = saved();
+        assert_eq!(progress.validated, Some((7, *tipset(7).parent_state())));
+        assert_eq!(progress.mismatches, vec![mismatch.clone()]);
+        assert_eq!(tracker.into_mismatches(), vec![mismatch]);
+    }
+
+    #[test]
+    fn stateroot_tipsets_require_parent_states() {
+        use crate::blocks::TipsetKeys;
+        use crate::db::MemoryDB;
+
+        // A chain of epochs 0 to 5, with the parent states of epochs 3 to 5
+        let db = Arc::new(MemoryDB::default());
+        let mut parents = TipsetKeys::default();
+        let mut head = None;
+        for epoch in 0..=5 {
+            let header = BlockHeader::builder()
+                .miner_address(Address::new_id(0))
+                .epoch(epoch)
+                .parents(parents)
+                .state_root(Cid::from_cbor_blake2b256(&(epoch - 1)).unwrap())
+                .build()
+                .unwrap();
+            crate::utils::db::CborStoreExt::put_cbor_default(&*db, &header).unwrap();
+            if epoch >= 3 {
+                crate::utils::db::CborStoreExt::put_cbor_default(&*db, &(epoch - 1)).unwrap();
+            }
+            let tipset = Tipset::from(header);
+            parents = tipset.key().clone();
+            head = Some(tipset);
+        }
+        let head = head.unwrap();
+        let chain_index = ChainIndex::new(db.clone());
+
+        let epochs = |last_epoch, resume_epoch| {
+            stateroot_tipsets(&*db, &chain_index, &head, last_epoch, resume_epoch)
+                .map(|tipsets| tipsets.iter().map(|ts| ts.epoch()).collect::<Vec<_>>())
+        };
+        assert_eq!(epochs(3, None).unwrap(), [5, 4, 3]);
+        // The resumed epoch was validated already
+        assert_eq!(epochs(3, Some(4)).unwrap(), [3]);
+        // The parent state of epoch 2 is missing
+        assert!(epochs(2, None).is_err());
+        assert!(epochs(0, Some(4)).is_err());
+    }
+
+    #[test]
+    fn parent_state_reports_earliest_executable_epoch() {
+        let db = Arc::new(crate::db::MemoryDB::default());
+        // Only the states resulting from epochs 1 and 2 are available
+        let mut chain = vec![Arc::new(tipset(0))];
+        for epoch in 1..=3 {
+            let header = BlockHeader::builder()
+                .miner_address(Address::new_id(0))
+                .parents(chain.last().unwrap().key().clone())
+                .epoch(epoch)
+                .state_root(Cid::from_cbor_blake2b256(&(epoch - 1)).unwrap())
+                .build()
+                .unwrap();
+            chain.push(Arc::new(header.into()));
+        }
+        for tipset in &chain {
+            db.put_cbor_default(tipset.min_ticket_block()).unwrap();
+        }
+        for epoch in 1..=2 {
+            db.put_cbor_default(&epoch).unwrap();
+        }
+        let chain_index = ChainIndex::new(Arc::clone(&db));
+        let head = chain.last().unwrap();
+
+        ensure_parent_state(db.as_ref(), &chain_index, head, &chain[2]).unwrap();
+        let error = ensure_parent_state(db.as_ref(), &chain_index, head, &chain[1]).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .ends_with("the earliest epoch that can be executed is 2"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn gas_profile_aggregates_calls() {
+        use crate::shim::trace::{Call, CallReturn, ExecutionEvent};
+        use fvm4::gas::{Gas, GasCharge};
+        use itertools::Either;
+
+        let charge = |name: &'static str, gas: u64| {
+            ExecutionEvent::GasCharge(GasCharge::new(name, Gas::new(gas), Gas::new(0)).into())
+        };
+        let call = |to: u64, method_num: u64| {
+            ExecutionEvent::Call(Call {
+                from: 100,
+                to: Address::new_id(to),
+                method_num,
+                params: Either::Right(None),
+                value: Default::default(),
+                gas_limit: None,
+                read_only: None,
+            })
+        };
+        let ret = || {
+            ExecutionEvent::CallReturn(CallReturn {
+                exit_code: None,
+                data: Either::Right(None),
+            })
+        };
+        // A miner method calling the reward actor, with a front loaded gas charge
+        let events = vec![
+            charge("OnChainMessage", 10),
+            call(1000, 2),
+            charge("OnMethodInvocation", 5),
+            call(2, 3),
+            charge("wasm_exec", 7),
+            ret(),
+            charge("wasm_exec", 1),
+            ret(),
+        ];
+        let mut actor_name = |address: &Address| match *address == Address::REWARD_ACTOR {
+            true => "reward".to_string(),
+            false => "storageminer".to_string(),
+        };
+        let mut profile = structured::GasProfile::default();
+        profile.add(events.clone(), &mut actor_name).unwrap();
+        profile.add(events, &mut actor_name).unwrap();
+
+        let mut folded = vec![];
+        profile.write_folded(&mut folded).unwrap();
+        assert_eq!(
+            String::from_utf8(folded).unwrap(),
+            "storageminer::2;OnChainMessage 20\n\
+             storageminer::2;OnMethodInvocation 10\n\
+             storageminer::2;reward::3;wasm_exec 14\n\
+             storageminer::2;wasm_exec 2\n"
+        );
+
+        let mut csv = vec![];
+        profile.write_csv(&mut csv, 2).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "actor,method,charge,gas,charges\n\
+             storageminer,2,OnChainMessage,20,2\n\
+             reward,3,wasm_exec,14,2\n"
+        );
+    }
+}
@@ -15,12 +15,13 @@ use serde::{Deserialize, Serialize};
 use std::io;
 use std::pin::Pin;
 use std::task::{Context, Poll};
-use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt as _, AsyncWrite, Take};
 use tokio_util::codec::Encoder;
 use tokio_util::codec::FramedRead;
 use tokio_util::either::Either;
 use unsigned_varint::codec::UviBytes;
 
+use crate::db::car::v2::{CarV2Header, CARV2_PRAGMA};
 use crate::utils::encoding::from_slice_with_fallback;
 
 #[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
@@ -71,10 +72,11 @@ impl CarBlock {
 
 pin_project! {
     /// Stream of CAR blocks. If the input data is compressed with zstd, it will
-    /// automatically be decompressed.
+    /// automatically be decompressed. The blocks of a CARv2 archive are
+    /// streamed from its CARv1 data, ignoring its index.
     pub struct CarStream<ReaderT> {
         #[pin]
-        reader: FramedRead<Either<ReaderT, ZstdDecoder<ReaderT>>, UviBytes>,
+        reader: FramedRead<Either<Take<ReaderT>, ZstdDecoder<Take<ReaderT>>>, UviBytes>,
         pub header: CarHeader,
         first_block: Option<CarBlock>,
     }
@@ -89,6 +91,24 @@ fn is_zstd(buf: &[u8]) -> bool {
 
 impl<ReaderT: AsyncBufRead + Unpin> CarStream<ReaderT> {
     pub async fn new(mut reader: ReaderT) -> io::Result<Self> {
+        let mut reader = if reader.fill_buf().await?.starts_with(&CARV2_PRAGMA) {
+            let mut header = [0; CARV2_PRAGMA.len() + CarV2Header::SIZE];
+            reader.read_exact(&mut header).await?;
+            let header = CarV2Header::from_le_bytes(
+                header[CARV2_PRAGMA.len()..].try_into().expect("infallible"),
+            );
+            let padding = header
+                .data_offset
+                .checked_sub((CARV2_PRAGMA.len() + CarV2Header::SIZE) as u64)
+                .ok_or(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid CARv2 data offset",
+                ))?;
+            tokio::io::copy(&mut (&mut reader).take(padding), &mut tokio::io::sink()).await?;
+            reader.take(header.data_size)
+        } else {
+            reader.take(u64::MAX)
+        };
         let is_compressed = is_zstd(reader.fill_buf().await?);
         let mut reader = if is_compressed {
             let mut zstd = ZstdDecoder::new(reader);
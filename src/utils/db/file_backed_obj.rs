@@ -7,6 +7,8 @@ use ahash::HashSet;
 use cid::Cid;
 use tracing::warn;
 
+use crate::utils::io::write_atomically;
+
 pub struct FileBacked<T: FileBackedObject> {
     inner: T,
     path: PathBuf,
@@ -59,7 +61,7 @@ impl<T: FileBackedObject> FileBacked<T> {
     /// Syncs the object to the file
     pub fn sync(&self) -> anyhow::Result<()> {
         let bytes = self.inner().serialize()?;
-        Ok(std::fs::write(&self.path, bytes)?)
+        Ok(write_atomically(&self.path, bytes)?)
     }
 }
 
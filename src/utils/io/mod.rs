@@ -53,6 +53,15 @@ pub fn write_to_file(message: &[u8], path: &Path, file_name: &str) -> Result<Fil
     Ok(file)
 }
 
+/// Replaces the contents of a file. They are written to a temporary file next
+/// to it first, so that an interruption never leaves a truncated file behind.
+pub fn write_atomically(path: &Path, contents: impl AsRef<[u8]>) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(&tmp, path)
+}
+
 /// Read file as a `Vec<u8>`
 pub fn read_file_to_vec(path: &Path) -> Result<Vec<u8>> {
     let mut file = File::open(path)?;
@@ -76,6 +76,12 @@ impl ProgressBar {
         }
     }
 
+    pub fn set_units(&self, units: Units) {
+        if self.display {
+            self.inner.lock().set_units(units);
+        }
+    }
+
     pub fn set(&self, i: u64) -> u64 {
         if self.display {
             self.inner.lock().set(i)
@@ -6,23 +6,37 @@ use std::{
     io::{self, copy as sync_copy, BufReader as SyncBufReader, ErrorKind},
     path::{Path, PathBuf},
     str::FromStr,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
-use crate::{shim::sector::SectorSize, utils::net::download_ipfs_file_trustlessly};
+use crate::{
+    shim::sector::SectorSize,
+    utils::net::{download_ipfs_file_trustlessly, global_http_client},
+};
 use ahash::HashMap;
+use anyhow::Context as _;
 use backoff::{future::retry, ExponentialBackoff};
 use blake2b_simd::{Hash, State as Blake2b};
 use cid::Cid;
+use futures::{StreamExt as _, TryStreamExt as _};
 use serde::{Deserialize, Serialize};
-use tokio::fs::{self};
+use tokio::{
+    fs::{self},
+    io::{AsyncRead, AsyncReadExt as _, AsyncWriteExt as _},
+};
 use tracing::{debug, error, info, warn};
+use url::Url;
 
 const GATEWAY: &str = "https://proofs.filecoin.io/ipfs/";
 const PARAM_DIR: &str = "filecoin-proof-parameters";
 const DIR_ENV: &str = "FIL_PROOFS_PARAMETER_CACHE";
 const GATEWAY_ENV: &str = "IPFS_GATEWAY";
 const TRUST_PARAMS_ENV: &str = "TRUST_PARAMS";
+const MIRROR_ENV: &str = "FOREST_PROOFS_PARAMS_MIRROR";
 const DEFAULT_PARAMETERS: &str = include_str!("./parameters.json");
 
 /// Sector size options for fetching.
@@ -35,6 +49,102 @@ pub enum SectorSizeOpt {
     Size(SectorSize),
 }
 
+/// A copy of the parameter cache to fetch the parameters from instead of the IPFS gateway.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParamsMirror {
+    /// A local directory, or a `file://` URL.
+    Directory(PathBuf),
+    /// The URL of a directory served over HTTP.
+    Http(Url),
+}
+
+impl FromStr for ParamsMirror {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match Url::parse(s) {
+            Ok(url) if url.scheme() == "file" => Ok(ParamsMirror::Directory(
+                url.to_file_path()
+                    .map_err(|_| anyhow::anyhow!("invalid file URL {url}"))?,
+            )),
+            Ok(mut url) if matches!(url.scheme(), "http" | "https") => {
+                // Make sure file names are appended to the path of the directory
+                if !url.path().ends_with('/') {
+                    url.set_path(&format!("{}/", url.path()));
+                }
+                Ok(ParamsMirror::Http(url))
+            }
+            Ok(url) => anyhow::bail!("unsupported mirror URL {url}"),
+            Err(_) => Ok(ParamsMirror::Directory(s.into())),
+        }
+    }
+}
+
+/// Options of parameter downloads.
+#[derive(Debug, Clone)]
+pub struct FetchOptions {
+    pub mirror: Option<ParamsMirror>,
+    /// Maximum number of files downloaded at once.
+    pub concurrency: usize,
+    /// Maximum combined download rate from the mirror, in bytes per second.
+    pub max_bandwidth: Option<u64>,
+}
+
+impl FetchOptions {
+    pub const DEFAULT_CONCURRENCY: usize = 4;
+
+    /// Reads the mirror from the `FOREST_PROOFS_PARAMS_MIRROR` environment variable.
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(FetchOptions {
+            mirror: match std::env::var(MIRROR_ENV) {
+                Ok(mirror) if !mirror.is_empty() => Some(
+                    mirror
+                        .parse()
+                        .with_context(|| format!("invalid {MIRROR_ENV}"))?,
+                ),
+                _ => None,
+            },
+            concurrency: Self::DEFAULT_CONCURRENCY,
+            max_bandwidth: None,
+        })
+    }
+}
+
+/// Result of the verification of a parameter file.
+#[derive(Debug, PartialEq)]
+pub enum ParamStatus {
+    Ok,
+    Missing,
+    Corrupt { expected: String, actual: String },
+}
+
+/// Limits the combined rate of the downloads sharing it.
+#[derive(Debug)]
+struct Bandwidth {
+    bytes_per_sec: u64,
+    start: Instant,
+    consumed: AtomicU64,
+}
+
+impl Bandwidth {
+    fn new(bytes_per_sec: u64) -> Self {
+        Bandwidth {
+            bytes_per_sec: bytes_per_sec.max(1),
+            start: Instant::now(),
+            consumed: AtomicU64::new(0),
+        }
+    }
+
+    /// Waits until `bytes` more can be downloaded.
+    async fn consume(&self, bytes: u64) {
+        let consumed = self.consumed.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        let due = Duration::from_secs_f64(consumed as f64 / self.bytes_per_sec as f64);
+        if let Some(wait) = due.checked_sub(self.start.elapsed()) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
 type ParameterMap = HashMap<String, ParameterData>;
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -74,7 +184,13 @@ pub async fn ensure_params_downloaded() -> anyhow::Result<()> {
     if data_dir.is_empty() {
         anyhow::bail!("Proof parameter data dir is not set");
     }
-    get_params_default(Path::new(&data_dir), SectorSizeOpt::Keys, false).await?;
+    get_params_default(
+        Path::new(&data_dir),
+        SectorSizeOpt::Keys,
+        false,
+        &FetchOptions::from_env()?,
+    )
+    .await?;
 
     Ok(())
 }
@@ -86,6 +202,7 @@ pub async fn get_params(
     param_json: &str,
     storage_size: SectorSizeOpt,
     dry_run: bool,
+    options: &FetchOptions,
 ) -> Result<(), anyhow::Error> {
     // Just print out the parameters download directory path and exit.
     if dry_run {
@@ -95,34 +212,39 @@ pub async fn get_params(
 
     fs::create_dir_all(param_dir(data_dir)).await?;
 
-    let params: ParameterMap = serde_json::from_str(param_json)?;
-    let mut tasks = Vec::with_capacity(params.len());
-
-    params
+    let bandwidth = options
+        .max_bandwidth
+        .map(|rate| Arc::new(Bandwidth::new(rate)));
+    let tasks = select_params(param_json, &storage_size)?
         .into_iter()
-        .filter(|(name, info)| match storage_size {
-            SectorSizeOpt::Keys => !name.ends_with("params"),
-            SectorSizeOpt::Size(size) => {
-                size as u64 == info.sector_size || !name.ends_with(".params")
-            }
-            SectorSizeOpt::All => true,
-        })
-        .for_each(|(name, info)| {
+        .map(|(name, info)| {
             let data_dir_clone = data_dir.to_owned();
-            tasks.push(tokio::task::spawn(async move {
-                fetch_verify_params(&data_dir_clone, &name, Arc::new(info))
-                    .await
-                    .map_err(|err| {
-                        error!("Error fetching param file {name}: {err}");
-                        err
-                    })
-            }))
+            let mirror = options.mirror.clone();
+            let bandwidth = bandwidth.clone();
+            tokio::task::spawn(async move {
+                fetch_verify_params(
+                    &data_dir_clone,
+                    &name,
+                    Arc::new(info),
+                    mirror.as_ref(),
+                    bandwidth.as_deref(),
+                )
+                .await
+                .map_err(|err| {
+                    error!("Error fetching param file {name}: {err}");
+                    err
+                })
+            })
         });
+    let results = futures::stream::iter(tasks)
+        .buffer_unordered(options.concurrency.max(1))
+        .collect::<Vec<_>>()
+        .await;
 
     let mut errors = Vec::<anyhow::Error>::new();
 
-    for t in tasks {
-        match t.await {
+    for result in results {
+        match result {
             Err(err) => errors.push(err.into()),
             Ok(Err(err)) => errors.push(err),
             _ => (),
@@ -147,14 +269,67 @@ pub async fn get_params_default(
     data_dir: &Path,
     storage_size: SectorSizeOpt,
     dry_run: bool,
+    options: &FetchOptions,
 ) -> Result<(), anyhow::Error> {
-    get_params(data_dir, DEFAULT_PARAMETERS, storage_size, dry_run).await
+    get_params(data_dir, DEFAULT_PARAMETERS, storage_size, dry_run, options).await
+}
+
+/// Hashes the parameter files of the cache selected by `storage_size`, given a parameter JSON
+/// manifest, regardless of `TRUST_PARAMS`. Returns the status of each file, sorted by name.
+pub async fn verify_params(
+    data_dir: &Path,
+    param_json: &str,
+    storage_size: SectorSizeOpt,
+) -> anyhow::Result<Vec<(String, ParamStatus)>> {
+    let mut statuses = vec![];
+    for (name, info) in select_params(param_json, &storage_size)? {
+        let path = param_dir(data_dir).join(&name);
+        let status = match file_digest(&path).await {
+            Ok(actual) if actual == info.digest => ParamStatus::Ok,
+            Ok(actual) => ParamStatus::Corrupt {
+                expected: info.digest,
+                actual,
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => ParamStatus::Missing,
+            Err(e) => return Err(e).with_context(|| format!("couldn't read {}", path.display())),
+        };
+        statuses.push((name, status));
+    }
+    statuses.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(statuses)
+}
+
+/// Same as [`verify_params`] with the default manifest.
+pub async fn verify_params_default(
+    data_dir: &Path,
+    storage_size: SectorSizeOpt,
+) -> anyhow::Result<Vec<(String, ParamStatus)>> {
+    verify_params(data_dir, DEFAULT_PARAMETERS, storage_size).await
+}
+
+fn select_params(
+    param_json: &str,
+    storage_size: &SectorSizeOpt,
+) -> anyhow::Result<Vec<(String, ParameterData)>> {
+    let params: ParameterMap = serde_json::from_str(param_json)?;
+    Ok(params
+        .into_iter()
+        .filter(|(name, info)| match storage_size {
+            SectorSizeOpt::Keys => !name.ends_with("params"),
+            SectorSizeOpt::Size(size) => {
+                *size as u64 == info.sector_size || !name.ends_with(".params")
+            }
+            SectorSizeOpt::All => true,
+        })
+        .collect())
 }
 
 async fn fetch_verify_params(
     data_dir: &Path,
     name: &str,
     info: Arc<ParameterData>,
+    mirror: Option<&ParamsMirror>,
+    bandwidth: Option<&Bandwidth>,
 ) -> Result<(), anyhow::Error> {
     let path: PathBuf = param_dir(data_dir).join(name);
 
@@ -167,7 +342,10 @@ async fn fetch_verify_params(
         }
     }
 
-    fetch_params(&path, &info).await?;
+    match mirror {
+        Some(mirror) => fetch_params_from_mirror(&path, name, mirror, bandwidth).await?,
+        None => fetch_params(&path, &info).await?,
+    }
 
     check_file(&path, &info).await?;
     Ok(())
@@ -185,25 +363,79 @@ async fn fetch_params(path: &Path, info: &ParameterData) -> anyhow::Result<()> {
     result
 }
 
+/// Copies the parameter file `name` of `mirror` to `path`.
+async fn fetch_params_from_mirror(
+    path: &Path,
+    name: &str,
+    mirror: &ParamsMirror,
+    bandwidth: Option<&Bandwidth>,
+) -> anyhow::Result<()> {
+    info!("Fetching param file {path:?} from mirror {mirror:?}");
+    match mirror {
+        ParamsMirror::Directory(dir) => {
+            let src = dir.join(name);
+            let reader = fs::File::open(&src)
+                .await
+                .with_context(|| format!("couldn't open {}", src.display()))?;
+            copy_to(reader, path, bandwidth).await
+        }
+        ParamsMirror::Http(url) => {
+            let url = url.join(name)?;
+            retry(ExponentialBackoff::default(), || async {
+                let stream = global_http_client()
+                    .get(url.clone())
+                    .send()
+                    .await
+                    .map_err(anyhow::Error::from)?
+                    .error_for_status()
+                    .map_err(|e| {
+                        // Retrying won't find a missing file
+                        match e.status() == Some(reqwest::StatusCode::NOT_FOUND) {
+                            true => backoff::Error::permanent(e.into()),
+                            false => backoff::Error::transient(e.into()),
+                        }
+                    })?
+                    .bytes_stream()
+                    .map_err(|e| io::Error::new(ErrorKind::Other, e));
+                Ok(copy_to(tokio_util::io::StreamReader::new(stream), path, bandwidth).await?)
+            })
+            .await
+        }
+    }
+}
+
+/// Writes `reader` to a temporary file, then moves it to `path`.
+async fn copy_to(
+    mut reader: impl AsyncRead + Unpin,
+    path: &Path,
+    bandwidth: Option<&Bandwidth>,
+) -> anyhow::Result<()> {
+    let tmp = tempfile::NamedTempFile::new_in(path.parent().unwrap_or_else(|| Path::new(".")))?
+        .into_temp_path();
+    let mut writer = tokio::io::BufWriter::new(fs::File::create(&tmp).await?);
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        if let Some(bandwidth) = bandwidth {
+            bandwidth.consume(n as u64).await;
+        }
+        writer.write_all(&buf[..n]).await?;
+    }
+    writer.flush().await?;
+    tmp.persist(path)?;
+    Ok(())
+}
+
 async fn check_file(path: &Path, info: &ParameterData) -> Result<(), io::Error> {
     if std::env::var(TRUST_PARAMS_ENV) == Ok("1".to_owned()) {
         warn!("Assuming parameter files are okay. Do not use in production!");
         return Ok(());
     }
 
-    let hash = tokio::task::spawn_blocking({
-        let file = SyncFile::open(path)?;
-        move || -> Result<Hash, io::Error> {
-            let mut reader = SyncBufReader::new(file);
-            let mut hasher = Blake2b::new();
-            sync_copy(&mut reader, &mut hasher)?;
-            Ok(hasher.finalize())
-        }
-    })
-    .await??;
-
-    let str_sum = hash.to_hex();
-    let str_sum = &str_sum[..32];
+    let str_sum = file_digest(path).await?;
     if str_sum == info.digest {
         debug!("Parameter file {:?} is ok", path);
         Ok(())
@@ -217,3 +449,147 @@ async fn check_file(path: &Path, info: &ParameterData) -> Result<(), io::Error>
         ))
     }
 }
+
+/// Returns the truncated BLAKE2b digest of a file, as listed in the parameter manifest.
+async fn file_digest(path: &Path) -> Result<String, io::Error> {
+    let hash = tokio::task::spawn_blocking({
+        let file = SyncFile::open(path)?;
+        move || -> Result<Hash, io::Error> {
+            let mut reader = SyncBufReader::new(file);
+            let mut hasher = Blake2b::new();
+            sync_copy(&mut reader, &mut hasher)?;
+            Ok(hasher.finalize())
+        }
+    })
+    .await??;
+
+    Ok(hash.to_hex()[..32].to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a parameter file to `mirror` and returns a manifest listing it.
+    fn mirror_manifest(mirror: &Path, name: &str, content: &[u8]) -> String {
+        std::fs::write(mirror.join(name), content).unwrap();
+        let digest = blake2b_simd::blake2b(content).to_hex()[..32].to_owned();
+        serde_json::json!({
+            name: {
+                "cid": "QmYkFNhHvRnXVn5jcAMkNvnTtfaLvkLYGjrTYoKY8yBcPj",
+                "digest": digest,
+                "sector_size": 2048,
+            }
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn parse_mirror() {
+        assert_eq!(
+            "/mnt/params".parse::<ParamsMirror>().unwrap(),
+            ParamsMirror::Directory("/mnt/params".into())
+        );
+        assert_eq!(
+            "file:///mnt/params".parse::<ParamsMirror>().unwrap(),
+            ParamsMirror::Directory("/mnt/params".into())
+        );
+        assert_eq!(
+            "http://mirror.local/params"
+                .parse::<ParamsMirror>()
+                .unwrap(),
+            ParamsMirror::Http(Url::parse("http://mirror.local/params/").unwrap())
+        );
+        "ftp://mirror.local/params"
+            .parse::<ParamsMirror>()
+            .unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn fetch_from_directory_mirror() {
+        let (data_dir, mirror) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let name = "v28-directory-mirror.vk";
+        let manifest = mirror_manifest(mirror.path(), name, b"verifying key");
+        let options = FetchOptions {
+            mirror: Some(ParamsMirror::Directory(mirror.path().into())),
+            concurrency: 1,
+            max_bandwidth: None,
+        };
+        get_params(
+            data_dir.path(),
+            &manifest,
+            SectorSizeOpt::Keys,
+            false,
+            &options,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            verify_params(data_dir.path(), &manifest, SectorSizeOpt::Keys)
+                .await
+                .unwrap(),
+            [(name.to_owned(), ParamStatus::Ok)]
+        );
+
+        std::fs::write(param_dir(data_dir.path()).join(name), b"corrupt").unwrap();
+        let statuses = verify_params(data_dir.path(), &manifest, SectorSizeOpt::Keys)
+            .await
+            .unwrap();
+        assert!(matches!(statuses[..], [(_, ParamStatus::Corrupt { .. })]));
+    }
+
+    #[tokio::test]
+    async fn fetch_from_http_mirror() {
+        let (data_dir, mirror) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let name = "v28-http-mirror.vk";
+        let content = vec![42; 256 * 1024];
+        let manifest = mirror_manifest(mirror.path(), name, &content);
+        let app = axum::Router::new().route(
+            "/params/:name",
+            axum::routing::get(move || async move { content }),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        let options = FetchOptions {
+            mirror: Some(format!("http://{addr}/params").parse().unwrap()),
+            concurrency: 1,
+            max_bandwidth: Some(1024 * 1024),
+        };
+        let start = Instant::now();
+        get_params(
+            data_dir.path(),
+            &manifest,
+            SectorSizeOpt::Keys,
+            false,
+            &options,
+        )
+        .await
+        .unwrap();
+        // 256 KiB at 1 MiB/s
+        assert!(start.elapsed() >= Duration::from_millis(250));
+        assert_eq!(
+            verify_params(data_dir.path(), &manifest, SectorSizeOpt::Keys)
+                .await
+                .unwrap(),
+            [(name.to_owned(), ParamStatus::Ok)]
+        );
+    }
+
+    #[tokio::test]
+    async fn verify_missing() {
+        let (data_dir, mirror) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let manifest = mirror_manifest(mirror.path(), "v28-missing.vk", b"verifying key");
+        assert_eq!(
+            verify_params(data_dir.path(), &manifest, SectorSizeOpt::Keys)
+                .await
+                .unwrap(),
+            [("v28-missing.vk".to_owned(), ParamStatus::Missing)]
+        );
+    }
+}
@@ -10,6 +10,10 @@
 //! Some modifications have been done to update the code regarding `tokio`,
 //! replace the `hyperx` dependency with `hyper` and add two unit tests.
 
+mod ranged;
+
+pub use ranged::{journal_path, RangedDownload, DEFAULT_CONNECTIONS};
+
 use bytes::Bytes;
 use futures::{ready, FutureExt as _, Stream, TryFutureExt as _};
 use hyper::header::{self, HeaderMap, HeaderValue};
@@ -47,7 +51,7 @@ impl Client {
     ///
     /// See [`reqwest::Client::get()`].
     pub fn get(&self, url: reqwest::Url) -> RequestBuilder {
-        RequestBuilder(self.0.clone(), reqwest::Method::GET, url)
+        RequestBuilder(self.0.clone(), reqwest::Method::GET, url, 0)
     }
 }
 
@@ -55,16 +59,26 @@ impl Client {
 ///
 /// See [`reqwest::RequestBuilder`].
 #[derive(Debug)]
-pub struct RequestBuilder(reqwest::Client, reqwest::Method, reqwest::Url);
+pub struct RequestBuilder(reqwest::Client, reqwest::Method, reqwest::Url, u64);
 impl RequestBuilder {
+    /// Requests the body from the byte at `pos` on. The server may ignore it and send the whole
+    /// body, see [`Response::pos`].
+    pub fn starting_at(self, pos: u64) -> Self {
+        let RequestBuilder(client, method, url, _) = self;
+        RequestBuilder(client, method, url, pos)
+    }
+
     /// Constructs the Request and sends it the target URL, returning a Response.
     ///
     /// See [`reqwest::RequestBuilder::send()`].
     pub async fn send(self) -> reqwest::Result<Response> {
-        let RequestBuilder(client, method, url) = self;
+        let RequestBuilder(client, method, url, pos) = self;
 
         let response = loop {
-            let builder = client.request(method.clone(), url.clone());
+            let mut builder = client.request(method.clone(), url.clone());
+            if pos > 0 {
+                builder = builder.header(header::RANGE, std::format!("bytes={pos}-"));
+            }
             match builder.send().await {
                 Err(err) if !err.is_builder() && !err.is_redirect() && !err.is_status() => {
                     sleep(Duration::from_secs(1)).await
@@ -77,13 +91,17 @@ impl RequestBuilder {
             .get(header::ACCEPT_RANGES)
             .map(HeaderValue::as_bytes)
             == Some(b"bytes");
+        let pos = match response.status() {
+            reqwest::StatusCode::PARTIAL_CONTENT => pos,
+            _ => 0,
+        };
         let resp = Response {
             client,
             method,
             url,
             response,
             accept_byte_ranges,
-            pos: 0,
+            pos,
         };
         Ok(resp)
     }
@@ -119,6 +137,28 @@ impl Response {
     pub fn response(&self) -> &reqwest::Response {
         &self.response
     }
+
+    /// Position in the resource of the first byte of the body.
+    pub fn pos(&self) -> u64 {
+        self.pos
+    }
+
+    /// Length of the whole resource, if known.
+    pub fn total_len(&self) -> Option<u64> {
+        match self.response.status() {
+            reqwest::StatusCode::PARTIAL_CONTENT => self
+                .response
+                .headers()
+                .get(header::CONTENT_RANGE)?
+                .to_str()
+                .ok()?
+                .rsplit_once('/')?
+                .1
+                .parse()
+                .ok(),
+            _ => self.response.content_length(),
+        }
+    }
 }
 
 struct Decoder {
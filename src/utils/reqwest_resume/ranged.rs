@@ -0,0 +1,336 @@
+// Copyright 2019-2023 ChainSafe Systems
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+//! Download of a file over multiple connections, each requesting a range of
+//! the file with the `Range` HTTP header. The completed ranges are recorded in
+//! a journal next to the file so that an interrupted download can be resumed.
+//! The file can be hashed while it is downloaded, as the downloaded chunks
+//! form a growing prefix of the file.
+
+use std::collections::BTreeSet;
+use std::io::{Read as _, Seek as _, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use anyhow::{ensure, Context as _};
+use futures::{StreamExt as _, TryStreamExt as _};
+use hyper::header::{self, HeaderValue};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use sha2::{digest::Output, Digest as _, Sha256};
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncSeekExt as _, AsyncWriteExt as _};
+use tokio::time::sleep;
+use tracing::{debug, warn};
+
+use crate::utils::io::{progress_bar::Units, write_atomically, ProgressBar};
+use crate::utils::net::global_http_client;
+
+/// Number of parallel connections of a [`RangedDownload`] by default.
+pub const DEFAULT_CONNECTIONS: usize = 4;
+
+/// Size of the ranges requested by each connection. Ranges are recorded in the
+/// journal once downloaded completely.
+const CHUNK_SIZE: u64 = 32 * 1024 * 1024;
+
+/// Attempts to download a chunk without receiving any data before giving up.
+const MAX_ATTEMPTS: usize = 5;
+
+/// A download of a file from a server accepting byte ranges.
+#[derive(Debug)]
+pub struct RangedDownload {
+    client: reqwest::Client,
+    url: reqwest::Url,
+    len: u64,
+    connections: usize,
+    pub(super) chunk_size: u64,
+}
+
+/// The chunks of a [`RangedDownload`] written to disk.
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+struct Journal {
+    url: String,
+    len: u64,
+    chunk_size: u64,
+    done: BTreeSet<u64>,
+}
+
+impl RangedDownload {
+    /// Requests `url` to find out whether the server accepts byte ranges.
+    /// Returns [`None`] if it does not, in which case the file has to be
+    /// downloaded as a single stream.
+    pub async fn probe(url: reqwest::Url, connections: usize) -> reqwest::Result<Option<Self>> {
+        let client = global_http_client();
+        // The body is never read, the connection is closed on drop
+        let response = client.get(url).send().await?.error_for_status()?;
+        let accept_byte_ranges = response
+            .headers()
+            .get(header::ACCEPT_RANGES)
+            .map(HeaderValue::as_bytes)
+            == Some(b"bytes");
+        Ok(match response.content_length() {
+            Some(len) if accept_byte_ranges && len > 0 => Some(RangedDownload {
+                url: response.url().clone(),
+                client,
+                len,
+                connections: connections.max(1),
+                chunk_size: CHUNK_SIZE,
+            }),
+            _ => None,
+        })
+    }
+
+    /// Downloads the file to `destination`, resuming from the journal of a
+    /// previous download of the same file if there is one. With `checksum`,
+    /// returns the SHA-256 hash of the file.
+    pub async fn run(
+        self,
+        destination: &Path,
+        checksum: bool,
+    ) -> anyhow::Result<Option<Output<Sha256>>> {
+        let journal_path = journal_path(destination);
+        let journal = match Journal::load(&journal_path) {
+            Some(journal)
+                if journal.url == self.url.as_str()
+                    && journal.len == self.len
+                    && journal.chunk_size == self.chunk_size
+                    && destination.exists() =>
+            {
+                debug!(
+                    "Resuming the download of {} with {} chunks done",
+                    self.url,
+                    journal.done.len()
+                );
+                journal
+            }
+            _ => Journal {
+                url: self.url.to_string(),
+                len: self.len,
+                chunk_size: self.chunk_size,
+                done: BTreeSet::new(),
+            },
+        };
+
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(journal.done.is_empty())
+            .open(destination)
+            .await
+            .with_context(|| format!("couldn't open {}", destination.display()))?;
+        file.set_len(self.len).await?;
+        drop(file);
+
+        let chunks = (0..self.len.div_ceil(self.chunk_size))
+            .filter(|chunk| !journal.done.contains(chunk))
+            .collect::<Vec<_>>();
+        let downloaded = AtomicU64::new(
+            journal
+                .done
+                .iter()
+                .map(|&chunk| self.chunk_range(chunk).1 - self.chunk_range(chunk).0)
+                .sum(),
+        );
+        let pb = ProgressBar::new(self.len);
+        pb.set_units(Units::Bytes);
+        pb.message("Downloading ");
+        pb.set(downloaded.load(Ordering::Relaxed));
+
+        // The chunks are hashed on a blocking thread as they complete, not to stall the
+        // downloads of the other chunks
+        let (done_tx, done_rx) = flume::unbounded();
+        let hashing = checksum.then(|| {
+            let mut hasher = PrefixHasher {
+                sha256: Sha256::default(),
+                next: 0,
+                done: journal.done.clone(),
+                len: self.len,
+                chunk_size: self.chunk_size,
+            };
+            let destination = destination.to_owned();
+            tokio::task::spawn_blocking(move || {
+                hasher.advance(&destination)?;
+                for chunk in done_rx {
+                    hasher.done.insert(chunk);
+                    hasher.advance(&destination)?;
+                }
+                anyhow::Ok(hasher.sha256.finalize())
+            })
+        });
+
+        let journal = Mutex::new(journal);
+        let (this, downloaded, pb) = (&self, &downloaded, &pb);
+        futures::stream::iter(chunks)
+            .map(|chunk| async move {
+                this.fetch_chunk(chunk, destination, downloaded, pb)
+                    .await
+                    .with_context(|| format!("couldn't download chunk {chunk} of {}", this.url))?;
+                Ok::<_, anyhow::Error>(chunk)
+            })
+            .buffer_unordered(self.connections)
+            .try_for_each(|chunk| {
+                let mut journal = journal.lock();
+                journal.done.insert(chunk);
+                let result = journal.save(&journal_path);
+                // The hasher only stops early on errors, returned when it is joined
+                let _ = done_tx.send(chunk);
+                futures::future::ready(result)
+            })
+            .await?;
+        drop(done_tx);
+        let sha256 = match hashing {
+            Some(hashing) => Some(hashing.await??),
+            None => None,
+        };
+        pb.finish();
+
+        std::fs::remove_file(&journal_path)?;
+        Ok(sha256)
+    }
+
+    /// Returns the start and end (exclusive) of `chunk`.
+    fn chunk_range(&self, chunk: u64) -> (u64, u64) {
+        chunk_range(self.len, self.chunk_size, chunk)
+    }
+
+    async fn fetch_chunk(
+        &self,
+        chunk: u64,
+        destination: &Path,
+        downloaded: &AtomicU64,
+        pb: &ProgressBar,
+    ) -> anyhow::Result<()> {
+        let (mut pos, end) = self.chunk_range(chunk);
+        let mut file = OpenOptions::new().write(true).open(destination).await?;
+        let mut attempts = 0;
+        while pos < end {
+            let start = pos;
+            let result = async {
+                file.seek(SeekFrom::Start(pos)).await?;
+                let response = self
+                    .client
+                    .get(self.url.clone())
+                    .header(header::RANGE, format!("bytes={pos}-{}", end - 1))
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                ensure!(
+                    response.status() == reqwest::StatusCode::PARTIAL_CONTENT,
+                    "server ignored the range request"
+                );
+                // The server may serve a shorter range, but not another one
+                let content_range = response
+                    .headers()
+                    .get(header::CONTENT_RANGE)
+                    .and_then(parse_content_range);
+                ensure!(
+                    matches!(content_range, Some((first, last, len))
+                        if first == pos && last < end && len == self.len),
+                    "server sent another range than bytes {pos}-{} of {}",
+                    end - 1,
+                    self.len
+                );
+                let mut stream = response.bytes_stream();
+                while let Some(bytes) = stream.try_next().await? {
+                    let bytes = &bytes[..bytes.len().min((end - pos) as usize)];
+                    file.write_all(bytes).await?;
+                    pos += bytes.len() as u64;
+                    pb.set(
+                        downloaded.fetch_add(bytes.len() as u64, Ordering::Relaxed)
+                            + bytes.len() as u64,
+                    );
+                }
+                Ok(())
+            }
+            .await;
+            if pos > start {
+                attempts = 0;
+            } else {
+                attempts += 1;
+            }
+            match result {
+                Err(e) if attempts >= MAX_ATTEMPTS => return Err(e),
+                Err(e) => {
+                    warn!(
+                        "Failed downloading chunk {chunk} of {}, retrying: {e}",
+                        self.url
+                    );
+                    sleep(Duration::from_secs(1)).await;
+                }
+                Ok(()) if pos < end && attempts >= MAX_ATTEMPTS => {
+                    anyhow::bail!("server closed the connection early")
+                }
+                Ok(()) => {}
+            }
+        }
+        // Make sure the chunk is written before recording it in the journal
+        file.flush().await?;
+        file.sync_data().await?;
+        Ok(())
+    }
+}
+
+/// Hashes the chunks of a download in order, as the chunks downloaded from
+/// the start of the file form a longer prefix.
+struct PrefixHasher {
+    sha256: Sha256,
+    /// The first chunk not hashed yet.
+    next: u64,
+    /// The chunks written to disk.
+    done: BTreeSet<u64>,
+    len: u64,
+    chunk_size: u64,
+}
+
+impl PrefixHasher {
+    /// Hashes the chunks following the hashed ones that are done.
+    fn advance(&mut self, destination: &Path) -> anyhow::Result<()> {
+        if !self.done.contains(&self.next) {
+            return Ok(());
+        }
+        let mut file = std::fs::File::open(destination)?;
+        while self.done.contains(&self.next) {
+            let (start, end) = chunk_range(self.len, self.chunk_size, self.next);
+            file.seek(SeekFrom::Start(start))?;
+            std::io::copy(&mut (&mut file).take(end - start), &mut self.sha256)?;
+            self.next += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the start and end (exclusive) of `chunk` of a file of `len` bytes.
+fn chunk_range(len: u64, chunk_size: u64, chunk: u64) -> (u64, u64) {
+    let start = chunk * chunk_size;
+    (start, (start + chunk_size).min(len))
+}
+
+/// Parses the first byte, last byte and length of a `Content-Range` header,
+/// e.g. `bytes 0-499/1234`.
+fn parse_content_range(value: &HeaderValue) -> Option<(u64, u64, u64)> {
+    let (range, len) = value
+        .to_str()
+        .ok()?
+        .strip_prefix("bytes ")?
+        .split_once('/')?;
+    let (first, last) = range.split_once('-')?;
+    Some((first.parse().ok()?, last.parse().ok()?, len.parse().ok()?))
+}
+
+impl Journal {
+    fn load(path: &Path) -> Option<Self> {
+        serde_json::from_slice(&std::fs::read(path).ok()?).ok()
+    }
+
+    fn save(&self, path: &Path) -> anyhow::Result<()> {
+        Ok(write_atomically(path, serde_json::to_vec(self)?)?)
+    }
+}
+
+/// The journal of the download to `destination`.
+pub fn journal_path(destination: &Path) -> PathBuf {
+    let mut path = destination.as_os_str().to_owned();
+    path.push(".journal");
+    path.into()
+}
@@ -1,16 +1,19 @@
 // Copyright 2019-2023 ChainSafe Systems
 // SPDX-License-Identifier: Apache-2.0, MIT
-use crate::utils::reqwest_resume::get;
+use crate::utils::reqwest_resume::{get, journal_path, RangedDownload};
 use bytes::Bytes;
 use const_random::const_random;
 use futures::stream::StreamExt;
 use http_range_header::parse_range_header;
 use hyper::header::{self, HeaderValue};
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Request, Response, Server};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use sha2::{Digest as _, Sha256};
 use std::convert::Infallible;
 use std::net::{Ipv4Addr, SocketAddr};
 use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::time::sleep;
 
@@ -111,3 +114,166 @@ async fn test_non_resumable_get() {
     assert!(err.is_body());
     assert!(stream.next().await.is_none());
 }
+
+/// Serves `RANDOM_BYTES` whole, or the requested range of it with at most `CHUNK_LEN` bytes sent
+/// before aborting the connection. Returns the address of the server and the number of range
+/// requests it received.
+async fn create_ranged_server(accept_ranges: bool) -> (SocketAddr, Arc<AtomicUsize>) {
+    let range_requests = Arc::new(AtomicUsize::new(0));
+    let counter = range_requests.clone();
+    let make_svc = make_service_fn(move |_conn| {
+        let counter = counter.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let counter = counter.clone();
+                async move {
+                    let mut response = match req.headers().get(header::RANGE) {
+                        Some(value) if accept_ranges => {
+                            counter.fetch_add(1, Ordering::Relaxed);
+                            let range = get_range(value);
+                            let (mut sender, body) = Body::channel();
+                            let content_range = format!(
+                                "bytes {}-{}/{}",
+                                range.start,
+                                range.end - 1,
+                                RANDOM_BYTES.len()
+                            );
+                            tokio::task::spawn(async move {
+                                let mut subset: Bytes = RANDOM_BYTES[range.clone()].into();
+                                subset.truncate(CHUNK_LEN);
+                                sender.send_data(subset).await.unwrap();
+                                if range.len() > CHUNK_LEN {
+                                    sleep(Duration::from_millis(100)).await;
+                                    sender.abort();
+                                }
+                            });
+                            let mut response = Response::new(body);
+                            *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+                            response.headers_mut().insert(
+                                header::CONTENT_RANGE,
+                                HeaderValue::from_str(&content_range).unwrap(),
+                            );
+                            response
+                        }
+                        _ => Response::new(Body::from(RANDOM_BYTES.as_slice())),
+                    };
+                    if accept_ranges {
+                        response
+                            .headers_mut()
+                            .insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+                    }
+                    Ok::<_, Infallible>(response)
+                }
+            }))
+        }
+    });
+
+    let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0 /* OS-assigned */);
+
+    let server = Server::bind(&addr).serve(make_svc);
+    let addr = server.local_addr();
+
+    tokio::task::spawn(server);
+    (addr, range_requests)
+}
+
+#[tokio::test]
+async fn test_ranged_download() {
+    let (addr, range_requests) = create_ranged_server(true).await;
+    let url = reqwest::Url::parse(&format!("http://{addr}")).unwrap();
+    let mut download = RangedDownload::probe(url, 2).await.unwrap().unwrap();
+    download.chunk_size = 3000;
+
+    let directory = tempfile::tempdir().unwrap();
+    let destination = directory.path().join("download");
+    let sha256 = download.run(&destination, true).await.unwrap();
+
+    assert_eq!(std::fs::read(&destination).unwrap(), RANDOM_BYTES);
+    assert_eq!(sha256, Some(Sha256::digest(RANDOM_BYTES)));
+    assert!(!journal_path(&destination).exists());
+    // Each of the 3 chunks is larger than `CHUNK_LEN` so it's resumed once
+    assert_eq!(range_requests.load(Ordering::Relaxed), 6);
+}
+
+#[tokio::test]
+async fn test_ranged_download_resume() {
+    let (addr, range_requests) = create_ranged_server(true).await;
+    let url = reqwest::Url::parse(&format!("http://{addr}")).unwrap();
+    let mut download = RangedDownload::probe(url.clone(), 2)
+        .await
+        .unwrap()
+        .unwrap();
+    download.chunk_size = CHUNK_LEN as u64;
+
+    // A previous download got the first and last chunks
+    let directory = tempfile::tempdir().unwrap();
+    let destination = directory.path().join("download");
+    let mut partial = RANDOM_BYTES;
+    partial[CHUNK_LEN..3 * CHUNK_LEN].fill(0);
+    std::fs::write(&destination, partial).unwrap();
+    std::fs::write(
+        journal_path(&destination),
+        serde_json::to_vec(&serde_json::json!({
+            "url": url.as_str(),
+            "len": RANDOM_BYTES.len(),
+            "chunk_size": CHUNK_LEN,
+            "done": [0, 3],
+        }))
+        .unwrap(),
+    )
+    .unwrap();
+    // The resumed chunks are hashed too
+    let sha256 = download.run(&destination, true).await.unwrap();
+
+    assert_eq!(std::fs::read(&destination).unwrap(), RANDOM_BYTES);
+    assert_eq!(sha256, Some(Sha256::digest(RANDOM_BYTES)));
+    assert_eq!(range_requests.load(Ordering::Relaxed), 2);
+}
+
+/// Serves all of `RANDOM_BYTES` as partial content, whatever the requested range.
+async fn create_misranged_server() -> SocketAddr {
+    let make_svc = make_service_fn(|_conn| async {
+        Ok::<_, Infallible>(service_fn(|_req: Request<Body>| async {
+            let mut response = Response::new(Body::from(RANDOM_BYTES.as_slice()));
+            *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+            let headers = response.headers_mut();
+            headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+            headers.insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&format!(
+                    "bytes 0-{}/{}",
+                    RANDOM_BYTES.len() - 1,
+                    RANDOM_BYTES.len()
+                ))
+                .unwrap(),
+            );
+            Ok::<_, Infallible>(response)
+        }))
+    });
+    let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0 /* OS-assigned */);
+    let server = Server::bind(&addr).serve(make_svc);
+    let addr = server.local_addr();
+    tokio::task::spawn(server);
+    addr
+}
+
+#[tokio::test]
+async fn test_ranged_download_wrong_range() {
+    let addr = create_misranged_server().await;
+    let url = reqwest::Url::parse(&format!("http://{addr}")).unwrap();
+    let mut download = RangedDownload::probe(url, 2).await.unwrap().unwrap();
+    download.chunk_size = 3000;
+
+    let directory = tempfile::tempdir().unwrap();
+    let destination = directory.path().join("download");
+    assert!(download.run(&destination, false).await.is_err());
+    // No chunk is recorded as done
+    assert!(!journal_path(&destination).exists());
+}
+
+#[tokio::test]
+async fn test_ranged_download_unsupported() {
+    let (addr, _) = create_ranged_server(false).await;
+    let url = reqwest::Url::parse(&format!("http://{addr}")).unwrap();
+    assert!(RangedDownload::probe(url, 2).await.unwrap().is_none());
+}